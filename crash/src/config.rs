@@ -0,0 +1,897 @@
+// Defines the TOML configuration file of crash
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use grid::access_log::{AccessLog, AccessLogFormat};
+use grid::admission::RateLimit;
+use grid::auth::{AccessControl, AclRule, TokenVerifier};
+use grid::ca::{load_certificates, load_crls};
+use grid::client::tls_config;
+use grid::definitions::{GridBlock, GRID_DEFAULT_PORT, string_to_domain};
+use grid::server::{
+    CertificateStore,
+    GridHandler,
+    GridServer,
+    NotFoundHandler,
+    RequestContext,
+    GRID_MAX_REQUEST_SIZE,
+    GRID_WORKER_QUEUE_SIZE
+};
+use grid::identity::{default_state_dir, load_or_create_identity};
+use grid::storage::UploadLimits;
+
+use crate::proxy::{DEFAULT_HEALTH_INTERVAL, DEFAULT_POOL_SIZE, DEFAULT_UPSTREAM_TIMEOUT, Proxy, ProxyRule, Upstream};
+use crate::static_files::StaticFiles;
+
+
+/// Default number of seconds a connection may stay idle
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 300;
+
+/// Default number of seconds pending responses get to finish on shutdown
+pub const DEFAULT_DRAIN_TIMEOUT: u64 = 10;
+
+/// Default number of requests a client may send in a burst when rate limited
+pub const DEFAULT_RATE_BURST: u32 = 20;
+
+/// Default number of threads request handlers run on
+pub const DEFAULT_WORKER_THREADS: usize = 4;
+
+
+/// structure defining the whole configuration file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to listen on, e.g. `"0.0.0.0:7500"`
+    #[serde(default = "default_listen")]
+    pub listen: Vec<String>,
+    /// Address serving Prometheus-style metrics over HTTP, e.g. `"127.0.0.1:9464"`. Off if unset
+    pub metrics: Option<String>,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub workers: Workers,
+    #[serde(default)]
+    pub logging: Logging,
+    #[serde(default)]
+    pub access: Access,
+    /// Client certificate checking, off unless the table is present
+    pub client_auth: Option<ClientAuth>,
+    /// Bearer tokens and per-path rules, off unless the table is present
+    pub authorization: Option<Authorization>,
+    /// Virtual hosts, each with its own certificate and document root
+    #[serde(default, rename = "host")]
+    pub hosts: Vec<Host>,
+    /// Requests forwarded to upstream servers instead of being answered by the hosts
+    #[serde(default, rename = "proxy")]
+    pub proxies: Vec<ProxyRoute>,
+    /// Directory generated self-signed identities are kept in
+    pub state_dir: Option<PathBuf>,
+
+    /// Directory relative paths in the file are resolved against
+    #[serde(skip)]
+    base_dir: PathBuf
+}
+
+/// structure defining the `[limits]` table
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct Limits {
+    /// Largest request frame accepted, in bytes
+    pub max_request_size: usize,
+    /// Seconds a connection may stay silent before it is closed. 0 disables the timeout
+    pub idle_timeout: u64,
+    /// Seconds pending responses get to finish on SIGINT/SIGTERM before connections are cut
+    pub drain_timeout: u64,
+    /// Connections open at once. 0 disables the limit
+    pub max_connections: usize,
+    /// Unanswered requests allowed per connection. 0 disables the limit
+    pub max_in_flight: usize,
+    /// Requests per second allowed per client address. 0 disables the limit
+    pub ip_rate: f64,
+    /// Requests a client address may send in a burst
+    pub ip_burst: u32,
+    /// Requests per second allowed per client certificate. 0 disables the limit
+    pub certificate_rate: f64,
+    /// Requests a client certificate may send in a burst
+    pub certificate_burst: u32
+}
+
+/// structure defining the `[workers]` table
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct Workers {
+    /// Threads request handlers run on. 0 runs them on the network thread
+    pub threads: usize,
+    /// Requests that may wait for a thread before clients stop being read from
+    pub queue: usize
+}
+
+/// structure defining the `[logging]` table
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct Logging {
+    /// One of `error`, `warn`, `info`, `debug` or `trace`
+    pub level: String,
+    /// Either `text` or `json`
+    pub format: String,
+    /// Where one line per request goes: `stdout`, `off`, or a file reopened on SIGHUP
+    pub access_log: String,
+    /// Either `text` or `json`
+    pub access_format: String
+}
+
+/// structure defining the `[access]` table
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct Access {
+    /// CIDR ranges allowed to connect. Empty allows everyone not denied
+    pub allow: Vec<String>,
+    /// CIDR ranges refused even if allowed
+    pub deny: Vec<String>
+}
+
+/// structure defining the `[client_auth]` table
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuth {
+    /// PEM file with the CA certificates client certificates must chain up to
+    pub ca: PathBuf,
+    /// PEM file with revocation lists issued by those CAs
+    pub crl: Option<PathBuf>,
+    /// Whether clients without a certificate are turned away
+    #[serde(default = "default_required")]
+    pub required: bool
+}
+
+/// structure defining the `[authorization]` table
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Authorization {
+    /// File listing `principal token` lines
+    pub tokens: Option<PathBuf>,
+    /// File holding the key HMAC signed tokens are checked with
+    pub hmac_key: Option<PathBuf>,
+    /// Who may read and write below which paths
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>
+}
+
+/// structure defining an `[[authorization.rule]]` entry
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Path the rule covers, along with everything below it
+    pub path: String,
+    /// A principal named by a token, `authenticated` for any of them or `*` for everyone
+    pub principal: String,
+    /// One of `read`, `write`, `read-write` or `none`
+    pub access: String
+}
+
+/// structure defining a `[[host]]` entry
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Host {
+    /// Names the host answers to via SNI. Wildcards like `*.example.org` are allowed, and
+    /// IP addresses end up in the subject alternative names of generated certificates
+    pub names: Vec<String>,
+    /// PEM certificate chain. A self-signed certificate is generated and persisted if absent
+    pub certificate: Option<PathBuf>,
+    /// PEM private key matching `certificate`
+    pub key: Option<PathBuf>,
+    /// Directory documents are served from
+    pub root: Option<PathBuf>,
+    /// Whether this host answers clients that ask for no known name
+    #[serde(default)]
+    pub default: bool,
+    /// Whether directories without an index document get a generated listing
+    #[serde(default = "default_listings")]
+    pub listings: bool,
+    /// Lets clients PUT documents below `root`, off unless the table is present
+    pub uploads: Option<Uploads>
+}
+
+/// structure defining a `[[proxy]]` entry
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyRoute {
+    /// Path forwarded along with everything below it, `/` for every path
+    #[serde(default = "default_proxy_path")]
+    pub path: String,
+    /// Server names asked for via SNI to forward requests for. Empty forwards any name
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// Servers like `grid!internal.example:7500`, tried in order
+    pub upstreams: Vec<String>,
+    /// PEM certificates trusted for the upstreams besides the public roots
+    pub ca: Option<PathBuf>,
+    /// Whether the upstreams speak GRID without TLS
+    #[serde(default)]
+    pub plaintext: bool,
+    /// Idle connections kept per upstream
+    #[serde(default = "default_pool_size")]
+    pub pool_size: usize,
+    /// Seconds an upstream gets to connect and to answer
+    #[serde(default = "default_upstream_timeout")]
+    pub timeout: u64,
+    /// Seconds between health checks
+    #[serde(default = "default_health_interval")]
+    pub health_interval: u64
+}
+
+/// structure defining a `[host.uploads]` table
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Uploads {
+    /// Largest document accepted, in bytes
+    pub max_size: Option<u64>,
+    /// Bytes the document root may hold, uploads or not
+    pub quota: Option<u64>,
+    /// Bytes a single client, by certificate or token principal, may have uploaded
+    pub user_quota: Option<u64>,
+    /// Whether clients may replace documents they uploaded before
+    #[serde(default = "default_overwrite")]
+    pub overwrite: bool,
+    /// Whether clients with neither a certificate nor a token may upload, all sharing one quota
+    #[serde(default)]
+    pub anonymous: bool
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_request_size: GRID_MAX_REQUEST_SIZE,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            max_connections: 0,
+            max_in_flight: 0,
+            ip_rate: 0.0,
+            ip_burst: DEFAULT_RATE_BURST,
+            certificate_rate: 0.0,
+            certificate_burst: DEFAULT_RATE_BURST
+        }
+    }
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Workers { threads: DEFAULT_WORKER_THREADS, queue: GRID_WORKER_QUEUE_SIZE }
+    }
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            level: "info".to_string(),
+            format: "text".to_string(),
+            access_log: "stdout".to_string(),
+            access_format: "text".to_string()
+        }
+    }
+}
+
+fn default_required() -> bool {
+    true
+}
+
+fn default_overwrite() -> bool {
+    true
+}
+
+fn default_listings() -> bool {
+    true
+}
+
+fn default_proxy_path() -> String {
+    "/".to_string()
+}
+
+fn default_pool_size() -> usize {
+    DEFAULT_POOL_SIZE
+}
+
+fn default_upstream_timeout() -> u64 {
+    DEFAULT_UPSTREAM_TIMEOUT
+}
+
+fn default_health_interval() -> u64 {
+    DEFAULT_HEALTH_INTERVAL
+}
+
+fn default_listen() -> Vec<String> {
+    vec![format!("0.0.0.0:{}", GRID_DEFAULT_PORT)]
+}
+
+/// Helper function reading the `access` of a rule as whether it grants reading and writing
+fn rule_access(access: &str) -> Result<(bool, bool), String> {
+    match access {
+        "read" => Ok((true, false)),
+        "write" => Ok((false, true)),
+        "read-write" => Ok((true, true)),
+        "none" => Ok((false, false)),
+        _ => Err(format!("unknown access '{}', expected read, write, read-write or none", access))
+    }
+}
+
+/// Helper function turning a rate of 0 into no limit at all
+fn rate_limit(rate: f64, burst: u32) -> Option<RateLimit> {
+    match rate > 0.0 {
+        true => Some(RateLimit::new(rate, burst)),
+        false => None
+    }
+}
+
+
+
+/// structure defining an IP address range in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+    /// Parses `address/prefix` or a bare address
+    ///
+    /// ## Params:
+    /// * s: the string to parse
+    ///
+    /// ## Returns:
+    /// * Ok: the parsed range
+    /// * Err: a string describing the issue encountered
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None)
+        };
+        let address: IpAddr = match address.parse() {
+            Ok(a) => a,
+            Err(_) => return Err(format!("'{}' is not an IP address", address))
+        };
+
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.map(|p| p.parse::<u8>()) {
+            None => max,
+            Some(Ok(p)) if p <= max => p,
+            _ => return Err(format!("'{}' has an invalid prefix length", s))
+        };
+
+        Ok(Cidr { address, prefix })
+    }
+
+    /// Returns whether the range contains an address
+    ///
+    /// IPv4 clients of a listener on `[::]` arrive as `::ffff:a.b.c.d`, and are
+    /// matched against IPv4 ranges as the plain IPv4 address they are.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(a)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(a) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(a)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(a) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+
+
+impl Config {
+    /// Reads and validates a configuration file
+    ///
+    /// ## Params:
+    /// * path: the TOML file to read
+    ///
+    /// ## Returns:
+    /// * Ok: the validated configuration
+    /// * Err: every problem found, each naming the offending key
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Vec<String>> {
+        let path = path.as_ref();
+        let text = match fs::read_to_string(path) {
+            Ok(a) => a,
+            Err(e) => return Err(vec![format!("Cannot read {}: {}", path.display(), e)])
+        };
+
+        let mut config: Config = match toml::from_str(&text) {
+            Ok(a) => a,
+            Err(e) => return Err(vec![e.to_string()])
+        };
+        config.base_dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Builds a configuration serving a single document root, as used without `--config`
+    ///
+    /// ## Params:
+    /// * port: the port to listen on
+    /// * root: the directory to serve, if any
+    ///
+    /// ## Returns:
+    /// * the configuration
+    pub fn simple(port: u16, root: Option<PathBuf>) -> Self {
+        Config {
+            listen: vec![format!("0.0.0.0:{}", port)],
+            metrics: None,
+            limits: Limits::default(),
+            workers: Workers::default(),
+            logging: Logging::default(),
+            access: Access::default(),
+            client_auth: None,
+            authorization: None,
+            hosts: vec![Host { names: vec!["localhost".to_string()], root, default: true, listings: true, ..Host::default() }],
+            proxies: Vec::new(),
+            state_dir: None,
+            base_dir: PathBuf::new()
+        }
+    }
+
+    /// Checks the configuration for mistakes
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: nothing, the configuration is usable
+    /// * Err: every problem found, each naming the offending key
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        if self.listen.is_empty() {
+            errors.push("listen: at least one address is required".to_string());
+        }
+        for (i, address) in self.listen.iter().enumerate() {
+            if address.parse::<SocketAddr>().is_err() {
+                errors.push(format!("listen[{}]: '{}' is not a socket address like \"0.0.0.0:7500\"", i, address));
+            }
+        }
+
+        if let Some(address) = &self.metrics {
+            if address.parse::<SocketAddr>().is_err() {
+                errors.push(format!("metrics: '{}' is not a socket address like \"127.0.0.1:9464\"", address));
+            }
+        }
+
+        if self.limits.max_request_size == 0 {
+            errors.push("limits.max_request_size: must be greater than 0".to_string());
+        }
+        if self.workers.queue == 0 {
+            errors.push("workers.queue: must be greater than 0".to_string());
+        }
+        for (key, rate, burst) in [
+            ("ip", self.limits.ip_rate, self.limits.ip_burst),
+            ("certificate", self.limits.certificate_rate, self.limits.certificate_burst)
+        ] {
+            if !rate.is_finite() || rate < 0.0 {
+                errors.push(format!("limits.{}_rate: must be a number of requests per second, or 0 for no limit", key));
+            }
+            if burst == 0 {
+                errors.push(format!("limits.{}_burst: must be greater than 0", key));
+            }
+        }
+
+        if !["error", "warn", "info", "debug", "trace"].contains(&&self.logging.level[..]) {
+            errors.push(format!("logging.level: unknown level '{}', expected error, warn, info, debug or trace", self.logging.level));
+        }
+        if !["text", "json"].contains(&&self.logging.format[..]) {
+            errors.push(format!("logging.format: unknown format '{}', expected text or json", self.logging.format));
+        }
+        if let Err(e) = AccessLogFormat::from_name(&self.logging.access_format) {
+            errors.push(format!("logging.access_format: {}", e));
+        }
+        if self.logging.access_log.is_empty() {
+            errors.push("logging.access_log: expected stdout, off or a file path".to_string());
+        }
+
+        for (key, ranges) in [("access.allow", &self.access.allow), ("access.deny", &self.access.deny)] {
+            for (i, range) in ranges.iter().enumerate() {
+                if let Err(e) = Cidr::parse(range) {
+                    errors.push(format!("{}[{}]: {}", key, i, e));
+                }
+            }
+        }
+
+        if let Some(auth) = &self.client_auth {
+            if !self.resolve(&auth.ca).is_file() {
+                errors.push(format!("client_auth.ca: {} does not exist", self.resolve(&auth.ca).display()));
+            }
+            if let Some(crl) = &auth.crl {
+                if !self.resolve(crl).is_file() {
+                    errors.push(format!("client_auth.crl: {} does not exist", self.resolve(crl).display()));
+                }
+            }
+        }
+
+        if let Some(authorization) = &self.authorization {
+            for (key, file) in [("tokens", &authorization.tokens), ("hmac_key", &authorization.hmac_key)] {
+                if let Some(file) = file {
+                    if !self.resolve(file).is_file() {
+                        errors.push(format!("authorization.{}: {} does not exist", key, self.resolve(file).display()));
+                    }
+                }
+            }
+            if authorization.tokens.is_some() && authorization.hmac_key.is_some() {
+                errors.push("authorization.hmac_key: cannot be used along with tokens".to_string());
+            }
+            for (i, rule) in authorization.rules.iter().enumerate() {
+                if !rule.path.starts_with('/') {
+                    errors.push(format!("authorization.rule[{}].path: '{}' must start with /", i, rule.path));
+                }
+                if rule.principal.is_empty() {
+                    errors.push(format!("authorization.rule[{}].principal: must not be empty", i));
+                }
+                if let Err(e) = rule_access(&rule.access) {
+                    errors.push(format!("authorization.rule[{}].access: {}", i, e));
+                }
+            }
+        }
+
+        if self.hosts.iter().filter(|h| h.default).count() > 1 {
+            errors.push("host: only one host can have default = true".to_string());
+        }
+        for (i, host) in self.hosts.iter().enumerate() {
+            if host.names.is_empty() {
+                errors.push(format!("host[{}].names: at least one name is required", i));
+            }
+            match (&host.certificate, &host.key) {
+                (Some(cert), Some(key)) => {
+                    if !self.resolve(cert).is_file() {
+                        errors.push(format!("host[{}].certificate: {} does not exist", i, self.resolve(cert).display()));
+                    }
+                    if !self.resolve(key).is_file() {
+                        errors.push(format!("host[{}].key: {} does not exist", i, self.resolve(key).display()));
+                    }
+                },
+                (Some(_), None) => errors.push(format!("host[{}].key: required when certificate is set", i)),
+                (None, Some(_)) => errors.push(format!("host[{}].certificate: required when key is set", i)),
+                (None, None) => ()
+            }
+            if let Some(root) = &host.root {
+                if !self.resolve(root).is_dir() {
+                    errors.push(format!("host[{}].root: {} is not a directory", i, self.resolve(root).display()));
+                }
+            }
+            if let Some(uploads) = &host.uploads {
+                if host.root.is_none() {
+                    errors.push(format!("host[{}].uploads: needs a root to store documents in", i));
+                }
+                if uploads.max_size == Some(0) {
+                    errors.push(format!("host[{}].uploads.max_size: must be greater than 0", i));
+                }
+            }
+        }
+
+        for (i, route) in self.proxies.iter().enumerate() {
+            if !route.path.starts_with('/') {
+                errors.push(format!("proxy[{}].path: '{}' must start with /", i, route.path));
+            }
+            if route.upstreams.is_empty() {
+                errors.push(format!("proxy[{}].upstreams: at least one upstream is required", i));
+            }
+            for (j, upstream) in route.upstreams.iter().enumerate() {
+                if let Err(e) = string_to_domain(upstream) {
+                    errors.push(format!("proxy[{}].upstreams[{}]: {}", i, j, e));
+                }
+            }
+            if let Some(ca) = &route.ca {
+                if route.plaintext {
+                    errors.push(format!("proxy[{}].ca: cannot be used along with plaintext", i));
+                } else if !self.resolve(ca).is_file() {
+                    errors.push(format!("proxy[{}].ca: {} does not exist", i, self.resolve(ca).display()));
+                }
+            }
+            if route.timeout == 0 {
+                errors.push(format!("proxy[{}].timeout: must be greater than 0", i));
+            }
+            if route.health_interval == 0 {
+                errors.push(format!("proxy[{}].health_interval: must be greater than 0", i));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors)
+        }
+    }
+
+    /// Builds a server from the configuration and binds it to the listen addresses
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: a bound server, ready to run, along with the proxy rules to start checking once it does
+    /// * Err: a string describing the issue encountered
+    pub fn build_server(&self) -> Result<(GridServer, Vec<Arc<ProxyRule>>), String> {
+        let (mut server, proxies) = self.configure_server()?;
+        server.set_workers(self.workers.threads, self.workers.queue);
+        for address in &self.listen {
+            match address.parse() {
+                Ok(a) => server.bind_to(a)?,
+                Err(_) => return Err(format!("listen: '{}' is not a socket address", address))
+            }
+        }
+        if let Some(address) = &self.metrics {
+            match address.parse() {
+                Ok(a) => { server.bind_metrics(a)?; },
+                Err(_) => return Err(format!("metrics: '{}' is not a socket address", address))
+            }
+        }
+        Ok((server, proxies))
+    }
+
+    /// Opens the access log the configuration asks for
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: the access log, or `None` if it is turned off
+    /// * Err: a string describing the issue encountered
+    pub fn open_access_log(&self) -> Result<Option<Arc<AccessLog>>, String> {
+        let format = AccessLogFormat::from_name(&self.logging.access_format)?;
+        match &self.logging.access_log[..] {
+            "off" => Ok(None),
+            "stdout" => Ok(Some(Arc::new(AccessLog::stdout(format)))),
+            path => Ok(Some(Arc::new(AccessLog::file(self.resolve(Path::new(path)), format)?)))
+        }
+    }
+
+    /// Builds an unbound server from the configuration, e.g. to hand to a `ReloadHandle`
+    ///
+    /// The health checks of the proxy rules are left to the caller, so a server that
+    /// never gets installed does not leave threads checking its upstreams behind.
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: the configured server, along with the proxy rules its handlers forward with
+    /// * Err: a string describing the issue encountered
+    pub fn configure_server(&self) -> Result<(GridServer, Vec<Arc<ProxyRule>>), String> {
+        // every host shares the proxy rules, and with them the upstream connections
+        let proxies = self.proxy_rules()?;
+
+        // the default host's certificate is presented to clients asking for anything else
+        let default = self.hosts.iter().position(|h| h.default).unwrap_or(0);
+        let mut server = match self.hosts.get(default) {
+            Some(host) => {
+                let mut server = GridServer::new(0, Some(self.host_certificates(host)?))?;
+                server.set_handler(self.host_handler(Some(host), &proxies)?);
+                server
+            },
+            None => {
+                let mut server = GridServer::new(0, None)?;
+                server.set_handler(self.host_handler(None, &proxies)?);
+                server
+            }
+        };
+        for (i, host) in self.hosts.iter().enumerate() {
+            if i != default {
+                server.add_host(self.host_certificates(host)?, self.host_handler(Some(host), &proxies)?)?;
+            }
+        }
+
+        server.set_software(&format!("crash/{}", env!("CARGO_PKG_VERSION")));
+
+        // limits
+        server.set_max_request_size(self.limits.max_request_size);
+        server.set_idle_timeout(match self.limits.idle_timeout {
+            0 => None,
+            a => Some(Duration::from_secs(a))
+        });
+        server.set_max_connections(Some(self.limits.max_connections).filter(|a| *a > 0));
+        server.set_max_in_flight(Some(self.limits.max_in_flight).filter(|a| *a > 0));
+        server.set_ip_rate_limit(rate_limit(self.limits.ip_rate, self.limits.ip_burst));
+        server.set_certificate_rate_limit(rate_limit(self.limits.certificate_rate, self.limits.certificate_burst));
+
+        // client certificates
+        if let Some(auth) = &self.client_auth {
+            let roots = load_certificates(self.resolve(&auth.ca))?;
+            let crls = match &auth.crl {
+                Some(crl) => load_crls(self.resolve(crl))?,
+                None => Vec::new()
+            };
+            server.set_client_ca(&roots, crls, auth.required)?;
+        }
+
+        // tokens and path rules
+        if let Some(authorization) = &self.authorization {
+            let verifier = match (&authorization.tokens, &authorization.hmac_key) {
+                (Some(tokens), _) => Some(TokenVerifier::from_file(self.resolve(tokens))?),
+                (None, Some(key)) => match fs::read(self.resolve(key)) {
+                    Ok(a) => Some(TokenVerifier::Hmac(a.trim_ascii().to_vec())),
+                    Err(e) => return Err(format!("authorization.hmac_key: {}", e))
+                },
+                (None, None) => None
+            };
+            let mut rules = Vec::new();
+            for rule in &authorization.rules {
+                let (read, write) = rule_access(&rule.access)?;
+                rules.push(AclRule::new(&rule.path, &rule.principal, read, write));
+            }
+            server.set_access_control(Some(Arc::new(AccessControl::new(verifier, rules))));
+        }
+
+        // access control
+        let allow: Vec<Cidr> = self.access.allow.iter().filter_map(|r| Cidr::parse(r).ok()).collect();
+        let deny: Vec<Cidr> = self.access.deny.iter().filter_map(|r| Cidr::parse(r).ok()).collect();
+        if !allow.is_empty() || !deny.is_empty() {
+            server.set_peer_filter(move |address| {
+                (allow.is_empty() || allow.iter().any(|r| r.contains(address)))
+                    && !deny.iter().any(|r| r.contains(address))
+            });
+        }
+        Ok((server, proxies))
+    }
+
+    /// Returns the files the configuration pulls in, which are watched for changes
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * the certificate, key, CA, revocation list, token and upstream CA paths
+    pub fn referenced_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for host in &self.hosts {
+            files.extend(host.certificate.iter().chain(host.key.iter()).map(|p| self.resolve(p)));
+        }
+        if let Some(auth) = &self.client_auth {
+            files.push(self.resolve(&auth.ca));
+            files.extend(auth.crl.iter().map(|p| self.resolve(p)));
+        }
+        if let Some(authorization) = &self.authorization {
+            files.extend(authorization.tokens.iter().chain(authorization.hmac_key.iter()).map(|p| self.resolve(p)));
+        }
+        for route in &self.proxies {
+            files.extend(route.ca.iter().map(|p| self.resolve(p)));
+        }
+        files
+    }
+
+    /// Helper function to resolve a path relative to the configuration file
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.base_dir.join(path)
+    }
+
+    /// Helper function to load or generate the certificate of a host
+    fn host_certificates(&self, host: &Host) -> Result<CertificateStore, String> {
+        match (&host.certificate, &host.key) {
+            (Some(cert), Some(key)) => CertificateStore::from_pem_files(self.resolve(cert), self.resolve(key), host.names.clone()),
+            _ => {
+                let state_dir = match &self.state_dir {
+                    Some(dir) => self.resolve(dir),
+                    None => default_state_dir()
+                };
+                load_or_create_identity(&state_dir, host.names.clone())
+            }
+        }
+    }
+
+    /// Helper function to build the proxy rules
+    fn proxy_rules(&self) -> Result<Vec<Arc<ProxyRule>>, String> {
+        let mut rules = Vec::new();
+        for (i, route) in self.proxies.iter().enumerate() {
+            let tls = match (route.plaintext, &route.ca) {
+                (true, _) => None,
+                (false, Some(ca)) => Some(tls_config(&load_certificates(self.resolve(ca))?)?),
+                (false, None) => Some(tls_config(&[])?)
+            };
+            let timeout = Duration::from_secs(route.timeout);
+            let mut upstreams = Vec::new();
+            for upstream in &route.upstreams {
+                match Upstream::new(upstream, tls.clone(), timeout, route.pool_size) {
+                    Ok(a) => upstreams.push(a),
+                    Err(e) => return Err(format!("proxy[{}].upstreams: {}", i, e))
+                }
+            }
+            tracing::info!(path = %route.path, names = ?route.names, upstreams = ?route.upstreams, "forwarding requests");
+            rules.push(Arc::new(ProxyRule::new(&route.path, route.names.clone(), upstreams, Duration::from_secs(route.health_interval))));
+        }
+        Ok(rules)
+    }
+
+    /// Helper function to build the handler tree of a host, behind the proxy rules if there are any
+    fn host_handler(&self, host: Option<&Host>, proxies: &[Arc<ProxyRule>]) -> Result<impl GridHandler, String> {
+        let mut handler: Box<dyn GridHandler> = match host.and_then(|h| h.root.as_ref().map(|r| (h, r))) {
+            Some((host, root)) => {
+                let mut files = StaticFiles::new(self.resolve(root))?.with_listings(host.listings);
+                tracing::info!(hosts = ?host.names, root = %files.root().display(), "serving documents");
+                if let Some(uploads) = &host.uploads {
+                    let limits = UploadLimits {
+                        max_size: uploads.max_size,
+                        host_quota: uploads.quota,
+                        user_quota: uploads.user_quota,
+                        overwrite: uploads.overwrite
+                    };
+                    files = files.with_uploads(limits, uploads.anonymous);
+                }
+                Box::new(files)
+            },
+            None => Box::new(NotFoundHandler)
+        };
+        if !proxies.is_empty() {
+            handler = Box::new(Proxy::new(proxies.to_vec(), handler));
+        }
+        Ok(move |request: &GridBlock, ctx: &RequestContext| handler.handle(request, ctx))
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validation_names_offending_keys() {
+        let mut config: Config = toml::from_str(r#"
+            listen = ["0.0.0.0:7500", "nowhere"]
+            metrics = "localhost"
+
+            [limits]
+            ip_rate = -1
+            certificate_burst = 0
+
+            [logging]
+            level = "loud"
+
+            [access]
+            allow = ["10.0.0.0/8", "10.0.0.0/33"]
+
+            [[authorization.rule]]
+            path = "private"
+            principal = "alice"
+            access = "everything"
+
+            [[host]]
+            names = ["docs.example"]
+            certificate = "missing.pem"
+
+            [[proxy]]
+            path = "/api"
+            upstreams = ["grid!internal:7500", "http://internal"]
+            timeout = 0
+        "#).unwrap();
+        config.base_dir = std::env::temp_dir();
+
+        let errors = config.validate().unwrap_err();
+        for key in ["listen[1]", "metrics", "limits.ip_rate", "limits.certificate_burst", "logging.level", "access.allow[1]", "authorization.rule[0].path", "authorization.rule[0].access", "host[0].key", "proxy[0].upstreams[1]", "proxy[0].timeout"] {
+            assert!(errors.iter().any(|e| e.starts_with(key)), "no error for {}: {:?}", key, errors);
+        }
+
+        // unknown keys are rejected by the parser, which points at them
+        let e = toml::from_str::<Config>("[limits]\nmax_size = 1").unwrap_err();
+        assert!(e.to_string().contains("max_size"));
+    }
+
+    #[test]
+    fn example_configuration_is_valid() {
+        // load validates, naming every problem if there are any
+        Config::load(Path::new(env!("CARGO_MANIFEST_DIR")).join("crash.example.toml")).unwrap();
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        let range = Cidr::parse("192.168.0.0/16").unwrap();
+        assert!(range.contains("192.168.4.2".parse().unwrap()));
+        assert!(!range.contains("192.169.0.1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!(Cidr::parse("::1").unwrap().contains("::1".parse().unwrap()));
+        assert!(Cidr::parse("fe80::/10").is_ok());
+        assert!(Cidr::parse("10.0.0.0/8").unwrap().contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!Cidr::parse("10.0.0.0/8").unwrap().contains("::ffff:11.1.2.3".parse().unwrap()));
+        assert!(Cidr::parse("banana").is_err());
+    }
+}
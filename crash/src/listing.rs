@@ -0,0 +1,210 @@
+// Defines the GML listings served for directories without an index document
+use std::cmp::Ordering;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use time::OffsetDateTime;
+
+use grid::path::percent_encode_path;
+use grid::gml::GmlDocument;
+
+
+/// What a listing is sorted by, chosen with the `sort` query parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified
+}
+
+impl SortKey {
+    /// Returns the name used in queries
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified"
+        }
+    }
+}
+
+
+/// structure describing how a listing is ordered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListingOrder {
+    pub key: SortKey,
+    pub descending: bool
+}
+
+impl Default for ListingOrder {
+    fn default() -> Self {
+        ListingOrder { key: SortKey::Name, descending: false }
+    }
+}
+
+impl ListingOrder {
+    /// Reads the order from a query like `sort=size&order=desc`
+    ///
+    /// ## Params:
+    /// * query: the query of the request, if any
+    ///
+    /// ## Returns:
+    /// * Ok: the order, by ascending name unless the query says otherwise
+    /// * Err: a string naming the offending parameter
+    pub fn from_query(query: Option<&str>) -> Result<Self, String> {
+        let mut order = ListingOrder::default();
+        for pair in query.unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match (key, value) {
+                ("sort", "name") => order.key = SortKey::Name,
+                ("sort", "size") => order.key = SortKey::Size,
+                ("sort", "modified") => order.key = SortKey::Modified,
+                ("sort", _) => return Err(format!("Cannot sort by '{}', expected name, size or modified", value)),
+                ("order", "asc") => order.descending = false,
+                ("order", "desc") => order.descending = true,
+                ("order", _) => return Err(format!("Unknown order '{}', expected asc or desc", value)),
+                _ => return Err(format!("Unknown listing parameter '{}', expected sort or order", key))
+            }
+        }
+        Ok(order)
+    }
+
+    /// Helper function comparing two entries, directories always coming first
+    fn compare(&self, a: &ListingEntry, b: &ListingEntry) -> Ordering {
+        let ordering = match self.key {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+            SortKey::Modified => a.modified.cmp(&b.modified).then_with(|| a.name.cmp(&b.name))
+        };
+        let ordering = match self.descending {
+            true => ordering.reverse(),
+            false => ordering
+        };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    }
+}
+
+
+/// structure describing one entry of a directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListingEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,                      // in bytes, 0 for directories
+    pub modified: Option<SystemTime>
+}
+
+
+/// Reads the entries of a directory that should be listed
+///
+/// Hidden entries are always left out.
+///
+/// ## Params:
+/// * dir: the directory
+/// * visible: decides whether an entry may be shown to the client
+///
+/// ## Returns:
+/// * Ok: the entries, in no particular order
+/// * Err: a string describing the issue encountered
+pub fn read_entries(dir: &Path, visible: impl Fn(&Path) -> bool) -> Result<Vec<ListingEntry>, String> {
+    let read = match fs::read_dir(dir) {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Cannot list {}: {}", dir.display(), e))
+    };
+
+    let mut entries = Vec::new();
+    for entry in read.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') || !visible(&entry.path()) {
+            continue;
+        }
+        // follow symlinks, the document root decides later whether their targets are served
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(a) => a,
+            Err(_) => continue
+        };
+        entries.push(ListingEntry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok()
+        });
+    }
+    Ok(entries)
+}
+
+/// Renders a directory listing as a GML document
+///
+/// ## Params:
+/// * path: the normalized request path of the directory
+/// * entries: the entries to list
+/// * order: how to sort them
+///
+/// ## Returns:
+/// * the listing
+pub fn render_listing(path: &str, mut entries: Vec<ListingEntry>, order: ListingOrder) -> GmlDocument {
+    entries.sort_by(|a, b| order.compare(a, b));
+    let base = match path.ends_with('/') {
+        true => path.to_string(),
+        false => format!("{}/", path)
+    };
+
+    let mut document = GmlDocument::new();
+    document.heading(1, &format!("Index of {}", base));
+    if base != "/" {
+        let parent = &base[..base[..base.len() - 1].rfind('/').unwrap_or(0) + 1];
+        document.link(&percent_encode_path(parent), "Parent directory");
+    }
+    document.blank();
+
+    // following the link of the current key flips the order
+    for key in [SortKey::Name, SortKey::Size, SortKey::Modified] {
+        let (direction, label) = match (key == order.key, order.descending) {
+            (true, false) => ("desc", format!("Sort by {} (ascending)", key.name())),
+            (true, true) => ("asc", format!("Sort by {} (descending)", key.name())),
+            (false, _) => ("asc", format!("Sort by {}", key.name()))
+        };
+        document.link(&format!("{}?sort={}&order={}", percent_encode_path(&base), key.name(), direction), &label);
+    }
+    document.blank();
+
+    if entries.is_empty() {
+        document.text("This directory is empty.");
+    }
+    for entry in &entries {
+        let name = match entry.is_dir {
+            true => format!("{}/", entry.name),
+            false => entry.name.clone()
+        };
+        let size = match entry.is_dir {
+            true => "-".to_string(),
+            false => format_size(entry.size)
+        };
+        let modified = entry.modified.map(format_time).unwrap_or_else(|| "-".to_string());
+        document.link(&percent_encode_path(&format!("{}{}", base, name)), &format!("{}  {}  {}", name, size, modified));
+    }
+    document
+}
+
+
+/// Helper function formatting a size with binary units
+fn format_size(size: u64) -> String {
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    for unit in ["KiB", "MiB"] {
+        if value < 1024.0 {
+            return format!("{:.1} {}", value, unit);
+        }
+        value /= 1024.0;
+    }
+    format!("{:.1} GiB", value)
+}
+
+/// Helper function formatting a modification time to the minute, in UTC
+fn format_time(time: SystemTime) -> String {
+    let time = OffsetDateTime::from(time);
+    format!("{}-{:02}-{:02} {:02}:{:02} UTC", time.year(), time.month() as u8, time.day(), time.hour(), time.minute())
+}
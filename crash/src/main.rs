@@ -1,20 +1,131 @@
-use grid::server::GridServer;
+use std::path::PathBuf;
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+
+use clap::Parser;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use grid::definitions::GRID_DEFAULT_PORT;
+use grid::server::ShutdownHandle;
+
+mod config;
+mod listing;
+mod proxy;
+mod reload;
+mod static_files;
+
+use config::Config;
+use proxy::ProxyRule;
+
+
+#[derive(Parser, Debug)]
+#[command(term_width = 0)]
+struct Arguments {
+    /// Port to listen on
+    #[arg(short='p', long="port", default_value_t=GRID_DEFAULT_PORT, conflicts_with="config")]
+    port: u16,
+
+    /// Directory to serve documents from. Ex: ./target/doc
+    #[arg(short='r', long="root", conflicts_with="config")]
+    root: Option<PathBuf>,
+
+    /// TOML configuration file
+    #[arg(short='c', long="config")]
+    config: Option<PathBuf>,
+
+    /// Validate the configuration file and exit
+    #[arg(long="check-config", requires="config")]
+    check_config: bool
+}
+
 
 fn main() {
-    // build our server instance
-    let server = match GridServer::new(1337, None) {
+    let args = Arguments::parse();
+
+    // load the configuration, or make one up from the command line
+    let config = match &args.config {
+        Some(path) => match Config::load(path) {
+            Ok(a) => a,
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("{}: {}", path.display(), e);
+                }
+                exit(1);
+            }
+        },
+        None => Config::simple(args.port, args.root.clone())
+    };
+    if args.check_config {
+        println!("Configuration OK");
+        return;
+    }
+
+    let level: tracing::Level = config.logging.level.parse().unwrap_or(tracing::Level::INFO);
+    // diagnostics go to stderr so stdout carries nothing but the access log
+    let subscriber = tracing_subscriber::fmt().with_max_level(level).with_writer(std::io::stderr);
+    match &config.logging.format[..] {
+        "json" => subscriber.json().init(),
+        _ => subscriber.init()
+    }
+
+    // build our server instance and bind to its addresses
+    let (mut server, proxies) = match config.build_server() {
         Ok(a) => a,
         Err(e) => panic!("Failed to create GRID server instance: {}", e)
     };
+    for rule in &proxies {
+        if let Err(e) = ProxyRule::start_health_checks(rule) {
+            panic!("Failed to check upstreams: {}", e);
+        }
+    }
+    let access_log = match config.open_access_log() {
+        Ok(a) => a,
+        Err(e) => panic!("Failed to open access log: {}", e)
+    };
+    server.set_access_log(access_log.clone());
 
-    // bind to the port
-    match server.bind() {
-        Ok(_) => (),
-        Err(e) => panic!("Failed to bind server to port: {}", e)
+    // pick up rotated certificates and edited configuration while running
+    let (config_file, port, root) = (args.config.clone(), args.port, args.root.clone());
+    let load = move || match &config_file {
+        Some(path) => Config::load(path).map_err(|errors| errors.join("; ")),
+        None => Ok(Config::simple(port, root.clone()))
+    };
+    if let Err(e) = reload::spawn(server.reload_handle(), access_log, args.config.clone(), &config, load) {
+        panic!("Failed to set up reloading: {}", e);
     }
 
-    // loop and handle connections
-    loop {
+    // drain and stop on SIGINT/SIGTERM
+    let grace = Duration::from_secs(config.limits.drain_timeout);
+    if let Err(e) = watch_termination(server.shutdown_handle(), grace) {
+        panic!("Failed to set up signal handling: {}", e);
+    }
 
+    // loop and handle connections
+    if let Err(e) = server.run() {
+        panic!("GRID server stopped: {}", e);
     }
 }
+
+/// Starts a thread shutting the server down gracefully on the first SIGINT or SIGTERM,
+/// and exiting right away on the second
+fn watch_termination(handle: ShutdownHandle, grace: Duration) -> Result<(), String> {
+    let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Cannot handle SIGINT/SIGTERM: {}", e))
+    };
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if handle.is_shutting_down() {
+                tracing::warn!(signal, "second signal, exiting without draining");
+                exit(1);
+            }
+            tracing::info!(signal, grace = ?grace, "shutting down");
+            handle.shutdown(grace);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,499 @@
+// Defines the handler forwarding requests to upstream GRID servers
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustls::ClientConfig;
+
+use tracing::{debug, info, warn};
+
+use grid::auth::Permission;
+use grid::path::{normalize_path, split_query};
+use grid::client::GridClient;
+use grid::definitions::{GridBlock, GridRequestCode, GridResponseCode, string_to_domain};
+use grid::metadata::MetadataKey;
+use grid::server::{GridHandler, RequestContext, busy_response, error_response};
+
+
+/// Idle connections kept per upstream for reuse
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+/// Seconds an upstream gets to connect and to answer
+pub const DEFAULT_UPSTREAM_TIMEOUT: u64 = 10;
+
+/// Seconds between health checks of the upstreams of a rule
+pub const DEFAULT_HEALTH_INTERVAL: u64 = 10;
+
+/// Pooled connections idle for longer are dropped, the upstream may have hung up on them
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Failed requests in a row after which an upstream is left out until a health check passes
+const MAX_FAILURES: u32 = 3;
+
+
+/// How forwarding a request to an upstream went wrong
+#[derive(Debug)]
+enum Failure {
+    /// No connection could be made, the upstream never saw the request
+    Connect(String),
+    /// The connection broke down after the request may have been sent
+    Exchange(String)
+}
+
+
+/// structure defining an upstream server along with its idle connections and health
+pub struct Upstream {
+    remote: String,                             // e.g. `grid!internal.example:7500`
+    tls: Option<Arc<ClientConfig>>,             // `None` speaks plaintext GRID
+    timeout: Duration,
+    pool_size: usize,
+    pool: Mutex<Vec<(GridClient, Instant)>>,    // idle connections and when they were last used
+    health: Mutex<Health>
+}
+
+/// structure tracking whether an upstream is taking requests
+struct Health {
+    failures: u32,                  // failed requests in a row
+    last_error: Option<String>
+}
+
+impl Upstream {
+    /// Creates a new `Upstream` instance
+    ///
+    /// ## Params:
+    /// * remote: the server, formatted as `"grid!domain:port"` or `"grid.ip:port"`
+    /// * tls: the TLS configuration to connect with, or `None` for plaintext GRID
+    /// * timeout: how long connecting and each response may take
+    /// * pool_size: how many idle connections to keep around
+    ///
+    /// ## Returns:
+    /// * Ok: an instance of the structure
+    /// * Err: a string describing what is wrong with `remote`
+    pub fn new(remote: &str, tls: Option<Arc<ClientConfig>>, timeout: Duration, pool_size: usize) -> Result<Self, String> {
+        string_to_domain(remote)?;
+        Ok(Upstream {
+            remote: remote.to_string(),
+            tls,
+            timeout,
+            pool_size,
+            pool: Mutex::new(Vec::new()),
+            health: Mutex::new(Health { failures: 0, last_error: None })
+        })
+    }
+
+    /// Returns whether requests should be sent to the upstream
+    pub fn is_healthy(&self) -> bool {
+        self.health.lock().unwrap_or_else(|e| e.into_inner()).failures < MAX_FAILURES
+    }
+
+    /// Sends a request to the upstream over a pooled connection, or a new one
+    ///
+    /// A read over a pooled connection that fails is retried once on a fresh
+    /// connection, since the upstream most likely hung up on it while it sat idle.
+    /// Writes are never sent twice, as the broken connection may have delivered them.
+    ///
+    /// ## Params:
+    /// * request: the request to forward
+    /// * write: whether the request changes documents on the upstream
+    ///
+    /// ## Returns:
+    /// * Ok: the response of the upstream
+    /// * Err: a `Failure` telling whether the upstream may have seen the request
+    fn send(&self, request: &GridBlock, write: bool) -> Result<GridBlock, Failure> {
+        let mut pooled = self.checkout();
+        loop {
+            let reused = pooled.is_some();
+            let mut client = match pooled.take() {
+                Some(a) => a,
+                None => match self.connect() {
+                    Ok(a) => a,
+                    Err(e) => {
+                        self.failed(&e);
+                        return Err(Failure::Connect(e));
+                    }
+                }
+            };
+
+            let mut forward = request.clone();
+            match client.send(&mut forward) {
+                Ok(response) => {
+                    self.succeeded();
+                    self.checkin(client);
+                    return Ok(response);
+                },
+                Err(e) if reused && !write => debug!(upstream = %self.remote, error = %e, "pooled connection failed, reconnecting"),
+                Err(e) => {
+                    let e = e.to_string();
+                    self.failed(&e);
+                    return Err(Failure::Exchange(e));
+                }
+            }
+        }
+    }
+
+    /// Checks whether the upstream answers, bringing it back into rotation if so
+    ///
+    /// Any response to an `INF` request counts, even a refusal.
+    pub fn check(&self) {
+        let result = self.connect().and_then(|mut client| {
+            let mut request = GridBlock::new(GridRequestCode::INF, Some("/"), &mut Vec::new())?;
+            client.send(&mut request).map_err(String::from)
+        });
+        match result {
+            Ok(_) => self.succeeded(),
+            Err(e) => {
+                // one failed check is enough to stop sending requests its way
+                let mut health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+                if health.failures < MAX_FAILURES {
+                    warn!(upstream = %self.remote, error = %e, "upstream failed its health check");
+                }
+                health.failures = health.failures.max(MAX_FAILURES);
+                health.last_error = Some(e);
+            }
+        }
+    }
+
+    /// Helper function opening a new connection with the timeouts applied
+    fn connect(&self) -> Result<GridClient, String> {
+        let (_, domain, port) = string_to_domain(&self.remote)?;
+        let addresses = match (&domain[..], port).to_socket_addrs() {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Failed to lookup {}: {}", domain, e))
+        };
+
+        let mut last_error = format!("No addresses found for {}", domain);
+        for address in addresses {
+            let stream = match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(a) => a,
+                Err(e) => {
+                    last_error = format!("Connection to {} failed: {}", address, e);
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_read_timeout(Some(self.timeout)).and_then(|_| stream.set_write_timeout(Some(self.timeout))) {
+                return Err(format!("Cannot set timeouts: {}", e));
+            }
+            let _ = stream.set_nodelay(true);
+            return GridClient::with_transport(stream, self.remote.clone(), self.tls.clone());
+        }
+        Err(last_error)
+    }
+
+    /// Helper function taking an idle connection from the pool, if a fresh enough one is there
+    fn checkout(&self) -> Option<GridClient> {
+        let mut pool = self.pool.lock().unwrap_or_else(|e| e.into_inner());
+        pool.retain(|(_, used)| used.elapsed() < POOL_IDLE_TIMEOUT);
+        pool.pop().map(|(client, _)| client)
+    }
+
+    /// Helper function putting a connection back into the pool, unless it is full
+    fn checkin(&self, client: GridClient) {
+        let mut pool = self.pool.lock().unwrap_or_else(|e| e.into_inner());
+        if pool.len() < self.pool_size {
+            pool.push((client, Instant::now()));
+        }
+    }
+
+    /// Helper function recording a successful exchange
+    fn succeeded(&self) {
+        let mut health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        if health.failures >= MAX_FAILURES {
+            info!(upstream = %self.remote, "upstream is back");
+        }
+        health.failures = 0;
+        health.last_error = None;
+    }
+
+    /// Helper function recording a failed exchange, taking the upstream out of rotation after a few
+    fn failed(&self, error: &str) {
+        let mut health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        health.failures += 1;
+        health.last_error = Some(error.to_string());
+        if health.failures == MAX_FAILURES {
+            warn!(upstream = %self.remote, error = %error, "upstream is down");
+        }
+    }
+}
+
+
+/// structure routing the requests matching a path prefix and server name to upstreams
+///
+/// Upstreams are tried in order, so the first one is the primary and the rest
+/// take over while it is down.
+pub struct ProxyRule {
+    path: String,                   // normalized prefix, `/` matches every path
+    names: Vec<String>,             // server names asked for via SNI, empty matches any
+    upstreams: Vec<Upstream>,
+    health_interval: Duration
+}
+
+impl ProxyRule {
+    /// Creates a new `ProxyRule` instance
+    ///
+    /// ## Params:
+    /// * path: the prefix of the paths to forward, along with everything below it
+    /// * names: the server names to forward requests for, or empty for any name
+    /// * upstreams: the servers to forward to, in order of preference
+    /// * health_interval: how often the upstreams are checked
+    ///
+    /// ## Returns:
+    /// * an instance of the structure
+    pub fn new(path: &str, names: Vec<String>, upstreams: Vec<Upstream>, health_interval: Duration) -> Self {
+        ProxyRule {
+            path: normalize_path(path),
+            names: names.iter().map(|n| n.to_lowercase()).collect(),
+            upstreams,
+            health_interval
+        }
+    }
+
+    /// Checks whether a request with a normalized path falls under the rule
+    pub fn matches(&self, path: &str, server_name: Option<&str>) -> bool {
+        let path_matches = self.path == "/" || path == self.path || path.starts_with(&format!("{}/", self.path));
+        let name_matches = self.names.is_empty() || server_name.is_some_and(|name| {
+            let name = name.to_lowercase();
+            self.names.iter().any(|n| match n.strip_prefix("*.") {
+                Some(suffix) => name.strip_suffix(suffix).is_some_and(|label| label.ends_with('.') && label.len() > 1),
+                None => *n == name
+            })
+        });
+        path_matches && name_matches
+    }
+
+    /// Starts a thread checking the upstreams of the rule until it is dropped
+    ///
+    /// ## Params:
+    /// * rule: the rule, the thread only keeps a weak reference to it
+    ///
+    /// ## Returns:
+    /// * Ok: nothing, the thread is running
+    /// * Err: a string describing the issue encountered
+    pub fn start_health_checks(rule: &Arc<ProxyRule>) -> Result<(), String> {
+        let (weak, interval) = (Arc::downgrade(rule), rule.health_interval);
+        let spawned = thread::Builder::new().name("crash-health".to_string()).spawn(move || loop {
+            thread::sleep(interval);
+            // a reload replaced the rule, so nobody needs its checks anymore
+            let rule = match Weak::upgrade(&weak) {
+                Some(a) => a,
+                None => return
+            };
+            for upstream in &rule.upstreams {
+                upstream.check();
+            }
+        });
+        match spawned {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to start health checks: {}", e))
+        }
+    }
+
+    /// Forwards a request, failing over to the next upstream where that is safe
+    ///
+    /// Reads move on after any failure. Writes only move on if the upstream could
+    /// not be reached, since a broken connection may have delivered them already.
+    /// The bearer token of the client is ours to check, so it never goes upstream.
+    ///
+    /// ## Params:
+    /// * request: the decompressed request
+    ///
+    /// ## Returns:
+    /// * the response of the first upstream to answer, or `BSY`/`GER` with the reason none did
+    pub fn forward(&self, request: &GridBlock) -> GridBlock {
+        let write = Permission::of(request) == Permission::Write;
+        let mut reasons = Vec::new();
+
+        let mut request = request.clone();
+        if let Err(e) = request.remove_header(&MetadataKey::AuthToken) {
+            return error_response(GridResponseCode::GER, &e);
+        }
+
+        for upstream in self.upstreams.iter().filter(|u| u.is_healthy()) {
+            match upstream.send(&request, write) {
+                Ok(response) => return response,
+                Err(Failure::Exchange(e)) if write => {
+                    return error_response(GridResponseCode::GER, &format!("Upstream {} failed during the request: {}", upstream.remote, e));
+                },
+                Err(Failure::Connect(e) | Failure::Exchange(e)) => {
+                    debug!(upstream = %upstream.remote, error = %e, "trying the next upstream");
+                    reasons.push(format!("{}: {}", upstream.remote, e));
+                }
+            }
+        }
+
+        // everyone was down before we even tried, so tell why they were taken out
+        if reasons.is_empty() {
+            for upstream in &self.upstreams {
+                let health = upstream.health.lock().unwrap_or_else(|e| e.into_inner());
+                reasons.push(format!("{}: {}", upstream.remote, health.last_error.as_deref().unwrap_or("down")));
+            }
+        }
+        let retry_after = self.health_interval.max(Duration::from_secs(1));
+        busy_response(&format!("No upstream available for {}: {}", self.path, reasons.join("; ")), retry_after)
+    }
+}
+
+
+/// structure defining a handler sending matching requests upstream and the rest to another handler
+pub struct Proxy {
+    rules: Vec<Arc<ProxyRule>>,     // tried in order, shared by the handlers of every host
+    fallback: Box<dyn GridHandler>
+}
+
+impl Proxy {
+    /// Creates a new `Proxy` handler
+    ///
+    /// ## Params:
+    /// * rules: the rules, the first one matching a request decides where it goes
+    /// * fallback: the handler answering requests no rule matches
+    ///
+    /// ## Returns:
+    /// * an instance of the structure
+    pub fn new(rules: Vec<Arc<ProxyRule>>, fallback: Box<dyn GridHandler>) -> Self {
+        Proxy { rules, fallback }
+    }
+}
+
+impl GridHandler for Proxy {
+    fn handle(&self, request: &GridBlock, ctx: &RequestContext) -> GridBlock {
+        let path = match request.path() {
+            Ok(a) => split_query(&a).0,
+            Err(e) => return error_response(GridResponseCode::GER, &e)
+        };
+        match self.rules.iter().find(|r| r.matches(&path, ctx.server_name.as_deref())) {
+            Some(rule) => rule.forward(request),
+            None => self.fallback.handle(request, ctx)
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use grid::definitions::{GridCode, GRID_HEADER_SIZE};
+    use grid::server::GridServer;
+
+    /// Starts a plaintext server answering every GET with its name
+    fn upstream_server(name: &'static str) -> String {
+        let mut server = GridServer::plaintext(0);
+        server.set_handler(move |_request: &GridBlock, _ctx: &RequestContext| {
+            GridBlock::new(GridResponseCode::ROK, None, &mut name.as_bytes().to_vec()).unwrap()
+        });
+        server.bind_to("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        format!("grid.{}", address)
+    }
+
+    /// Returns a remote nothing listens on
+    fn closed_port() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("grid.{}", listener.local_addr().unwrap())
+    }
+
+    fn rule(path: &str, names: Vec<String>, remotes: &[&str]) -> ProxyRule {
+        let upstreams = remotes.iter().map(|r| Upstream::new(r, None, Duration::from_secs(2), 2).unwrap()).collect();
+        ProxyRule::new(path, names, upstreams, Duration::from_secs(5))
+    }
+
+    #[test]
+    fn requests_fail_over_and_report_down_upstreams() {
+        let (down, up) = (closed_port(), upstream_server("backup"));
+        let proxy = Proxy::new(
+            vec![Arc::new(rule("/api", Vec::new(), &[&down, &up])), Arc::new(rule("/", vec!["*.internal".to_string()], &[&down]))],
+            Box::new(grid::server::NotFoundHandler)
+        );
+        let get = |path: &str, name: Option<&str>| {
+            let request = GridBlock::new(GridRequestCode::GET, Some(path), &mut Vec::new()).unwrap();
+            let ctx = RequestContext { server_name: name.map(|n| n.to_string()), ..RequestContext::default() };
+            proxy.handle(&request, &ctx)
+        };
+
+        // the first upstream is down, so the second one answers, over the same connection the next time
+        for _ in 0..2 {
+            let response = get("/api/users?page=2", None);
+            assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::ROK));
+            assert_eq!(response.content(), b"backup");
+        }
+        assert_eq!(proxy.rules[0].upstreams[0].health.lock().unwrap().failures, 2);
+        assert_eq!(proxy.rules[0].upstreams[1].pool.lock().unwrap().len(), 1);
+
+        // paths next to the prefix and other names go elsewhere
+        assert_eq!(get("/apis", None).opcode(), GridCode::Response(GridResponseCode::NOF));
+        assert_eq!(get("/x", Some("internal")).opcode(), GridCode::Response(GridResponseCode::NOF));
+
+        // with every upstream down the client learns why
+        let response = get("/x", Some("db.internal"));
+        assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::BSY));
+        let reason = String::from_utf8_lossy(response.content()).into_owned();
+        assert!(reason.starts_with("No upstream available for /: "), "{}", reason);
+        assert!(reason.contains(&down), "{}", reason);
+        assert!(response.retry_after().is_some());
+    }
+
+    #[test]
+    fn writes_are_not_replayed_on_a_new_connection() {
+        use std::io::{Read, Write};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // the upstream hangs up after every answer, so each pooled connection goes stale
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = format!("grid.{}", listener.local_addr().unwrap());
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = seen.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut header = vec![0u8; GRID_HEADER_SIZE];
+                if stream.read_exact(&mut header).is_err() {
+                    continue;
+                }
+                let size = GridBlock::frame_length(&header).unwrap() as usize;
+                let mut rest = vec![0u8; size - GRID_HEADER_SIZE];
+                if stream.read_exact(&mut rest).is_err() {
+                    continue;
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.write_all(&error_response(GridResponseCode::ROK, "stored").serialize());
+            }
+        });
+
+        let proxy = Proxy::new(vec![Arc::new(rule("/", Vec::new(), &[&remote]))], Box::new(grid::server::NotFoundHandler));
+        let send = |opcode: GridRequestCode| {
+            let request = GridBlock::new(opcode, Some("/doc"), &mut b"draft".to_vec()).unwrap();
+            proxy.handle(&request, &RequestContext::default()).opcode()
+        };
+
+        // reads retry the stale connection on a fresh one
+        assert_eq!(send(GridRequestCode::GET), GridCode::Response(GridResponseCode::ROK));
+        assert_eq!(send(GridRequestCode::GET), GridCode::Response(GridResponseCode::ROK));
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+
+        // writes fail instead of being sent a second time
+        assert_eq!(send(GridRequestCode::PUT), GridCode::Response(GridResponseCode::GER));
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn client_tokens_are_not_forwarded() {
+        // the upstream answers with the token it was handed, if any
+        let mut server = GridServer::plaintext(0);
+        server.set_handler(|request: &GridBlock, _ctx: &RequestContext| {
+            let token = request.headers().ok().flatten().and_then(|h| h.auth_token().map(|t| t.to_string()));
+            GridBlock::new(GridResponseCode::ROK, None, &mut token.unwrap_or_default().into_bytes()).unwrap()
+        });
+        server.bind_to("127.0.0.1:0".parse().unwrap()).unwrap();
+        let remote = format!("grid.{}", server.local_addr().unwrap());
+        thread::spawn(move || server.run());
+
+        let proxy = Proxy::new(vec![Arc::new(rule("/", Vec::new(), &[&remote]))], Box::new(grid::server::NotFoundHandler));
+        let mut request = GridBlock::new(GridRequestCode::GET, Some("/"), &mut Vec::new()).unwrap();
+        request.insert_header(MetadataKey::AuthToken, b"secret".to_vec()).unwrap();
+        let response = proxy.handle(&request, &RequestContext::default());
+        assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::ROK));
+        assert_eq!(response.content(), b"");
+        assert_eq!(request.headers().unwrap().unwrap().auth_token(), Some("secret"));
+    }
+}
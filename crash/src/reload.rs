@@ -0,0 +1,101 @@
+// Defines the reloading of certificates and configuration on SIGHUP or file change
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use signal_hook::consts::SIGHUP;
+
+use tracing::{error, info};
+
+use grid::access_log::AccessLog;
+use grid::server::ReloadHandle;
+
+use crate::config::Config;
+use crate::proxy::ProxyRule;
+
+
+/// How often watched files are checked for changes
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+
+/// Starts a thread reloading the server whenever SIGHUP arrives or a watched file changes
+///
+/// The configuration is read again and fully built before it replaces the live
+/// one; if anything about it is wrong the error is logged and the old settings
+/// stay in place. Listen addresses, limits on idle time, workers and logging are only
+/// read at startup, but SIGHUP reopens the access log file for log rotation.
+///
+/// ## Params:
+/// * handle: the handle of the running server
+/// * access_log: the access log of the server, kept across reloads
+/// * config_file: the configuration file to watch, if any
+/// * config: the configuration currently running, for the files it references
+/// * load: builds the configuration to switch to
+///
+/// ## Returns:
+/// * Ok: nothing, the thread is running
+/// * Err: a string describing the issue encountered
+pub fn spawn(
+    handle: ReloadHandle,
+    access_log: Option<Arc<AccessLog>>,
+    config_file: Option<PathBuf>,
+    config: &Config,
+    load: impl Fn() -> Result<Config, String> + Send + 'static
+) -> Result<(), String> {
+    let hangup = Arc::new(AtomicBool::new(false));
+    if let Err(e) = signal_hook::flag::register(SIGHUP, hangup.clone()) {
+        return Err(format!("Cannot handle SIGHUP: {}", e));
+    }
+
+    let watched = move |config: &Config| -> Vec<PathBuf> {
+        config_file.iter().cloned().chain(config.referenced_files()).collect()
+    };
+    let mut files = watched(config);
+
+    thread::spawn(move || {
+        let mut stamps = modified(&files);
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+            let signalled = hangup.swap(false, Ordering::SeqCst);
+            let current = modified(&files);
+            if !signalled && current == stamps {
+                continue;
+            }
+            stamps = current;
+            info!(trigger = if signalled { "SIGHUP" } else { "file change" }, "reloading configuration");
+            if let (true, Some(log)) = (signalled, &access_log) {
+                if let Err(e) = log.reopen() {
+                    error!(error = %e, "cannot reopen access log, still writing to the old file");
+                }
+            }
+
+            // build everything first so a broken file never replaces a working setup
+            let next = load().and_then(|config| {
+                let (mut server, proxies) = config.configure_server()?;
+                server.set_access_log(access_log.clone());
+                Ok((server, proxies, config))
+            });
+            match next.and_then(|(server, proxies, config)| handle.reload(server).map(|_| (proxies, config))) {
+                Ok((proxies, config)) => {
+                    // only now the new rules are live, the old ones stop checking once dropped
+                    for rule in &proxies {
+                        if let Err(e) = ProxyRule::start_health_checks(rule) {
+                            error!(error = %e, "upstreams of the new configuration are not checked");
+                        }
+                    }
+                    files = watched(&config);
+                    stamps = modified(&files);
+                },
+                Err(e) => error!(error = %e, "rejected new configuration, keeping the current one")
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Helper function reading the modification times of the watched files
+fn modified(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files.iter().map(|f| f.metadata().and_then(|m| m.modified()).ok()).collect()
+}
@@ -0,0 +1,419 @@
+// Defines the handler serving documents from a directory on disk
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, warn};
+
+use grid::path::{normalize_path, split_query};
+use grid::definitions::{GridBlock, GridCode, GridRequestCode, GridResponseCode};
+use grid::gml::GML_CONTENT_TYPE;
+use grid::metadata::Metadata;
+use grid::properties::{AccessPolicy, Properties};
+use grid::server::{GridHandler, RequestContext, error_response};
+use grid::storage::{FileStorage, Storage, UploadLimits, handle_put, handle_set};
+
+use crate::listing::{ListingOrder, read_entries, render_listing};
+
+
+/// Documents served when a GET hits a directory, in order of preference
+pub const INDEX_DOCUMENTS: [&str; 3] = ["index.gml", "index.html", "index.txt"];
+
+
+/// structure defining a handler serving files below a document root
+pub struct StaticFiles {
+    root: PathBuf,
+    storage: FileStorage,       // keeps uploads, their owners and document properties
+    uploads: Option<Uploads>,   // how PUT and SET requests are treated, `None` to refuse them
+    listings: bool              // whether directories without an index document are listed
+}
+
+/// structure describing how a document root takes uploads
+struct Uploads {
+    limits: UploadLimits,
+    anonymous: bool             // whether clients without a certificate may upload
+}
+
+impl StaticFiles {
+    /// Creates a new `StaticFiles` handler
+    ///
+    /// ## Params:
+    /// * root: the directory documents are served from
+    ///
+    /// ## Returns:
+    /// * Ok: an instance of the structure
+    /// * Err: a string describing the issue encountered
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, String> {
+        let root = match fs::canonicalize(root.as_ref()) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Cannot open document root {}: {}", root.as_ref().display(), e))
+        };
+        if !root.is_dir() {
+            return Err(format!("Document root {} is not a directory", root.display()));
+        }
+
+        let storage = FileStorage::open(&root)?;
+        Ok(StaticFiles { root, storage, uploads: None, listings: true })
+    }
+
+    /// Lets clients upload documents below the root with PUT, and change their properties with SET
+    ///
+    /// ## Params:
+    /// * limits: the size cap, quotas and overwrite rule uploads must keep to
+    /// * anonymous: whether clients with neither a client certificate nor a token may upload
+    ///
+    /// ## Returns:
+    /// * the handler, now taking uploads
+    pub fn with_uploads(mut self, limits: UploadLimits, anonymous: bool) -> Self {
+        self.uploads = Some(Uploads { limits, anonymous });
+        self
+    }
+
+    /// Turns the generated listings of directories without an index document on or off
+    ///
+    /// ## Params:
+    /// * enabled: whether to list directories, otherwise they are not found
+    ///
+    /// ## Returns:
+    /// * the handler
+    pub fn with_listings(mut self, enabled: bool) -> Self {
+        self.listings = enabled;
+        self
+    }
+
+    /// Returns the directory documents are served from
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Maps a request path to a file or directory below the root
+    ///
+    /// `..` segments can't climb above the root, and hidden files as well as
+    /// symlinks pointing out of the root are refused.
+    ///
+    /// ## Params:
+    /// * path: the path from the request
+    ///
+    /// ## Returns:
+    /// The location on disk, or `None` if it doesn't exist or lies outside the root
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = normalize_path(path);
+        if relative.split('/').any(|segment| segment.starts_with('.')) {
+            debug!(path = %path, "refusing hidden path");
+            return None;
+        }
+        let candidate = self.root.join(relative.trim_start_matches('/'));
+
+        let resolved = fs::canonicalize(candidate).ok()?;
+        if !resolved.starts_with(&self.root) {
+            debug!(path = %path, "refusing path outside of document root");
+            return None;
+        }
+        Some(resolved)
+    }
+
+    /// Finds the index document of a directory
+    fn index_of(&self, dir: &Path) -> Option<PathBuf> {
+        INDEX_DOCUMENTS
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|p| p.is_file())
+    }
+
+    /// Checks whether a client may see a file, by the access policy in its properties
+    ///
+    /// A policy that can't be understood lets nobody in.
+    fn may_read(&self, file: &Path, properties: &Properties, ctx: &RequestContext) -> bool {
+        match properties.access() {
+            Ok(AccessPolicy::Public) => true,
+            Ok(AccessPolicy::Authenticated) => ctx.identity().is_some(),
+            Ok(AccessPolicy::Owner) => ctx.identity().is_some() && self.storage.owner(&self.relative(file)).as_deref() == ctx.identity(),
+            Err(e) => {
+                warn!(file = %file.display(), error = %e, "denying access to file with an unknown access policy");
+                false
+            }
+        }
+    }
+
+    /// Helper function returning the path of a file relative to the root
+    fn relative(&self, file: &Path) -> String {
+        file.strip_prefix(&self.root).map(|p| p.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+
+    /// Builds the listing of a directory without an index document
+    fn listing(&self, dir: &Path, path: &str, query: Option<&str>, ctx: &RequestContext) -> GridBlock {
+        let order = match ListingOrder::from_query(query) {
+            Ok(a) => a,
+            Err(e) => return error_response(GridResponseCode::GER, &e)
+        };
+        // files whose access policy can't be read stay out, rather than show up as public
+        let visible = |entry: &Path| entry.is_dir() || match self.storage.properties(&self.relative(entry)) {
+            Ok(properties) => self.may_read(entry, &properties, ctx),
+            Err(e) => {
+                warn!(file = %entry.display(), error = %e, "leaving file with unreadable properties out of listing");
+                false
+            }
+        };
+        match read_entries(dir, visible) {
+            Ok(entries) => {
+                let document = render_listing(path, entries, order);
+                document_response(GML_CONTENT_TYPE, &Properties::default(), document.to_string().into_bytes())
+            },
+            Err(e) => {
+                debug!(dir = %dir.display(), error = %e, "failed to list directory");
+                error_response(GridResponseCode::NOF, "Not found")
+            }
+        }
+    }
+}
+
+impl GridHandler for StaticFiles {
+    fn handle(&self, request: &GridBlock, ctx: &RequestContext) -> GridBlock {
+        let opcode = match request.opcode() {
+            GridCode::Request(a) => a,
+            GridCode::Response(_) => return error_response(GridResponseCode::GER, "Expected a request OPCODE")
+        };
+        let uploads = match (opcode, &self.uploads) {
+            (GridRequestCode::GET, _) => None,
+            (GridRequestCode::PUT | GridRequestCode::SET, Some(uploads)) => Some(uploads),
+            (_, Some(_)) => return error_response(GridResponseCode::GER, "Only GET, PUT and SET are supported"),
+            (_, None) => return error_response(GridResponseCode::GER, "Only GET is supported")
+        };
+        if let Some(uploads) = uploads {
+            let owner = match (ctx.identity(), uploads.anonymous) {
+                (Some(identity), _) => identity,
+                (None, true) => "anonymous",
+                (None, false) => return error_response(GridResponseCode::GER, "Uploads need a client certificate or token")
+            };
+            return match opcode {
+                GridRequestCode::PUT => handle_put(&self.storage, &uploads.limits, request, owner),
+                _ => handle_set(&self.storage, request, owner)
+            };
+        }
+
+        let raw_path = match request.path() {
+            Ok(a) => a,
+            Err(e) => return error_response(GridResponseCode::GER, &e)
+        };
+        let (path, query) = split_query(&raw_path);
+
+        // figure out what we are actually serving
+        let mut file = match self.resolve(&path) {
+            Some(a) => a,
+            None => return error_response(GridResponseCode::NOF, "Not found")
+        };
+        if file.is_dir() {
+            file = match (self.index_of(&file), self.listings) {
+                (Some(a), _) => a,
+                (None, true) => return self.listing(&file, &path, query, ctx),
+                (None, false) => return error_response(GridResponseCode::NOF, "Not found")
+            };
+        }
+
+        // the document's own properties decide who may see it and how it is described
+        let properties = match self.storage.properties(&self.relative(&file)) {
+            Ok(a) => a,
+            Err(e) => {
+                warn!(file = %file.display(), error = %e, "refusing document with unreadable properties");
+                return error_response(GridResponseCode::DNY, "Access policy of the document cannot be read");
+            }
+        };
+        if !self.may_read(&file, &properties, ctx) {
+            debug!(file = %file.display(), "refusing document to client not allowed by its access policy");
+            return error_response(GridResponseCode::NOF, "Not found");
+        }
+
+        match fs::read(&file) {
+            Ok(body) => document_response(content_type(&file), &properties, body),
+            Err(e) => {
+                debug!(file = %file.display(), error = %e, "failed to read document");
+                error_response(GridResponseCode::NOF, "Not found")
+            }
+        }
+    }
+}
+
+
+
+/// Derives the content type of a file from its extension
+///
+/// ## Params:
+/// * path: the file name or path
+///
+/// ## Returns:
+/// A MIME type, `application/octet-stream` if the extension is unknown
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match &extension[..] {
+        "gml" => "text/gml",
+        "html" | "htm" => "text/html",
+        "txt" | "md" => "text/plain",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream"
+    }
+}
+
+/// Builds an `ROK` response carrying a document
+///
+/// The document follows structured headers giving its `content-type`,
+/// `content-length` and its other properties.
+///
+/// ## Params:
+/// * content_type: the MIME type of the document, unless its properties name another
+/// * properties: the properties of the document
+/// * body: the document
+///
+/// ## Returns:
+/// * a response GridBlock
+pub fn document_response(content_type: &str, properties: &Properties, body: Vec<u8>) -> GridBlock {
+    let mut headers = Metadata::new();
+    if let Err(e) = properties.write_metadata(&mut headers) {
+        return error_response(GridResponseCode::GER, &e);
+    }
+    if headers.content_type().is_none() {
+        if let Err(e) = headers.set_content_type(content_type) {
+            return error_response(GridResponseCode::GER, &e);
+        }
+    }
+    headers.set_content_length(body.len() as u64);
+
+    // building a block without a path can't fail
+    GridBlock::with_metadata(GridResponseCode::ROK, None, &headers, &body).unwrap()
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use grid::metadata::MetadataKey;
+
+    #[test]
+    fn resolve_stays_inside_root() {
+        let base = std::env::temp_dir().join(format!("crash-static-test-{}", std::process::id()));
+        let root = base.join("root");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(base.join("secret.txt"), b"nope").unwrap();
+        fs::write(root.join("docs").join("index.gml"), b"hello").unwrap();
+
+        let files = StaticFiles::new(&root).unwrap();
+        assert!(files.resolve("/docs").is_some());
+        assert!(files.resolve("/docs/../docs/index.gml").is_some());
+        assert_eq!(files.resolve("/../secret.txt"), None);
+        assert_eq!(files.resolve("/../../../../secret.txt"), None);
+
+        // a directory answers with its index document
+        let request = GridBlock::new(GridRequestCode::GET, Some("/docs/"), &mut Vec::new()).unwrap();
+        let response = files.handle(&request, &RequestContext::default());
+        assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::ROK));
+        let headers = response.headers().unwrap().unwrap();
+        assert_eq!(headers.content_type(), Some("text/gml"));
+        assert_eq!(headers.content_length(), Some(5));
+        assert_eq!(response.content(), b"hello");
+
+        // uploads land below the root and are served right away, but only for known clients
+        let files = files.with_uploads(UploadLimits::default(), false);
+        let put = GridBlock::new(GridRequestCode::PUT, Some("/docs/new.txt"), &mut b"fresh".to_vec()).unwrap();
+        assert_eq!(files.handle(&put, &RequestContext::default()).opcode(), GridCode::Response(GridResponseCode::GER));
+        let alice = RequestContext { client_subject: Some("CN=alice".to_string()), ..RequestContext::default() };
+        assert_eq!(files.handle(&put, &alice).opcode(), GridCode::Response(GridResponseCode::ROK));
+        let request = GridBlock::new(GridRequestCode::GET, Some("/docs/new.txt"), &mut Vec::new()).unwrap();
+        assert_eq!(files.handle(&request, &alice).content(), b"fresh");
+        assert_eq!(files.resolve("/.grid-owners"), None);
+
+        // SET describes the upload, and an owner-only document hides from everyone else
+        let mut changes = Metadata::new();
+        changes.set_text(MetadataKey::Title, "Fresh").unwrap();
+        changes.set_text(MetadataKey::Access, "owner").unwrap();
+        let set = GridBlock::with_metadata(GridRequestCode::SET, Some("/docs/new.txt"), &changes, &[]).unwrap();
+        let response = files.handle(&set, &alice);
+        assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::ROK));
+        assert_eq!(response.headers().unwrap().unwrap().get_text(&MetadataKey::Title), Some("Fresh"));
+        let response = files.handle(&request, &alice);
+        let headers = response.headers().unwrap().unwrap();
+        assert_eq!(headers.content_type(), Some("text/plain"));
+        assert_eq!(headers.get_text(&MetadataKey::Access), Some("owner"));
+        assert_eq!(response.content(), b"fresh");
+        let bob = RequestContext { client_subject: Some("CN=bob".to_string()), ..RequestContext::default() };
+        assert_eq!(files.handle(&request, &bob).opcode(), GridCode::Response(GridResponseCode::NOF));
+        assert_eq!(files.handle(&set, &bob).opcode(), GridCode::Response(GridResponseCode::GER));
+        let mut colour = Metadata::new();
+        colour.set_text(MetadataKey::from_name("colour").unwrap(), "red").unwrap();
+        let bad = GridBlock::with_metadata(GridRequestCode::SET, Some("/docs/new.txt"), &colour, &[]).unwrap();
+        assert_eq!(files.handle(&bad, &alice).opcode(), GridCode::Response(GridResponseCode::GER));
+
+        // token holders upload and own documents under their principal, without a certificate
+        let carol = RequestContext { principal: Some("carol".to_string()), ..RequestContext::default() };
+        let put = GridBlock::new(GridRequestCode::PUT, Some("/docs/carol.txt"), &mut b"hers".to_vec()).unwrap();
+        assert_eq!(files.handle(&put, &carol).opcode(), GridCode::Response(GridResponseCode::ROK));
+        let set = GridBlock::with_metadata(GridRequestCode::SET, Some("/docs/carol.txt"), &changes, &[]).unwrap();
+        assert_eq!(files.handle(&set, &carol).opcode(), GridCode::Response(GridResponseCode::ROK));
+        let request = GridBlock::new(GridRequestCode::GET, Some("/docs/carol.txt"), &mut Vec::new()).unwrap();
+        assert_eq!(files.handle(&request, &carol).content(), b"hers");
+        assert_eq!(files.handle(&request, &alice).opcode(), GridCode::Response(GridResponseCode::NOF));
+        let dave = RequestContext { principal: Some("dave".to_string()), ..RequestContext::default() };
+        assert_eq!(files.handle(&put, &dave).opcode(), GridCode::Response(GridResponseCode::GER));
+
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn directories_without_index_are_listed() {
+        let root = std::env::temp_dir().join(format!("crash-listing-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("guides")).unwrap();
+        fs::write(root.join("small notes.txt"), b"hi").unwrap();
+        fs::write(root.join("big.gml"), vec![b'x'; 2048]).unwrap();
+        fs::write(root.join(".hidden"), b"secret").unwrap();
+
+        let files = StaticFiles::new(&root).unwrap();
+        let listing = |path: &str| {
+            let request = GridBlock::new(GridRequestCode::GET, Some(path), &mut Vec::new()).unwrap();
+            let response = files.handle(&request, &RequestContext::default());
+            (response.opcode(), String::from_utf8(response.content().to_vec()).unwrap())
+        };
+        let position = |text: &str, needle: &str| text.find(needle).unwrap_or_else(|| panic!("{} missing from {}", needle, text));
+
+        // directories come first, hidden files never show up
+        let (code, text) = listing("/");
+        assert_eq!(code, GridCode::Response(GridResponseCode::ROK));
+        assert!(text.starts_with("# Index of /\n"));
+        assert!(text.contains("=> /small%20notes.txt small notes.txt  2 B  "));
+        assert!(text.contains("=> /big.gml big.gml  2.0 KiB  "));
+        assert!(!text.contains("hidden"));
+        assert!(position(&text, "=> /guides/") < position(&text, "=> /big.gml"));
+        assert!(position(&text, "=> /big.gml") < position(&text, "=> /small%20notes.txt"));
+
+        // the query picks the order, and the entries link back to files that are served
+        let (_, text) = listing("/?sort=size&order=desc");
+        assert!(position(&text, "=> /big.gml") < position(&text, "=> /small%20notes.txt"));
+        assert_eq!(listing("/small%20notes.txt").1, "hi");
+        assert_eq!(listing("/?sort=colour").0, GridCode::Response(GridResponseCode::GER));
+        assert!(listing("/guides").1.contains("=> / Parent directory"));
+
+        // a broken properties file hides its document instead of making it public
+        fs::write(root.join("guides/restricted.txt"), b"secret").unwrap();
+        fs::write(root.join("guides/.restricted.txt.props"), b"\xff garbage without a colon").unwrap();
+        assert!(!listing("/guides").1.contains("restricted"));
+        assert_eq!(listing("/guides/restricted.txt").0, GridCode::Response(GridResponseCode::DNY));
+
+        let files = files.with_listings(false);
+        let request = GridBlock::new(GridRequestCode::GET, Some("/guides/"), &mut Vec::new()).unwrap();
+        assert_eq!(files.handle(&request, &RequestContext::default()).opcode(), GridCode::Response(GridResponseCode::NOF));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,156 @@
+// Defines how GML documents are shown to web browsers
+use grid::gml::{GmlDocument, GmlLine};
+
+
+/// Renders a GML document as an HTML page
+///
+/// Links to GRID servers and absolute paths are rewritten to go through the
+/// gateway. Relative links are left alone, browsers resolve them just fine.
+/// Links with schemes other than `http`, `https` and `mailto` are shown as text,
+/// so a document can't run `javascript:` or `data:` on the gateway's origin.
+///
+/// ## Params:
+/// * document: the parsed document
+/// * remote: the server the document came from, e.g. `grid!example.org:7500`
+/// * path: the path of the document, shown as the title if it has no heading
+///
+/// ## Returns:
+/// * the page
+pub fn render_html(document: &GmlDocument, remote: &str, path: &str) -> String {
+    let title = document.title().map(|t| t.to_string()).unwrap_or_else(|| format!("{}{}", remote, path));
+    let mut body = String::new();
+    let mut in_list = false;
+
+    for line in &document.lines {
+        // consecutive list items share a list
+        let is_item = matches!(line, GmlLine::ListItem(_));
+        if in_list && !is_item {
+            body.push_str("</ul>\n");
+        } else if !in_list && is_item {
+            body.push_str("<ul>\n");
+        }
+        in_list = is_item;
+
+        match line {
+            GmlLine::Heading(level, text) => body.push_str(&format!("<h{0}>{1}</h{0}>\n", level, escape(text))),
+            GmlLine::Link { target, label } => {
+                let label = if label.is_empty() { target } else { label };
+                match link_target(target, remote) {
+                    Some(href) => body.push_str(&format!("<p><a href=\"{}\">{}</a></p>\n", escape(&href), escape(label))),
+                    None => body.push_str(&format!("<p>{}</p>\n", escape(label)))
+                }
+            },
+            GmlLine::ListItem(text) => body.push_str(&format!("<li>{}</li>\n", escape(text))),
+            GmlLine::Quote(text) => body.push_str(&format!("<blockquote>{}</blockquote>\n", escape(text))),
+            GmlLine::Preformatted { alt, lines } => {
+                let lines: Vec<String> = lines.iter().map(|l| escape(l)).collect();
+                match alt.is_empty() {
+                    true => body.push_str(&format!("<pre>{}</pre>\n", lines.join("\n"))),
+                    false => body.push_str(&format!("<pre title=\"{}\">{}</pre>\n", escape(alt), lines.join("\n")))
+                }
+            },
+            GmlLine::Text(text) if text.trim().is_empty() => (),
+            GmlLine::Text(text) => body.push_str(&format!("<p>{}</p>\n", escape(text)))
+        }
+    }
+    if in_list {
+        body.push_str("</ul>\n");
+    }
+
+    page(&title, &body)
+}
+
+/// Renders a short page explaining why a document could not be shown
+///
+/// ## Params:
+/// * title: e.g. `404 Not Found`
+/// * message: what went wrong, in plain text
+///
+/// ## Returns:
+/// * the page
+pub fn render_error(title: &str, message: &str) -> String {
+    page(title, &format!("<h1>{}</h1>\n<p>{}</p>\n", escape(title), escape(message)))
+}
+
+
+/// Helper function wrapping a body into a whole page
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape(title), body
+    )
+}
+
+/// Helper function pointing a link of a document at the gateway where needed
+///
+/// ## Returns:
+/// The link for the page, or `None` if its scheme is not one browsers may follow from it
+fn link_target(target: &str, remote: &str) -> Option<String> {
+    if target.starts_with("grid!") || target.starts_with("grid.") {
+        return Some(format!("/{}", target));
+    }
+    if target.starts_with('/') && !target.starts_with("//") {
+        return Some(format!("/{}{}", remote, target));
+    }
+
+    // browsers skip whitespace and control characters when they look for the scheme
+    let cleaned: String = target.chars().filter(|c| !c.is_ascii_whitespace() && !c.is_control()).collect();
+    let scheme = match cleaned.find([':', '/', '?', '#']) {
+        Some(i) if cleaned[i..].starts_with(':') => Some(cleaned[..i].to_ascii_lowercase()),
+        _ => None
+    };
+    match scheme.as_deref() {
+        None | Some("http" | "https" | "mailto") => Some(target.to_string()),
+        Some(_) => None
+    }
+}
+
+/// Helper function escaping text for use in HTML content and attributes
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gml_becomes_html() {
+        let document = GmlDocument::parse(
+            "# Docs & more\n=> /guide.gml The <guide>\n=> grid!other:7500/ Elsewhere\n=> notes.gml\n* one\n* two\n> wise\n```rust\nfn x() {}\n```\n"
+        );
+        let html = render_html(&document, "grid!localhost:7500", "/docs/index.gml");
+        assert!(html.contains("<title>Docs &amp; more</title>"));
+        assert!(html.contains("<h1>Docs &amp; more</h1>"));
+        assert!(html.contains("<a href=\"/grid!localhost:7500/guide.gml\">The &lt;guide&gt;</a>"));
+        assert!(html.contains("<a href=\"/grid!other:7500/\">Elsewhere</a>"));
+        assert!(html.contains("<a href=\"notes.gml\">notes.gml</a>"));
+        assert!(html.contains("<ul>\n<li>one</li>\n<li>two</li>\n</ul>\n<blockquote>wise</blockquote>"));
+        assert!(html.contains("<pre title=\"rust\">fn x() {}</pre>"));
+
+        // links that would run on the gateway's origin lose their target
+        let document = GmlDocument::parse("=> javascript:fetch('/grid!10.0.0.5:7500/secret') Click\n=> JaVaScRiPt:x() Sneaky\n=> data:text/html,<script>x()</script> Data\n=> https://example.org/ Web\n=> mailto:me@example.org Mail\n");
+        let html = render_html(&document, "grid!localhost:7500", "/docs/index.gml");
+        assert!(!html.to_lowercase().contains("javascript") && !html.contains("data:"), "{}", html);
+        assert!(html.contains("<p>Click</p>") && html.contains("<p>Sneaky</p>") && html.contains("<p>Data</p>"));
+        assert!(html.contains("<a href=\"https://example.org/\">Web</a>"));
+        assert!(html.contains("<a href=\"mailto:me@example.org\">Mail</a>"));
+
+        // without a heading the page is named after where it came from
+        let html = render_html(&GmlDocument::parse("plain"), "grid!localhost:7500", "/a.gml");
+        assert!(html.contains("<title>grid!localhost:7500/a.gml</title>"));
+    }
+}
@@ -0,0 +1,484 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use clap::Parser;
+
+use rustls::ClientConfig;
+
+use tracing::{debug, info, warn};
+
+use grid::ca::load_certificates;
+use grid::client::{GridClient, tls_config};
+use grid::definitions::{GridBlock, GridCode, GridRequestCode, GridResponseCode, string_to_domain};
+use grid::gml::{GML_CONTENT_TYPE, GmlDocument};
+
+mod html;
+
+use html::{render_error, render_html};
+
+
+/// Largest HTTP request head read before giving up
+const MAX_HTTP_REQUEST: usize = 16 * 1024;
+
+/// How long a browser may take to send its request
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+#[derive(Parser, Debug)]
+#[command(term_width = 0)]
+struct Arguments {
+    /// Address to listen for HTTP on. Anyone who can reach it can fetch through the gateway
+    #[arg(short='l', long="listen", default_value="127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    /// PEM file with a CA certificate to trust besides the public roots
+    #[arg(long="ca", conflicts_with="plaintext")]
+    ca: Option<PathBuf>,
+
+    /// Speak GRID without TLS, e.g. to servers started with GridServer::plaintext
+    #[arg(long="plaintext")]
+    plaintext: bool,
+
+    /// Seconds GRID servers get to connect and to answer
+    #[arg(short='t', long="timeout", default_value_t=10)]
+    timeout: u64,
+
+    /// Also fetch from loopback, private and link-local addresses. Browsers could reach services behind the gateway
+    #[arg(long="allow-private")]
+    allow_private: bool,
+
+    /// HTTP connections answered at once, the rest get 503
+    #[arg(short='c', long="max-connections", default_value_t=64)]
+    max_connections: usize,
+
+    /// Print diagnostics to stderr. Repeat for more detail
+    #[arg(short='v', long="verbose", action=clap::ArgAction::Count)]
+    verbose: u8
+}
+
+
+/// structure describing how the gateway reaches GRID servers
+struct Gateway {
+    tls: Option<Arc<ClientConfig>>,     // `None` speaks plaintext GRID
+    timeout: Duration,
+    allow_private: bool                 // whether servers on this host and private networks may be fetched from
+}
+
+/// structure holding an HTTP response on its way to the browser
+struct HttpResponse {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>
+}
+
+impl HttpResponse {
+    /// Creates an HTML page response
+    fn html(status: u16, reason: &'static str, page: String) -> Self {
+        HttpResponse {
+            status,
+            reason,
+            headers: vec![
+                ("Content-Type", "text/html; charset=utf-8".to_string()),
+                // our pages need neither scripts nor anything from elsewhere
+                ("Content-Security-Policy", "default-src 'none'; style-src 'unsafe-inline'".to_string())
+            ],
+            body: page.into_bytes()
+        }
+    }
+
+    /// Creates an error page response
+    fn error(status: u16, reason: &'static str, message: &str) -> Self {
+        HttpResponse::html(status, reason, render_error(&format!("{} {}", status, reason), message))
+    }
+}
+
+
+impl Gateway {
+    /// Answers an HTTP GET for a gateway path
+    ///
+    /// ## Params:
+    /// * target: the request target, e.g. `/grid!localhost:7500/docs/?sort=size`
+    ///
+    /// ## Returns:
+    /// * the response for the browser
+    fn fetch(&self, target: &str) -> HttpResponse {
+        let rest = match target.strip_prefix('/') {
+            Some(a) if a.starts_with("grid!") || a.starts_with("grid.") => a,
+            _ => return HttpResponse::error(404, "Not Found", "Gateway paths look like /grid!example.org:7500/path")
+        };
+
+        // without a trailing slash relative links in the root document would lose the remote
+        let (remote, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('/') => (&rest[..i], &rest[i..]),
+            _ => {
+                let (remote, query) = rest.split_once('?').map(|(r, q)| (r, format!("?{}", q))).unwrap_or((rest, String::new()));
+                let mut response = HttpResponse::html(301, "Moved Permanently", String::new());
+                response.headers.push(("Location", format!("/{}/{}", remote, query)));
+                return response;
+            }
+        };
+        if let Err(e) = string_to_domain(remote) {
+            return HttpResponse::error(400, "Bad Request", &e);
+        }
+
+        let response = match self.request(remote, path) {
+            Ok(a) => a,
+            Err(e) => {
+                warn!(remote = %remote, path = %path, error = %e, "GRID request failed");
+                return HttpResponse::error(502, "Bad Gateway", &format!("Cannot reach {}: {}", remote, e));
+            }
+        };
+        let message = || String::from_utf8_lossy(response.content()).into_owned();
+
+        match response.opcode() {
+            GridCode::Response(GridResponseCode::ROK) => {
+                let headers = response.headers().ok().flatten();
+                let content_type = headers.as_ref().and_then(|h| h.content_type()).map(|t| t.to_string());
+                let is_gml = match &content_type {
+                    Some(t) => t.split(';').next().unwrap_or("").trim() == GML_CONTENT_TYPE,
+                    None => path.split('?').next().unwrap_or("").ends_with(".gml")
+                };
+                if is_gml {
+                    let document = GmlDocument::parse(&String::from_utf8_lossy(response.content()));
+                    return HttpResponse::html(200, "OK", render_html(&document, remote, path));
+                }
+                // anything else is the server's to decide, so it must not run as part of our origin
+                HttpResponse {
+                    status: 200,
+                    reason: "OK",
+                    headers: vec![
+                        ("Content-Type", content_type.unwrap_or_else(|| "application/octet-stream".to_string())),
+                        ("Content-Disposition", "attachment".to_string()),
+                        ("X-Content-Type-Options", "nosniff".to_string()),
+                        ("Content-Security-Policy", "default-src 'none'; sandbox".to_string())
+                    ],
+                    body: response.content().to_vec()
+                }
+            },
+            GridCode::Response(GridResponseCode::NOF) => HttpResponse::error(404, "Not Found", &format!("{}{} does not exist", remote, path)),
+            GridCode::Response(GridResponseCode::DNY) => HttpResponse::error(403, "Forbidden", &message()),
+            GridCode::Response(GridResponseCode::BSY) => {
+                let mut http = HttpResponse::error(503, "Service Unavailable", &message());
+                if let Some(wait) = response.retry_after() {
+                    // HTTP counts whole seconds, rounding down would have browsers come back too early
+                    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                    http.headers.push(("Retry-After", seconds.to_string()));
+                }
+                http
+            },
+            GridCode::Response(GridResponseCode::GER) => {
+                HttpResponse::error(502, "Bad Gateway", &format!("{} answered with an error: {}", remote, message()))
+            },
+            code => HttpResponse::error(502, "Bad Gateway", &format!("{} answered with an unexpected {:?}", remote, code))
+        }
+    }
+
+    /// Helper function sending a GRID GET over a new connection
+    fn request(&self, remote: &str, path: &str) -> Result<GridBlock, String> {
+        let (_, domain, port) = string_to_domain(remote)?;
+        let addresses = match (&domain[..], port).to_socket_addrs() {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Failed to lookup {}: {}", domain, e))
+        };
+
+        let mut last_error = format!("No addresses found for {}", domain);
+        for address in addresses {
+            // checked after the lookup, so names pointing inwards are caught as well
+            if !self.allow_private && is_internal(&address.ip()) {
+                last_error = format!("{} is a private address, the gateway needs --allow-private to fetch from it", address);
+                continue;
+            }
+            let stream = match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(a) => a,
+                Err(e) => {
+                    last_error = format!("Connection to {} failed: {}", address, e);
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_read_timeout(Some(self.timeout)).and_then(|_| stream.set_write_timeout(Some(self.timeout))) {
+                return Err(format!("Cannot set timeouts: {}", e));
+            }
+            let mut client = GridClient::with_transport(stream, remote, self.tls.clone())?;
+            let mut request = GridBlock::new(GridRequestCode::GET, Some(path), &mut Vec::new())?;
+            return client.send(&mut request).map_err(String::from);
+        }
+        Err(last_error)
+    }
+}
+
+
+/// Helper function telling whether an address belongs to this host or a private network
+fn is_internal(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V4(a) => {
+            // 100.64.0.0/10 is shared address space behind carrier-grade NAT
+            let shared = a.octets()[0] == 100 && a.octets()[1] & 0xc0 == 64;
+            a.is_loopback() || a.is_private() || a.is_link_local() || a.is_unspecified() || a.is_broadcast() || shared
+        },
+        IpAddr::V6(a) => match a.to_ipv4_mapped() {
+            Some(v4) => is_internal(&IpAddr::V4(v4)),
+            // fc00::/7 is unique local, fe80::/10 link-local
+            None => a.is_loopback() || a.is_unspecified() || a.segments()[0] & 0xfe00 == 0xfc00 || a.segments()[0] & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+
+/// Accepts HTTP connections and answers each on a thread of its own
+///
+/// ## Params:
+/// * listener: the bound listener
+/// * gateway: how GRID servers are reached
+/// * max_connections: connections answered at once, the rest are turned away with 503
+///
+/// ## Returns:
+/// None, the loop runs until accepting fails for good
+fn serve(listener: TcpListener, gateway: Arc<Gateway>, max_connections: usize) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(a) => a,
+            Err(e) => {
+                warn!(error = %e, "failed to accept connection");
+                continue;
+            }
+        };
+        if open.fetch_add(1, Ordering::SeqCst) >= max_connections {
+            open.fetch_sub(1, Ordering::SeqCst);
+            debug!("too many connections, refusing");
+            let response = HttpResponse::error(503, "Service Unavailable", "The gateway is busy, try again shortly");
+            let _ = stream.set_write_timeout(Some(HTTP_READ_TIMEOUT));
+            if let Err(e) = write_response(&mut stream, &response, true) {
+                debug!(error = %e, "HTTP request failed");
+            }
+            continue;
+        }
+
+        let (gateway, open) = (gateway.clone(), open.clone());
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &gateway) {
+                debug!(error = %e, "HTTP request failed");
+            }
+            open.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Helper function answering a single HTTP request and hanging up
+fn handle_connection(mut stream: TcpStream, gateway: &Gateway) -> Result<(), String> {
+    if let Err(e) = stream.set_read_timeout(Some(HTTP_READ_TIMEOUT)) {
+        return Err(format!("Cannot set read timeout: {}", e));
+    }
+
+    // the request head is all we need, GET carries no body
+    let mut head = Vec::new();
+    let mut buff = [0u8; 4096];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HTTP_REQUEST {
+            let response = HttpResponse::error(431, "Request Header Fields Too Large", "The request is too large");
+            return write_response(&mut stream, &response, true);
+        }
+        match stream.read(&mut buff) {
+            Ok(0) => return Err("Client hung up before finishing its request".to_string()),
+            Ok(a) => head.extend_from_slice(&buff[..a]),
+            Err(e) => return Err(format!("Failed to read request: {}", e))
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+    let (method, target) = (request_line.next().unwrap_or(""), request_line.next().unwrap_or(""));
+    let response = match method {
+        "GET" | "HEAD" => gateway.fetch(target),
+        _ => {
+            let mut response = HttpResponse::error(405, "Method Not Allowed", "The gateway only reads documents");
+            response.headers.push(("Allow", "GET, HEAD".to_string()));
+            response
+        }
+    };
+    info!(method = %method, target = %target, status = response.status, "request");
+    write_response(&mut stream, &response, method != "HEAD")
+}
+
+/// Helper function writing an HTTP response that closes the connection
+fn write_response(stream: &mut TcpStream, response: &HttpResponse, with_body: bool) -> Result<(), String> {
+    let mut out = format!("HTTP/1.1 {} {}\r\n", response.status, response.reason);
+    for (name, value) in &response.headers {
+        // values may come from GRID servers, which must not get to end the header
+        let value: String = value.chars().filter(|c| !c.is_control()).collect();
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.body.len()));
+
+    let mut bytes = out.into_bytes();
+    if with_body {
+        bytes.extend_from_slice(&response.body);
+    }
+    match stream.write_all(&bytes) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to write response: {}", e))
+    }
+}
+
+
+fn main() {
+    let args = Arguments::parse();
+
+    let level = match args.verbose {
+        0 => tracing::Level::WARN,
+        1 => tracing::Level::INFO,
+        2 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let tls = match (args.plaintext, &args.ca) {
+        (true, _) => None,
+        (false, Some(ca)) => Some(load_certificates(ca).and_then(|roots| tls_config(&roots))),
+        (false, None) => Some(tls_config(&[]))
+    };
+    let tls = match tls.transpose() {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("Failed to set up TLS: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if !args.listen.ip().is_loopback() {
+        warn!(address = %args.listen, "listening beyond localhost, anyone reaching it can fetch through the gateway");
+    }
+    let listener = match TcpListener::bind(args.listen) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", args.listen, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Serving GRID at http://{}/grid!host:port/path", args.listen);
+    let gateway = Gateway { tls, timeout: Duration::from_secs(args.timeout), allow_private: args.allow_private };
+    serve(listener, Arc::new(gateway), args.max_connections.max(1));
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use grid::metadata::Metadata;
+    use grid::server::{GridServer, RequestContext, busy_response, error_response};
+
+    #[test]
+    fn browsers_read_grid_over_loopback() {
+        let mut server = GridServer::plaintext(0);
+        server.set_handler(|request: &GridBlock, _ctx: &RequestContext| {
+            let mut headers = Metadata::new();
+            match &request.path().unwrap()[..] {
+                "/docs/index.gml" => {
+                    headers.set_content_type(GML_CONTENT_TYPE).unwrap();
+                    GridBlock::with_metadata(GridResponseCode::ROK, None, &headers, b"# Hello\n=> /other.gml Other\n").unwrap()
+                },
+                "/data.bin" => GridBlock::new(GridResponseCode::ROK, None, &mut vec![0, 1, 2]).unwrap(),
+                "/page.html" => {
+                    headers.set_content_type("text/html\r\nSet-Cookie: session=stolen").unwrap();
+                    GridBlock::with_metadata(GridResponseCode::ROK, None, &headers, b"<script>alert(1)</script>").unwrap()
+                },
+                "/secret" => error_response(GridResponseCode::DNY, "Authentication required"),
+                "/busy" => busy_response("Try later", Duration::from_millis(1500)),
+                _ => error_response(GridResponseCode::NOF, "")
+            }
+        });
+        server.bind_to("127.0.0.1:0".parse().unwrap()).unwrap();
+        let remote = format!("grid.{}", server.local_addr().unwrap());
+        thread::spawn(move || server.run());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let gateway = Arc::new(Gateway { tls: None, timeout: Duration::from_secs(5), allow_private: true });
+        thread::spawn(move || serve(listener, gateway, 8));
+
+        let get = |method: &str, target: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, target).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        };
+
+        let page = get("GET", &format!("/{}/docs/index.gml", remote));
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/html"), "{}", page);
+        assert!(page.contains("Content-Security-Policy: default-src 'none'; style-src 'unsafe-inline'\r\n"));
+        assert!(page.contains("<h1>Hello</h1>"));
+        assert!(page.contains(&format!("<a href=\"/{}/other.gml\">Other</a>", remote)));
+
+        let data = get("GET", &format!("/{}/data.bin", remote));
+        assert!(data.contains("Content-Type: application/octet-stream\r\n") && data.contains("Content-Length: 3\r\n"));
+
+        // content other than GML is handed over as a download, whatever it claims to be
+        let html = get("GET", &format!("/{}/page.html", remote));
+        assert!(html.contains("Content-Type: text/htmlSet-Cookie: session=stolen\r\n"), "{}", html);
+        assert!(!html.contains("\r\nSet-Cookie"));
+        assert!(html.contains("Content-Disposition: attachment\r\n"));
+        assert!(html.contains("X-Content-Type-Options: nosniff\r\n"));
+        assert!(html.contains("Content-Security-Policy: default-src 'none'; sandbox\r\n"));
+
+        assert!(get("GET", &format!("/{}/missing", remote)).starts_with("HTTP/1.1 404"));
+        let denied = get("GET", &format!("/{}/secret", remote));
+        assert!(denied.starts_with("HTTP/1.1 403") && denied.contains("Authentication required"));
+        let busy = get("GET", &format!("/{}/busy", remote));
+        assert!(busy.starts_with("HTTP/1.1 503") && busy.contains("Retry-After: 2\r\n"), "{}", busy);
+
+        // everything else is the browser's or the network's fault
+        assert!(get("GET", &format!("/{}", remote)).contains(&format!("Location: /{}/\r\n", remote)));
+        assert!(get("GET", "/index.html").starts_with("HTTP/1.1 404"));
+        assert!(get("POST", &format!("/{}/", remote)).starts_with("HTTP/1.1 405"));
+        let head = get("HEAD", &format!("/{}/data.bin", remote));
+        assert!(head.starts_with("HTTP/1.1 200") && head.ends_with("\r\n\r\n"));
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        assert!(get("GET", &format!("/grid.{}/", closed)).starts_with("HTTP/1.1 502"));
+
+        // unless told otherwise the gateway only fetches from public addresses
+        let public = Gateway { tls: None, timeout: Duration::from_secs(5), allow_private: false };
+        for remote in [remote.clone(), format!("grid.localhost:{}", closed.port())] {
+            let response = public.fetch(&format!("/{}/docs/index.gml", remote));
+            assert_eq!(response.status, 502);
+            assert!(String::from_utf8_lossy(&response.body).contains("--allow-private"));
+        }
+        assert!(is_internal(&"::ffff:10.1.2.3".parse().unwrap()) && is_internal(&"fd00::1".parse().unwrap()));
+        assert!(!is_internal(&"93.184.215.14".parse().unwrap()) && !is_internal(&"2606:4700::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn connections_beyond_the_limit_are_turned_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let gateway = Arc::new(Gateway { tls: None, timeout: Duration::from_secs(5), allow_private: false });
+        thread::spawn(move || serve(listener, gateway, 1));
+
+        // the first connection holds the only slot while it takes its time with the request
+        let mut slow = TcpStream::connect(address).unwrap();
+        let mut busy = TcpStream::connect(address).unwrap();
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+
+        // once it is done the slot is free again
+        write!(slow, "GET / HTTP/1.1\r\n\r\n").unwrap();
+        slow.read_to_string(&mut String::new()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let mut next = TcpStream::connect(address).unwrap();
+        write!(next, "GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        next.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+}
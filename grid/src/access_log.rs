@@ -0,0 +1,278 @@
+// Defines the access log servers write one line per request to
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+use tracing::warn;
+
+
+use crate::connection::RequestContext;
+use crate::definitions::{GridCode, GRID_HEADER_SIZE};
+
+
+/// Layout of the lines written to an access log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    /// Space separated fields, `-` for anything unknown
+    Text,
+    /// One JSON object per line
+    Json
+}
+
+impl AccessLogFormat {
+    /// Parses a format name, either `text` or `json`
+    ///
+    /// ## Params:
+    /// * name: the name of the format
+    ///
+    /// ## Returns:
+    /// * Ok: the format
+    /// * Err: a string describing the issue encountered
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "text" => Ok(AccessLogFormat::Text),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(format!("Unknown access log format '{}', expected text or json", name))
+        }
+    }
+}
+
+
+/// structure describing a single request and how it was answered
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+    /// When the request started being answered
+    pub timestamp: OffsetDateTime,
+    /// Address of the client, if the transport has one
+    pub peer: Option<SocketAddr>,
+    /// Server name the client asked for via SNI
+    pub server_name: Option<String>,
+    /// Subject of the client certificate
+    pub client_subject: Option<String>,
+    /// Request OPCODE, e.g. `GET`
+    pub opcode: String,
+    /// Requested path, if it could be read
+    pub path: Option<String>,
+    /// Response code, e.g. `ROK`
+    pub code: String,
+    /// Size of the request frame, in bytes
+    pub bytes_in: usize,
+    /// Size of the response frame, in bytes
+    pub bytes_out: usize,
+    /// Time spent answering the request
+    pub latency: Duration,
+
+    started: Instant
+}
+
+impl AccessRecord {
+    /// Starts a record for a raw request frame, before it is answered
+    pub(crate) fn start(frame: &[u8], ctx: &RequestContext) -> Self {
+        // only the header and path are looked at, the frame may be garbage
+        let path = frame.get(1..17)
+            .and_then(|b| <[u8; 16]>::try_from(b).ok())
+            .map(u128::from_be_bytes)
+            .and_then(|size| frame.get(GRID_HEADER_SIZE..GRID_HEADER_SIZE.saturating_add(size.try_into().ok()?)))
+            .and_then(|b| std::str::from_utf8(b).ok())
+            .map(|p| p.to_string());
+
+        AccessRecord {
+            timestamp: OffsetDateTime::now_utc(),
+            peer: ctx.peer,
+            server_name: ctx.server_name.clone(),
+            client_subject: ctx.client_subject.clone(),
+            opcode: code_name(frame.first()),
+            path,
+            code: "-".to_string(),
+            bytes_in: frame.len(),
+            bytes_out: 0,
+            latency: Duration::ZERO,
+            started: Instant::now()
+        }
+    }
+
+    /// Completes the record with the serialized response
+    pub(crate) fn finish(mut self, response: &[u8]) -> Self {
+        self.code = code_name(response.first());
+        self.bytes_out = response.len();
+        self.latency = self.started.elapsed();
+        self
+    }
+
+    /// Formats the record as a single line, without the line break
+    ///
+    /// ## Params:
+    /// * format: the layout of the line
+    ///
+    /// ## Returns:
+    /// * the line
+    pub fn to_line(&self, format: AccessLogFormat) -> String {
+        let timestamp = self.timestamp.format(&Rfc3339).unwrap_or_else(|_| "-".to_string());
+        let peer = self.peer.map(|p| p.to_string());
+        let latency_ms = self.latency.as_secs_f64() * 1000.0;
+
+        match format {
+            AccessLogFormat::Text => {
+                // free form fields are quoted so every line splits into the same columns
+                let quoted = |value: &Option<String>| match value {
+                    Some(a) => format!("{:?}", a),
+                    None => "-".to_string()
+                };
+                format!(
+                    "{} {} {} {} {} {} {} {} {} {:.3}ms",
+                    timestamp,
+                    peer.as_deref().unwrap_or("-"),
+                    self.server_name.as_deref().unwrap_or("-"),
+                    quoted(&self.client_subject),
+                    self.opcode,
+                    quoted(&self.path),
+                    self.code,
+                    self.bytes_in,
+                    self.bytes_out,
+                    latency_ms
+                )
+            },
+            AccessLogFormat::Json => format!(
+                "{{\"timestamp\":{},\"peer\":{},\"sni\":{},\"client_subject\":{},\"opcode\":{},\"path\":{},\"code\":{},\"bytes_in\":{},\"bytes_out\":{},\"latency_ms\":{:.3}}}",
+                json_string(Some(&timestamp)),
+                json_string(peer.as_deref()),
+                json_string(self.server_name.as_deref()),
+                json_string(self.client_subject.as_deref()),
+                json_string(Some(&self.opcode)),
+                json_string(self.path.as_deref()),
+                json_string(Some(&self.code)),
+                self.bytes_in,
+                self.bytes_out,
+                latency_ms
+            )
+        }
+    }
+}
+
+
+/// where access log lines end up
+enum Sink {
+    Stdout,
+    File { path: PathBuf, file: File }
+}
+
+/// structure defining an access log shared by every connection of a server
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sink: Mutex<Sink>
+}
+
+impl AccessLog {
+    /// Creates an access log writing to standard output
+    ///
+    /// ## Params:
+    /// * format: the layout of the lines
+    ///
+    /// ## Returns:
+    /// * an instance of the structure
+    pub fn stdout(format: AccessLogFormat) -> Self {
+        AccessLog { format, sink: Mutex::new(Sink::Stdout) }
+    }
+
+    /// Creates an access log appending to a file
+    ///
+    /// ## Params:
+    /// * path: the file to append to, created if missing
+    /// * format: the layout of the lines
+    ///
+    /// ## Returns:
+    /// * Ok: an instance of the structure
+    /// * Err: a string describing the issue encountered
+    pub fn file(path: impl AsRef<Path>, format: AccessLogFormat) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path)?;
+        Ok(AccessLog { format, sink: Mutex::new(Sink::File { path, file }) })
+    }
+
+    /// Opens the log file again, picking up a file moved away by log rotation
+    ///
+    /// Does nothing when logging to standard output.
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered. The old file keeps being written to
+    pub fn reopen(&self) -> Result<(), String> {
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        if let Sink::File { path, file } = &mut *sink {
+            *file = open_append(path)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a record to the log
+    ///
+    /// ## Params:
+    /// * record: the request to log
+    ///
+    /// ## Returns:
+    /// None, failures are reported through tracing since there is no one to return them to
+    pub fn write(&self, record: &AccessRecord) {
+        let mut line = record.to_line(self.format);
+        line.push('\n');
+
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        let result = match &mut *sink {
+            Sink::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Sink::File { file, .. } => file.write_all(line.as_bytes())
+        };
+        if let Err(e) = result {
+            warn!(error = %e, "failed to write access log");
+        }
+    }
+}
+
+
+/// Helper function opening a file for appending, creating it if needed
+fn open_append(path: &Path) -> Result<File, String> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(a) => Ok(a),
+        Err(e) => Err(format!("Cannot open access log {}: {}", path.display(), e))
+    }
+}
+
+/// Helper function naming the OPCODE in the first byte of a frame
+fn code_name(byte: Option<&u8>) -> String {
+    match byte.map(|b| (b, GridCode::from_byte(*b))) {
+        Some((_, Ok(GridCode::Request(code)))) => format!("{:?}", code),
+        Some((_, Ok(GridCode::Response(code)))) => format!("{:?}", code),
+        Some((b, Err(_))) => b.to_string(),
+        None => "-".to_string()
+    }
+}
+
+/// Helper function encoding an optional string as a JSON string or `null`
+fn json_string(value: Option<&str>) -> String {
+    let value = match value {
+        Some(a) => a,
+        None => return "null".to_string()
+    };
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,191 @@
+// Defines the admission control used to answer over-limit clients with BSY
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::connection::RequestContext;
+
+
+/// Number of tracked clients below which idle buckets are never swept
+const PRUNE_THRESHOLD: usize = 4096;
+
+
+/// structure describing a token bucket rate limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Requests per second a client may sustain
+    pub rate: f64,
+    /// Requests a client may send in a burst after being quiet
+    pub burst: u32
+}
+
+impl RateLimit {
+    /// Creates a new `RateLimit` instance
+    ///
+    /// ## Params:
+    /// * rate: requests per second a client may sustain
+    /// * burst: requests a client may send in a burst, at least 1
+    ///
+    /// ## Returns:
+    /// * an instance of the structure
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimit { rate, burst: burst.max(1) }
+    }
+}
+
+
+/// structure defining a single token bucket
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    updated: Instant
+}
+
+impl TokenBucket {
+    /// Helper function adding the tokens earned since the last update
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let earned = now.saturating_duration_since(self.updated).as_secs_f64() * limit.rate;
+        self.tokens = (self.tokens + earned).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+
+/// structure holding the token buckets of a limiter and when to sweep them next
+pub(crate) struct Buckets<K> {
+    pub clients: HashMap<K, TokenBucket>,           // token bucket of each client seen lately
+    pub next_prune: usize                           // number of tracked clients triggering the next sweep
+}
+
+
+/// structure keeping one token bucket per client
+pub(crate) struct RateLimiter<K> {
+    limit: RateLimit,
+    pub buckets: Mutex<Buckets<K>>
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    /// Creates a new `RateLimiter` instance
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter { limit, buckets: Mutex::new(Buckets { clients: HashMap::new(), next_prune: PRUNE_THRESHOLD }) }
+    }
+
+    /// Takes a token for a client
+    ///
+    /// ## Params:
+    /// * key: the client
+    ///
+    /// ## Returns:
+    /// * Ok: the request may go ahead
+    /// * Err: how long until the client has a token again
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        // forget clients whose buckets have filled up again, they look exactly like new ones.
+        // the next sweep waits until the map doubles, so each request pays for a sweep only once
+        if buckets.clients.len() >= buckets.next_prune {
+            let limit = self.limit;
+            buckets.clients.retain(|_, bucket| {
+                bucket.refill(&limit, now);
+                bucket.tokens < limit.burst as f64
+            });
+            buckets.next_prune = (buckets.clients.len() * 2).max(PRUNE_THRESHOLD);
+        }
+
+        let bucket = buckets.clients.entry(key).or_insert(TokenBucket { tokens: self.limit.burst as f64, updated: now });
+        bucket.refill(&self.limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        // a rate of 0 divides to infinity, which means never
+        Err(Duration::try_from_secs_f64((1.0 - bucket.tokens) / self.limit.rate).unwrap_or(Duration::MAX))
+    }
+}
+
+
+/// structure holding the per-client rate limits of a server
+#[derive(Clone, Default)]
+pub(crate) struct RateLimits {
+    pub per_ip: Option<Arc<RateLimiter<IpAddr>>>,
+    pub per_certificate: Option<Arc<RateLimiter<String>>>
+}
+
+impl RateLimits {
+    /// Checks a request against every limit that applies to its client
+    ///
+    /// ## Params:
+    /// * ctx: the connection the request arrived on
+    ///
+    /// ## Returns:
+    /// * Ok: the request may go ahead
+    /// * Err: how long the client should wait before retrying
+    pub fn check(&self, ctx: &RequestContext) -> Result<(), Duration> {
+        if let (Some(limiter), Some(peer)) = (&self.per_ip, ctx.peer) {
+            limiter.check(client_network(peer.ip()))?;
+        }
+        if let (Some(limiter), Some(subject)) = (&self.per_certificate, &ctx.client_subject) {
+            limiter.check(subject.clone())?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Returns the address a client's requests are counted against
+///
+/// IPv6 clients usually get a whole /64, so they are limited per /64 rather than
+/// per address. IPv4-mapped addresses count as the IPv4 address they carry.
+///
+/// ## Params:
+/// * address: the address of the peer
+///
+/// ## Returns:
+/// The IPv4 address, or the IPv6 address with its host bits cleared
+pub(crate) fn client_network(address: IpAddr) -> IpAddr {
+    match address.to_canonical() {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(v6.to_bits() & !u128::from(u64::MAX))),
+        v4 => v4
+    }
+}
+
+
+/// structure counting the open connections of a server
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionCounter {
+    open: Arc<AtomicUsize>
+}
+
+impl ConnectionCounter {
+    /// Counts a new connection until the returned slot is dropped
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * the slot of the connection, and how many connections were open before it
+    pub fn open(&self) -> (ConnectionSlot, usize) {
+        let before = self.open.fetch_add(1, Ordering::SeqCst);
+        (ConnectionSlot { open: self.open.clone() }, before)
+    }
+
+    /// Returns how many connections are open
+    pub fn count(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+}
+
+/// structure releasing a counted connection when dropped
+pub(crate) struct ConnectionSlot {
+    open: Arc<AtomicUsize>
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,295 @@
+// Defines the bearer token checks and path rules servers answer DNY with
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::path::{normalize_path, split_query};
+use crate::definitions::{GridBlock, GridCode, GridRequestCode, from_hex, to_hex};
+
+/// Principal name matching every client, including those without a token
+pub const ANYONE: &str = "*";
+
+/// Principal name matching every client with a valid token
+pub const AUTHENTICATED: &str = "authenticated";
+
+
+/// What a request wants to do with its path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    /// Fetch the resource, e.g. with `GET`
+    Read,
+    /// Change the resource, e.g. with `PUT` or `SET`
+    Write
+}
+
+impl Permission {
+    /// Returns the permission a request needs
+    pub fn of(request: &GridBlock) -> Self {
+        match request.opcode() {
+            GridCode::Request(GridRequestCode::PUT | GridRequestCode::SET) => Permission::Write,
+            _ => Permission::Read
+        }
+    }
+}
+
+
+/// How bearer tokens are checked
+pub enum TokenVerifier {
+    /// Tokens listed one per line as `principal token`
+    File(HashMap<String, String>),
+    /// Tokens signed with a shared key, see `issue_token`
+    Hmac(Vec<u8>)
+}
+
+impl TokenVerifier {
+    /// Loads the tokens listed in a file
+    ///
+    /// Each line holds a principal and its token separated by whitespace. Empty
+    /// lines and lines starting with `#` are skipped.
+    ///
+    /// ## Params:
+    /// * path: the token file
+    ///
+    /// ## Returns:
+    /// * Ok: a verifier accepting the listed tokens
+    /// * Err: a string naming the offending line
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = match fs::read_to_string(path) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Cannot read token file {}: {}", path.display(), e))
+        };
+
+        let mut tokens = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (principal, token) = match line.split_once(char::is_whitespace) {
+                Some((p, t)) if !t.trim().is_empty() => (p, t.trim()),
+                _ => return Err(format!("{} line {}: expected 'principal token'", path.display(), i + 1))
+            };
+            if principal == ANYONE || principal == AUTHENTICATED {
+                return Err(format!("{} line {}: '{}' is reserved for access rules and can't be a principal", path.display(), i + 1, principal));
+            }
+            if tokens.insert(token.to_string(), principal.to_string()).is_some() {
+                return Err(format!("{} line {}: token is given twice", path.display(), i + 1));
+            }
+        }
+        Ok(TokenVerifier::File(tokens))
+    }
+
+    /// Checks a token and returns who it belongs to
+    ///
+    /// ## Params:
+    /// * token: the bearer token sent by the client
+    ///
+    /// ## Returns:
+    /// * Ok: the principal the token stands for
+    /// * Err: a string describing why the token is refused
+    pub fn verify(&self, token: &str) -> Result<String, String> {
+        match self {
+            TokenVerifier::File(tokens) => match tokens.get(token) {
+                Some(principal) => Ok(principal.clone()),
+                None => Err("Unknown token".to_string())
+            },
+            TokenVerifier::Hmac(key) => {
+                // principals may contain dots, the expiry and signature can't
+                let mut parts = token.rsplitn(3, '.');
+                let (signature, expiry, principal) = match (parts.next(), parts.next(), parts.next()) {
+                    (Some(s), Some(e), Some(p)) if !p.is_empty() => (s, e, p),
+                    _ => return Err("Malformed token".to_string())
+                };
+                let signature = match from_hex(signature) {
+                    Ok(a) => a,
+                    Err(_) => return Err("Malformed token".to_string())
+                };
+                if hmac_sha256(key, format!("{}.{}", principal, expiry).as_bytes()).verify_slice(&signature).is_err() {
+                    return Err("Bad token signature".to_string());
+                }
+                let expiry: u64 = match expiry.parse() {
+                    Ok(a) => a,
+                    Err(_) => return Err("Malformed token".to_string())
+                };
+                if unix_now() >= expiry {
+                    return Err("Token has expired".to_string());
+                }
+                Ok(principal.to_string())
+            }
+        }
+    }
+}
+
+/// Issues a token for a `TokenVerifier::Hmac` holding the same key
+///
+/// Tokens read `principal.expiry.signature`, where expiry is in seconds since
+/// the UNIX epoch and the signature is the hex HMAC-SHA256 of what precedes it.
+///
+/// ## Params:
+/// * key: the shared signing key
+/// * principal: who the token stands for
+/// * valid_for: how long the token is accepted
+///
+/// ## Returns:
+/// * the token
+pub fn issue_token(key: &[u8], principal: &str, valid_for: Duration) -> String {
+    let claims = format!("{}.{}", principal, unix_now().saturating_add(valid_for.as_secs()));
+    let signature = to_hex(&hmac_sha256(key, claims.as_bytes()).finalize().into_bytes());
+    format!("{}.{}", claims, signature)
+}
+
+
+/// structure granting a principal access below a path
+#[derive(Debug, Clone, PartialEq)]
+pub struct AclRule {
+    /// Path the rule covers, along with everything below it
+    pub path: String,
+    /// Principal the rule applies to: a name, `ANYONE` or `AUTHENTICATED`
+    pub principal: String,
+    /// Whether the principal may read
+    pub read: bool,
+    /// Whether the principal may write
+    pub write: bool
+}
+
+impl AclRule {
+    /// Creates a new `AclRule` instance
+    ///
+    /// ## Params:
+    /// * path: the path the rule covers, normalized
+    /// * principal: the principal the rule applies to
+    /// * read: whether the principal may read
+    /// * write: whether the principal may write
+    ///
+    /// ## Returns:
+    /// * an instance of the structure
+    pub fn new(path: &str, principal: &str, read: bool, write: bool) -> Self {
+        AclRule { path: normalize_path(path), principal: principal.to_string(), read, write }
+    }
+
+    /// Helper function checking whether the rule covers a normalized path
+    fn covers(&self, path: &str) -> bool {
+        self.path == "/" || path == self.path || path.starts_with(&format!("{}/", self.path))
+    }
+
+    /// Helper function checking whether the rule applies to a client
+    fn applies_to(&self, principal: Option<&str>) -> bool {
+        match (self.principal.as_str(), principal) {
+            (ANYONE, _) => true,
+            (AUTHENTICATED, p) => p.is_some(),
+            (name, Some(p)) => name == p,
+            (_, None) => false
+        }
+    }
+}
+
+
+/// structure deciding which requests a server answers with `DNY`
+///
+/// Paths no rule covers are open to everyone. Otherwise only the rules with the
+/// longest path covering the request count, and one of them must grant the
+/// client the permission it needs.
+pub struct AccessControl {
+    verifier: Option<TokenVerifier>,    // `None` refuses every token
+    rules: Vec<AclRule>
+}
+
+impl AccessControl {
+    /// Creates a new `AccessControl` instance
+    ///
+    /// ## Params:
+    /// * verifier: how tokens are checked, `None` to refuse every token
+    /// * rules: who may do what below which paths
+    ///
+    /// ## Returns:
+    /// * an instance of the structure
+    pub fn new(verifier: Option<TokenVerifier>, rules: Vec<AclRule>) -> Self {
+        AccessControl { verifier, rules }
+    }
+
+    /// Finds out who sent a request, from the `auth-token` header it carries
+    ///
+    /// ## Params:
+    /// * request: the decompressed request
+    ///
+    /// ## Returns:
+    /// * Ok: the principal, or `None` if the request carries no token
+    /// * Err: a string describing why the token is refused
+    pub fn principal(&self, request: &GridBlock) -> Result<Option<String>, String> {
+        let headers = match request.headers()? {
+            Some(a) => a,
+            None => return Ok(None)
+        };
+        let token = match headers.auth_token() {
+            Some(a) => a,
+            None => return Ok(None)
+        };
+        match &self.verifier {
+            Some(verifier) => verifier.verify(token).map(Some),
+            None => Err("This server does not take tokens".to_string())
+        }
+    }
+
+    /// Checks whether a client may do something with a path
+    ///
+    /// ## Params:
+    /// * principal: who is asking, `None` for clients without a token
+    /// * path: the path of the request
+    /// * permission: what the request wants to do
+    ///
+    /// ## Returns:
+    /// `true` if the request may go ahead
+    pub fn allows(&self, principal: Option<&str>, path: &str, permission: Permission) -> bool {
+        let path = normalize_path(path);
+        let longest = match self.rules.iter().filter(|r| r.covers(&path)).map(|r| r.path.len()).max() {
+            Some(a) => a,
+            None => return true
+        };
+        self.rules
+            .iter()
+            .filter(|r| r.path.len() == longest && r.covers(&path) && r.applies_to(principal))
+            .any(|r| match permission {
+                Permission::Read => r.read,
+                Permission::Write => r.write
+            })
+    }
+
+    /// Checks a request, returning the reason to send along with `DNY`
+    ///
+    /// ## Params:
+    /// * request: the decompressed request
+    ///
+    /// ## Returns:
+    /// * Ok: the principal the request may go ahead as
+    /// * Err: a human readable reason the request is denied
+    pub fn check(&self, request: &GridBlock) -> Result<Option<String>, String> {
+        let principal = match self.principal(request) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Invalid token: {}", e))
+        };
+        let (path, _) = split_query(&request.path()?);
+        match (self.allows(principal.as_deref(), &path, Permission::of(request)), &principal) {
+            (true, _) => Ok(principal),
+            (false, None) => Err("Authentication required".to_string()),
+            (false, Some(_)) => Err("Permission denied".to_string())
+        }
+    }
+}
+
+
+/// Helper function returning an HMAC-SHA256 fed with a message
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(message);
+    mac
+}
+
+/// Helper function returning the seconds since the UNIX epoch
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -0,0 +1,393 @@
+// Defines a small local certificate authority for private GRID deployments
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use rcgen::{
+    BasicConstraints,
+    Certificate,
+    CertificateParams,
+    CertificateRevocationList,
+    CertificateRevocationListParams,
+    DistinguishedName,
+    DnType,
+    ExtendedKeyUsagePurpose,
+    IsCa,
+    KeyIdMethod,
+    KeyPair,
+    KeyUsagePurpose,
+    RevokedCertParams,
+    SerialNumber
+};
+use time::{Duration, OffsetDateTime};
+
+use tracing::info;
+
+
+use crate::definitions::{from_hex, to_hex};
+use crate::identity::{certificate_params, write_private};
+use crate::server::CertificateStore;
+
+
+/// Number of days a freshly created root CA is valid for
+pub const CA_VALIDITY_DAYS: i64 = 3650;
+
+/// Number of days a revocation list is valid for before it has to be reissued
+pub const CRL_VALIDITY_DAYS: i64 = 30;
+
+const CA_CERTIFICATE_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca.key";
+const REVOKED_FILE: &str = "revoked.txt";   // one "<hex serial> <unix time>" line per revoked certificate
+const CRL_FILE: &str = "crl.pem";
+
+
+/// structure defining a root CA kept in a directory on disk
+pub struct CertificateAuthority {
+    dir: PathBuf,
+    signer: Certificate,    // rcgen certificate built around the CA key, only used for signing
+    der: Vec<u8>            // the CA certificate as stored, which is what peers trust
+}
+
+impl CertificateAuthority {
+    /// Creates a new root CA and stores it in `dir`
+    ///
+    /// ## Params:
+    /// * dir: the directory to keep the CA in. It must not hold a CA already
+    /// * name: the common name of the CA
+    ///
+    /// ## Returns:
+    /// * Ok: an instance of the structure
+    /// * Err: a string describing the issue encountered
+    pub fn create(dir: &Path, name: &str) -> Result<Self, String> {
+        let cert_path = dir.join(CA_CERTIFICATE_FILE);
+        if cert_path.exists() {
+            return Err(format!("{} already holds a certificate authority", dir.display()));
+        }
+
+        let mut params = CertificateParams::default();
+        let mut dn = DistinguishedName::new();
+        dn.push(DnType::CommonName, name);
+        params.distinguished_name = dn;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature
+        ];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::hours(1);
+        params.not_after = now + Duration::days(CA_VALIDITY_DAYS);
+
+        let cert = match Certificate::from_params(params) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Certificate generation failed: {}", e))
+        };
+        let cert_pem = match cert.serialize_pem() {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Certificate serialization failed: {}", e))
+        };
+
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(format!("Cannot create CA directory {}: {}", dir.display(), e));
+        }
+        write_private(&dir.join(CA_KEY_FILE), cert.serialize_private_key_pem().as_bytes())?;
+        if let Err(e) = fs::write(&cert_path, cert_pem) {
+            return Err(format!("Cannot write {}: {}", cert_path.display(), e));
+        }
+        info!(dir = %dir.display(), name = %name, "created certificate authority");
+
+        // go through the stored copy, like every later user of the CA will
+        CertificateAuthority::open(dir)
+    }
+
+    /// Opens a CA previously made with `create`
+    ///
+    /// ## Params:
+    /// * dir: the directory the CA is kept in
+    ///
+    /// ## Returns:
+    /// * Ok: an instance of the structure
+    /// * Err: a string describing the issue encountered
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        let der = match load_certificates(dir.join(CA_CERTIFICATE_FILE))?.into_iter().next() {
+            Some(a) => a.0,
+            None => return Err(format!("No CA certificate found in {}", dir.display()))
+        };
+
+        let key_path = dir.join(CA_KEY_FILE);
+        let key_pem = match fs::read_to_string(&key_path) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Cannot read CA key {}: {}", key_path.display(), e))
+        };
+        let key = match KeyPair::from_pem(&key_pem) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Cannot parse CA key {}: {}", key_path.display(), e))
+        };
+
+        let signer = match CertificateParams::from_ca_cert_der(&der, key).and_then(Certificate::from_params) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Cannot load CA certificate: {}", e))
+        };
+        Ok(CertificateAuthority { dir: dir.to_path_buf(), signer, der })
+    }
+
+    /// Returns the CA certificate, for use as a trust anchor by clients and servers
+    pub fn certificate(&self) -> rustls::Certificate {
+        rustls::Certificate(self.der.clone())
+    }
+
+    /// Returns the path of the CA certificate in PEM format
+    pub fn certificate_path(&self) -> PathBuf {
+        self.dir.join(CA_CERTIFICATE_FILE)
+    }
+
+    /// Issues a certificate GRID servers can present for a set of names
+    ///
+    /// ## Params:
+    /// * names: the DNS names and IP addresses the certificate is valid for
+    /// * validity_days: how many days from now the certificate is valid for
+    /// * cert_path: where to write the certificate chain, leaf first
+    /// * key_path: where to write the private key
+    ///
+    /// ## Returns:
+    /// * Ok: the issued certificate
+    /// * Err: a string describing the issue encountered
+    pub fn issue_server(
+        &self,
+        names: Vec<String>,
+        validity_days: i64,
+        cert_path: &Path,
+        key_path: &Path
+    ) -> Result<CertificateStore, String> {
+        if names.is_empty() {
+            return Err("A server certificate needs at least one name".to_string());
+        }
+        let mut params = certificate_params(&names, validity_days);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        self.issue(params, names, cert_path, key_path)
+    }
+
+    /// Issues a certificate GRID clients can authenticate with
+    ///
+    /// ## Params:
+    /// * name: the common name identifying the client
+    /// * validity_days: how many days from now the certificate is valid for
+    /// * cert_path: where to write the certificate chain, leaf first
+    /// * key_path: where to write the private key
+    ///
+    /// ## Returns:
+    /// * Ok: the issued certificate
+    /// * Err: a string describing the issue encountered
+    pub fn issue_client(
+        &self,
+        name: &str,
+        validity_days: i64,
+        cert_path: &Path,
+        key_path: &Path
+    ) -> Result<CertificateStore, String> {
+        let mut params = certificate_params(&[name.to_string()], validity_days);
+        params.subject_alt_names.clear();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        self.issue(params, Vec::new(), cert_path, key_path)
+    }
+
+    /// Helper function signing a leaf and writing it out along with the CA certificate
+    fn issue(
+        &self,
+        mut params: CertificateParams,
+        names: Vec<String>,
+        cert_path: &Path,
+        key_path: &Path
+    ) -> Result<CertificateStore, String> {
+        params.use_authority_key_identifier_extension = true;
+        let cert = match Certificate::from_params(params) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Certificate generation failed: {}", e))
+        };
+        let leaf_pem = match cert.serialize_pem_with_signer(&self.signer) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Certificate signing failed: {}", e))
+        };
+        let ca_pem = match fs::read_to_string(self.certificate_path()) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Cannot read CA certificate: {}", e))
+        };
+
+        write_private(key_path, cert.serialize_private_key_pem().as_bytes())?;
+        if let Err(e) = fs::write(cert_path, leaf_pem + &ca_pem) {
+            return Err(format!("Cannot write {}: {}", cert_path.display(), e));
+        }
+        info!(path = %cert_path.display(), "issued certificate");
+
+        CertificateStore::from_pem_files(cert_path, key_path, names)
+    }
+
+    /// Revokes a certificate issued by this CA and rewrites the revocation list
+    ///
+    /// ## Params:
+    /// * certificate: the certificate to revoke
+    ///
+    /// ## Returns:
+    /// * Ok: the path of the updated revocation list
+    /// * Err: a string describing the issue encountered
+    pub fn revoke(&self, certificate: &rustls::Certificate) -> Result<PathBuf, String> {
+        let (_, cert) = match x509_parser::parse_x509_certificate(&certificate.0) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Cannot parse certificate: {}", e))
+        };
+        let (_, ca) = match x509_parser::parse_x509_certificate(&self.der) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Cannot parse CA certificate: {}", e))
+        };
+        if cert.issuer() != ca.subject() {
+            return Err("Certificate was not issued by this CA".to_string());
+        }
+
+        let serial = to_hex(cert.raw_serial());
+        if !self.revoked()?.iter().any(|(s, _)| *s == serial) {
+            let line = format!("{} {}\n", serial, OffsetDateTime::now_utc().unix_timestamp());
+            let path = self.dir.join(REVOKED_FILE);
+            let mut contents = fs::read_to_string(&path).unwrap_or_default();
+            contents.push_str(&line);
+            if let Err(e) = fs::write(&path, contents) {
+                return Err(format!("Cannot write {}: {}", path.display(), e));
+            }
+            info!(serial = %serial, "revoked certificate");
+        }
+
+        self.write_crl()
+    }
+
+    /// Signs a revocation list covering every certificate revoked so far
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: the DER encoded revocation list
+    /// * Err: a string describing the issue encountered
+    pub fn crl(&self) -> Result<Vec<u8>, String> {
+        match self.revocation_list()?.serialize_der_with_signer(&self.signer) {
+            Ok(a) => Ok(a),
+            Err(e) => Err(format!("Revocation list signing failed: {}", e))
+        }
+    }
+
+    /// Signs a fresh revocation list and writes it to `crl.pem` in the CA directory
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: the path of the revocation list
+    /// * Err: a string describing the issue encountered
+    pub fn write_crl(&self) -> Result<PathBuf, String> {
+        let pem = match self.revocation_list()?.serialize_pem_with_signer(&self.signer) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Revocation list signing failed: {}", e))
+        };
+        let path = self.dir.join(CRL_FILE);
+        if let Err(e) = fs::write(&path, pem) {
+            return Err(format!("Cannot write {}: {}", path.display(), e));
+        }
+        Ok(path)
+    }
+
+    /// Helper function building the revocation list from the revoked serials
+    fn revocation_list(&self) -> Result<CertificateRevocationList, String> {
+        let mut revoked_certs = Vec::new();
+        for (serial, time) in self.revoked()? {
+            let revocation_time = match OffsetDateTime::from_unix_timestamp(time) {
+                Ok(a) => a,
+                Err(e) => return Err(format!("Bad revocation time for {}: {}", serial, e))
+            };
+            revoked_certs.push(RevokedCertParams {
+                serial_number: SerialNumber::from_slice(&from_hex(&serial)?),
+                revocation_time,
+                reason_code: None,
+                invalidity_date: None
+            });
+        }
+
+        // the CRL number only has to grow, so the time of signing does nicely
+        let now = OffsetDateTime::now_utc();
+        let params = CertificateRevocationListParams {
+            this_update: now,
+            next_update: now + Duration::days(CRL_VALIDITY_DAYS),
+            crl_number: SerialNumber::from_slice(&now.unix_timestamp().to_be_bytes()),
+            issuing_distribution_point: None,
+            revoked_certs,
+            alg: self.signer.get_params().alg,
+            key_identifier_method: KeyIdMethod::Sha256
+        };
+
+        match CertificateRevocationList::from_params(params) {
+            Ok(a) => Ok(a),
+            Err(e) => Err(format!("Cannot build revocation list: {}", e))
+        }
+    }
+
+    /// Helper function reading the list of revoked serials and when they were revoked
+    fn revoked(&self) -> Result<Vec<(String, i64)>, String> {
+        let path = self.dir.join(REVOKED_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(a) => a,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Cannot read {}: {}", path.display(), e))
+        };
+
+        let mut revoked = Vec::new();
+        for (i, line) in contents.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next().map(str::parse::<i64>)) {
+                (Some(serial), Some(Ok(time))) => revoked.push((serial.to_string(), time)),
+                _ => return Err(format!("{}:{}: malformed entry", path.display(), i + 1))
+            }
+        }
+        Ok(revoked)
+    }
+}
+
+
+
+/// Loads every certificate in a PEM file, e.g. a CA certificate to trust
+///
+/// ## Params:
+/// * path: the PEM file to read
+///
+/// ## Returns:
+/// * Ok: the certificates, in file order
+/// * Err: a string describing the issue encountered
+pub fn load_certificates(path: impl AsRef<Path>) -> Result<Vec<rustls::Certificate>, String> {
+    let path = path.as_ref();
+    let mut reader = match File::open(path) {
+        Ok(a) => BufReader::new(a),
+        Err(e) => return Err(format!("Cannot open certificate {}: {}", path.display(), e))
+    };
+    match rustls_pemfile::certs(&mut reader) {
+        Ok(a) if !a.is_empty() => Ok(a.into_iter().map(rustls::Certificate).collect()),
+        Ok(_) => Err(format!("No certificates found in {}", path.display())),
+        Err(e) => Err(format!("Cannot parse certificate {}: {}", path.display(), e))
+    }
+}
+
+/// Loads every revocation list in a PEM file
+///
+/// ## Params:
+/// * path: the PEM file to read
+///
+/// ## Returns:
+/// * Ok: the DER encoded revocation lists
+/// * Err: a string describing the issue encountered
+pub fn load_crls(path: impl AsRef<Path>) -> Result<Vec<Vec<u8>>, String> {
+    let path = path.as_ref();
+    let mut reader = match File::open(path) {
+        Ok(a) => BufReader::new(a),
+        Err(e) => return Err(format!("Cannot open revocation list {}: {}", path.display(), e))
+    };
+    match rustls_pemfile::crls(&mut reader) {
+        Ok(a) if !a.is_empty() => Ok(a),
+        Ok(_) => Err(format!("No revocation lists found in {}", path.display())),
+        Err(e) => Err(format!("Cannot parse revocation list {}: {}", path.display(), e))
+    }
+}
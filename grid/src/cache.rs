@@ -0,0 +1,204 @@
+// Defines the client-side response cache
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::definitions::{
+    GridBlock,
+    DigestAlgorithm,
+    string_to_domain,
+    to_hex
+};
+use crate::path::normalize_path;
+
+
+/// Default number of responses kept in memory
+pub const GRID_CACHE_CAPACITY: usize = 128;
+
+
+/// structure defining a cached response and the validator used to revalidate it
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    validator: String,
+    response: GridBlock
+}
+
+impl CacheEntry {
+    /// Creates a new `CacheEntry` from a response
+    ///
+    /// ## Params:
+    /// * response: the (decompressed) response block to cache
+    ///
+    /// ## Returns:
+    /// * instance of the structure
+    pub fn new(response: GridBlock) -> Self {
+        CacheEntry { validator: response.validator(), response }
+    }
+
+    /// Returns the validator identifying the cached content
+    pub fn validator(&self) -> &str {
+        &self.validator
+    }
+
+    /// Returns a copy of the cached response
+    pub fn response(&self) -> GridBlock {
+        self.response.clone()
+    }
+}
+
+
+/// structure defining an LRU response cache, optionally backed by a directory on disk
+pub struct ResponseCache {
+    capacity: usize,
+    entries: HashMap<String, CacheEntry>,
+    order: VecDeque<String>,    // least recently used first
+    disk: Option<PathBuf>
+}
+
+impl ResponseCache {
+    /// Creates a new in-memory `ResponseCache`
+    ///
+    /// ## Params:
+    /// * capacity: the maximum number of responses held in memory
+    ///
+    /// ## Returns:
+    /// * instance of the structure
+    pub fn new(capacity: usize) -> Self {
+        ResponseCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            disk: None
+        }
+    }
+
+    /// Backs the cache with a directory on disk, creating it if needed
+    ///
+    /// ## Params:
+    /// * dir: the directory cached responses are written to
+    ///
+    /// ## Returns:
+    /// * Ok: the updated structure
+    /// * Err: a string describing the issue encountered
+    pub fn with_disk(mut self, dir: impl Into<PathBuf>) -> Result<Self, String> {
+        let dir = dir.into();
+        if let Err(e) = fs::create_dir_all(&dir) {
+            return Err(format!("Failed to create cache directory {}: {}", dir.display(), e));
+        }
+        self.disk = Some(dir);
+        Ok(self)
+    }
+
+    /// Looks up the cached response for a URL
+    ///
+    /// ## Params:
+    /// * url: the normalized URL, as returned by `normalize_url`
+    ///
+    /// ## Returns:
+    /// The cached entry, if the memory or disk store holds one
+    pub fn get(&mut self, url: &str) -> Option<CacheEntry> {
+        if let Some(entry) = self.entries.get(url).cloned() {
+            self.touch(url);
+            return Some(entry);
+        }
+
+        // fall back to the disk store, promoting hits into memory
+        let bytes = fs::read(self.disk_path(url)?).ok()?;
+        let entry = CacheEntry::new(GridBlock::from_bytes(bytes).ok()?);
+        self.insert_memory(url, entry.clone());
+        Some(entry)
+    }
+
+    /// Stores a response for a URL in memory and on disk
+    ///
+    /// ## Params:
+    /// * url: the normalized URL, as returned by `normalize_url`
+    /// * response: the (decompressed) response block
+    ///
+    /// ## Returns:
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered writing to disk
+    pub fn insert(&mut self, url: &str, response: GridBlock) -> Result<(), String> {
+        let entry = CacheEntry::new(response);
+
+        if let Some(path) = self.disk_path(url) {
+            if let Err(e) = fs::write(&path, entry.response().serialize()) {
+                return Err(format!("Failed to write cache entry {}: {}", path.display(), e));
+            }
+        }
+
+        self.insert_memory(url, entry);
+        Ok(())
+    }
+
+    /// Drops the cached response for a URL from memory and disk
+    pub fn remove(&mut self, url: &str) {
+        self.entries.remove(url);
+        self.order.retain(|k| k != url);
+        if let Some(path) = self.disk_path(url) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Returns the number of responses held in memory
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the in-memory store is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Helper function to add an entry to memory, evicting the least recently used ones
+    fn insert_memory(&mut self, url: &str, entry: CacheEntry) {
+        self.entries.insert(url.to_string(), entry);
+        self.touch(url);
+
+        while self.entries.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(old) => { self.entries.remove(&old); },
+                None => break
+            }
+        }
+    }
+
+    /// Helper function to mark a URL as most recently used
+    fn touch(&mut self, url: &str) {
+        self.order.retain(|k| k != url);
+        self.order.push_back(url.to_string());
+    }
+
+    /// Helper function to get the file holding a URL in the disk store
+    fn disk_path(&self, url: &str) -> Option<PathBuf> {
+        let dir = self.disk.as_ref()?;
+        Some(dir.join(to_hex(&DigestAlgorithm::Sha256.compute(url.as_bytes()))))
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache::new(GRID_CACHE_CAPACITY)
+    }
+}
+
+
+
+/// Normalizes a remote and path into a cache key
+///
+/// Hosts are lowercased, the port is always spelled out, and the path has
+/// empty, `.` and `..` segments resolved.
+///
+/// ## Params:
+/// * remote: String formatted as `"grid!domain:port"` or `"grid.ip:port"`. Port is optional
+/// * path: the requested path
+///
+/// ## Returns:
+/// * Ok: the normalized URL
+/// * Err: a string describing the issue encountered
+pub fn normalize_url(remote: &str, path: &str) -> Result<String, String> {
+    let (_, domain, port) = string_to_domain(remote)?;
+    let prefix = &remote[..5];
+
+    Ok(format!("{}{}:{}{}", prefix, domain.to_lowercase(), port, normalize_path(path)))
+}
@@ -1,28 +1,85 @@
-use std::io::{Write, Read, self};
 // Defines all client-related functions and structures
+use std::io::{Read, Write};
 use std::sync::Arc;
-use std::net::{SocketAddr, ToSocketAddrs};
-
-use mio::net::TcpStream;
+use std::net::{SocketAddr, ToSocketAddrs, TcpStream};
 
 use rustls::{
     ClientConfig,
-    ClientConnection, 
-    Stream
+    ClientConnection,
+    StreamOwned
 };
 
+use tracing::{debug, info, trace, warn};
+
 
+use crate::cache::{ResponseCache, normalize_url};
 use crate::definitions::{
     GridBlock,
-    string_to_domain
+    GridCode,
+    GridRequestCode,
+    GridResponseCode,
+    DigestAlgorithm,
+    IntegrityError,
+    GRID_COMPRESSION_THRESHOLD,
+    GRID_HEADER_SIZE,
+    string_to_domain,
+    to_hex
 };
+use crate::metadata::MetadataKey;
+use crate::server::CertificateStore;
+use crate::stats::ServerInfo;
+use crate::transport::Transport;
+
+
+/// Default size (in bytes) of the largest response a client accepts
+pub const GRID_MAX_RESPONSE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Size of the chunks responses are read in, so a bogus length can't make us allocate it all up front
+const READ_CHUNK: usize = 64 * 1024;
+
+
+/// Describes why a request sent by a `GridClient` failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientError {
+    /// The response failed its integrity check
+    Integrity(IntegrityError),
+    /// Anything else, e.g. a broken connection or a malformed response
+    Failed(String)
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Integrity(e) => write!(f, "Response failed integrity check: {}", e),
+            ClientError::Failed(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<String> for ClientError {
+    fn from(e: String) -> Self {
+        ClientError::Failed(e)
+    }
+}
+
+impl From<ClientError> for String {
+    fn from(e: ClientError) -> Self {
+        e.to_string()
+    }
+}
 
 
 /// structure defining a GRID client instance
 pub struct GridClient {
-    socket: TcpStream,
-    client: ClientConnection,
-    tls_config: Arc<ClientConfig>
+    stream: Box<dyn Transport>,     // transport to the server, wrapped in TLS unless running in plaintext
+    peer_compression: u8,           // compression algorithms the server accepts, learned from its responses
+    compression_threshold: usize,   // metadata size below which requests are not compressed
+    remote: String,                 // remote string we connected with, used for cache keys
+    cache: Option<ResponseCache>,   // opt-in response cache used by `get`
+    auth_token: Option<String>,     // bearer token sent with every request
+    max_response_size: usize        // largest response frame, and decompressed metadata, accepted
 }
 
 
@@ -37,60 +94,227 @@ impl GridClient {
     pub fn new(
         connection: impl Into<String>
     ) -> Result<Self, String>{
+        // build a rustls configuration trusting the public roots
+        GridClient::with_tls(connection, tls_config(&[])?)
+    }
+
+    /// Creates a new `GridClient` instance with a custom TLS configuration
+    ///
+    /// Use this to trust a private CA or to authenticate with a client certificate,
+    /// see `tls_config` and `tls_config_with_identity`.
+    /// 
+    /// ## Params: 
+    /// * connection: String formatted as `"grid!domain:port"` or `"grid.ip:port"`
+    /// * rc_config: the TLS configuration to connect with
+    /// 
+    /// ## Returns:
+    /// * Ok: an instance of the structure, with the TLS handshake completed
+    /// * Err: a string describing the issue encountered
+    pub fn with_tls(
+        connection: impl Into<String>,
+        rc_config: Arc<ClientConfig>
+    ) -> Result<Self, String> {
         // make sure we convert the thing into a string
-        let connection: String = connection.try_into().unwrap();
-
-        // set up the root TLS store 
-        let mut root_store = rustls::RootCertStore::empty();
-        root_store.add_server_trust_anchors(
-            webpki_roots::TLS_SERVER_ROOTS
-                .0
-                .iter()
-                .map(|ta| {
-                    rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                        ta.subject,
-                        ta.spki,
-                        ta.name_constraints,
-                    )
-                })
-        );
-
-        // build a rustls configuration using the new TLS store
-        let config = ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-        // set up remote connection to server
-        let rc_config = Arc::new(config);
-        let target_domain = string_to_domain(connection)?;
-        // convert domain into rustls target
-        let remote = match target_domain.1[..].try_into() {
-            Ok(a) => a,
-            Err(e) => return Err(format!("Cannot put remote into TLS target type: {}", e))
-        };
+        let connection: String = connection.into();
+        let span = tracing::info_span!("connection", remote = %connection);
+        let _enter = span.enter();
 
-        // build client connection
-        let mut client = match ClientConnection::new(rc_config.clone(), remote){
-            Ok(a) => a,
-            Err(e) => return Err(format!("{}", e))
-        };
+        let target_domain = string_to_domain(connection.clone())?;
         
         let tmp = GridClient::build_socket_connect(target_domain.1, target_domain.2)?;
+        debug!(address = %tmp, "connecting");
         
-        let mut tcp_conn = match TcpStream::connect(tmp) {
+        let tcp_conn = match TcpStream::connect(tmp) {
             Ok(a) => a,
-            Err(e) => return Err(format!("Connection failed: {}", e))
+            Err(e) => {
+                warn!(address = %tmp, error = %e, "connection failed");
+                return Err(format!("Connection failed: {}", e))
+            }
+        };
+        info!(address = %tmp, "connected");
+
+        GridClient::with_transport(tcp_conn, connection, Some(rc_config))
+    }
+
+    /// Creates a new `GridClient` instance over an already established transport
+    /// 
+    /// ## Params: 
+    /// * transport: the byte stream connected to the server, e.g. a `TcpStream` or `MemoryPipe`
+    /// * connection: String formatted as `"grid!domain:port"` or `"grid.ip:port"`, used for SNI and cache keys
+    /// * tls: the TLS configuration to use, or `None` to speak plaintext GRID
+    /// 
+    /// ## Returns:
+    /// * Ok: an instance of the structure, with the TLS handshake completed
+    /// * Err: a string describing the issue encountered
+    pub fn with_transport(
+        transport: impl Transport + 'static,
+        connection: impl Into<String>,
+        tls: Option<Arc<ClientConfig>>
+    ) -> Result<Self, String> {
+        let connection: String = connection.into();
+        let mut transport: Box<dyn Transport> = Box::new(transport);
+
+        let stream: Box<dyn Transport> = match tls {
+            Some(rc_config) => {
+                let target_domain = string_to_domain(connection.clone())?;
+                // convert domain into rustls target
+                let remote = match target_domain.1[..].try_into() {
+                    Ok(a) => a,
+                    Err(e) => return Err(format!("Cannot put remote into TLS target type: {}", e))
+                };
+
+                // build client connection
+                let mut client = match ClientConnection::new(rc_config, remote){
+                    Ok(a) => a,
+                    Err(e) => return Err(format!("{}", e))
+                };
+
+                // get the handshake out of the way
+                let span = tracing::debug_span!("handshake");
+                let _enter = span.enter();
+                while client.is_handshaking() {
+                    if let Err(e) = client.complete_io(&mut transport) {
+                        warn!(error = %e, "TLS handshake failed");
+                        return Err(format!("TLS handshake failed: {}", e));
+                    }
+                }
+                debug!(
+                    version = ?client.protocol_version(),
+                    cipher = ?client.negotiated_cipher_suite().map(|c| c.suite()),
+                    "TLS handshake complete"
+                );
+
+                Box::new(StreamOwned::new(client, transport))
+            },
+            None => transport
         };
 
         // return an instance of the structure
         Ok(GridClient {
-            socket: tcp_conn,
-            client,
-            tls_config: rc_config
+            stream,
+            peer_compression: 0,
+            compression_threshold: GRID_COMPRESSION_THRESHOLD,
+            remote: connection,
+            cache: None,
+            auth_token: None,
+            max_response_size: GRID_MAX_RESPONSE_SIZE
         })
     }
 
+    /// Sets the metadata size below which requests are sent uncompressed
+    /// 
+    /// ## Params:
+    /// * threshold: size in bytes. `usize::MAX` disables request compression entirely
+    /// 
+    /// ## Returns:
+    /// None
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = threshold;
+    }
+
+    /// Sets the size of the largest response accepted
+    /// 
+    /// Larger responses are refused before anything is allocated for them, and so is
+    /// compressed metadata growing past this size once decompressed.
+    /// 
+    /// ## Params:
+    /// * size: size in bytes, `GRID_MAX_RESPONSE_SIZE` by default
+    /// 
+    /// ## Returns:
+    /// None
+    pub fn set_max_response_size(&mut self, size: usize) {
+        self.max_response_size = size;
+    }
+
+    /// Enables response caching for `get` requests
+    /// 
+    /// ## Params:
+    /// * cache: the cache to store responses in and revalidate against
+    /// 
+    /// ## Returns:
+    /// None
+    pub fn set_cache(&mut self, cache: ResponseCache) {
+        self.cache = Some(cache);
+    }
+
+    /// Sets the bearer token sent with every request in an `auth-token` header
+    /// 
+    /// ## Params:
+    /// * token: the token, or `None` to send requests without one
+    /// 
+    /// ## Returns:
+    /// None
+    pub fn set_auth_token(&mut self, token: Option<String>) {
+        self.auth_token = token;
+    }
+
+    /// Fetches the resource at `path`, going through the cache if one is set
+    /// 
+    /// Cached resources are revalidated with a conditional GET; if the server
+    /// answers `NMD` the cached copy is returned.
+    /// 
+    /// ## Params:
+    /// * path: the path of the resource on the remote
+    /// 
+    /// ## Returns:
+    /// * Ok: the response GridBlock from the server or the cache
+    /// * Err: a `ClientError` describing the issue encountered
+    pub fn get(
+        &mut self,
+        path: &str
+    ) -> Result<GridBlock, ClientError> {
+        let mut request = GridBlock::new(GridRequestCode::GET, Some(path), &mut Vec::new())?;
+        if self.cache.is_none() {
+            return self.send(&mut request);
+        }
+
+        // see if we hold a copy we can revalidate
+        let url = normalize_url(&self.remote, path)?;
+        let cached = self.cache.as_mut().and_then(|c| c.get(&url));
+        if let Some(entry) = &cached {
+            request.set_condition(entry.validator())?;
+        }
+        request.request_digest(Some(DigestAlgorithm::Sha256));
+
+        let response = self.send(&mut request)?;
+        match (response.opcode(), cached) {
+            (GridCode::Response(GridResponseCode::NMD), Some(entry)) => Ok(entry.response()),
+            (GridCode::Response(GridResponseCode::ROK), _) => {
+                if let Some(cache) = self.cache.as_mut() {
+                    cache.insert(&url, response.clone())?;
+                }
+                Ok(response)
+            },
+            (GridCode::Response(GridResponseCode::NOF), _) => {
+                if let Some(cache) = self.cache.as_mut() {
+                    cache.remove(&url);
+                }
+                Ok(response)
+            },
+            _ => Ok(response)
+        }
+    }
+
+    /// Asks the remote about itself and its statistics with an `INF` request
+    /// 
+    /// ## Params:
+    /// None
+    /// 
+    /// ## Returns:
+    /// * Ok: what the server reported
+    /// * Err: a string describing the issue encountered, including non-`ROK` responses
+    pub fn info(&mut self) -> Result<ServerInfo, String> {
+        let mut request = GridBlock::new(GridRequestCode::INF, None, &mut Vec::new())?;
+        let response = self.send(&mut request)?;
+        if response.opcode() != GridCode::Response(GridResponseCode::ROK) {
+            return Err(format!("Server answered {:?}: {}", response.opcode(), String::from_utf8_lossy(response.content())));
+        }
+        match response.headers()? {
+            Some(headers) => ServerInfo::from_metadata(&headers),
+            None => Err("Server sent no information".to_string())
+        }
+    }
+
     /// Sends a GridRequest to the remote server 
     /// 
     /// ## Params:
@@ -98,90 +322,117 @@ impl GridClient {
     /// 
     /// ## Returns:
     /// * Ok: a response GridBlock structure from the server
-    /// * Err: a string describing the issue encountered
+    /// * Err: a `ClientError`, `Integrity` if the response carries a digest that doesn't check out
     pub fn send(
         &mut self,
         request: &mut GridBlock
-    ) -> Result<GridBlock, String> {
+    ) -> Result<GridBlock, ClientError> {
+        if let Some(token) = &self.auth_token {
+            request.insert_header(MetadataKey::AuthToken, token.as_bytes().to_vec())?;
+        }
+
+        // advertise what we can decompress, and compress the request if the
+        // server has told us what it accepts
+        request.negotiate_compression(self.peer_compression, self.compression_threshold)?;
+
+        let span = tracing::debug_span!("request", opcode = ?request.opcode());
+        let _enter = span.enter();
+
         // first we need to serialize the request
         let serialized_request = request.serialize();
-        println!("Serialized: {:?}", serialized_request);
-
-        self.client.writer().write(&serialized_request);
+        debug!(bytes = serialized_request.len(), "sending request");
+        trace!(frame = %to_hex(&serialized_request), "request frame");
 
         // then we can send it to the connected server
-        while self.client.wants_write() {
-            match self.client.write_tls(&mut self.socket){//.write_all(&mut serialized_request) {
-                Ok(_) => (),
-                Err(e) => return Err(format!("{}", e))
-            };
+        if let Err(e) = self.stream.write_all(&serialized_request).and_then(|_| self.stream.flush()) {
+            warn!(error = %e, "failed to write request");
+            return Err(format!("Failed to send request: {}", e).into());
         }
 
-
         // now we read back from the server
-        let mut response_raw: Vec<u8> = Vec::new();
-        match self.read_into(&mut response_raw) {
-            Ok(a) => println!("Read {}", a),
-            Err(e) => return Err(format!("Failed to recieve response from server: {}",e)) 
+        let response_raw = match self.read_frame() {
+            Ok(a) => a,
+            Err(e) => {
+                warn!(error = %e, "failed to receive response");
+                return Err(format!("Failed to recieve response from server: {}", e).into())
+            }
         };
+        debug!(bytes = response_raw.len(), "received response");
+        trace!(frame = %to_hex(&response_raw), "response frame");
+
+        // deserialize the bytes, remember what the server accepts and
+        // undo any compression before handing it back
+        let mut response = GridBlock::from_bytes(response_raw)?;
+        self.peer_compression = response.accepted_compression();
+        response.decompress(self.max_response_size)?;
 
-        println!("Response: {:?}", response_raw);
+        // if the server flagged a digest, hold it to it
+        if let Err(e) = response.check_digest() {
+            warn!(error = %e, "response failed integrity check");
+            return Err(ClientError::Integrity(e));
+        }
 
-        // deserialize the bytes and return them
-        GridBlock::from_bytes(response_raw)
+        debug!(opcode = ?response.opcode(), content = response.content().len(), "response");
+        Ok(response)
     }
 
-    /// Helper function to read TLS data into a buffer
+    /// Helper function to read one complete GRID frame from the server
     /// 
     /// ## Params:
-    /// `buff`: A buffer to write the data into
+    /// None
     /// 
     /// ## Returns:
+    /// Ok: the raw bytes of the frame, header included
+    /// Err: Returns a string that describes the error encountered
+    fn read_frame(
+        &mut self
+    ) -> Result<Vec<u8>, String> {
+        // first grab the header so we know how much more is coming
+        let mut frame = vec![0u8; GRID_HEADER_SIZE];
+        if let Err(e) = self.stream.read_exact(&mut frame) {
+            return Err(format!("Failed to read header: {}", e));
+        }
+
+        let length = match GridBlock::frame_length(&frame) {
+            Some(a) if a <= self.max_response_size as u128 => a as usize,
+            Some(a) => return Err(format!("Response of {} bytes exceeds the limit of {} bytes", a, self.max_response_size)),
+            None => return Err("Malformed response header".to_string())
+        };
+
+        // then the rest of the frame, growing the buffer only as data arrives
+        while frame.len() < length {
+            let start = frame.len();
+            frame.resize(length.min(start + READ_CHUNK), 0);
+            if let Err(e) = self.stream.read_exact(&mut frame[start..]) {
+                return Err(format!("Failed to read payload: {}", e));
+            }
+        }
+
+        Ok(frame)
+    }
+
+    /// Helper function to read data from the connection into a buffer
+    ///
+    /// Reads whatever the server has sent, with no regard for frame boundaries.
+    /// Mixing it with `send` loses track of where responses start.
+    ///
+    /// ## Params:
+    /// `buff`: A buffer to write the data into
+    ///
+    /// ## Returns:
     /// Ok: Returns the number of bytes read from the connection
     /// Err: Returns a string that describes the error encountered
+    #[deprecated(note = "read whole responses with `send` instead")]
+    #[allow(clippy::ptr_arg)] // keeps the signature callers were written against
     pub fn read_into(
         &mut self,
         buff: &mut Vec<u8>,
     ) -> Result<usize, String> {
-        // first read TLS data
-        if self.client.wants_read() {
-            let mut blocked = true;
-            
-            while blocked {
-                let count = match self.client.read_tls(&mut self.socket) {
-                    Ok(a) => {blocked = false; a},
-                    Err(e) => {
-                        if e.kind() != io::ErrorKind::WouldBlock {
-                            return Err(format!("Failed to get tls data because {}", e))
-                        }
-                        0
-                    }
-                };
-                
-            }
-    
-            // next we process the packets
-            let io_state = match self.client.process_new_packets() {
-                Ok(a) => a,
-                Err(e) => return Err(format!("TLS error {}", e))
-            };
-            
-            if io_state.peer_has_closed() {
-                return Err(format!("remote closed"))
-            }
-    
-            // and finally we read back from the buffer
-            let ret_cnt = match self.client.reader().read(buff){
-                Ok(a) => a,
-                Err(e) => return Err(format!("TLS read failed {}", e))
-            };
-            
-            return Ok(ret_cnt);
+        match self.stream.read(buff) {
+            Ok(0) if !buff.is_empty() => Err("remote closed".to_string()),
+            Ok(a) => Ok(a),
+            Err(e) => Err(format!("Read failed {}", e))
         }
-        
-        // nothing to read, bail
-        Err(format!("no data available for reading"))
-    
     }
 
 
@@ -197,7 +448,10 @@ impl GridClient {
     /// * Ok: returns a socket address for use in connections
     /// * Err: returns a string describing the issue encountered
     fn build_socket_connect(domain: String, port: u16) -> Result<SocketAddr, String>{
-        let tmp = (&domain[..], port).to_socket_addrs().unwrap();
+        let tmp = match (&domain[..], port).to_socket_addrs() {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Failed to lookup domain {}: {}", domain, e))
+        };
         for addr in tmp {
             if let SocketAddr::V4(_) = addr {
                 return Ok(addr);
@@ -210,3 +464,62 @@ impl GridClient {
 }
 
 
+
+/// Builds a client TLS configuration trusting the public web roots and any extra certificates
+/// 
+/// ## Params:
+/// * extra_roots: additional trust anchors, e.g. a self-signed server certificate or a private CA
+/// 
+/// ## Returns:
+/// * Ok: a shareable rustls client configuration
+/// * Err: a string describing the issue encountered
+pub fn tls_config(extra_roots: &[rustls::Certificate]) -> Result<Arc<ClientConfig>, String> {
+    tls_config_with_identity(extra_roots, None)
+}
+
+/// Builds a client TLS configuration that can also authenticate with a client certificate
+/// 
+/// ## Params:
+/// * extra_roots: additional trust anchors, e.g. a self-signed server certificate or a private CA
+/// * identity: the client certificate and key to present to servers asking for one, if any
+/// 
+/// ## Returns:
+/// * Ok: a shareable rustls client configuration
+/// * Err: a string describing the issue encountered
+pub fn tls_config_with_identity(
+    extra_roots: &[rustls::Certificate],
+    identity: Option<CertificateStore>
+) -> Result<Arc<ClientConfig>, String> {
+    // set up the root TLS store 
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_trust_anchors(
+        webpki_roots::TLS_SERVER_ROOTS
+            .iter()
+            .map(|ta| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            })
+    );
+    for root in extra_roots {
+        if let Err(e) = root_store.add(root) {
+            return Err(format!("Failed to add trust anchor: {}", e));
+        }
+    }
+
+    // build a rustls configuration using the new TLS store
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store);
+    let config = match identity {
+        Some(store) => match builder.with_client_auth_cert(store.clone().get_certificates()?, store.get_privkey()) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Bad client certificate: {}", e))
+        },
+        None => builder.with_no_client_auth()
+    };
+
+    Ok(Arc::new(config))
+}
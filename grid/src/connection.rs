@@ -0,0 +1,383 @@
+// Defines the per-connection state shared by every server transport
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustls::{ServerConfig, ServerConnection};
+
+use tracing::debug;
+
+
+use crate::access_log::AccessRecord;
+use crate::admission::ConnectionSlot;
+use crate::definitions::GridBlock;
+use crate::transport::Transport;
+
+
+/// Size of the chunks read from plaintext transports
+const READ_CHUNK: usize = 16 * 1024;
+
+
+/// structure describing where a request came from, handed to request handlers
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    /// Address of the remote, if the transport has one
+    pub peer: Option<SocketAddr>,
+    /// Server name the client asked for via SNI, if any
+    pub server_name: Option<String>,
+    /// Subject of the verified client certificate, e.g. `"CN=alice"`, if the client presented one
+    pub client_subject: Option<String>,
+    /// Principal of the verified bearer token, e.g. `"alice"`, if the request carried one
+    pub principal: Option<String>
+}
+
+impl RequestContext {
+    /// Returns who the client is: the token principal if there is one, else the certificate subject
+    pub fn identity(&self) -> Option<&str> {
+        self.principal.as_deref().or(self.client_subject.as_deref())
+    }
+}
+
+
+/// an answer owed to the client, kept in the order the requests arrived
+pub(crate) enum Queued {
+    /// A response that is ready to go out
+    Ready(Vec<u8>),
+    /// A request frame still waiting for a handler
+    Request(Vec<u8>, Option<Box<AccessRecord>>)
+}
+
+
+/// structure defining a single server-side connection and its buffers
+pub(crate) struct Connection<T: Transport> {
+    transport: T,
+    tls: Option<ServerConnection>,
+    blocking: bool,         // whether reads on the transport block instead of returning WouldBlock
+    inbound: Vec<u8>,       // plaintext bytes that don't form a whole frame yet
+    outbound: Vec<u8>,      // plaintext bytes waiting to go out when running without TLS
+    closing: bool,
+    close_sent: bool,       // whether close_notify has been queued already
+    peer: Option<SocketAddr>,
+    max_frame_size: u128,   // frames larger than this are refused
+    last_active: Instant,   // when we last heard from the client
+    in_flight: usize,       // requests received but not answered yet
+    refusing: bool,         // over the connection limit: answer with BSY and hang up
+    slot: Option<ConnectionSlot>,
+    backlog: VecDeque<Queued>,  // answers waiting their turn behind the one being worked on
+    dispatched: bool        // whether a request is with a worker thread
+}
+
+impl<T: Transport> Connection<T> {
+    /// Creates a new `Connection` instance
+    ///
+    /// ## Params:
+    /// * transport: the byte stream to the client
+    /// * tls: the TLS configuration to use, or `None` for plaintext
+    /// * peer: the address of the client, if known
+    /// * blocking: whether the transport blocks on reads
+    ///
+    /// ## Returns:
+    /// * Ok: an instance of the structure
+    /// * Err: a string describing the issue encountered
+    pub fn new(
+        transport: T,
+        tls: Option<Arc<ServerConfig>>,
+        peer: Option<SocketAddr>,
+        blocking: bool
+    ) -> Result<Self, String> {
+        let tls = match tls {
+            Some(config) => match ServerConnection::new(config) {
+                Ok(a) => Some(a),
+                Err(e) => return Err(format!("Failed to create TLS session: {}", e))
+            },
+            None => None
+        };
+
+        Ok(Connection {
+            transport,
+            tls,
+            blocking,
+            inbound: Vec::new(),
+            outbound: Vec::new(),
+            closing: false,
+            close_sent: false,
+            peer,
+            max_frame_size: u128::MAX,
+            last_active: Instant::now(),
+            in_flight: 0,
+            refusing: false,
+            slot: None,
+            backlog: VecDeque::new(),
+            dispatched: false
+        })
+    }
+
+    /// Sets the size of the largest frame the client may send
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_size = size as u128;
+    }
+
+    /// Holds on to the slot counting this connection until it is dropped
+    pub fn set_slot(&mut self, slot: ConnectionSlot) {
+        self.slot = Some(slot);
+    }
+
+    /// Marks the connection as over the connection limit
+    pub fn set_refusing(&mut self) {
+        self.refusing = true;
+    }
+
+    /// Returns whether the connection is over the connection limit
+    pub fn is_refusing(&self) -> bool {
+        self.refusing
+    }
+
+    /// Returns whether the TLS handshake is still going on
+    pub fn is_handshaking(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.is_handshaking())
+    }
+
+    /// Counts a request as in flight, unless `cap` requests already are
+    ///
+    /// ## Params:
+    /// * cap: the most requests allowed in flight, or `None` for no limit
+    ///
+    /// ## Returns:
+    /// * whether the request was admitted. Admitted requests must be finished with `finish_request`
+    pub fn start_request(&mut self, cap: Option<usize>) -> bool {
+        if cap.is_some_and(|cap| self.in_flight >= cap) {
+            return false;
+        }
+        self.in_flight += 1;
+        true
+    }
+
+    /// Marks an admitted request as answered
+    pub fn finish_request(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+    }
+
+    /// Returns the answers owed to the client that have not been queued for writing yet
+    pub fn backlog(&mut self) -> &mut VecDeque<Queued> {
+        &mut self.backlog
+    }
+
+    /// Records whether a request of this connection is with a worker thread
+    pub fn set_dispatched(&mut self, dispatched: bool) {
+        self.dispatched = dispatched;
+    }
+
+    /// Returns whether a request of this connection is with a worker thread
+    pub fn is_dispatched(&self) -> bool {
+        self.dispatched
+    }
+
+    /// Returns whether the connection still owes the client answers
+    pub fn is_busy(&self) -> bool {
+        self.dispatched || !self.backlog.is_empty()
+    }
+
+    /// Returns whether the connection should read more requests
+    ///
+    /// Nothing is read while answers are backed up, which pushes back on the client.
+    pub fn wants_read(&self) -> bool {
+        self.backlog.is_empty()
+    }
+
+    /// Returns how long it has been since the client last sent anything
+    pub fn idle_for(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+
+    /// Returns the context requests on this connection are handled with
+    pub fn context(&self) -> RequestContext {
+        RequestContext {
+            peer: self.peer,
+            server_name: self.tls.as_ref().and_then(|t| t.server_name()).map(|s| s.to_string()),
+            client_subject: self.tls
+                .as_ref()
+                .and_then(|t| t.peer_certificates())
+                .and_then(|certs| certs.first())
+                .and_then(|leaf| x509_parser::parse_x509_certificate(&leaf.0).ok())
+                .map(|(_, cert)| cert.subject().to_string()),
+            principal: None
+        }
+    }
+
+    /// Returns the underlying transport
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Reads whatever the transport has available and splits off complete frames
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: the raw bytes of every complete frame received, in order
+    /// * Err: a string describing the issue encountered. The connection should be dropped
+    pub fn read_ready(&mut self) -> Result<Vec<Vec<u8>>, String> {
+        loop {
+            let done = match &mut self.tls {
+                Some(tls) => {
+                    let read = tls.read_tls(&mut self.transport);
+                    match read {
+                        Ok(0) => { self.closing = true; true },
+                        Ok(_) => {
+                            self.last_active = Instant::now();
+                            let state = match tls.process_new_packets() {
+                                Ok(a) => a,
+                                Err(e) => {
+                                    // try to let the client know before bailing
+                                    let _ = tls.write_tls(&mut self.transport);
+                                    return Err(format!("TLS error: {}", e));
+                                }
+                            };
+
+                            let pending = state.plaintext_bytes_to_read();
+                            if pending > 0 {
+                                let start = self.inbound.len();
+                                self.inbound.resize(start + pending, 0);
+                                if let Err(e) = tls.reader().read_exact(&mut self.inbound[start..]) {
+                                    return Err(format!("TLS read failed: {}", e));
+                                }
+                            }
+                            if state.peer_has_closed() {
+                                self.closing = true;
+                            }
+                            self.blocking || self.closing
+                        },
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => true,
+                        Err(e) => return Err(format!("Failed to read from client: {}", e))
+                    }
+                },
+                None => {
+                    let mut buff = [0u8; READ_CHUNK];
+                    match self.transport.read(&mut buff) {
+                        Ok(0) => { self.closing = true; true },
+                        Ok(a) => {
+                            self.last_active = Instant::now();
+                            self.inbound.extend_from_slice(&buff[..a]);
+                            self.blocking
+                        },
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => true,
+                        Err(e) => return Err(format!("Failed to read from client: {}", e))
+                    }
+                }
+            };
+
+            if done {
+                break;
+            }
+        }
+
+        // split off every complete frame we have
+        let mut frames = Vec::new();
+        while let Some(length) = GridBlock::frame_length(&self.inbound) {
+            if length > self.max_frame_size {
+                return Err(format!("Request of {} bytes exceeds the limit of {} bytes", length, self.max_frame_size));
+            }
+            if length > self.inbound.len() as u128 {
+                break;
+            }
+            frames.push(self.inbound.drain(..length as usize).collect());
+        }
+        Ok(frames)
+    }
+
+    /// Queues a serialized response for sending
+    ///
+    /// ## Params:
+    /// * bytes: the serialized response block
+    ///
+    /// ## Returns:
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered
+    pub fn queue(&mut self, bytes: &[u8]) -> Result<(), String> {
+        match &mut self.tls {
+            Some(tls) => match tls.writer().write_all(bytes) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("Failed to queue response: {}", e))
+            },
+            None => {
+                self.outbound.extend_from_slice(bytes);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes as much pending data to the transport as it will take
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered. The connection should be dropped
+    pub fn write_ready(&mut self) -> Result<(), String> {
+        match &mut self.tls {
+            Some(tls) => {
+                while tls.wants_write() {
+                    match tls.write_tls(&mut self.transport) {
+                        Ok(_) => (),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(format!("Failed to write to client: {}", e))
+                    }
+                }
+            },
+            None => {
+                while !self.outbound.is_empty() {
+                    match self.transport.write(&self.outbound) {
+                        Ok(a) => { self.outbound.drain(..a); },
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(format!("Failed to write to client: {}", e))
+                    }
+                }
+            }
+        }
+
+        match self.transport.flush() {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(format!("Failed to flush to client: {}", e))
+        }
+    }
+
+    /// Returns whether data is waiting to be written
+    pub fn wants_write(&self) -> bool {
+        match &self.tls {
+            Some(tls) => tls.wants_write(),
+            None => !self.outbound.is_empty()
+        }
+    }
+
+    /// Starts closing the connection, sending a TLS close_notify if applicable
+    pub fn close(&mut self) {
+        if let Some(tls) = &mut self.tls {
+            if !self.close_sent {
+                tls.send_close_notify();
+            }
+        }
+        self.close_sent = true;
+        self.closing = true;
+    }
+
+    /// Returns whether `close` has been called
+    pub fn is_closing(&self) -> bool {
+        self.close_sent
+    }
+
+    /// Returns whether the connection is done and everything has been written
+    pub fn is_closed(&self) -> bool {
+        self.closing && !self.wants_write()
+    }
+}
+
+impl<T: Transport> Drop for Connection<T> {
+    fn drop(&mut self) {
+        debug!(peer = ?self.peer, "connection closed");
+    }
+}
@@ -1,30 +1,58 @@
 // defines common definitions and structures 
+use crate::metadata::{Metadata, MetadataKey};
 
 //////////////////////// DEFAULTS ////////////////////////
 
 /// Default GRID connection port
 pub const GRID_DEFAULT_PORT: u16 = 7500;
 
+/// Size of the serialized GridBlock header: opcode, path size, metadata size and reserved field
+pub const GRID_HEADER_SIZE: usize = 1 + 16 * 3;
+
+/// Default metadata size (in bytes) below which payloads are not compressed
+pub const GRID_COMPRESSION_THRESHOLD: usize = 1024;
+
+
+//////////////////////// HEADER FLAGS ////////////////////////
+
+/// Bits of the reserved header field holding the compression used by the block
+const COMPRESSION_USED_MASK: u128 = 0xff;
+
+/// Offset of the bits in the reserved header field holding the compression 
+/// algorithms the sender is willing to receive
+const COMPRESSION_ACCEPT_SHIFT: u32 = 8;
+
+/// Offset of the bits in the reserved header field holding the digest
+/// algorithm the sender would like the reply to carry
+const DIGEST_REQUEST_SHIFT: u32 = 24;
+
+
+/// Bit of the reserved header field marking the body as starting with
+/// structured `Metadata` headers
+const STRUCTURED_FLAG: u128 = 1 << 33;
+
 
 //////////////////////// REQUESTS ////////////////////////
 
 /// Defines the GRID request OPCODES
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GridRequestCode {
     /// Get resource at path
     GET=0,
     /// Put payload
     PUT=1,
-    /// Set?
+    /// Set properties of an existing resource without replacing it
     SET=2,
     /// Client error
-    CER=3
+    CER=3,
+    /// Information about the server and its statistics
+    INF=4
 }
 
 
 
 /// Defines the GRID response OPCODES
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GridResponseCode {
     /// Response OK
     ROK=128,
@@ -33,14 +61,18 @@ pub enum GridResponseCode {
     /// Requested resource not found
     NOF=130,
     /// Remote is busy
-    BSY=131
+    BSY=131,
+    /// Resource has not changed since the validator sent with a conditional request
+    NMD=132,
+    /// Client is not allowed to make the request
+    DNY=133
 }
 
 
 
 
 /// General enum for different GRID OPCODES
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GridCode {
     Response(GridResponseCode),
     Request(GridRequestCode)
@@ -97,6 +129,7 @@ impl GridCode {
                 b if b == GridRequestCode::PUT as u8 => Ok(GridCode::Request(GridRequestCode::PUT)),
                 b if b == GridRequestCode::SET as u8 => Ok(GridCode::Request(GridRequestCode::SET)),
                 b if b == GridRequestCode::CER as u8 => Ok(GridCode::Request(GridRequestCode::CER)),
+                b if b == GridRequestCode::INF as u8 => Ok(GridCode::Request(GridRequestCode::INF)),
                 _ => Err(format!("Invalid request code {}", b))
             }
         } else {
@@ -106,6 +139,8 @@ impl GridCode {
                 b if b == GridResponseCode::BSY as u8 => Ok(GridCode::Response(GridResponseCode::BSY)),
                 b if b == GridResponseCode::NOF as u8 => Ok(GridCode::Response(GridResponseCode::NOF)),
                 b if b == GridResponseCode::GER as u8 => Ok(GridCode::Response(GridResponseCode::GER)),
+                b if b == GridResponseCode::NMD as u8 => Ok(GridCode::Response(GridResponseCode::NMD)),
+                b if b == GridResponseCode::DNY as u8 => Ok(GridCode::Response(GridResponseCode::DNY)),
                 _ => Err(format!("Invalid response code {}", b))
             }
         }
@@ -114,8 +149,263 @@ impl GridCode {
 
 
 
+/// Defines the compression algorithms that can be applied to the metadata segment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// Metadata is sent as-is
+    None=0,
+    /// DEFLATE (RFC 1951)
+    Deflate=1,
+    /// Zstandard
+    Zstd=2
+}
+
+impl Compression {
+    /// Converts the given byte into the appropriate compression algorithm
+    /// 
+    /// ## Params:
+    /// b: The byte to convert
+    /// 
+    /// ## Returns
+    /// Ok: the matching `Compression` variant
+    /// Err: a string that explains the error encountered
+    pub fn from_byte(
+        b: u8
+    ) -> Result<Self, String> {
+        match b {
+            b if b == Compression::None as u8 => Ok(Compression::None),
+            b if b == Compression::Deflate as u8 => Ok(Compression::Deflate),
+            b if b == Compression::Zstd as u8 => Ok(Compression::Zstd),
+            _ => Err(format!("Invalid compression algorithm {}", b))
+        }
+    }
+
+    /// Returns the compression algorithms compiled into this build, most preferred first
+    pub fn supported() -> Vec<Compression> {
+        [
+            (Compression::Zstd, cfg!(feature = "zstd")),
+            (Compression::Deflate, cfg!(feature = "deflate"))
+        ]
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(c, _)| c)
+            .collect()
+    }
+
+    /// Builds the bit mask advertised to the remote for the supported algorithms
+    /// 
+    /// ## Params:
+    /// None
+    /// 
+    /// ## Returns:
+    /// A byte with bit `n` set for every supported algorithm with value `n`
+    pub fn supported_mask() -> u8 {
+        Compression::supported()
+            .iter()
+            .fold(0, |mask, c| mask | (1 << *c as u8))
+    }
+
+    /// Picks the preferred algorithm that both we and the remote support
+    /// 
+    /// ## Params:
+    /// * accepted: the bit mask of algorithms advertised by the remote
+    /// 
+    /// ## Returns:
+    /// The algorithm to use, or `Compression::None` if there is nothing in common
+    pub fn negotiate(
+        accepted: u8
+    ) -> Compression {
+        Compression::supported()
+            .into_iter()
+            .find(|c| accepted & (1 << *c as u8) != 0)
+            .unwrap_or(Compression::None)
+    }
+
+    /// Compresses the given bytes with this algorithm
+    /// 
+    /// ## Params:
+    /// * data: the bytes to compress
+    /// 
+    /// ## Returns:
+    /// * Ok: the compressed bytes
+    /// * Err: a string describing the issue encountered
+    fn compress(
+        &self,
+        data: &[u8]
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => {
+                use std::io::Write;
+                let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                match encoder.write_all(data).and_then(|_| encoder.finish()) {
+                    Ok(a) => Ok(a),
+                    Err(e) => Err(format!("Deflate compression failed: {}", e))
+                }
+            },
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => match zstd::bulk::compress(data, 0) {
+                Ok(a) => Ok(a),
+                Err(e) => Err(format!("Zstd compression failed: {}", e))
+            },
+            #[allow(unreachable_patterns)]
+            _ => Err(format!("Compression algorithm {:?} is not supported by this build", self))
+        }
+    }
+
+    /// Decompresses the given bytes with this algorithm
+    /// 
+    /// ## Params:
+    /// * data: the bytes to decompress
+    /// * limit: the most bytes the output may grow to
+    /// 
+    /// ## Returns:
+    /// * Ok: the decompressed bytes
+    /// * Err: a string describing the issue encountered, including output over `limit`
+    fn decompress(
+        &self,
+        data: &[u8],
+        limit: usize
+    ) -> Result<Vec<u8>, String> {
+        // read one byte past the limit so we can tell whether it was reached or crossed
+        use std::io::Read;
+        let cap = (limit as u64).saturating_add(1);
+        let mut decoded = Vec::new();
+        let read = match self {
+            Compression::None => Ok(data.take(cap).read_to_end(&mut decoded).map(|_| ())),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => Ok(flate2::read::DeflateDecoder::new(data).take(cap).read_to_end(&mut decoded).map(|_| ())),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::stream::read::Decoder::new(data).and_then(|d| d.take(cap).read_to_end(&mut decoded)).map(|_| ())),
+            #[allow(unreachable_patterns)]
+            _ => Err(format!("Compression algorithm {:?} is not supported by this build", self))
+        };
+        match read? {
+            Ok(_) if decoded.len() > limit => Err(format!("Metadata exceeds {} bytes once decompressed", limit)),
+            Ok(_) => Ok(decoded),
+            Err(e) => Err(format!("{:?} decompression failed: {}", self, e))
+        }
+    }
+}
+
+
+
+/// Defines the digest algorithms used for end-to-end integrity checks of the metadata
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DigestAlgorithm {
+    /// SHA-256
+    Sha256=1,
+    /// BLAKE3 (256 bit output)
+    Blake3=2
+}
+
+impl DigestAlgorithm {
+    /// Converts the given byte into the appropriate digest algorithm
+    /// 
+    /// ## Params:
+    /// b: The byte to convert. 0 means no digest
+    /// 
+    /// ## Returns
+    /// Ok: the matching `DigestAlgorithm`, or `None` for 0
+    /// Err: the unknown byte
+    pub fn from_byte(
+        b: u8
+    ) -> Result<Option<Self>, IntegrityError> {
+        match b {
+            0 => Ok(None),
+            b if b == DigestAlgorithm::Sha256 as u8 => Ok(Some(DigestAlgorithm::Sha256)),
+            b if b == DigestAlgorithm::Blake3 as u8 => Ok(Some(DigestAlgorithm::Blake3)),
+            _ => Err(IntegrityError::UnknownAlgorithm(b))
+        }
+    }
+
+    /// Returns the name of the algorithm as used in validators
+    pub fn name(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Blake3 => "blake3"
+        }
+    }
+
+    /// Converts the given name into the matching digest algorithm, if any
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(DigestAlgorithm::Sha256),
+            "blake3" => Some(DigestAlgorithm::Blake3),
+            _ => None
+        }
+    }
+
+    /// Returns the size in bytes of digests produced by this algorithm
+    pub fn digest_size(&self) -> usize {
+        32
+    }
+
+    /// Computes the digest of the given bytes
+    /// 
+    /// ## Params:
+    /// * data: the bytes to hash
+    /// 
+    /// ## Returns:
+    /// The raw digest bytes
+    pub fn compute(
+        &self,
+        data: &[u8]
+    ) -> Vec<u8> {
+        match self {
+            DigestAlgorithm::Sha256 => {
+                use sha2::Digest;
+                sha2::Sha256::digest(data).to_vec()
+            },
+            DigestAlgorithm::Blake3 => blake3::hash(data).as_bytes().to_vec()
+        }
+    }
+}
+
+/// Describes why a block failed its integrity check
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrityError {
+    /// The `digest` header names a digest algorithm we don't know
+    UnknownAlgorithm(u8),
+    /// The `digest` header is too short to hold a digest of its algorithm
+    Truncated,
+    /// The structured headers that would carry the digest can't be read
+    Malformed(String),
+    /// The block does not carry a digest at all
+    Missing,
+    /// The digest carried by the block doesn't match its body
+    Mismatch {
+        algorithm: DigestAlgorithm,
+        expected: Vec<u8>,
+        computed: Vec<u8>
+    }
+}
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityError::UnknownAlgorithm(b) => write!(f, "Unknown digest algorithm {}", b),
+            IntegrityError::Truncated => write!(f, "Digest header too short for its algorithm"),
+            IntegrityError::Malformed(e) => write!(f, "Cannot read the digest header: {}", e),
+            IntegrityError::Missing => write!(f, "Block carries no digest"),
+            IntegrityError::Mismatch { algorithm, expected, computed } => write!(
+                f, 
+                "{:?} digest mismatch: expected {}, computed {}", 
+                algorithm, 
+                to_hex(expected), 
+                to_hex(computed)
+            )
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+
+
 /// Defines our GRID request header
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GridBlock {
     opcode: GridCode,       // OPCODE of the request. Translates to one of the enum codes
     path_size: u128,        // Size of the path segment in the payload
@@ -151,7 +441,7 @@ impl GridBlock {
                 // given we have a path, 
                 // add it to the payload
                 let str_bytes = a.as_bytes();
-                request_payload.extend_from_slice(&str_bytes); 
+                request_payload.extend_from_slice(str_bytes); 
                 a.len()
             },
             None => 0
@@ -182,7 +472,7 @@ impl GridBlock {
         bytes: Vec<u8>
     ) -> Result<Self, String> {
         // make sure the length is at least the length of the header
-        let header_size = 1+16*3;
+        let header_size = GRID_HEADER_SIZE;
         if bytes.len() < header_size {
             return Err(format!("Too few bytes to recreate header: got {}", bytes.len()))
         }
@@ -198,8 +488,9 @@ impl GridBlock {
         let reserved = u128::from_be_bytes(u128_buff);
 
         // now that we have the rest of the bytes, make sure we got everything
-        if bytes.len() != header_size + (path_size + metadata_size) as usize {
-            return Err(format!("Incorrect bytes received. Size mismatch. Expected {}, got {}", header_size + (path_size + metadata_size) as usize, bytes.len()))
+        let expected = (header_size as u128).saturating_add(path_size).saturating_add(metadata_size);
+        if bytes.len() as u128 != expected {
+            return Err(format!("Incorrect bytes received. Size mismatch. Expected {}, got {}", expected, bytes.len()))
         }
 
         // now that the rest is looking OK, lets return the structure
@@ -246,6 +537,471 @@ impl GridBlock {
         // return the buffer
         buffer
     }
+
+    /// Returns the total size of the serialized block starting at `bytes`
+    /// 
+    /// ## Params:
+    /// * bytes: the start of a serialized block, at least as long as the header
+    /// 
+    /// ## Returns:
+    /// The size of the whole frame in bytes, or `None` if `bytes` doesn't hold a complete header yet
+    pub fn frame_length(
+        bytes: &[u8]
+    ) -> Option<u128> {
+        if bytes.len() < GRID_HEADER_SIZE {
+            return None;
+        }
+
+        let mut u128_buff = [0u8; std::mem::size_of::<u128>()];
+        u128_buff.copy_from_slice(&bytes[1..17]);
+        let path_size = u128::from_be_bytes(u128_buff);
+        u128_buff.copy_from_slice(&bytes[17..33]);
+        let metadata_size = u128::from_be_bytes(u128_buff);
+
+        Some((GRID_HEADER_SIZE as u128).saturating_add(path_size).saturating_add(metadata_size))
+    }
+
+    /// Returns the OPCODE of the block
+    pub fn opcode(&self) -> GridCode {
+        self.opcode
+    }
+
+    /// Returns the path segment of the block
+    /// 
+    /// ## Params:
+    /// None
+    /// 
+    /// ## Returns:
+    /// * Ok: the path as a string (empty if the block carries none)
+    /// * Err: a string describing the issue encountered
+    pub fn path(&self) -> Result<String, String> {
+        match std::str::from_utf8(&self.payload[..self.path_size as usize]) {
+            Ok(a) => Ok(a.to_string()),
+            Err(e) => Err(format!("Path is not valid UTF-8: {}", e))
+        }
+    }
+
+    /// Returns the metadata segment of the block
+    pub fn metadata(&self) -> &[u8] {
+        &self.payload[self.path_size as usize..]
+    }
+
+    /// Returns the digest carried in the `digest` header, if any
+    /// 
+    /// ## Params:
+    /// None
+    /// 
+    /// ## Returns:
+    /// * Ok: the algorithm and raw digest bytes, or `None` if the block carries no digest
+    /// * Err: an `IntegrityError` if the digest header can't be read
+    pub fn digest(&self) -> Result<Option<(DigestAlgorithm, Vec<u8>)>, IntegrityError> {
+        match self.headers() {
+            Ok(Some(headers)) => headers.digest().map(|d| d.map(|(a, d)| (a, d.to_vec()))),
+            Ok(None) => Ok(None),
+            Err(e) => Err(IntegrityError::Malformed(e))
+        }
+    }
+
+    /// Computes a digest of the content and carries it in the `digest` header
+    /// 
+    /// Must be called before `compress`, as the digest covers the uncompressed content.
+    /// Any digest already present is replaced.
+    /// 
+    /// ## Params:
+    /// * algorithm: the digest algorithm to use
+    /// 
+    /// ## Returns:
+    /// * Ok: nothing, the block is updated in place
+    /// * Err: a string describing the issue encountered
+    pub fn add_digest(
+        &mut self,
+        algorithm: DigestAlgorithm
+    ) -> Result<(), String> {
+        if self.compression()? != Compression::None {
+            return Err("Cannot add a digest to a compressed block".to_string());
+        }
+
+        let digest = algorithm.compute(self.content());
+        let mut headers = self.headers()?.unwrap_or_default();
+        headers.set_digest(algorithm, &digest)?;
+        self.replace_headers(&headers)
+    }
+
+    /// Checks the digest carried by the block against its content
+    /// 
+    /// Must be called after `decompress`.
+    /// 
+    /// ## Params:
+    /// None
+    /// 
+    /// ## Returns:
+    /// * Ok: the algorithm and digest that were verified
+    /// * Err: an `IntegrityError` describing why the check failed
+    pub fn verify_digest(&self) -> Result<(DigestAlgorithm, Vec<u8>), IntegrityError> {
+        let (algorithm, expected) = match self.digest()? {
+            Some(a) => a,
+            None => return Err(IntegrityError::Missing)
+        };
+
+        let computed = algorithm.compute(self.content());
+        if computed != expected {
+            return Err(IntegrityError::Mismatch { 
+                algorithm, 
+                expected, 
+                computed 
+            });
+        }
+        Ok((algorithm, computed))
+    }
+
+    /// Checks the digest of the block if it carries one
+    /// 
+    /// Unlike `verify_digest`, a block without a digest passes. A digest header
+    /// that can't be read fails just like one that doesn't match. Must be called
+    /// after `decompress`.
+    /// 
+    /// ## Params:
+    /// None
+    /// 
+    /// ## Returns:
+    /// * Ok: the algorithm that was verified, or `None` if the block carries no digest
+    /// * Err: an `IntegrityError` describing why the check failed
+    pub fn check_digest(&self) -> Result<Option<DigestAlgorithm>, IntegrityError> {
+        match self.digest()? {
+            Some(_) => self.verify_digest().map(|(algorithm, _)| Some(algorithm)),
+            None => Ok(None)
+        }
+    }
+
+    /// Returns the digest algorithm the sender asked the reply to carry, if any
+    pub fn requested_digest(&self) -> Option<DigestAlgorithm> {
+        DigestAlgorithm::from_byte((self.reserved >> DIGEST_REQUEST_SHIFT) as u8).unwrap_or(None)
+    }
+
+    /// Returns a validator identifying the current content of the block
+    /// 
+    /// The validator is the `version` header if the block carries one. Otherwise
+    /// it is the name of a digest algorithm and the hex encoded digest, e.g.
+    /// `sha256:9f86…`, using the carried digest or a SHA-256 digest of the content.
+    pub fn validator(&self) -> String {
+        let headers = self.headers().ok().flatten();
+        if let Some(version) = headers.as_ref().and_then(|h| h.version()) {
+            return version.to_string();
+        }
+
+        let (algorithm, digest) = match self.digest() {
+            Ok(Some(a)) => a,
+            _ => (DigestAlgorithm::Sha256, DigestAlgorithm::Sha256.compute(self.content()))
+        };
+        digest_validator(algorithm, &digest)
+    }
+
+    /// Returns the validator carried by a conditional request, if any
+    pub fn condition(&self) -> Option<String> {
+        self.headers().ok().flatten()?.version().map(|v| v.to_string())
+    }
+
+    /// Turns the block into a conditional request for the copy we already hold
+    /// 
+    /// The validator is carried in the `version` header. Must be called before `compress`.
+    /// 
+    /// ## Params:
+    /// * validator: a validator as returned by `GridBlock::validator`
+    /// 
+    /// ## Returns:
+    /// * Ok: nothing, the block is updated in place
+    /// * Err: a string describing the issue encountered
+    pub fn set_condition(&mut self, validator: &str) -> Result<(), String> {
+        if self.compression()? != Compression::None {
+            return Err("Cannot make a compressed block conditional".to_string());
+        }
+
+        let mut headers = self.headers()?.unwrap_or_default();
+        headers.set_version(validator)?;
+        self.replace_headers(&headers)
+    }
+
+    /// Builds a GRID block whose body starts with structured headers
+    /// 
+    /// ## Params:
+    /// * opcode: the GRID code to be used for the block
+    /// * path: optional argument providing the path of the request
+    /// * headers: the headers to lead the body with
+    /// * content: the data following the headers
+    /// 
+    /// ## Returns:
+    /// * Ok: Returns a GRID block structure
+    /// * Err: Returns a string describing the issue encountered
+    pub fn with_metadata(
+        opcode: impl Into<GridCode>,
+        path: Option<&str>,
+        headers: &Metadata,
+        content: &[u8]
+    ) -> Result<Self, String> {
+        let mut payload = headers.encode();
+        payload.extend_from_slice(content);
+
+        let mut block = GridBlock::new(opcode, path, &mut payload)?;
+        block.reserved |= STRUCTURED_FLAG;
+        Ok(block)
+    }
+
+    /// Returns the structured headers leading the body, if the block has any
+    /// 
+    /// Must be called after `decompress`.
+    /// 
+    /// ## Params:
+    /// None
+    /// 
+    /// ## Returns:
+    /// * Ok: the headers, or `None` if the block is not flagged as carrying any
+    /// * Err: a string describing why the headers can't be read
+    pub fn headers(&self) -> Result<Option<Metadata>, String> {
+        if self.reserved & STRUCTURED_FLAG == 0 {
+            return Ok(None);
+        }
+        Metadata::decode(self.metadata()).map(|(headers, _)| Some(headers))
+    }
+
+    /// Sets a structured header, leading the metadata segment with headers if it had none
+    /// 
+    /// Must be called before `compress`.
+    /// 
+    /// ## Params:
+    /// * key: the key of the header
+    /// * value: the encoded value
+    /// 
+    /// ## Returns:
+    /// * Ok: nothing, the block is updated in place
+    /// * Err: a string describing the issue encountered
+    pub fn insert_header(&mut self, key: MetadataKey, value: Vec<u8>) -> Result<(), String> {
+        if self.compression()? != Compression::None {
+            return Err("Cannot add a header to a compressed block".to_string());
+        }
+
+        let mut headers = self.headers()?.unwrap_or_default();
+        headers.insert(key, value)?;
+        self.replace_headers(&headers)
+    }
+
+    /// Removes a structured header, if the block carries it
+    /// 
+    /// Must be called before `compress`.
+    /// 
+    /// ## Params:
+    /// * key: the key of the header
+    /// 
+    /// ## Returns:
+    /// * Ok: the value the header had, if any, the block is updated in place
+    /// * Err: a string describing the issue encountered
+    pub fn remove_header(&mut self, key: &MetadataKey) -> Result<Option<Vec<u8>>, String> {
+        if self.compression()? != Compression::None {
+            return Err("Cannot remove a header from a compressed block".to_string());
+        }
+
+        let mut headers = match self.headers()? {
+            Some(a) => a,
+            None => return Ok(None)
+        };
+        let removed = headers.remove(key);
+        if removed.is_some() {
+            self.replace_headers(&headers)?;
+        }
+        Ok(removed)
+    }
+
+    /// Helper function swapping the structured headers leading the body for others
+    fn replace_headers(&mut self, headers: &Metadata) -> Result<(), String> {
+        let mut metadata = headers.encode();
+        metadata.extend_from_slice(self.content());
+
+        self.payload.truncate(self.path_size as usize);
+        self.metadata_size = metadata.len() as u128;
+        self.payload.append(&mut metadata);
+        self.reserved |= STRUCTURED_FLAG;
+        Ok(())
+    }
+
+    /// Returns the metadata segment without any structured headers leading it
+    pub fn content(&self) -> &[u8] {
+        if self.reserved & STRUCTURED_FLAG == 0 {
+            return self.metadata();
+        }
+        match Metadata::decode(self.metadata()) {
+            Ok((_, consumed)) => &self.metadata()[consumed..],
+            Err(_) => self.metadata()
+        }
+    }
+
+    /// Returns how long a `BSY` response asks the client to wait before retrying
+    /// 
+    /// ## Params:
+    /// None
+    /// 
+    /// ## Returns:
+    /// The retry hint, or `None` if this is not a `BSY` response or it carries no hint
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        if self.opcode != GridCode::Response(GridResponseCode::BSY) {
+            return None;
+        }
+        self.headers().ok()??.retry_after()
+    }
+
+    /// Checks whether a conditional request is satisfied by the given response
+    /// 
+    /// ## Params:
+    /// * response: the response the server would send for the request
+    /// 
+    /// ## Returns:
+    /// `true` if the request carried a validator matching the response, meaning the
+    /// client's copy is current and `NMD` may be sent instead
+    pub fn is_not_modified(&self, response: &GridBlock) -> bool {
+        let validator = match self.condition() {
+            Some(a) => a,
+            None => return false
+        };
+        if response.opcode != GridCode::Response(GridResponseCode::ROK) {
+            return false;
+        }
+
+        // a version tag is a cheap validator, no need to hash anything
+        let headers = response.headers().ok().flatten();
+        if let Some(version) = headers.as_ref().and_then(|h| h.version()) {
+            return validator == version;
+        }
+
+        // compare using the algorithm the client's validator was made with
+        let algorithm = match validator.split_once(':').and_then(|(name, _)| DigestAlgorithm::from_name(name)) {
+            Some(a) => a,
+            None => return false
+        };
+        let current = match response.digest() {
+            Ok(Some((b, d))) if algorithm == b => digest_validator(b, &d),
+            _ => digest_validator(algorithm, &algorithm.compute(response.content()))
+        };
+        current == validator
+    }
+
+    /// Asks the remote to attach a digest of the given algorithm to its reply
+    /// 
+    /// ## Params:
+    /// * algorithm: the digest algorithm wanted, or `None` to clear the request
+    /// 
+    /// ## Returns:
+    /// None
+    pub fn request_digest(&mut self, algorithm: Option<DigestAlgorithm>) {
+        self.reserved &= !(0xff << DIGEST_REQUEST_SHIFT);
+        if let Some(a) = algorithm {
+            self.reserved |= (a as u128) << DIGEST_REQUEST_SHIFT;
+        }
+    }
+
+    /// Returns the compression algorithm applied to the metadata segment
+    /// 
+    /// ## Params:
+    /// None
+    /// 
+    /// ## Returns:
+    /// * Ok: the compression algorithm flagged in the header
+    /// * Err: a string describing the issue encountered
+    pub fn compression(&self) -> Result<Compression, String> {
+        Compression::from_byte((self.reserved & COMPRESSION_USED_MASK) as u8)
+    }
+
+    /// Returns the bit mask of compression algorithms the sender of this block accepts
+    pub fn accepted_compression(&self) -> u8 {
+        (self.reserved >> COMPRESSION_ACCEPT_SHIFT) as u8
+    }
+
+    /// Advertises the compression algorithms we are willing to receive in reply to this block
+    /// 
+    /// ## Params:
+    /// * mask: bit mask of accepted algorithms, usually `Compression::supported_mask()`
+    /// 
+    /// ## Returns:
+    /// None
+    pub fn set_accepted_compression(&mut self, mask: u8) {
+        self.reserved &= !(0xff << COMPRESSION_ACCEPT_SHIFT);
+        self.reserved |= (mask as u128) << COMPRESSION_ACCEPT_SHIFT;
+    }
+
+    /// Compresses the metadata segment in place and flags it in the header
+    /// 
+    /// Compression is skipped if the block is already compressed, if the metadata
+    /// is smaller than `threshold`, or if compressing would not make it smaller.
+    /// 
+    /// ## Params:
+    /// * algorithm: the compression algorithm to apply
+    /// * threshold: the minimum metadata size (in bytes) worth compressing
+    /// 
+    /// ## Returns:
+    /// * Ok: nothing, the block is updated in place
+    /// * Err: a string describing the issue encountered
+    pub fn compress(
+        &mut self,
+        algorithm: Compression,
+        threshold: usize
+    ) -> Result<(), String> {
+        // check if this is worth our time
+        if algorithm == Compression::None 
+            || self.compression()? != Compression::None
+            || (self.metadata_size as usize) < threshold {
+            return Ok(());
+        }
+
+        let compressed = algorithm.compress(self.metadata())?;
+        if compressed.len() >= self.metadata_size as usize {
+            return Ok(());
+        }
+
+        // swap out the metadata and flag the algorithm
+        self.metadata_size = compressed.len() as u128;
+        self.payload.truncate(self.path_size as usize);
+        self.payload.extend_from_slice(&compressed);
+        self.reserved = (self.reserved & !COMPRESSION_USED_MASK) | algorithm as u128;
+        Ok(())
+    }
+
+    /// Decompresses the metadata segment in place if the header flags it as compressed
+    /// 
+    /// ## Params:
+    /// * limit: the most bytes the metadata may grow to, guarding against decompression bombs
+    /// 
+    /// ## Returns:
+    /// * Ok: nothing, the block is updated in place
+    /// * Err: a string describing the issue encountered
+    pub fn decompress(&mut self, limit: usize) -> Result<(), String> {
+        let algorithm = self.compression()?;
+        if algorithm == Compression::None {
+            return Ok(());
+        }
+
+        let decompressed = algorithm.decompress(self.metadata(), limit)?;
+        self.metadata_size = decompressed.len() as u128;
+        self.payload.truncate(self.path_size as usize);
+        self.payload.extend_from_slice(&decompressed);
+        self.reserved &= !COMPRESSION_USED_MASK;
+        Ok(())
+    }
+
+    /// Advertises our supported algorithms and compresses the block with the
+    /// preferred algorithm the remote accepts
+    /// 
+    /// ## Params:
+    /// * peer_accepted: bit mask of algorithms the remote advertised
+    /// * threshold: the minimum metadata size (in bytes) worth compressing
+    /// 
+    /// ## Returns:
+    /// * Ok: nothing, the block is updated in place
+    /// * Err: a string describing the issue encountered
+    pub fn negotiate_compression(
+        &mut self,
+        peer_accepted: u8,
+        threshold: usize
+    ) -> Result<(), String> {
+        self.set_accepted_compression(Compression::supported_mask());
+        self.compress(Compression::negotiate(peer_accepted), threshold)
+    }
 }
 
 
@@ -273,7 +1029,7 @@ pub fn string_to_domain(
     ) -> Result<(ConnectionType, String, u16), String> {
     // make sure the string begins with "grid"
     let conn_type: ConnectionType;
-    let remote: String = remote.try_into().unwrap();
+    let remote: String = remote.into();
     if remote.starts_with("grid!") {
         // connecting to domain name
         conn_type = ConnectionType::Domain;
@@ -322,4 +1078,39 @@ pub fn string_to_domain(
     Ok((conn_type, domain, port))
 }
 
+/// Formats bytes as a lowercase hexadecimal string
+/// 
+/// ## Params:
+/// * bytes: the bytes to format
+/// 
+/// ## Returns:
+/// The hexadecimal representation of `bytes`
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
+/// Turns a hexadecimal string back into bytes
+/// 
+/// ## Params:
+/// * hex: the hexadecimal digits, two per byte
+/// 
+/// ## Returns:
+/// * Ok: the bytes
+/// * Err: a string describing the issue encountered
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(format!("Odd number of digits in {}", hex));
+    }
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("Bad hex in {}", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Bad hex in {}", hex)))
+        .collect()
+}
+
+/// Builds the validator naming a digest algorithm and a digest made with it
+fn digest_validator(algorithm: DigestAlgorithm, digest: &[u8]) -> String {
+    format!("{}:{}", algorithm.name(), to_hex(digest))
+}
@@ -0,0 +1,168 @@
+// Defines GML, the line based markup GRID documents are written in
+use std::fmt;
+
+
+/// MIME type of GML documents
+pub const GML_CONTENT_TYPE: &str = "text/gml";
+
+/// Line opening and closing a preformatted block
+const PREFORMATTED_MARKER: &str = "```";
+
+
+/// A single element of a GML document
+#[derive(Debug, Clone, PartialEq)]
+pub enum GmlLine {
+    /// Heading of level 1 to 3, written `#`, `##` or `###`
+    Heading(u8, String),
+    /// Link written `=> target label`, the label may be empty
+    Link { target: String, label: String },
+    /// List item written `* text`
+    ListItem(String),
+    /// Quote written `> text`
+    Quote(String),
+    /// Lines shown as is, between two ```` ``` ```` lines. The first may carry alt text
+    Preformatted { alt: String, lines: Vec<String> },
+    /// Paragraph text, an empty line separates paragraphs
+    Text(String)
+}
+
+
+/// structure holding a parsed GML document
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GmlDocument {
+    pub lines: Vec<GmlLine>
+}
+
+impl GmlDocument {
+    /// Creates an empty `GmlDocument` instance
+    pub fn new() -> Self {
+        GmlDocument::default()
+    }
+
+    /// Parses a GML document
+    ///
+    /// Parsing never fails: anything that is not markup is text, and an
+    /// unterminated preformatted block runs to the end of the document.
+    ///
+    /// ## Params:
+    /// * text: the document
+    ///
+    /// ## Returns:
+    /// * the parsed document
+    pub fn parse(text: &str) -> Self {
+        let mut document = GmlDocument::new();
+        let mut preformatted: Option<(String, Vec<String>)> = None;
+
+        for line in text.lines() {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+
+            // everything up to the closing marker is taken as is
+            if let Some((alt, lines)) = &mut preformatted {
+                if line.starts_with(PREFORMATTED_MARKER) {
+                    document.lines.push(GmlLine::Preformatted { alt: std::mem::take(alt), lines: std::mem::take(lines) });
+                    preformatted = None;
+                } else {
+                    lines.push(line.to_string());
+                }
+                continue;
+            }
+
+            let element = if let Some(alt) = line.strip_prefix(PREFORMATTED_MARKER) {
+                preformatted = Some((alt.trim().to_string(), Vec::new()));
+                continue;
+            } else if let Some(link) = line.strip_prefix("=>") {
+                let link = link.trim();
+                let (target, label) = link.split_once(char::is_whitespace).unwrap_or((link, ""));
+                GmlLine::Link { target: target.to_string(), label: label.trim().to_string() }
+            } else if let Some(text) = line.strip_prefix("### ") {
+                GmlLine::Heading(3, text.trim().to_string())
+            } else if let Some(text) = line.strip_prefix("## ") {
+                GmlLine::Heading(2, text.trim().to_string())
+            } else if let Some(text) = line.strip_prefix("# ") {
+                GmlLine::Heading(1, text.trim().to_string())
+            } else if let Some(text) = line.strip_prefix("* ") {
+                GmlLine::ListItem(text.trim().to_string())
+            } else if let Some(text) = line.strip_prefix('>') {
+                GmlLine::Quote(text.trim().to_string())
+            } else {
+                GmlLine::Text(line.to_string())
+            };
+            document.lines.push(element);
+        }
+
+        if let Some((alt, lines)) = preformatted {
+            document.lines.push(GmlLine::Preformatted { alt, lines });
+        }
+        document
+    }
+
+    /// Appends a heading
+    pub fn heading(&mut self, level: u8, text: &str) {
+        self.lines.push(GmlLine::Heading(level.clamp(1, 3), single_line(text)));
+    }
+
+    /// Appends a link, percent-encoding whitespace in the target
+    pub fn link(&mut self, target: &str, label: &str) {
+        let target = target.split(char::is_whitespace).collect::<Vec<&str>>().join("%20");
+        self.lines.push(GmlLine::Link { target, label: single_line(label) });
+    }
+
+    /// Appends a list item
+    pub fn list_item(&mut self, text: &str) {
+        self.lines.push(GmlLine::ListItem(single_line(text)));
+    }
+
+    /// Appends a paragraph of text
+    pub fn text(&mut self, text: &str) {
+        self.lines.push(GmlLine::Text(single_line(text)));
+    }
+
+    /// Appends an empty line
+    pub fn blank(&mut self) {
+        self.lines.push(GmlLine::Text(String::new()));
+    }
+
+    /// Returns the text of the first heading, which browsers use as the title
+    pub fn title(&self) -> Option<&str> {
+        self.lines.iter().find_map(|line| match line {
+            GmlLine::Heading(_, text) => Some(text.as_str()),
+            _ => None
+        })
+    }
+}
+
+impl fmt::Display for GmlDocument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            match line {
+                GmlLine::Heading(level, text) => writeln!(f, "{} {}", "#".repeat(*level as usize), text)?,
+                GmlLine::Link { target, label } if label.is_empty() => writeln!(f, "=> {}", target)?,
+                GmlLine::Link { target, label } => writeln!(f, "=> {} {}", target, label)?,
+                GmlLine::ListItem(text) => writeln!(f, "* {}", text)?,
+                GmlLine::Quote(text) => writeln!(f, "> {}", text)?,
+                GmlLine::Preformatted { alt, lines } => {
+                    writeln!(f, "{}{}", PREFORMATTED_MARKER, alt)?;
+                    for line in lines {
+                        writeln!(f, "{}", line)?;
+                    }
+                    writeln!(f, "{}", PREFORMATTED_MARKER)?;
+                },
+                // text that would read as markup is indented by a space
+                GmlLine::Text(text) if is_markup(text) => writeln!(f, " {}", text)?,
+                GmlLine::Text(text) => writeln!(f, "{}", text)?
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Helper function folding line breaks into spaces
+fn single_line(text: &str) -> String {
+    text.split(['\r', '\n']).filter(|l| !l.is_empty()).collect::<Vec<&str>>().join(" ")
+}
+
+/// Helper function checking whether a text line would parse as something else
+fn is_markup(text: &str) -> bool {
+    ["=>", "# ", "## ", "### ", "* ", ">", PREFORMATTED_MARKER].iter().any(|m| text.starts_with(m))
+}
@@ -0,0 +1,203 @@
+// Defines persisted self-signed server identities
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, SanType};
+use time::{Duration, OffsetDateTime};
+
+use tracing::{info, warn};
+
+
+use crate::definitions::DigestAlgorithm;
+use crate::server::CertificateStore;
+
+
+/// Number of days generated self-signed certificates are valid for
+pub const IDENTITY_VALIDITY_DAYS: i64 = 365;
+
+/// Identities expiring sooner than this are regenerated instead of reused
+const RENEWAL_MARGIN_DAYS: i64 = 1;
+
+
+/// Builds certificate parameters for a set of names
+///
+/// Names that parse as IP addresses become IP address SANs, everything else a DNS name SAN.
+///
+/// ## Params:
+/// * names: the names the certificate should be valid for. The first one becomes the common name
+/// * validity_days: how many days from now the certificate is valid for
+///
+/// ## Returns:
+/// * rcgen certificate parameters, ready to be signed
+pub fn certificate_params(names: &[String], validity_days: i64) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.subject_alt_names = names
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.clone())
+        })
+        .collect();
+
+    let mut dn = DistinguishedName::new();
+    dn.push(DnType::CommonName, names.first().cloned().unwrap_or_else(|| "GRID server".to_string()));
+    params.distinguished_name = dn;
+
+    // backdate a little to be forgiving about clock skew
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::hours(1);
+    params.not_after = now + Duration::days(validity_days);
+    params
+}
+
+/// Returns the directory server identities are stored in when none is given
+///
+/// Uses `$GRID_STATE_DIR` if set, then `$XDG_STATE_HOME/grid`, then `~/.local/state/grid`.
+pub fn default_state_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("GRID_STATE_DIR") {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = std::env::var_os("XDG_STATE_HOME") {
+        return PathBuf::from(dir).join("grid");
+    }
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".local").join("state").join("grid"),
+        None => std::env::temp_dir().join("grid")
+    }
+}
+
+/// Loads the self-signed identity for a set of names, generating and storing it if needed
+///
+/// The certificate and key are kept as PEM files named after the first name. They
+/// are reused until they are about to expire or no longer cover `names`.
+///
+/// ## Params:
+/// * state_dir: the directory identities are stored in
+/// * names: the DNS names and IP addresses the certificate should be valid for
+///
+/// ## Returns:
+/// * Ok: the identity
+/// * Err: a string describing the issue encountered
+pub fn load_or_create_identity(state_dir: &Path, names: Vec<String>) -> Result<CertificateStore, String> {
+    if names.is_empty() {
+        return Err("An identity needs at least one name".to_string());
+    }
+    let stem: String = names[0]
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect();
+    let cert_path = state_dir.join(format!("{}.pem", stem));
+    let key_path = state_dir.join(format!("{}.key", stem));
+
+    // try the stored identity first
+    if cert_path.is_file() && key_path.is_file() {
+        match CertificateStore::from_pem_files(&cert_path, &key_path, names.clone()) {
+            Ok(store) => match identity_is_current(&store, &names) {
+                Ok(true) => {
+                    info!(path = %cert_path.display(), fingerprint = %fingerprint(&store)?, "reusing self-signed identity");
+                    return Ok(store);
+                },
+                Ok(false) => info!(path = %cert_path.display(), "stored identity expired or names changed, regenerating"),
+                Err(e) => warn!(path = %cert_path.display(), error = %e, "stored identity unreadable, regenerating")
+            },
+            Err(e) => warn!(path = %cert_path.display(), error = %e, "stored identity unreadable, regenerating")
+        }
+    }
+
+    // make a new one and write it out
+    let cert = match Certificate::from_params(certificate_params(&names, IDENTITY_VALIDITY_DAYS)) {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Certificate generation failed: {}", e))
+    };
+    let cert_pem = match cert.serialize_pem() {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Certificate serialization failed: {}", e))
+    };
+
+    if let Err(e) = fs::create_dir_all(state_dir) {
+        return Err(format!("Cannot create state directory {}: {}", state_dir.display(), e));
+    }
+    write_private(&key_path, cert.serialize_private_key_pem().as_bytes())?;
+    if let Err(e) = fs::write(&cert_path, cert_pem) {
+        return Err(format!("Cannot write {}: {}", cert_path.display(), e));
+    }
+
+    // read it back, since every serialization re-signs the certificate and only the stored copy counts
+    let store = CertificateStore::from_pem_files(&cert_path, &key_path, names)?;
+    info!(path = %cert_path.display(), fingerprint = %fingerprint(&store)?, "generated self-signed identity");
+    Ok(store)
+}
+
+/// Returns the SHA-256 fingerprint of the leaf certificate, as colon separated hex
+///
+/// ## Params:
+/// * store: the certificate store to fingerprint
+///
+/// ## Returns:
+/// * Ok: the fingerprint, e.g. `"ab:cd:..."`
+/// * Err: a string describing the issue encountered
+pub fn fingerprint(store: &CertificateStore) -> Result<String, String> {
+    let leaf = match store.clone().get_certificates()?.into_iter().next() {
+        Some(a) => a,
+        None => return Err("Certificate store holds no certificates".to_string())
+    };
+
+    let digest = DigestAlgorithm::Sha256.compute(&leaf.0);
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":"))
+}
+
+/// Helper function checking that a stored identity is valid for a while and covers `names`
+fn identity_is_current(store: &CertificateStore, names: &[String]) -> Result<bool, String> {
+    let leaf = match store.clone().get_certificates()?.into_iter().next() {
+        Some(a) => a,
+        None => return Ok(false)
+    };
+    let (_, cert) = match x509_parser::parse_x509_certificate(&leaf.0) {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Cannot parse certificate: {}", e))
+    };
+
+    let renew_at = OffsetDateTime::now_utc() + Duration::days(RENEWAL_MARGIN_DAYS);
+    if cert.validity().not_after.to_datetime() < renew_at {
+        return Ok(false);
+    }
+
+    // every requested name must be in the SANs
+    let sans: Vec<String> = match cert.subject_alternative_name() {
+        Ok(Some(ext)) => ext.value.general_names.iter().filter_map(|n| match n {
+            x509_parser::extensions::GeneralName::DNSName(d) => Some(d.to_string()),
+            x509_parser::extensions::GeneralName::IPAddress(ip) => match ip.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(*ip).ok()?).to_string()),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(*ip).ok()?).to_string()),
+                _ => None
+            },
+            _ => None
+        }).collect(),
+        _ => Vec::new()
+    };
+    Ok(names.iter().all(|n| {
+        let canonical = n.parse::<IpAddr>().map(|ip| ip.to_string()).unwrap_or_else(|_| n.clone());
+        sans.contains(&canonical)
+    }))
+}
+
+/// Helper function writing a file only the current user can read
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let result = options.open(path).and_then(|mut f| {
+        use std::io::Write;
+        f.write_all(contents)
+    });
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot write {}: {}", path.display(), e))
+    }
+}
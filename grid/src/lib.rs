@@ -3,14 +3,30 @@ This library implements the GRID protocol. It should eventually have C bindings
 but that will be down the road a little bit
 */
 
+pub mod access_log;
+pub mod admission;
+pub mod auth;
+pub mod ca;
+pub mod cache;
 pub mod client;
+mod connection;
 pub mod definitions;
+pub mod gml;
+pub mod identity;
+pub mod metadata;
+mod metrics;
+pub mod path;
+pub mod properties;
 pub mod server;
+pub mod sni;
+pub mod stats;
+pub mod storage;
+pub mod transport;
+mod workers;
 
 // test cases
+#[cfg(test)]
 mod test {
-    use std::string;
-
     use crate::definitions::ConnectionType;
 
     use super::*;
@@ -29,7 +45,7 @@ mod test {
                 assert_eq!(a.1, "testdomain");
                 assert_eq!(a.2, GRID_DEFAULT_PORT);
             },
-            Err(e) => unreachable!()
+            Err(_) => unreachable!()
         }
 
         let test2 = string_to_domain("grid!testdomain:1234"); 
@@ -40,7 +56,7 @@ mod test {
                 assert_eq!(a.1, "testdomain");
                 assert_eq!(a.2, 1234);
             },
-            Err(e) => unreachable!()
+            Err(_) => unreachable!()
         }
 
         let test3 = string_to_domain("grid.1.2.3.4"); 
@@ -51,7 +67,7 @@ mod test {
                 assert_eq!(a.1, "1.2.3.4");
                 assert_eq!(a.2, GRID_DEFAULT_PORT);
             },
-            Err(e) => unreachable!()
+            Err(_) => unreachable!()
         }
 
         let test4 = string_to_domain("grid.1.2.3.4:1234"); 
@@ -62,7 +78,7 @@ mod test {
                 assert_eq!(a.1, "1.2.3.4");
                 assert_eq!(a.2, 1234);
             },
-            Err(e) => unreachable!()
+            Err(_) => unreachable!()
         }
         
 
@@ -79,4 +95,350 @@ mod test {
         assert!(string_to_domain("grid 1.2.3.4:1234").is_err());
         assert!(string_to_domain("grid@1.2.3.4:1234").is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn block_compression_roundtrip() {
+        use definitions::{GridBlock, GridRequestCode, Compression};
+        // compressible payloads above the threshold should shrink and
+        // come back out unchanged on the other side
+        let body = "GRID documentation ".repeat(200).into_bytes();
+        let mut block = GridBlock::new(GridRequestCode::PUT, Some("/docs"), &mut body.clone()).unwrap();
+        block.negotiate_compression(Compression::supported_mask(), 64).unwrap();
+        assert_eq!(block.compression().unwrap(), Compression::negotiate(Compression::supported_mask()));
+
+        let mut received = GridBlock::from_bytes(block.serialize()).unwrap();
+        assert_eq!(received.accepted_compression(), Compression::supported_mask());
+        let mut bomb = received.clone();
+        received.decompress(body.len()).unwrap();
+        assert_eq!(received.compression().unwrap(), Compression::None);
+        assert_eq!(received.metadata(), &body[..]);
+
+        // metadata growing past the limit once decompressed is refused
+        assert!(bomb.decompress(body.len() - 1).is_err());
+
+        // small payloads are left alone
+        let mut small = GridBlock::new(GridRequestCode::PUT, None, &mut b"tiny".to_vec()).unwrap();
+        small.negotiate_compression(Compression::supported_mask(), 64).unwrap();
+        assert_eq!(small.compression().unwrap(), Compression::None);
+
+        // remotes that advertise nothing get nothing
+        assert_eq!(Compression::negotiate(0), Compression::None);
+    }
+
+    #[test]
+    fn block_digest_verification() {
+        use definitions::{GridBlock, GridResponseCode, DigestAlgorithm, IntegrityError, Compression};
+        use metadata::{Metadata, MetadataKey};
+        let body = "integrity ".repeat(300).into_bytes();
+
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Blake3] {
+            // digest survives compression and comes back intact
+            let mut block = GridBlock::new(GridResponseCode::ROK, Some("/a"), &mut body.clone()).unwrap();
+            block.add_digest(algorithm).unwrap();
+            block.negotiate_compression(Compression::supported_mask(), 64).unwrap();
+
+            let mut received = GridBlock::from_bytes(block.serialize()).unwrap();
+            received.decompress(usize::MAX).unwrap();
+            assert_eq!(received.content(), &body[..]);
+            assert_eq!(received.verify_digest().unwrap().0, algorithm);
+
+            // flip a byte of the body and the check should fail
+            let mut raw = GridBlock::new(GridResponseCode::ROK, None, &mut body.clone()).unwrap();
+            raw.add_digest(algorithm).unwrap();
+            let mut bytes = raw.serialize();
+            let last = bytes.len() - 1;
+            bytes[last] ^= 0xff;
+            let tampered = GridBlock::from_bytes(bytes).unwrap();
+            assert!(matches!(tampered.verify_digest(), Err(IntegrityError::Mismatch { .. })));
+        }
+
+        let plain = GridBlock::new(GridResponseCode::ROK, None, &mut body.clone()).unwrap();
+        assert_eq!(plain.verify_digest(), Err(IntegrityError::Missing));
+        assert_eq!(plain.content(), &body[..]);
+        assert_eq!(plain.check_digest(), Ok(None));
+
+        // the digest rides in the digest header, next to any other header
+        let mut titled = GridBlock::new(GridResponseCode::ROK, None, &mut body.clone()).unwrap();
+        titled.add_digest(DigestAlgorithm::Sha256).unwrap();
+        titled.insert_header(MetadataKey::Title, b"integrity".to_vec()).unwrap();
+        assert_eq!(titled.content(), &body[..]);
+        assert!(titled.headers().unwrap().unwrap().get(&MetadataKey::Digest).is_some());
+        assert_eq!(titled.verify_digest().unwrap().0, DigestAlgorithm::Sha256);
+
+        // a digest header that can't be read fails the check rather than being skipped
+        let bad = |value: Vec<u8>| {
+            let mut headers = Metadata::new();
+            headers.insert(MetadataKey::Digest, value).unwrap();
+            GridBlock::with_metadata(GridResponseCode::ROK, None, &headers, b"tiny").unwrap()
+        };
+        assert_eq!(bad(vec![DigestAlgorithm::Sha256 as u8, 0, 0]).check_digest(), Err(IntegrityError::Truncated));
+        assert_eq!(bad(Vec::new()).check_digest(), Err(IntegrityError::Truncated));
+        assert_eq!(bad(vec![9; 33]).check_digest(), Err(IntegrityError::UnknownAlgorithm(9)));
+    }
+
+    #[test]
+    fn rate_limiter_sweeps_and_client_networks() {
+        use admission::{RateLimit, RateLimiter, client_network};
+        use std::net::IpAddr;
+
+        // clients that never get their tokens back are kept, and the next sweep waits for the map to double
+        let strict = RateLimiter::new(RateLimit::new(0.0, 1));
+        for i in 0..4096u32 {
+            assert!(strict.check(i).is_ok());
+        }
+        assert!(strict.check(4096).is_ok());
+        let buckets = strict.buckets.lock().unwrap();
+        assert_eq!((buckets.clients.len(), buckets.next_prune), (4097, 8192));
+        drop(buckets);
+        assert!(strict.check(0).is_err());
+
+        // clients whose buckets filled up again are forgotten
+        let lenient = RateLimiter::new(RateLimit::new(1e12, 1));
+        for i in 0..4097u32 {
+            assert!(lenient.check(i).is_ok());
+        }
+        assert!(lenient.buckets.lock().unwrap().clients.len() < 4097);
+
+        // IPv6 clients are counted per /64, mapped IPv4 ones as IPv4
+        let network = |a: &str| client_network(a.parse::<IpAddr>().unwrap());
+        assert_eq!(network("2001:db8:1:2:aaaa::1"), network("2001:db8:1:2:bbbb::2"));
+        assert_ne!(network("2001:db8:1:2::1"), network("2001:db8:1:3::1"));
+        assert_eq!(network("::ffff:10.1.2.3"), network("10.1.2.3"));
+        assert_ne!(network("10.1.2.3"), network("10.1.2.4"));
+    }
+
+    #[test]
+    fn response_cache_lru_and_disk() {
+        use cache::{ResponseCache, normalize_url};
+        use definitions::{GridBlock, GridRequestCode, GridResponseCode};
+        use metadata::Metadata;
+
+        // URLs differing only in spelling share a key
+        assert_eq!(
+            normalize_url("grid!Docs.Example", "/a/./b//../c").unwrap(),
+            normalize_url("grid!docs.example:7500", "a/c").unwrap()
+        );
+        assert_eq!(normalize_url("grid!x", "/../../etc").unwrap(), "grid!x:7500/etc");
+
+        let dir = std::env::temp_dir().join(format!("grid-cache-test-{}", std::process::id()));
+        let mut cache = ResponseCache::new(2).with_disk(&dir).unwrap();
+        for name in ["a", "b", "c"] {
+            let response = GridBlock::new(GridResponseCode::ROK, None, &mut name.as_bytes().to_vec()).unwrap();
+            cache.insert(name, response).unwrap();
+        }
+        // "a" was evicted from memory but is still on disk
+        assert_eq!(cache.len(), 2);
+        let entry = cache.get("a").unwrap();
+        assert_eq!(entry.response().content(), b"a");
+
+        // a conditional request with the cached validator is satisfied by the same body
+        let mut request = GridBlock::new(GridRequestCode::GET, Some("/a"), &mut Vec::new()).unwrap();
+        request.set_condition(entry.validator()).unwrap();
+        assert!(request.is_not_modified(&entry.response()));
+        let changed = GridBlock::new(GridResponseCode::ROK, None, &mut b"a2".to_vec()).unwrap();
+        assert!(!request.is_not_modified(&changed));
+        assert!(entry.validator().starts_with("sha256:"));
+
+        // a version header is the validator, and is compared without hashing the content
+        let mut versioned = Metadata::new();
+        versioned.set_version("v7").unwrap();
+        let response = GridBlock::with_metadata(GridResponseCode::ROK, None, &versioned, b"a").unwrap();
+        assert_eq!(response.validator(), "v7");
+        let mut request = GridBlock::new(GridRequestCode::GET, Some("/a"), &mut Vec::new()).unwrap();
+        request.set_condition("v7").unwrap();
+        assert_eq!(request.condition().as_deref(), Some("v7"));
+        let restyled = GridBlock::with_metadata(GridResponseCode::ROK, None, &versioned, b"a, restyled").unwrap();
+        assert!(request.is_not_modified(&restyled));
+        versioned.set_version("v8").unwrap();
+        let bumped = GridBlock::with_metadata(GridResponseCode::ROK, None, &versioned, b"a").unwrap();
+        assert!(!request.is_not_modified(&bumped));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn persisted_identity_is_reused() {
+        use identity::{load_or_create_identity, fingerprint};
+        let dir = std::env::temp_dir().join(format!("grid-identity-test-{}", std::process::id()));
+        let names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+
+        let first = load_or_create_identity(&dir, names.clone()).unwrap();
+        let second = load_or_create_identity(&dir, names.clone()).unwrap();
+        assert_eq!(fingerprint(&first).unwrap(), fingerprint(&second).unwrap());
+
+        // asking for a name the stored certificate doesn't cover makes a new one
+        let mut more = names.clone();
+        more.push("10.0.0.1".to_string());
+        let third = load_or_create_identity(&dir, more).unwrap();
+        assert_ne!(fingerprint(&first).unwrap(), fingerprint(&third).unwrap());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn file_storage_quotas_and_overwrites() {
+        use storage::{FileStorage, Storage, StorageError, UploadLimits};
+        let dir = std::env::temp_dir().join(format!("grid-storage-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStorage::open(&dir).unwrap();
+        let limits = UploadLimits { max_size: Some(8), host_quota: Some(12), user_quota: Some(7), overwrite: true };
+
+        store.put("/docs/a.gml", b"hello", "alice", &limits).unwrap();
+        assert_eq!(store.get("/docs/../docs/a.gml").unwrap(), b"hello");
+        assert_eq!(store.put("/b", b"123456789", "bob", &limits), Err(StorageError::TooLarge { size: 9, limit: 8 }));
+        assert_eq!(store.put("/b", b"xxx", "alice", &limits), Err(StorageError::QuotaExceeded { quota: "user", limit: 7 }));
+        store.put("/b", b"1234567", "bob", &limits).unwrap();
+        assert_eq!(store.put("/c", b"x", "carol", &limits), Err(StorageError::QuotaExceeded { quota: "host", limit: 12 }));
+
+        // only the uploader may replace a document, and replacing frees the old size
+        assert_eq!(store.put("/docs/a.gml", b"hi", "bob", &limits), Err(StorageError::NotOwner));
+        store.put("/docs/a.gml", b"hi", "alice", &limits).unwrap();
+        assert_eq!(store.usage(Some("alice")), 2);
+        let no_overwrite = UploadLimits { overwrite: false, ..limits.clone() };
+        assert_eq!(store.put("/docs/a.gml", b"yo", "alice", &no_overwrite), Err(StorageError::Exists));
+
+        // hidden files are off limits, and ownership survives a restart
+        assert!(matches!(store.put("/.grid-owners", b"", "alice", &limits), Err(StorageError::InvalidPath(_))));
+        #[cfg(unix)]
+        {
+            // links inside the root can't lead uploads anywhere else, not even to make directories
+            let outside = std::env::temp_dir().join(format!("grid-storage-outside-{}", std::process::id()));
+            std::fs::create_dir_all(&outside).unwrap();
+            std::os::unix::fs::symlink(&outside, dir.join("escape")).unwrap();
+            assert!(matches!(store.put("/escape/deep/x", b"x", "alice", &limits), Err(StorageError::InvalidPath(_))));
+            assert!(!outside.join("deep").exists());
+            std::os::unix::fs::symlink(dir.join("docs"), dir.join("alias")).unwrap();
+            store.put("/alias/new/y", b"y", "alice", &limits).unwrap();
+            assert_eq!(store.get("/docs/new/y").unwrap(), b"y");
+            store.put("/alias/new/y", b"", "alice", &limits).unwrap();
+            let _ = std::fs::remove_dir_all(&outside);
+        }
+        let reopened = FileStorage::open(&dir).unwrap();
+        assert_eq!(reopened.usage(Some("bob")), 7);
+
+        // the root is only added up once something needs the total, then kept current
+        std::fs::write(dir.join("served.gml"), b"abc").unwrap();
+        assert_eq!(reopened.usage(None), 12);
+        assert_eq!(reopened.put("/c", b"x", "carol", &limits), Err(StorageError::QuotaExceeded { quota: "host", limit: 12 }));
+        reopened.put("/c", b"x", "carol", &UploadLimits { host_quota: None, ..limits.clone() }).unwrap();
+        assert_eq!(reopened.usage(None), 13);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn property_encoding_is_validated() {
+        use properties::{AccessPolicy, Properties};
+
+        let changes = Properties::parse(b"title: Field notes\ntags: rust, grid\ncontent-type: text/gml; charset=utf-8\n").unwrap();
+        assert_eq!(changes.tags(), vec!["rust", "grid"]);
+        assert_eq!(Properties::parse(&changes.encode()).unwrap(), changes);
+
+        // unknown keys, duplicates, bad values and missing separators are all refused
+        for bad in ["colour: red", "title: a\ntitle: b", "tags: two words", "content-type: gml", "access: friends", "title"] {
+            assert!(Properties::parse(bad.as_bytes()).is_err(), "accepted {:?}", bad);
+        }
+
+        // empty values remove properties when merged
+        let mut stored = changes.clone();
+        stored.merge(&Properties::parse(b"tags:\naccess: owner").unwrap());
+        assert_eq!(stored.get("tags"), None);
+        assert_eq!(stored.access(), Ok(AccessPolicy::Owner));
+        assert_eq!(changes.access(), Ok(AccessPolicy::Public));
+    }
+
+    #[test]
+    fn metadata_round_trips_and_rejects_garbage() {
+        use definitions::{GridBlock, GridResponseCode};
+        use metadata::{Metadata, MetadataKey};
+
+        let mut headers = Metadata::new();
+        headers.set_content_type("text/gml").unwrap();
+        headers.set_content_length(5);
+        headers.set_retry_after(std::time::Duration::from_millis(250));
+        headers.set_text(MetadataKey::from_name("x-mirror").unwrap(), "eu").unwrap();
+        assert!(headers.set_text(MetadataKey::ContentLength, "five").is_err());
+        assert!(MetadataKey::from_name("Not A Key").is_err());
+        assert_eq!(MetadataKey::from_name("title").unwrap(), MetadataKey::Title);
+
+        let mut encoded = headers.encode();
+        let size = encoded.len();
+        encoded.extend_from_slice(b"hello");
+        let (decoded, consumed) = Metadata::decode(&encoded).unwrap();
+        assert_eq!((decoded.clone(), consumed), (headers.clone(), size));
+        assert_eq!(decoded.retry_after(), Some(std::time::Duration::from_millis(250)));
+
+        // blocks carry the headers ahead of their content
+        let mut block = GridBlock::with_metadata(GridResponseCode::ROK, None, &headers, b"hello").unwrap();
+        let block = GridBlock::from_bytes(block.serialize()).unwrap();
+        assert_eq!(block.headers().unwrap(), Some(headers));
+        assert_eq!(block.content(), b"hello");
+
+        // unknown ids survive, truncation and repeated keys do not
+        assert_eq!(Metadata::decode(&[0, 1, 200, 0, 1, 7]).unwrap().0.get(&MetadataKey::Unregistered(200)), Some(&[7u8][..]));
+        assert!(Metadata::decode(&encoded[..size - 1]).is_err());
+        assert!(Metadata::decode(&[0, 2, 7, 0, 0, 7, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn tokens_and_rules_decide_access() {
+        use auth::{AccessControl, AclRule, Permission, TokenVerifier, issue_token};
+        use std::time::Duration;
+
+        // signed tokens name their principal until they expire
+        let verifier = TokenVerifier::Hmac(b"key".to_vec());
+        let token = issue_token(b"key", "ops.team", Duration::from_secs(60));
+        assert_eq!(verifier.verify(&token), Ok("ops.team".to_string()));
+        assert!(verifier.verify(&issue_token(b"key", "ops.team", Duration::ZERO)).is_err());
+        assert!(verifier.verify(&token.replace("ops.team", "ops.teams")).is_err());
+        assert!(verifier.verify("garbage").is_err());
+        assert!(verifier.verify("ops.team.99999999999.+1é").is_err());
+
+        // token files can't hand out the names access rules reserve
+        let file = std::env::temp_dir().join(format!("grid-tokens-test-{}", std::process::id()));
+        std::fs::write(&file, "# ops\nops.team s3cret\n").unwrap();
+        assert_eq!(TokenVerifier::from_file(&file).unwrap().verify("s3cret"), Ok("ops.team".to_string()));
+        for reserved in ["*", "authenticated"] {
+            std::fs::write(&file, format!("{} s3cret\n", reserved)).unwrap();
+            assert!(TokenVerifier::from_file(&file).is_err(), "accepted {}", reserved);
+        }
+        let _ = std::fs::remove_file(&file);
+
+        // the longest covering path wins, and uncovered paths are open
+        let control = AccessControl::new(None, vec![
+            AclRule::new("/", "*", true, false),
+            AclRule::new("/drafts", "ops.team", true, true),
+            AclRule::new("/drafts/public", "*", true, false)
+        ]);
+        assert!(control.allows(None, "/index", Permission::Read));
+        assert!(!control.allows(None, "/index", Permission::Write));
+        assert!(!control.allows(None, "/drafts/plan", Permission::Read));
+        assert!(control.allows(Some("ops.team"), "/drafts/../drafts/plan", Permission::Write));
+        assert!(!control.allows(Some("ops.team"), "/drafts/public/faq", Permission::Write));
+        assert!(!control.allows(Some("ops.team"), "/draftsman", Permission::Write));
+    }
+
+    #[test]
+    fn gml_round_trips_and_paths_split() {
+        use path::{percent_encode_path, percent_decode, split_query};
+        use gml::{GmlDocument, GmlLine};
+
+        let text = "# Title\n=> /docs/a%20b.gml A and B\n* item\n> quoted\n```rust\n# not a heading\n```\nplain\n";
+        let document = GmlDocument::parse(text);
+        assert_eq!(document.title(), Some("Title"));
+        assert_eq!(document.lines[1], GmlLine::Link { target: "/docs/a%20b.gml".to_string(), label: "A and B".to_string() });
+        assert_eq!(document.lines[4], GmlLine::Preformatted { alt: "rust".to_string(), lines: vec!["# not a heading".to_string()] });
+        assert_eq!(document.to_string(), text);
+
+        // text that looks like markup survives a round trip as text
+        let mut document = GmlDocument::new();
+        document.text("* not a list");
+        assert!(matches!(&GmlDocument::parse(&document.to_string()).lines[0], GmlLine::Text(_)));
+
+        assert_eq!(split_query("/docs/../a%20b?sort=size"), ("/a b".to_string(), Some("sort=size")));
+        assert_eq!(split_query("/%2e%2e/secret").0, "/secret");
+        assert_eq!(percent_encode_path("/a b/ü"), "/a%20b/%C3%BC");
+        // escapes must be two hex digits, not anything from_str_radix takes
+        assert_eq!(percent_decode("%+1%-1%2B"), "%+1%-1+");
+    }
+}
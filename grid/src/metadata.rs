@@ -0,0 +1,402 @@
+// Defines the structured key/value headers that can lead the metadata segment
+use std::time::Duration;
+
+use crate::definitions::{DigestAlgorithm, IntegrityError};
+
+
+/// Key id marking an entry whose key is spelled out by name
+const CUSTOM_KEY_ID: u8 = 0;
+
+/// Longest value a single entry can hold, in bytes
+pub const MAX_METADATA_VALUE: usize = u16::MAX as usize;
+
+
+/// How the value of a metadata entry is encoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+    /// UTF-8 text
+    Text,
+    /// Unsigned 64 bit integer, big endian
+    Integer,
+    /// Raw bytes
+    Bytes
+}
+
+
+/// Names a metadata entry
+///
+/// Well-known keys are sent as a single id byte; anything else is sent by name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataKey {
+    /// MIME type of the content, e.g. `text/gml`
+    ContentType,
+    /// Size of the content in bytes
+    ContentLength,
+    /// Digest of the content: the digest algorithm byte followed by the digest,
+    /// set by `GridBlock::add_digest` and checked by `GridBlock::verify_digest`
+    Digest,
+    /// Opaque tag identifying the version of a resource; in a request, the
+    /// validator of the cached copy being revalidated
+    Version,
+    /// Bearer token authenticating the client
+    AuthToken,
+    /// Milliseconds a client should wait before retrying a `BSY` request
+    RetryAfter,
+    /// Human readable title of a resource
+    Title,
+    /// Comma separated tags of a resource
+    Tags,
+    /// Who may read a resource: `public`, `authenticated` or `owner`
+    Access,
+    /// A well-known id this version does not know yet, kept as is
+    Unregistered(u8),
+    /// A key outside the registry, made of lowercase letters, digits and dashes
+    Custom(String)
+}
+
+/// Every registered key, in id order
+pub const METADATA_REGISTRY: [MetadataKey; 9] = [
+    MetadataKey::ContentType,
+    MetadataKey::ContentLength,
+    MetadataKey::Digest,
+    MetadataKey::Version,
+    MetadataKey::AuthToken,
+    MetadataKey::RetryAfter,
+    MetadataKey::Title,
+    MetadataKey::Tags,
+    MetadataKey::Access
+];
+
+impl MetadataKey {
+    /// Returns the id the key is sent as, `0` for custom keys
+    pub fn id(&self) -> u8 {
+        match self {
+            MetadataKey::ContentType => 1,
+            MetadataKey::ContentLength => 2,
+            MetadataKey::Digest => 3,
+            MetadataKey::Version => 4,
+            MetadataKey::AuthToken => 5,
+            MetadataKey::RetryAfter => 6,
+            MetadataKey::Title => 7,
+            MetadataKey::Tags => 8,
+            MetadataKey::Access => 9,
+            MetadataKey::Unregistered(id) => *id,
+            MetadataKey::Custom(_) => CUSTOM_KEY_ID
+        }
+    }
+
+    /// Returns the name of the key, e.g. `content-type`
+    pub fn name(&self) -> String {
+        match self {
+            MetadataKey::ContentType => "content-type".to_string(),
+            MetadataKey::ContentLength => "content-length".to_string(),
+            MetadataKey::Digest => "digest".to_string(),
+            MetadataKey::Version => "version".to_string(),
+            MetadataKey::AuthToken => "auth-token".to_string(),
+            MetadataKey::RetryAfter => "retry-after".to_string(),
+            MetadataKey::Title => "title".to_string(),
+            MetadataKey::Tags => "tags".to_string(),
+            MetadataKey::Access => "access".to_string(),
+            MetadataKey::Unregistered(id) => format!("key-{}", id),
+            MetadataKey::Custom(name) => name.clone()
+        }
+    }
+
+    /// Returns how values of this key are encoded
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            MetadataKey::ContentLength | MetadataKey::RetryAfter => ValueKind::Integer,
+            MetadataKey::Digest | MetadataKey::Unregistered(_) | MetadataKey::Custom(_) => ValueKind::Bytes,
+            _ => ValueKind::Text
+        }
+    }
+
+    /// Looks a key up by name, falling back to a custom key
+    ///
+    /// ## Params:
+    /// * name: the name of the key
+    ///
+    /// ## Returns:
+    /// * Ok: the registered key of that name, or a custom one
+    /// * Err: a string describing why the name can't be a key
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        if let Some(key) = METADATA_REGISTRY.iter().find(|k| k.name() == name) {
+            return Ok(key.clone());
+        }
+        let valid = !name.is_empty()
+            && name.len() <= u8::MAX as usize
+            && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        match valid {
+            true => Ok(MetadataKey::Custom(name.to_string())),
+            false => Err(format!("'{}' is not a metadata key, expected lowercase letters, digits and dashes", name))
+        }
+    }
+
+    /// Helper function mapping an id back to its key
+    fn from_id(id: u8) -> Self {
+        METADATA_REGISTRY.iter().find(|k| k.id() == id).cloned().unwrap_or(MetadataKey::Unregistered(id))
+    }
+}
+
+
+/// structure holding the structured headers of a metadata segment
+///
+/// Encoded as an entry count (2 bytes, big endian) followed by the entries. Each
+/// entry is a key id byte, the key name (length byte and bytes) if the id is `0`,
+/// the value length (2 bytes, big endian) and the value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    entries: Vec<(MetadataKey, Vec<u8>)>
+}
+
+impl Metadata {
+    /// Creates an empty `Metadata` instance
+    pub fn new() -> Self {
+        Metadata::default()
+    }
+
+    /// Sets an entry, replacing any entry with the same key
+    ///
+    /// ## Params:
+    /// * key: the key of the entry
+    /// * value: the encoded value, which must suit the kind of the key
+    ///
+    /// ## Returns:
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered
+    pub fn insert(&mut self, key: MetadataKey, value: Vec<u8>) -> Result<(), String> {
+        check_value(&key, &value)?;
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key, value))
+        }
+        Ok(())
+    }
+
+    /// Removes an entry, returning its value
+    pub fn remove(&mut self, key: &MetadataKey) -> Option<Vec<u8>> {
+        let index = self.entries.iter().position(|(k, _)| k == key)?;
+        Some(self.entries.remove(index).1)
+    }
+
+    /// Returns the raw value of an entry
+    pub fn get(&self, key: &MetadataKey) -> Option<&[u8]> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_slice())
+    }
+
+    /// Returns the value of a text entry
+    pub fn get_text(&self, key: &MetadataKey) -> Option<&str> {
+        std::str::from_utf8(self.get(key)?).ok()
+    }
+
+    /// Returns the value of an integer entry
+    pub fn get_integer(&self, key: &MetadataKey) -> Option<u64> {
+        let bytes: [u8; 8] = self.get(key)?.try_into().ok()?;
+        Some(u64::from_be_bytes(bytes))
+    }
+
+    /// Sets a text entry
+    pub fn set_text(&mut self, key: MetadataKey, value: &str) -> Result<(), String> {
+        self.insert(key, value.as_bytes().to_vec())
+    }
+
+    /// Sets an integer entry
+    pub fn set_integer(&mut self, key: MetadataKey, value: u64) -> Result<(), String> {
+        self.insert(key, value.to_be_bytes().to_vec())
+    }
+
+    /// Iterates over the entries in the order they were set
+    pub fn iter(&self) -> impl Iterator<Item = (&MetadataKey, &[u8])> {
+        self.entries.iter().map(|(k, v)| (k, v.as_slice()))
+    }
+
+    /// Returns the number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether there are no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the MIME type of the content
+    pub fn content_type(&self) -> Option<&str> {
+        self.get_text(&MetadataKey::ContentType)
+    }
+
+    /// Sets the MIME type of the content
+    pub fn set_content_type(&mut self, content_type: &str) -> Result<(), String> {
+        self.set_text(MetadataKey::ContentType, content_type)
+    }
+
+    /// Returns the size of the content in bytes
+    pub fn content_length(&self) -> Option<u64> {
+        self.get_integer(&MetadataKey::ContentLength)
+    }
+
+    /// Sets the size of the content in bytes
+    pub fn set_content_length(&mut self, length: u64) {
+        // integers always fit
+        let _ = self.set_integer(MetadataKey::ContentLength, length);
+    }
+
+    /// Returns the digest of the content
+    /// 
+    /// ## Params:
+    /// None
+    /// 
+    /// ## Returns:
+    /// * Ok: the algorithm and raw digest bytes, or `None` if no digest is set
+    /// * Err: an `IntegrityError` if the digest header can't be read
+    pub fn digest(&self) -> Result<Option<(DigestAlgorithm, &[u8])>, IntegrityError> {
+        let value = match self.get(&MetadataKey::Digest) {
+            Some(a) => a,
+            None => return Ok(None)
+        };
+        let algorithm = match value.first() {
+            Some(&byte) => match DigestAlgorithm::from_byte(byte) {
+                Ok(Some(a)) => a,
+                _ => return Err(IntegrityError::UnknownAlgorithm(byte))
+            },
+            None => return Err(IntegrityError::Truncated)
+        };
+        if value.len() - 1 < algorithm.digest_size() {
+            return Err(IntegrityError::Truncated);
+        }
+        Ok(Some((algorithm, &value[1..=algorithm.digest_size()])))
+    }
+
+    /// Sets the digest of the content
+    pub fn set_digest(&mut self, algorithm: DigestAlgorithm, digest: &[u8]) -> Result<(), String> {
+        let mut value = vec![algorithm as u8];
+        value.extend_from_slice(digest);
+        self.insert(MetadataKey::Digest, value)
+    }
+
+    /// Returns the version tag of the resource, or the validator of a conditional request
+    pub fn version(&self) -> Option<&str> {
+        self.get_text(&MetadataKey::Version)
+    }
+
+    /// Sets the version tag of the resource
+    pub fn set_version(&mut self, version: &str) -> Result<(), String> {
+        self.set_text(MetadataKey::Version, version)
+    }
+
+    /// Returns the bearer token authenticating the client
+    pub fn auth_token(&self) -> Option<&str> {
+        self.get_text(&MetadataKey::AuthToken)
+    }
+
+    /// Sets the bearer token authenticating the client
+    pub fn set_auth_token(&mut self, token: &str) -> Result<(), String> {
+        self.set_text(MetadataKey::AuthToken, token)
+    }
+
+    /// Returns how long the client should wait before retrying
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.get_integer(&MetadataKey::RetryAfter).map(Duration::from_millis)
+    }
+
+    /// Sets how long the client should wait before retrying, in whole milliseconds
+    pub fn set_retry_after(&mut self, wait: Duration) {
+        let millis = u64::try_from(wait.as_millis()).unwrap_or(u64::MAX);
+        let _ = self.set_integer(MetadataKey::RetryAfter, millis);
+    }
+
+    /// Encodes the entries
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = (self.entries.len() as u16).to_be_bytes().to_vec();
+        for (key, value) in &self.entries {
+            bytes.push(key.id());
+            if let MetadataKey::Custom(name) = key {
+                bytes.push(name.len() as u8);
+                bytes.extend_from_slice(name.as_bytes());
+            }
+            bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    /// Decodes entries from the start of a byte slice
+    ///
+    /// ## Params:
+    /// * bytes: the encoded entries, possibly followed by other data
+    ///
+    /// ## Returns:
+    /// * Ok: the entries and the number of bytes they took up
+    /// * Err: a string describing why the bytes are not valid metadata
+    pub fn decode(bytes: &[u8]) -> Result<(Self, usize), String> {
+        let mut reader = Reader { bytes, offset: 0 };
+        let count = u16::from_be_bytes(reader.take_array()?);
+
+        let mut metadata = Metadata::new();
+        for _ in 0..count {
+            let key = match reader.take(1)?[0] {
+                CUSTOM_KEY_ID => {
+                    let length = reader.take(1)?[0] as usize;
+                    let name = match std::str::from_utf8(reader.take(length)?) {
+                        Ok(a) => a,
+                        Err(_) => return Err("Metadata key is not valid UTF-8".to_string())
+                    };
+                    match MetadataKey::from_name(name)? {
+                        MetadataKey::Custom(name) => MetadataKey::Custom(name),
+                        _ => return Err(format!("Registered metadata key '{}' sent by name", name))
+                    }
+                },
+                id => MetadataKey::from_id(id)
+            };
+            let length = u16::from_be_bytes(reader.take_array()?) as usize;
+            let value = reader.take(length)?.to_vec();
+
+            if metadata.get(&key).is_some() {
+                return Err(format!("Metadata key '{}' is given twice", key.name()));
+            }
+            check_value(&key, &value)?;
+            metadata.entries.push((key, value));
+        }
+        Ok((metadata, reader.offset))
+    }
+}
+
+
+/// structure walking through encoded metadata
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Reader<'a> {
+    /// Helper function taking the next `length` bytes
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        match self.bytes.get(self.offset..self.offset + length) {
+            Some(a) => {
+                self.offset += length;
+                Ok(a)
+            },
+            None => Err("Metadata is truncated".to_string())
+        }
+    }
+
+    /// Helper function taking the next bytes as a fixed size array
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let bytes = self.take(N)?;
+        let mut array = [0u8; N];
+        array.copy_from_slice(bytes);
+        Ok(array)
+    }
+}
+
+/// Helper function checking that a value suits the kind of its key
+fn check_value(key: &MetadataKey, value: &[u8]) -> Result<(), String> {
+    if value.len() > MAX_METADATA_VALUE {
+        return Err(format!("Value of metadata key '{}' is longer than {} bytes", key.name(), MAX_METADATA_VALUE));
+    }
+    match key.kind() {
+        ValueKind::Text if std::str::from_utf8(value).is_err() => Err(format!("Value of metadata key '{}' is not valid UTF-8", key.name())),
+        ValueKind::Integer if value.len() != 8 => Err(format!("Value of metadata key '{}' is not an 8 byte integer", key.name())),
+        _ => Ok(())
+    }
+}
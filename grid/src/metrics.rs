@@ -0,0 +1,165 @@
+// Defines the text metrics endpoint monitoring systems scrape, next to INF
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use crate::stats::ServerStats;
+
+
+/// Upper bounds of the request latency buckets, in microseconds
+pub(crate) const LATENCY_BUCKETS: [u64; 10] = [100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000];
+
+/// Upper bounds of the frame size buckets, in bytes
+pub(crate) const SIZE_BUCKETS: [u64; 9] = [64, 256, 1024, 4096, 16_384, 65_536, 262_144, 1_048_576, 16_777_216];
+
+/// MIME type of the text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Largest HTTP request head the endpoint reads before giving up
+const MAX_HTTP_REQUEST: usize = 8 * 1024;
+
+/// How long a scraper may take to send its request
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// structure counting observations into fixed buckets, as Prometheus histograms do
+pub(crate) struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<AtomicU64>,    // one per bound plus one for everything larger, not cumulative
+    sum: AtomicU64
+}
+
+impl Histogram {
+    /// Creates a new `Histogram` instance
+    ///
+    /// ## Params:
+    /// * bounds: the ascending upper bounds of the buckets
+    ///
+    /// ## Returns:
+    /// * an instance of the structure
+    pub fn new(bounds: &'static [u64]) -> Self {
+        Histogram { bounds, buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(), sum: AtomicU64::new(0) }
+    }
+
+    /// Counts a value into the first bucket it fits
+    pub fn observe(&self, value: u64) {
+        let i = self.bounds.iter().position(|b| value <= *b).unwrap_or(self.bounds.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Writes the buckets, sum and count in the text exposition format
+    ///
+    /// ## Params:
+    /// * out: where to write
+    /// * name: the name of the metric
+    /// * labels: labels every line carries, e.g. `opcode="GET"`, or empty
+    /// * scale: what observations are divided by for display, e.g. 1e6 for microseconds as seconds
+    ///
+    /// ## Returns:
+    /// None
+    pub fn write(&self, out: &mut String, name: &str, labels: &str, scale: f64) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let bound = match self.bounds.get(i) {
+                Some(b) => format!("{}", *b as f64 / scale),
+                None => "+Inf".to_string()
+            };
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, cumulative);
+        }
+        let braces = |labels: &str| if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, braces(labels), self.sum.load(Ordering::Relaxed) as f64 / scale);
+        let _ = writeln!(out, "{}_count{} {}", name, braces(labels), cumulative);
+    }
+}
+
+
+/// Helper function writing the HELP and TYPE lines introducing a metric
+pub(crate) fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+
+/// Starts a thread answering HTTP `GET /metrics` on a listener
+///
+/// Scrapes are answered one at a time, which is all a monitoring system needs.
+/// The thread serves until the process exits.
+///
+/// ## Params:
+/// * listener: the bound listener
+/// * stats: the statistics to export
+///
+/// ## Returns:
+/// * Ok: nothing, the thread is running
+/// * Err: a string describing the issue encountered
+pub(crate) fn serve(listener: TcpListener, stats: Arc<ServerStats>) -> Result<(), String> {
+    let spawned = thread::Builder::new().name("grid-metrics".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = scrape(stream, &stats) {
+                        debug!(error = %e, "metrics request failed");
+                    }
+                },
+                Err(e) => warn!(error = %e, "failed to accept metrics connection")
+            }
+        }
+    });
+    match spawned {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to start metrics thread: {}", e))
+    }
+}
+
+/// Helper function answering a single HTTP request and hanging up
+fn scrape(mut stream: TcpStream, stats: &ServerStats) -> Result<(), String> {
+    if let Err(e) = stream.set_read_timeout(Some(HTTP_READ_TIMEOUT)) {
+        return Err(format!("Cannot set read timeout: {}", e));
+    }
+
+    // the request head is all we care about, bodies are never expected
+    let mut head = Vec::new();
+    let mut buff = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HTTP_REQUEST {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "Request too large\n");
+        }
+        match stream.read(&mut buff) {
+            Ok(0) => return Err("Client hung up before finishing its request".to_string()),
+            Ok(a) => head.extend_from_slice(&buff[..a]),
+            Err(e) => return Err(format!("Failed to read request: {}", e))
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+    let (method, target) = (request_line.next().unwrap_or(""), request_line.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+    match (method, path) {
+        ("GET", "/metrics") => respond(&mut stream, "200 OK", &stats.render_metrics()),
+        (_, "/metrics") => respond(&mut stream, "405 Method Not Allowed", "Only GET is supported\n"),
+        _ => respond(&mut stream, "404 Not Found", "Metrics are served at /metrics\n")
+    }
+}
+
+/// Helper function writing an HTTP response that closes the connection
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), String> {
+    let content_type = if status.starts_with("200") { METRICS_CONTENT_TYPE } else { "text/plain; charset=utf-8" };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    );
+    match stream.write_all(response.as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to write response: {}", e))
+    }
+}
@@ -0,0 +1,89 @@
+// Defines helpers for normalizing, decoding and escaping request paths
+
+
+/// Resolves empty, `.` and `..` segments of a path
+///
+/// ## Params:
+/// * path: the path to normalize
+///
+/// ## Returns:
+/// An absolute path that never climbs above `/`
+pub fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => { segments.pop(); },
+            a => segments.push(a)
+        }
+    }
+
+    format!("/{}", segments.join("/"))
+}
+
+/// Splits a request path into the resource path and the query after `?`
+///
+/// The resource path is percent-decoded and normalized, so that every check
+/// made on it sees the same path the document is served from.
+///
+/// ## Params:
+/// * path: the path from the request, e.g. `/docs/?sort=size`
+///
+/// ## Returns:
+/// The normalized path and the raw query, if there is one
+pub fn split_query(path: &str) -> (String, Option<&str>) {
+    let (path, query) = match path.split_once('?') {
+        Some((p, q)) => (p, Some(q)),
+        None => (path, None)
+    };
+    (normalize_path(&percent_decode(path)), query)
+}
+
+/// Decodes `%XX` escapes, leaving malformed ones as they are
+///
+/// ## Params:
+/// * text: the text to decode
+///
+/// ## Returns:
+/// The decoded text, with invalid UTF-8 replaced
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match (bytes[i], bytes.get(i + 1..i + 3)) {
+            // from_str_radix would take a sign, so check the digits first
+            (b'%', Some(hex)) if hex.iter().all(u8::is_ascii_hexdigit) => {
+                std::str::from_utf8(hex).ok().and_then(|h| u8::from_str_radix(h, 16).ok())
+            },
+            _ => None
+        };
+        match escaped {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Escapes everything but letters, digits, `/` and `-._~` as `%XX`
+///
+/// ## Params:
+/// * path: the path to encode
+///
+/// ## Returns:
+/// The path, safe to use as a link target
+pub fn percent_encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            true => (b as char).to_string(),
+            false => format!("%{:02X}", b)
+        })
+        .collect()
+}
@@ -0,0 +1,230 @@
+// Defines the properties SET stores next to documents
+use std::collections::BTreeMap;
+
+use crate::metadata::{Metadata, MetadataKey};
+
+
+/// Keys a document can have properties for
+pub const PROPERTY_KEYS: [&str; 4] = ["title", "tags", "content-type", "access"];
+
+/// Longest value a property may have, in bytes
+pub const MAX_PROPERTY_SIZE: usize = 1024;
+
+
+/// Who may read a document, as set by its `access` property
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessPolicy {
+    /// Everyone
+    Public,
+    /// Clients that presented a certificate or a valid bearer token
+    Authenticated,
+    /// Only the user who uploaded it
+    Owner
+}
+
+impl AccessPolicy {
+    /// Parses the value of an `access` property
+    ///
+    /// ## Params:
+    /// * value: `public`, `authenticated` or `owner`
+    ///
+    /// ## Returns:
+    /// * Ok: the policy
+    /// * Err: a string describing the issue encountered
+    pub fn from_name(value: &str) -> Result<Self, String> {
+        match value {
+            "public" => Ok(AccessPolicy::Public),
+            "authenticated" => Ok(AccessPolicy::Authenticated),
+            "owner" => Ok(AccessPolicy::Owner),
+            _ => Err(format!("Unknown access policy '{}', expected public, authenticated or owner", value))
+        }
+    }
+}
+
+
+/// structure holding the properties of a document
+///
+/// On the wire properties are structured `Metadata` headers, on disk they are
+/// UTF-8 lines of `key: value`. In a SET request an empty value removes the property.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Properties {
+    values: BTreeMap<String, String>
+}
+
+impl Properties {
+    /// Parses and validates `key: value` lines
+    ///
+    /// ## Params:
+    /// * bytes: the encoded properties, e.g. the body of a SET request
+    ///
+    /// ## Returns:
+    /// * Ok: the properties, empty values included
+    /// * Err: a string naming the offending line
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let text = match std::str::from_utf8(bytes) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Properties are not valid UTF-8: {}", e))
+        };
+
+        let mut properties = Properties::default();
+        for (i, line) in text.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = match line.split_once(':') {
+                Some(a) => a,
+                None => return Err(format!("Line {}: expected 'key: value'", i + 1))
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if properties.values.contains_key(key) {
+                return Err(format!("Line {}: '{}' is given twice", i + 1, key));
+            }
+            if let Err(e) = validate(key, value) {
+                return Err(format!("Line {}: {}", i + 1, e));
+            }
+            properties.values.insert(key.to_string(), value.to_string());
+        }
+        Ok(properties)
+    }
+
+    /// Encodes the properties as `key: value` lines
+    pub fn encode(&self) -> Vec<u8> {
+        self.values.iter().map(|(k, v)| format!("{}: {}\n", k, v)).collect::<String>().into_bytes()
+    }
+
+    /// Reads and validates properties from structured headers
+    ///
+    /// The `auth-token` header is not a property and is skipped.
+    ///
+    /// ## Params:
+    /// * headers: the headers of a SET request
+    ///
+    /// ## Returns:
+    /// * Ok: the properties, empty values included
+    /// * Err: a string naming the offending header
+    pub fn from_metadata(headers: &Metadata) -> Result<Self, String> {
+        let mut properties = Properties::default();
+        for (key, value) in headers.iter().filter(|(key, _)| **key != MetadataKey::AuthToken) {
+            let name = key.name();
+            let value = match std::str::from_utf8(value) {
+                Ok(a) => a,
+                Err(_) => return Err(format!("Value of '{}' is not valid UTF-8", name))
+            };
+            validate(&name, value)?;
+            properties.values.insert(name, value.to_string());
+        }
+        Ok(properties)
+    }
+
+    /// Adds the properties to structured headers
+    ///
+    /// ## Params:
+    /// * headers: the headers to add to, e.g. those of a GET response
+    ///
+    /// ## Returns:
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered
+    pub fn write_metadata(&self, headers: &mut Metadata) -> Result<(), String> {
+        for (key, value) in &self.values {
+            headers.set_text(MetadataKey::from_name(key)?, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the value of a property, if set
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.as_str())
+    }
+
+    /// Sets a property after validating it. An empty value removes it
+    ///
+    /// ## Params:
+    /// * key: one of `PROPERTY_KEYS`
+    /// * value: the new value
+    ///
+    /// ## Returns:
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        validate(key, value)?;
+        match value.is_empty() {
+            true => self.values.remove(key),
+            false => self.values.insert(key.to_string(), value.to_string())
+        };
+        Ok(())
+    }
+
+    /// Applies the changes of a SET request, removing properties given an empty value
+    pub fn merge(&mut self, changes: &Properties) {
+        for (key, value) in &changes.values {
+            match value.is_empty() {
+                true => self.values.remove(key),
+                false => self.values.insert(key.clone(), value.clone())
+            };
+        }
+    }
+
+    /// Returns whether no property is set
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Iterates over the properties as `(key, value)` pairs, ordered by key
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Returns the tags of the document
+    pub fn tags(&self) -> Vec<&str> {
+        match self.get("tags") {
+            Some(tags) => tags.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Returns who may read the document
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: the policy, `Public` if the document has no `access` property
+    /// * Err: a string describing why the `access` property is not a policy
+    pub fn access(&self) -> Result<AccessPolicy, String> {
+        match self.get("access") {
+            Some(a) => AccessPolicy::from_name(a),
+            None => Ok(AccessPolicy::Public)
+        }
+    }
+}
+
+
+/// Helper function checking a single property
+fn validate(key: &str, value: &str) -> Result<(), String> {
+    if !PROPERTY_KEYS.contains(&key) {
+        return Err(format!("Unknown property '{}', expected one of {}", key, PROPERTY_KEYS.join(", ")));
+    }
+    if value.len() > MAX_PROPERTY_SIZE {
+        return Err(format!("Value of '{}' is longer than {} bytes", key, MAX_PROPERTY_SIZE));
+    }
+    if value.chars().any(|c| c.is_control()) {
+        return Err(format!("Value of '{}' contains control characters", key));
+    }
+    if value.is_empty() {
+        return Ok(());
+    }
+
+    let token = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c));
+    match key {
+        "tags" => match value.split(',').map(|t| t.trim()).all(|t| !t.is_empty() && t.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')) {
+            true => Ok(()),
+            false => Err("Tags must be a comma separated list of words".to_string())
+        },
+        "content-type" => match value.split(';').next().unwrap_or("").trim().split_once('/') {
+            Some((kind, subtype)) if token(kind) && token(subtype) => Ok(()),
+            _ => Err(format!("'{}' is not a MIME type like text/gml", value))
+        },
+        "access" => AccessPolicy::from_name(value).map(|_| ()),
+        _ => Ok(())
+    }
+}
@@ -1,25 +1,83 @@
-// Defines all client-related functions and structures
-use std::sync::Arc;
-use std::net::{SocketAddr, ToSocketAddrs};
+// Defines all server-related functions and structures
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{mpsc, Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::time::{Duration, Instant};
 
 use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
 
-use rustls::{
-    ServerConfig,
-    ServerConnection
+use rustls::{RootCertStore, ServerConfig};
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, UnparsedCertRevocationList};
+use rustls::sign::CertifiedKey;
+
+use rcgen::Certificate;
+
+use tracing::{debug, info, trace, warn};
+
+
+use crate::access_log::{AccessLog, AccessRecord};
+use crate::admission::{ConnectionCounter, RateLimit, RateLimiter, RateLimits};
+use crate::auth::AccessControl;
+use crate::connection::{Connection, Queued};
+use crate::definitions::{
+    GridBlock,
+    GridCode,
+    GridRequestCode,
+    GridResponseCode,
+    GRID_COMPRESSION_THRESHOLD,
+    to_hex
+};
+use crate::identity::{
+    IDENTITY_VALIDITY_DAYS,
+    certificate_params,
+    default_state_dir,
+    load_or_create_identity
 };
+use crate::metadata::Metadata;
+use crate::sni::{SniResolver, lookup_host};
+use crate::metrics;
+use crate::stats::{ServerStats, library_capabilities, request_opcode};
+use crate::transport::Transport;
+use crate::workers::WorkerPool;
+
+pub use crate::connection::RequestContext;
 
-use rcgen::generate_simple_self_signed;
 
+/// Default size (in bytes) of the largest request frame a server accepts
+pub const GRID_MAX_REQUEST_SIZE: usize = 64 * 1024 * 1024;
 
-use crate::definitions::string_to_domain;
+/// Default number of requests that may wait for a worker thread before connections are made to wait
+pub const GRID_WORKER_QUEUE_SIZE: usize = 1024;
 
+/// How often the event loop wakes up to look for idle connections
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
+/// How long the event loop waits before accepting again after running out of e.g. file descriptors
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Token of the waker shutdown handles use to interrupt the event loop
+const WAKER_TOKEN: Token = Token(usize::MAX);
+
+/// Retry hint given to clients turned away for having too many connections open
+const CONNECTION_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Retry hint given to requests beyond the in-flight limit of their connection
+const IN_FLIGHT_RETRY_AFTER: Duration = Duration::from_millis(100);
+
+
+/// Decides whether a client address may connect
+pub type PeerFilter = Arc<dyn Fn(IpAddr) -> bool + Send + Sync>;
 
 
 /// defines a structure for holding certificates and private keys
 #[derive(Clone)]
 pub struct CertificateStore {
+    certificates: Vec<Vec<u8>>,
     priv_key: Vec<u8>,
     ocsp: Vec<u8>,
     domains: Vec<String>,
@@ -27,23 +85,70 @@ pub struct CertificateStore {
 
 impl CertificateStore {
     /// Creates a new `CertificateStore` instance
-    /// 
+    ///
     /// ## Params:
-    /// * priv_key: private key as a vector of bytes in PKCS#8 format  
-    /// * ocsp: ???
+    /// * certificates: the X.509 certificate chain in DER format, leaf first
+    /// * priv_key: private key as a vector of bytes in PKCS#8 format
+    /// * ocsp: DER-encoded OCSP response stapled to the handshake, or empty
     /// * domains: vector of all domain names the X.509 certificate is valid for
-    /// 
+    ///
     /// ## Returns:
     /// * instance of the structure
-    pub fn new(priv_key: Vec<u8>, ocsp: Vec<u8>, domains: Vec<String>) -> Self {
-        CertificateStore{priv_key, ocsp, domains}
+    pub fn new(certificates: Vec<Vec<u8>>, priv_key: Vec<u8>, ocsp: Vec<u8>, domains: Vec<String>) -> Self {
+        CertificateStore{certificates, priv_key, ocsp, domains}
+    }
+
+    /// Loads a certificate chain and private key from PEM files
+    ///
+    /// ## Params:
+    /// * cert_path: file holding the certificate chain, leaf first
+    /// * key_path: file holding the private key (PKCS#8, PKCS#1 or SEC1)
+    /// * domains: vector of all domain names the X.509 certificate is valid for
+    ///
+    /// ## Returns:
+    /// * Ok: an instance of the structure
+    /// * Err: a string describing the issue encountered
+    pub fn from_pem_files(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+        domains: Vec<String>
+    ) -> Result<Self, String> {
+        let cert_path = cert_path.as_ref();
+        let key_path = key_path.as_ref();
+
+        let mut reader = match File::open(cert_path) {
+            Ok(a) => BufReader::new(a),
+            Err(e) => return Err(format!("Cannot open certificate {}: {}", cert_path.display(), e))
+        };
+        let certificates = match rustls_pemfile::certs(&mut reader) {
+            Ok(a) if !a.is_empty() => a,
+            Ok(_) => return Err(format!("No certificates found in {}", cert_path.display())),
+            Err(e) => return Err(format!("Cannot parse certificate {}: {}", cert_path.display(), e))
+        };
+
+        let mut reader = match File::open(key_path) {
+            Ok(a) => BufReader::new(a),
+            Err(e) => return Err(format!("Cannot open private key {}: {}", key_path.display(), e))
+        };
+        let key = loop {
+            match rustls_pemfile::read_one(&mut reader) {
+                Ok(Some(rustls_pemfile::Item::PKCS8Key(a)))
+                | Ok(Some(rustls_pemfile::Item::RSAKey(a)))
+                | Ok(Some(rustls_pemfile::Item::ECKey(a))) => break a,
+                Ok(Some(_)) => continue,
+                Ok(None) => return Err(format!("No private key found in {}", key_path.display())),
+                Err(e) => return Err(format!("Cannot parse private key {}: {}", key_path.display(), e))
+            }
+        };
+
+        Ok(CertificateStore::new(certificates, key, vec![], domains))
     }
 
     /// Returns the private key of the certificate
-    /// 
+    ///
     /// ## Params:
     /// None
-    /// 
+    ///
     /// ## Returns:
     /// * a rustls::PrivateKey structure
     pub fn get_privkey(self) -> rustls::PrivateKey {
@@ -58,33 +163,353 @@ impl CertificateStore {
         self.domains.clone()
     }
 
-    /// Generates a vector of rustls certificates 
-    /// 
-    /// ## Params: 
+    /// Generates a vector of rustls certificates
+    ///
+    /// ## Params:
     /// None
-    /// 
+    ///
     /// ## Returns:
     /// * Ok: returns a vector of `rustls::Certificate` structures
     /// * Err: returns a string describing the issue encountered
     pub fn get_certificates(self) -> Result<Vec<rustls::Certificate>,String> {
-        todo!()
+        if self.certificates.is_empty() {
+            return Err("Certificate store holds no certificates".to_string());
+        }
+        Ok(self.certificates.into_iter().map(rustls::Certificate).collect())
+    }
+
+    /// Builds the certificate chain and signing key rustls presents during handshakes
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: a rustls `CertifiedKey`
+    /// * Err: returns a string describing the issue encountered
+    pub fn certified_key(&self) -> Result<Arc<CertifiedKey>, String> {
+        let certificates = self.clone().get_certificates()?;
+        let key = match rustls::sign::any_supported_type(&self.clone().get_privkey()) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Bad private key: {}", e))
+        };
+
+        let mut certified = CertifiedKey::new(certificates, key);
+        if !self.ocsp.is_empty() {
+            certified.ocsp = Some(self.ocsp.clone());
+        }
+        Ok(Arc::new(certified))
+    }
+}
+
+
+
+/// Implemented by anything that can answer GRID requests
+pub trait GridHandler: Send + Sync {
+    /// Builds the response to a request
+    ///
+    /// ## Params:
+    /// * request: the decompressed request block
+    /// * ctx: information about the connection the request arrived on
+    ///
+    /// ## Returns:
+    /// The response block to send back
+    fn handle(&self, request: &GridBlock, ctx: &RequestContext) -> GridBlock;
+}
+
+impl<F> GridHandler for F
+where
+    F: Fn(&GridBlock, &RequestContext) -> GridBlock + Send + Sync
+{
+    fn handle(&self, request: &GridBlock, ctx: &RequestContext) -> GridBlock {
+        self(request, ctx)
+    }
+}
+
+/// Handler used until one is set: answers every request with `NOF`
+pub struct NotFoundHandler;
+
+impl GridHandler for NotFoundHandler {
+    fn handle(&self, _request: &GridBlock, _ctx: &RequestContext) -> GridBlock {
+        error_response(GridResponseCode::NOF, "Not found")
+    }
+}
+
+
+
+/// structure holding everything needed to turn a request frame into a response frame
+#[derive(Clone)]
+pub(crate) struct Service {
+    handler: Arc<dyn GridHandler>,                  // handler for requests not matching a virtual host
+    hosts: HashMap<String, Arc<dyn GridHandler>>,   // handlers per SNI name
+    compression_threshold: usize,
+    max_request_size: usize,                        // largest metadata segment accepted once decompressed
+    rate_limits: RateLimits,                        // requests per second allowed per client
+    access_log: Option<Arc<AccessLog>>,             // where every answered request is logged
+    access_control: Option<Arc<AccessControl>>,     // who may do what, `None` to allow everything
+    stats: Arc<ServerStats>,                        // kept across reloads, reported through INF
+    software: String                                // name and version reported through INF
+}
+
+impl Service {
+    /// Processes a raw request frame
+    ///
+    /// Takes care of everything around the handler: decompression, integrity
+    /// checks, conditional requests, digests and compression of the response.
+    ///
+    /// ## Params:
+    /// * frame: the raw request bytes, header included
+    /// * ctx: information about the connection the request arrived on
+    ///
+    /// ## Returns:
+    /// The serialized response frame
+    pub fn process(&self, frame: Vec<u8>, ctx: &RequestContext) -> Vec<u8> {
+        trace!(frame = %to_hex(&frame), "request frame");
+
+        let mut request = match GridBlock::from_bytes(frame) {
+            Ok(a) => a,
+            Err(e) => {
+                warn!(error = %e, "malformed request");
+                return error_response(GridResponseCode::GER, &e).serialize();
+            }
+        };
+        let span = tracing::debug_span!("request", opcode = ?request.opcode(), path = ?request.path().ok());
+        let _enter = span.enter();
+
+        let mut response = self.respond(&mut request, ctx);
+
+        // we can only add digests and compression once we know the block is sane
+        if let Some(algorithm) = request.requested_digest() {
+            if let Err(e) = response.add_digest(algorithm) {
+                warn!(error = %e, "failed to add digest");
+            }
+        }
+        if let Err(e) = response.negotiate_compression(request.accepted_compression(), self.compression_threshold) {
+            warn!(error = %e, "failed to compress response");
+        }
+
+        debug!(opcode = ?response.opcode(), "response");
+        let bytes = response.serialize();
+        trace!(frame = %to_hex(&bytes), "response frame");
+        bytes
+    }
+
+    /// Processes a raw request frame and writes it to the access log
+    pub fn answer(&self, frame: Vec<u8>, ctx: &RequestContext, record: Option<AccessRecord>) -> Vec<u8> {
+        let (opcode, size, started) = (request_opcode(&frame), frame.len(), Instant::now());
+        let response = self.process(frame, ctx);
+        self.stats.record(opcode, size, &response, started.elapsed());
+        self.log(record, &response);
+        response
+    }
+
+    /// Completes an access log record with the response it got and writes it out
+    pub fn log(&self, record: Option<AccessRecord>, response: &[u8]) {
+        if let (Some(log), Some(record)) = (&self.access_log, record) {
+            log.write(&record.finish(response));
+        }
+    }
+
+    /// Helper function to run a parsed request through the handler
+    fn respond(&self, request: &mut GridBlock, ctx: &RequestContext) -> GridBlock {
+        if !matches!(request.opcode(), GridCode::Request(_)) {
+            return error_response(GridResponseCode::GER, "Expected a request OPCODE");
+        }
+        if let Err(wait) = self.rate_limits.check(ctx) {
+            debug!(peer = ?ctx.peer, client = ?ctx.client_subject, "rate limit exceeded");
+            return busy_response("Rate limit exceeded", wait);
+        }
+        if let Err(e) = request.decompress(self.max_request_size) {
+            return error_response(GridResponseCode::GER, &e);
+        }
+        if let Err(e) = request.check_digest() {
+            warn!(error = %e, "request failed integrity check");
+            return error_response(GridResponseCode::GER, &format!("Integrity check failed: {}", e));
+        }
+        // handlers learn who the token says the client is
+        let mut ctx = ctx.clone();
+        if let Some(control) = &self.access_control {
+            ctx.principal = match control.check(request) {
+                Ok(a) => a,
+                Err(reason) => {
+                    debug!(peer = ?ctx.peer, reason = %reason, "request denied");
+                    return error_response(GridResponseCode::DNY, &reason);
+                }
+            };
+        }
+        if request.opcode() == GridCode::Request(GridRequestCode::INF) {
+            return self.info();
+        }
+
+        // pick the handler tree for the host the client asked for
+        let handler = ctx.server_name
+            .as_deref()
+            .and_then(|name| lookup_host(&self.hosts, name))
+            .unwrap_or(&self.handler);
+
+        let response = handler.handle(request, &ctx);
+        if request.is_not_modified(&response) {
+            return error_response(GridResponseCode::NMD, "");
+        }
+        response
+    }
+
+    /// Helper function building the `INF` response
+    fn info(&self) -> GridBlock {
+        let mut capabilities = library_capabilities();
+        if self.access_control.is_some() {
+            capabilities.push("tokens".to_string());
+        }
+        if !self.hosts.is_empty() {
+            capabilities.push("virtual-hosts".to_string());
+        }
+        match self.stats.snapshot(&self.software, capabilities).to_metadata() {
+            // building a block without a path can't fail
+            Ok(headers) => GridBlock::with_metadata(GridResponseCode::ROK, None, &headers, &[]).unwrap(),
+            Err(e) => error_response(GridResponseCode::GER, &e)
+        }
     }
 }
 
 
-/// structure defining a GRID client instance
+
+/// structure describing how a server checks client certificates
+struct ClientAuth {
+    roots: RootCertStore,
+    crls: Vec<Vec<u8>>,     // DER encoded revocation lists
+    required: bool          // whether anonymous clients are refused
+}
+
+/// structure holding the settings a running server can swap out, see `ReloadHandle`
+#[derive(Clone)]
+struct Live {
+    tls_config: Option<Arc<ServerConfig>>,  // used for every new connection, `None` when running in plaintext
+    service: Arc<Service>,
+    peer_filter: Option<PeerFilter>,        // connections from addresses it refuses are dropped
+    max_request_size: usize,
+    max_connections: Option<usize>,         // connections beyond this are answered with BSY
+    max_in_flight: Option<usize>            // unanswered requests allowed per connection
+}
+
+/// structure defining a GRID server instance
 pub struct GridServer {
-    socket: TcpListener,
-    remote: ServerConnection,
-    tls_config: Arc<ServerConfig>
+    sockets: Vec<TcpListener>,
+    port: u16,
+    idle_timeout: Option<Duration>,         // connections silent for longer are closed
+    certificates: Option<SniResolver>,      // certificates per SNI name, `None` when running in plaintext
+    client_auth: Option<ClientAuth>,        // how client certificates are checked, `None` to not ask for them
+    live: Arc<RwLock<Live>>,                // shared with reload handles
+    shutdown: Arc<Shutdown>,                // shared with shutdown handles
+    connections: ConnectionCounter,         // connections currently open
+    stats: Arc<ServerStats>,                // shared with the service, see `Service::info`
+    worker_threads: usize,                  // threads handlers run on, 0 to run them on the event loop
+    worker_queue: usize                     // requests that may wait for a worker thread
+}
+
+/// structure holding the shutdown state shared between a server and its handles
+#[derive(Default)]
+struct Shutdown {
+    deadline: Mutex<Option<Instant>>,       // set once a shutdown is requested
+    waker: Mutex<Option<Arc<Waker>>>        // wakes the event loop, once it runs
+}
+
+impl Shutdown {
+    /// Returns the drain deadline if a shutdown was requested
+    fn deadline(&self) -> Option<Instant> {
+        *self.deadline.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sets the waker of the running event loop
+    fn set_waker(&self, waker: Arc<Waker>) {
+        *self.waker.lock().unwrap_or_else(|e| e.into_inner()) = Some(waker);
+    }
+}
+
+/// Handle for stopping a running server
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shutdown: Arc<Shutdown>
+}
+
+impl ShutdownHandle {
+    /// Asks the server to shut down gracefully
+    ///
+    /// The server stops accepting connections and answers any further requests
+    /// with `BSY`. Connections are closed with a TLS close_notify as soon as their
+    /// pending responses are written, and `run` returns once all are gone or
+    /// `grace` has passed, whichever is first. Calling this again can only bring
+    /// the deadline forward.
+    ///
+    /// ## Params:
+    /// * grace: how long pending responses get to finish
+    ///
+    /// ## Returns:
+    /// None
+    pub fn shutdown(&self, grace: Duration) {
+        let deadline = Instant::now() + grace;
+        {
+            let mut current = self.shutdown.deadline.lock().unwrap_or_else(|e| e.into_inner());
+            *current = Some(current.map_or(deadline, |d| d.min(deadline)));
+        }
+        if let Some(waker) = self.shutdown.waker.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            if let Err(e) = waker.wake() {
+                warn!(error = %e, "failed to wake the event loop");
+            }
+        }
+    }
+
+    /// Returns whether a shutdown has been requested
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.deadline().is_some()
+    }
+}
+
+/// Handle for swapping the certificates and handlers of a running server
+///
+/// Connections accepted after a reload use the new TLS configuration, while
+/// established ones keep the session they negotiated. Requests are answered by
+/// the new handlers as soon as the reload returns.
+#[derive(Clone)]
+pub struct ReloadHandle {
+    live: Arc<RwLock<Live>>
+}
+
+impl ReloadHandle {
+    /// Atomically replaces the live settings with those of another server
+    ///
+    /// `next` is typically built from a freshly read configuration and never bound
+    /// or run. Its certificates, client CA, handlers, peer filter and request size
+    /// limit are taken over; listening addresses and the idle timeout stay as they are.
+    ///
+    /// ## Params:
+    /// * next: the server to take the settings from
+    ///
+    /// ## Returns:
+    /// * Ok: nothing, the new settings are live
+    /// * Err: a string describing why the settings were rejected. The old ones stay live
+    pub fn reload(&self, next: GridServer) -> Result<(), String> {
+        let mut next = next.live();
+        let mut live = self.live.write().unwrap_or_else(|e| e.into_inner());
+        if live.tls_config.is_some() != next.tls_config.is_some() {
+            return Err("Cannot switch between TLS and plaintext while running".to_string());
+        }
+
+        // statistics describe the running server, not its settings
+        Arc::make_mut(&mut next.service).stats = live.service.stats.clone();
+
+        *live = next;
+        info!("reloaded certificates and handlers");
+        Ok(())
+    }
 }
 
 impl GridServer {
-    /// Creates a new `GridClient` instance
-    /// 
-    /// ## Params: 
+    /// Creates a new `GridServer` instance
+    ///
+    /// ## Params:
     /// * port: the port to be listening on
-    /// 
+    /// * certs: the default certificate to serve, or `None` to use the persisted
+    ///   self-signed identity for "localhost" from the default state directory
+    ///
     /// ## Returns:
     /// * Ok: an instance of a GridServer structure
     /// * Err: a string describing the issue encountered
@@ -92,73 +517,901 @@ impl GridServer {
         // see if we need to load certificates from default location or if they're pre-provided
         let c = match certs {
             Some(a) => a,
-            None => gen_certificate(None)?
-        };
-
-        let certificates = c.clone().get_certificates()?;
-        let privkey = c.clone().get_privkey();
-        let ocsp = c.get_ocsp();
-        
-
-        // build a rustls configuration using the new TLS store
-        let config = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert_with_ocsp_and_sct(certificates, privkey, ocsp, vec![])
-            .expect("bad certificates/private key");
-
-        // set up remote connection to server
-        let rc_config = Arc::new(config);
-        
-        
-        
-        ////////////////////////////////////////////////////////////////////////
-        // THIS IS WRONG! ServerConnection IS WHAT HANDLES INBOUND CONNECTIONS,
-        // NOT FOR BINDING TO AN ADDRESS! MOVE TO DIFFERENT FUNCTION!
-        // 
-        // for example of how thats supposed to work, see the following:
-        // https://github.com/rustls/rustls/blob/main/rustls-mio/examples/tlsserver.rs
-        // 
-        // REWORK!!!
-        ////////////////////////////////////////////////////////////////////////
-        
-        // build client connection
-        let client = match ServerConnection::new(rc_config.clone()){
+            None => load_or_create_identity(&default_state_dir(), vec!["localhost".to_string()])?
+        };
+
+        // the initial certificate is presented to anyone not asking for a virtual host
+        let mut resolver = SniResolver::new();
+        resolver.set_default(c.certified_key()?);
+
+        let mut server = GridServer::plaintext(port);
+        server.certificates = Some(resolver);
+        server.rebuild_tls_config()?;
+        Ok(server)
+    }
+
+    /// Creates a new `GridServer` instance that speaks GRID without TLS
+    ///
+    /// Only meant for testing and for running behind something that terminates TLS.
+    ///
+    /// ## Params:
+    /// * port: the port to be listening on
+    ///
+    /// ## Returns:
+    /// * an instance of a GridServer structure
+    pub fn plaintext(port: u16) -> Self {
+        let connections = ConnectionCounter::default();
+        let stats = Arc::new(ServerStats::new(connections.clone()));
+        let live = Live {
+            tls_config: None,
+            service: Arc::new(Service {
+                handler: Arc::new(NotFoundHandler),
+                hosts: HashMap::new(),
+                compression_threshold: GRID_COMPRESSION_THRESHOLD,
+                max_request_size: GRID_MAX_REQUEST_SIZE,
+                rate_limits: RateLimits::default(),
+                access_log: None,
+                access_control: None,
+                stats: stats.clone(),
+                software: format!("libgrid/{}", env!("CARGO_PKG_VERSION"))
+            }),
+            peer_filter: None,
+            max_request_size: GRID_MAX_REQUEST_SIZE,
+            max_connections: None,
+            max_in_flight: None
+        };
+
+        GridServer {
+            sockets: Vec::new(),
+            port,
+            idle_timeout: None,
+            certificates: None,
+            client_auth: None,
+            live: Arc::new(RwLock::new(live)),
+            shutdown: Arc::new(Shutdown::default()),
+            connections,
+            stats,
+            worker_threads: 0,
+            worker_queue: GRID_WORKER_QUEUE_SIZE
+        }
+    }
+
+    /// Adds a virtual host with its own certificate and handler tree
+    ///
+    /// Every domain listed in the certificate store is routed to `handler`, and
+    /// clients asking for one of them via SNI are presented with its certificate.
+    ///
+    /// ## Params:
+    /// * certs: the certificate of the host. Its domains select the host
+    /// * handler: the handler answering requests for the host
+    ///
+    /// ## Returns:
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered
+    pub fn add_host(
+        &mut self,
+        certs: CertificateStore,
+        handler: impl GridHandler + 'static
+    ) -> Result<(), String> {
+        let key = certs.certified_key()?;
+        let resolver = match self.certificates.as_mut() {
+            Some(a) => a,
+            None => return Err("Virtual hosts need TLS, but this server runs in plaintext".to_string())
+        };
+        if certs.domains.is_empty() {
+            return Err("Certificate store lists no domains to serve".to_string());
+        }
+
+        let handler: Arc<dyn GridHandler> = Arc::new(handler);
+        let mut hosts = Vec::new();
+        for domain in &certs.domains {
+            debug!(host = %domain, "adding virtual host");
+            resolver.add(domain, key.clone());
+            hosts.push(domain.to_ascii_lowercase());
+        }
+        let mut live = self.live_mut();
+        let service = Arc::make_mut(&mut live.service);
+        for host in hosts {
+            service.hosts.insert(host, handler.clone());
+        }
+        drop(live);
+
+        self.rebuild_tls_config()
+    }
+
+    /// Trusts a CA for client certificates
+    ///
+    /// Clients presenting a certificate must chain up to one of `roots` and must
+    /// not be listed in any of `crls`. Handlers see who connected in `RequestContext::client_subject`.
+    ///
+    /// ## Params:
+    /// * roots: the CA certificates client certificates are checked against
+    /// * crls: DER encoded revocation lists issued by those CAs
+    /// * required: whether clients without a certificate are turned away
+    ///
+    /// ## Returns:
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered
+    pub fn set_client_ca(
+        &mut self,
+        roots: &[rustls::Certificate],
+        crls: Vec<Vec<u8>>,
+        required: bool
+    ) -> Result<(), String> {
+        if self.certificates.is_none() {
+            return Err("Client certificates need TLS, but this server runs in plaintext".to_string());
+        }
+        let mut store = RootCertStore::empty();
+        for root in roots {
+            if let Err(e) = store.add(root) {
+                return Err(format!("Failed to add client CA: {}", e));
+            }
+        }
+
+        self.client_auth = Some(ClientAuth { roots: store, crls, required });
+        self.rebuild_tls_config()
+    }
+
+    /// Helper function to rebuild the rustls configuration from the current certificates
+    fn rebuild_tls_config(&mut self) -> Result<(), String> {
+        let resolver = match &self.certificates {
+            Some(a) => Arc::new(a.clone()),
+            None => return Ok(())
+        };
+        let builder = ServerConfig::builder().with_safe_defaults();
+
+        let config = match &self.client_auth {
+            Some(auth) => {
+                let crls = auth.crls.iter().cloned().map(UnparsedCertRevocationList);
+                let verifier = match auth.required {
+                    true => AllowAnyAuthenticatedClient::new(auth.roots.clone()).with_crls(crls).map(|v| v.boxed()),
+                    false => AllowAnyAnonymousOrAuthenticatedClient::new(auth.roots.clone()).with_crls(crls).map(|v| v.boxed())
+                };
+                match verifier {
+                    Ok(a) => builder.with_client_cert_verifier(a).with_cert_resolver(resolver),
+                    Err(e) => return Err(format!("Bad revocation list: {:?}", e))
+                }
+            },
+            None => builder.with_no_client_auth().with_cert_resolver(resolver)
+        };
+        self.live_mut().tls_config = Some(Arc::new(config));
+        Ok(())
+    }
+
+    /// Sets the handler requests are answered by
+    ///
+    /// ## Params:
+    /// * handler: anything implementing `GridHandler`, including closures
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_handler(&mut self, handler: impl GridHandler + 'static) {
+        Arc::make_mut(&mut self.live_mut().service).handler = Arc::new(handler);
+    }
+
+    /// Sets the metadata size below which responses are sent uncompressed
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        Arc::make_mut(&mut self.live_mut().service).compression_threshold = threshold;
+    }
+
+    /// Sets the size (in bytes) of the largest request frame accepted
+    ///
+    /// Clients sending anything larger get a `GER` and are disconnected. Compressed
+    /// metadata growing past the same size once decompressed is answered with `GER`.
+    pub fn set_max_request_size(&mut self, size: usize) {
+        let mut live = self.live_mut();
+        live.max_request_size = size;
+        Arc::make_mut(&mut live.service).max_request_size = size;
+    }
+
+    /// Sets how long a connection may stay silent before it is closed
+    ///
+    /// ## Params:
+    /// * timeout: the idle timeout, or `None` to keep idle connections forever
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Restricts which client addresses may connect
+    ///
+    /// ## Params:
+    /// * filter: returns `true` for addresses that are allowed in
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_peer_filter(&mut self, filter: impl Fn(IpAddr) -> bool + Send + Sync + 'static) {
+        self.live_mut().peer_filter = Some(Arc::new(filter));
+    }
+
+    /// Limits how many connections may be open at once
+    ///
+    /// Clients connecting beyond the limit get a `BSY` with a retry hint and are
+    /// disconnected. Beyond twice the limit they are disconnected straight away.
+    ///
+    /// ## Params:
+    /// * limit: the most open connections, or `None` for no limit
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_max_connections(&mut self, limit: Option<usize>) {
+        self.live_mut().max_connections = limit;
+    }
+
+    /// Limits how many requests a single connection may have unanswered at once
+    ///
+    /// Pipelined requests beyond the limit are answered with `BSY` and a retry hint.
+    ///
+    /// ## Params:
+    /// * limit: the most requests in flight per connection, or `None` for no limit
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_max_in_flight(&mut self, limit: Option<usize>) {
+        self.live_mut().max_in_flight = limit;
+    }
+
+    /// Limits how many requests per second each client address may send
+    ///
+    /// Requests over the limit are answered with `BSY` and a retry hint. IPv6
+    /// clients share a limit with the rest of their /64.
+    ///
+    /// ## Params:
+    /// * limit: the rate limit, or `None` for no limit
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_ip_rate_limit(&mut self, limit: Option<RateLimit>) {
+        Arc::make_mut(&mut self.live_mut().service).rate_limits.per_ip = limit.map(|l| Arc::new(RateLimiter::new(l)));
+    }
+
+    /// Limits how many requests per second each client certificate may send
+    ///
+    /// Applies on top of the per address limit. Clients without a certificate are not affected.
+    ///
+    /// ## Params:
+    /// * limit: the rate limit, or `None` for no limit
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_certificate_rate_limit(&mut self, limit: Option<RateLimit>) {
+        Arc::make_mut(&mut self.live_mut().service).rate_limits.per_certificate = limit.map(|l| Arc::new(RateLimiter::new(l)));
+    }
+
+    /// Runs request handlers on a pool of worker threads instead of the event loop thread
+    ///
+    /// The event loop keeps reading and writing for everyone while a slow handler
+    /// works. Once `queue_size` requests are waiting for a thread, connections with
+    /// more requests stop being read from until there is room again. Only read by `run`.
+    ///
+    /// ## Params:
+    /// * threads: how many worker threads to start, 0 to run handlers on the event loop
+    /// * queue_size: how many requests may wait for a worker thread
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_workers(&mut self, threads: usize, queue_size: usize) {
+        self.worker_threads = threads;
+        self.worker_queue = queue_size;
+    }
+
+    /// Sets the access log every answered request is written to
+    ///
+    /// ## Params:
+    /// * log: the access log, or `None` to not log requests
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_access_log(&mut self, log: Option<Arc<AccessLog>>) {
+        Arc::make_mut(&mut self.live_mut().service).access_log = log;
+    }
+
+    /// Sets the token checks and path rules requests are denied by
+    ///
+    /// ## Params:
+    /// * control: the access control, or `None` to allow every request
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_access_control(&mut self, control: Option<Arc<AccessControl>>) {
+        Arc::make_mut(&mut self.live_mut().service).access_control = control;
+    }
+
+    /// Sets the name and version of the server software reported through `INF`
+    ///
+    /// ## Params:
+    /// * software: e.g. `crash/0.1.0`
+    ///
+    /// ## Returns:
+    /// None
+    pub fn set_software(&mut self, software: &str) {
+        Arc::make_mut(&mut self.live_mut().service).software = software.to_string();
+    }
+
+    /// Serves Prometheus-style text metrics over HTTP on a separate address
+    ///
+    /// `GET /metrics` is answered from a thread of its own with counters and
+    /// histograms of connections, TLS handshake failures, request latency per
+    /// OPCODE, response codes, frame sizes and the worker queue. The endpoint has
+    /// no authentication, so it should listen on a local address. The statistics
+    /// survive reloads, and the thread serves until the process exits.
+    ///
+    /// ## Params:
+    /// * address: where to listen, e.g. `127.0.0.1:9464`. Port 0 picks a free one
+    ///
+    /// ## Returns:
+    /// * Ok: the address the endpoint listens on
+    /// * Err: a string describing the issue encountered
+    pub fn bind_metrics(&mut self, address: SocketAddr) -> Result<SocketAddr, String> {
+        let listener = match std::net::TcpListener::bind(address) {
             Ok(a) => a,
-            Err(e) => return Err(format!("{}", e))
+            Err(e) => return Err(format!("Failed to bind metrics to {}: {}", address, e))
         };
-        
-            
-        // return an instance of the structure
-        Ok(GridServer {
-            socket: todo!(),
-            remote: client,
-            tls_config: rc_config
-        })
+        let local = match listener.local_addr() {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Failed to read metrics address: {}", e))
+        };
+        metrics::serve(listener, self.stats.clone())?;
+        Ok(local)
+    }
+
+    /// Returns a handle for swapping certificates and handlers while the server runs
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle { live: self.live.clone() }
+    }
+
+    /// Returns a handle for shutting down the server gracefully from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle { shutdown: self.shutdown.clone() }
     }
 
+    /// Helper function taking a snapshot of the live settings
+    fn live(&self) -> Live {
+        self.live.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Helper function to change the live settings while configuring the server
+    fn live_mut(&mut self) -> RwLockWriteGuard<'_, Live> {
+        self.live.write().unwrap_or_else(|e| e.into_inner())
+    }
 
-    /// Helper function to help build a TLS connection target
-    /// 
-    /// Basically stolen from the rustls tlsclient example. [Link here](https://github.com/rustls/rustls/blob/main/rustls-mio/examples/tlsclient.rs)
-    /// 
+    /// Helper function to check a client address against the peer filter
+    fn admits(&self, peer: Option<SocketAddr>) -> bool {
+        match (&self.live().peer_filter, peer) {
+            (Some(filter), Some(address)) => filter(address.ip()),
+            _ => true
+        }
+    }
+
+    /// Binds the server to its port on all interfaces
+    ///
     /// ## Params:
-    /// * domain: the domain string of the remote target
-    /// * port: the port used to connect to the remote
-    /// 
+    /// None
+    ///
     /// ## Returns:
-    /// * Ok: returns a socket address for use in connections
-    /// * Err: returns a string describing the issue encountered
-    fn build_socket_connect(domain: String, port: u16) -> Result<SocketAddr, String>{
-        let tmp = (&domain[..], port).to_socket_addrs().unwrap();
-        for addr in tmp {
-            if let SocketAddr::V4(_) = addr {
-                return Ok(addr);
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered
+    pub fn bind(&mut self) -> Result<(), String> {
+        let address = SocketAddr::from(([0, 0, 0, 0], self.port));
+        self.bind_to(address)
+    }
+
+    /// Binds the server to a specific address
+    ///
+    /// Can be called several times to listen on multiple addresses.
+    ///
+    /// ## Params:
+    /// * address: the address to listen on. Port 0 picks a free port
+    ///
+    /// ## Returns:
+    /// * Ok: nothing
+    /// * Err: a string describing the issue encountered
+    pub fn bind_to(&mut self, address: SocketAddr) -> Result<(), String> {
+        let listener = match TcpListener::bind(address) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Failed to bind to {}: {}", address, e))
+        };
+        info!(address = ?listener.local_addr().ok(), "listening");
+
+        self.sockets.push(listener);
+        Ok(())
+    }
+
+    /// Returns the first address the server is bound to, if any
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.sockets.first().and_then(|s| s.local_addr().ok())
+    }
+
+    /// Helper function counting a new connection against the connection limit
+    ///
+    /// ## Returns:
+    /// * false: the connection should be dropped without a word
+    fn admit_connection<T: Transport>(&self, conn: &mut Connection<T>, max_connections: Option<usize>) -> bool {
+        let (slot, open) = self.connections.open();
+        conn.set_slot(slot);
+        self.stats.connection_opened();
+        match max_connections {
+            // far over the limit even a BSY is too much work
+            Some(max) if open >= max.saturating_mul(2) => false,
+            Some(max) if open >= max => {
+                debug!(open, "too many connections, refusing");
+                conn.set_refusing();
+                true
+            },
+            _ => true
+        }
+    }
+
+    /// Helper function counting a failed read as a failed handshake if the connection was still in one
+    fn read_failed<T: Transport>(&self, conn: &Connection<T>) {
+        if conn.is_handshaking() {
+            self.stats.handshake_failed();
+        }
+    }
+
+    /// Helper function answering the request frames read from a connection
+    ///
+    /// Frames get `BSY` while the server drains, while the connection is over the
+    /// connection limit, or beyond the in-flight limit of the connection. The rest
+    /// go to the handlers, on the worker pool if `dispatch` is given.
+    ///
+    /// ## Returns:
+    /// * Ok(false): the worker queue is full and the connection has to wait for room
+    fn answer<T: Transport>(
+        &self,
+        conn: &mut Connection<T>,
+        frames: Vec<Vec<u8>>,
+        draining: bool,
+        dispatch: Option<(&Dispatch, Token)>
+    ) -> Result<bool, String> {
+        let live = self.live();
+        let refusal = match (draining, conn.is_refusing()) {
+            (true, _) => Some(shutdown_response().serialize()),
+            (false, true) => Some(busy_response("Too many connections", CONNECTION_RETRY_AFTER).serialize()),
+            (false, false) => None
+        };
+
+        // everything read in one go counts as in flight before any of it is answered
+        let ctx = conn.context();
+        for frame in frames {
+            let started = Instant::now();
+            let record = live.service.access_log.as_ref().map(|_| AccessRecord::start(&frame, &ctx));
+            let response = match &refusal {
+                Some(refusal) => refusal.clone(),
+                None if conn.start_request(live.max_in_flight) => {
+                    conn.backlog().push_back(Queued::Request(frame, record.map(Box::new)));
+                    continue;
+                },
+                None => busy_response("Too many requests in flight", IN_FLIGHT_RETRY_AFTER).serialize()
+            };
+            live.service.stats.record(request_opcode(&frame), frame.len(), &response, started.elapsed());
+            live.service.log(record, &response);
+            conn.backlog().push_back(Queued::Ready(response));
+        }
+
+        let room = self.pump(conn, &live.service, dispatch)?;
+        if refusal.is_some() && !conn.is_busy() {
+            conn.close();
+        }
+        Ok(room)
+    }
+
+    /// Helper function moving the backlog of a connection along, strictly in order
+    ///
+    /// Each connection has at most one request with the workers, so responses
+    /// never overtake each other.
+    ///
+    /// ## Returns:
+    /// * Ok(false): the worker queue is full and the connection has to wait for room
+    fn pump<T: Transport>(
+        &self,
+        conn: &mut Connection<T>,
+        service: &Arc<Service>,
+        dispatch: Option<(&Dispatch, Token)>
+    ) -> Result<bool, String> {
+        while !conn.is_dispatched() {
+            let (frame, record) = match conn.backlog().pop_front() {
+                Some(Queued::Ready(response)) => {
+                    conn.queue(&response)?;
+                    continue;
+                },
+                Some(Queued::Request(frame, record)) => (frame, record.map(|r| *r)),
+                None => break
+            };
+
+            let (dispatch, token) = match dispatch {
+                Some(a) => a,
+                None => {
+                    let response = service.answer(frame, &conn.context(), record);
+                    conn.finish_request();
+                    conn.queue(&response)?;
+                    continue;
+                }
+            };
+            if !dispatch.pool.has_room() {
+                conn.backlog().push_front(Queued::Request(frame, record.map(Box::new)));
+                return Ok(false);
+            }
+            dispatch.submit(token, service.clone(), frame, conn.context(), record);
+            conn.set_dispatched(true);
+        }
+        Ok(true)
+    }
+
+    /// Accepts and serves connections on the bound sockets until shut down or an error occurs
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: the server was shut down through a `ShutdownHandle` and has drained
+    /// * Err: a string describing the issue that stopped the server
+    pub fn run(&mut self) -> Result<(), String> {
+        if self.sockets.is_empty() {
+            return Err("Server is not bound, call bind() first".to_string());
+        }
+        let mut listeners = std::mem::take(&mut self.sockets);
+        let result = self.event_loop(&mut listeners);
+        self.sockets = listeners;
+        result
+    }
+
+    /// Serves a single connection over any transport until the client goes away
+    ///
+    /// Blocks the calling thread. This is what lets a server run over a `MemoryPipe`.
+    /// A shutdown is noticed the next time the client sends something, which is
+    /// then answered with `BSY` before the connection is closed.
+    ///
+    /// ## Params:
+    /// * transport: the byte stream to the client
+    /// * peer: the address of the client, if known
+    ///
+    /// ## Returns:
+    /// * Ok: the client closed the connection or the server shut down
+    /// * Err: a string describing the issue encountered
+    pub fn serve_transport(
+        &self,
+        transport: impl Transport,
+        peer: Option<SocketAddr>
+    ) -> Result<(), String> {
+        if !self.admits(peer) {
+            debug!(peer = ?peer, "refusing connection");
+            return Err("Connection refused by peer filter".to_string());
+        }
+        let live = self.live();
+        let mut conn = Connection::new(transport, live.tls_config, peer, true)?;
+        conn.set_max_frame_size(live.max_request_size);
+        if !self.admit_connection(&mut conn, live.max_connections) {
+            return Err("Too many connections".to_string());
+        }
+        debug!(peer = ?peer, "connection accepted");
+
+        while !conn.is_closed() {
+            let frames = match conn.read_ready() {
+                Ok(a) => a,
+                Err(e) => {
+                    self.read_failed(&conn);
+                    // let the client know why before hanging up
+                    let _ = conn.queue(&error_response(GridResponseCode::GER, &e).serialize());
+                    conn.close();
+                    let _ = conn.write_ready();
+                    return Err(e);
+                }
+            };
+            self.answer(&mut conn, frames, self.shutdown.deadline().is_some(), None)?;
+            conn.write_ready()?;
+        }
+        Ok(())
+    }
+
+    /// Helper function running the mio event loop over the listening sockets
+    fn event_loop(&self, listeners: &mut [TcpListener]) -> Result<(), String> {
+        let mut poll = match Poll::new() {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Failed to create poll instance: {}", e))
+        };
+        // listeners get the first tokens, connections the ones after
+        for (i, listener) in listeners.iter_mut().enumerate() {
+            if let Err(e) = poll.registry().register(listener, Token(i), Interest::READABLE) {
+                return Err(format!("Failed to register listener: {}", e));
+            }
+        }
+        let waker = match Waker::new(poll.registry(), WAKER_TOKEN) {
+            Ok(a) => Arc::new(a),
+            Err(e) => return Err(format!("Failed to create waker: {}", e))
+        };
+        self.shutdown.set_waker(waker.clone());
+
+        // workers hand their responses back through a channel and wake us up
+        let (done, finished) = mpsc::channel();
+        let dispatch = match self.worker_threads {
+            0 => None,
+            threads => Some(Dispatch { pool: WorkerPool::new(threads, self.worker_queue, self.stats.worker_queue())?, done, waker })
+        };
+
+        let mut connections: HashMap<Token, Connection<TcpStream>> = HashMap::new();
+        let mut parked: HashSet<Token> = HashSet::new();   // connections deregistered while backed up
+        let mut starved: Vec<Token> = Vec::new();          // connections waiting for room in the worker queue
+        let mut next_token = listeners.len();
+        let mut events = Events::with_capacity(256);
+        let mut draining = false;
+        let mut stalled: Vec<usize> = Vec::new();          // listeners we stopped accepting from for now
+
+        loop {
+            // when draining we wake up in time for the deadline
+            let deadline = self.shutdown.deadline();
+            let poll_timeout = match deadline {
+                Some(d) => Some(d.saturating_duration_since(Instant::now())),
+                None => self.idle_timeout.map(|t| t.min(IDLE_SWEEP_INTERVAL))
+            };
+            // connections left waiting in a stalled listener's backlog won't raise another event
+            let poll_timeout = match stalled.is_empty() {
+                true => poll_timeout,
+                false => Some(poll_timeout.map_or(ACCEPT_RETRY_INTERVAL, |t| t.min(ACCEPT_RETRY_INTERVAL)))
+            };
+            if let Err(e) = poll.poll(&mut events, poll_timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(format!("Poll failed: {}", e));
+            }
+
+            if let Some(deadline) = self.shutdown.deadline() {
+                if !draining {
+                    // stop taking new connections and close the ones with nothing left to send
+                    info!(connections = connections.len(), "shutting down, draining connections");
+                    for listener in listeners.iter_mut() {
+                        let _ = poll.registry().deregister(listener);
+                    }
+                    draining = true;
+                    let tokens: Vec<Token> = connections.keys().copied().collect();
+                    for token in tokens {
+                        settle(poll.registry(), &mut connections, &mut parked, token, draining);
+                    }
+                }
+                if Instant::now() >= deadline {
+                    if !connections.is_empty() {
+                        warn!(connections = connections.len(), "drain deadline passed, closing remaining connections");
+                    }
+                    break;
+                }
+            }
+
+            // hand finished responses to their connections, then give waiting ones another go
+            let mut touched: Vec<Token> = Vec::new();
+            while let Ok((token, response)) = finished.try_recv() {
+                let conn = match connections.get_mut(&token) {
+                    Some(a) => a,
+                    None => continue
+                };
+                conn.set_dispatched(false);
+                conn.finish_request();
+                if let Err(e) = conn.queue(&response) {
+                    warn!(error = %e, "failed to queue response");
+                    conn.close();
+                }
+                touched.push(token);
+            }
+            if !touched.is_empty() {
+                touched.append(&mut starved);
+                touched.sort();
+                touched.dedup();
+            }
+            for token in touched {
+                let conn = match connections.get_mut(&token) {
+                    Some(a) => a,
+                    None => continue
+                };
+                match self.pump(conn, &self.live().service, dispatch.as_ref().map(|d| (d, token))) {
+                    Ok(true) => (),
+                    Ok(false) => starved.push(token),
+                    Err(e) => {
+                        warn!(error = %e, "failed to queue response");
+                        conn.close();
+                    }
+                }
+                settle(poll.registry(), &mut connections, &mut parked, token, draining);
+            }
+
+            // accept everyone who is waiting
+            let mut ready: Vec<usize> = std::mem::take(&mut stalled);
+            ready.extend(events.iter().map(|e| e.token().0).filter(|i| *i < listeners.len()));
+            ready.sort();
+            ready.dedup();
+            for index in ready {
+                if draining {
+                    continue;
+                }
+                let listener = &listeners[index];
+                loop {
+                    let (mut stream, peer) = match listener.accept() {
+                        Ok(a) => a,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted) => {
+                            debug!(error = %e, "connection went away before it was accepted");
+                            continue;
+                        },
+                        // e.g. out of file descriptors, so give connections a moment to close
+                        Err(e) => {
+                            warn!(error = %e, "failed to accept connection, trying again shortly");
+                            stalled.push(index);
+                            break;
+                        }
+                    };
+                    if !self.admits(Some(peer)) {
+                        debug!(peer = %peer, "refusing connection");
+                        continue;
+                    }
+
+                    let token = Token(next_token);
+                    next_token += 1;
+                    if let Err(e) = poll.registry().register(&mut stream, token, Interest::READABLE) {
+                        warn!(peer = %peer, error = %e, "failed to register connection");
+                        continue;
+                    }
+                    let live = self.live();
+                    match Connection::new(stream, live.tls_config, Some(peer), false) {
+                        Ok(mut a) => {
+                            a.set_max_frame_size(live.max_request_size);
+                            if !self.admit_connection(&mut a, live.max_connections) {
+                                let _ = poll.registry().deregister(a.transport());
+                                continue;
+                            }
+                            debug!(peer = %peer, "connection accepted");
+                            connections.insert(token, a);
+                        },
+                        Err(e) => warn!(peer = %peer, error = %e, "failed to set up connection")
+                    }
+                }
+            }
+
+            for event in events.iter() {
+                if event.token() == WAKER_TOKEN || event.token().0 < listeners.len() {
+                    continue;
+                }
+
+                let token = event.token();
+                let conn = match connections.get_mut(&token) {
+                    Some(a) => a,
+                    None => continue
+                };
+
+                if event.is_readable() && conn.wants_read() {
+                    match conn.read_ready() {
+                        Ok(frames) => match self.answer(conn, frames, draining, dispatch.as_ref().map(|d| (d, token))) {
+                            Ok(true) => (),
+                            Ok(false) => starved.push(token),
+                            Err(e) => {
+                                warn!(error = %e, "failed to queue response");
+                                conn.close();
+                            }
+                        },
+                        Err(e) => {
+                            debug!(error = %e, "dropping connection");
+                            self.read_failed(conn);
+                            let _ = conn.queue(&error_response(GridResponseCode::GER, &e).serialize());
+                            conn.close();
+                        }
+                    }
+                }
+                settle(poll.registry(), &mut connections, &mut parked, token, draining);
             }
+
+            // hang up on anyone who has been quiet for too long, unless we still owe them
+            if let Some(timeout) = self.idle_timeout {
+                connections.retain(|token, conn| {
+                    if conn.idle_for() < timeout || conn.is_busy() {
+                        return true;
+                    }
+                    debug!("closing idle connection");
+                    conn.close();
+                    let _ = conn.write_ready();
+                    if !parked.remove(token) {
+                        let _ = poll.registry().deregister(conn.transport());
+                    }
+                    false
+                });
+            }
+
+            if draining && connections.is_empty() {
+                break;
+            }
+        }
+
+        // whatever is left gets a last chance at its close_notify
+        for conn in connections.values_mut() {
+            conn.close();
+            let _ = conn.write_ready();
         }
+        info!("server stopped");
+        Ok(())
+    }
+}
+
+
+/// structure connecting the event loop to its worker pool
+struct Dispatch {
+    pool: WorkerPool,
+    done: mpsc::Sender<(Token, Vec<u8>)>,   // responses on their way back to the event loop
+    waker: Arc<Waker>
+}
+
+impl Dispatch {
+    /// Hands a request to the workers, who send the response back tagged with `token`
+    fn submit(&self, token: Token, service: Arc<Service>, frame: Vec<u8>, ctx: RequestContext, record: Option<AccessRecord>) {
+        let (done, waker) = (self.done.clone(), self.waker.clone());
+        self.pool.submit(Box::new(move || {
+            // a panicking handler costs the client its request, not the worker its life
+            let response = match panic::catch_unwind(AssertUnwindSafe(|| service.answer(frame, &ctx, record))) {
+                Ok(a) => a,
+                Err(_) => {
+                    warn!(peer = ?ctx.peer, "request handler panicked");
+                    error_response(GridResponseCode::GER, "Internal server error").serialize()
+                }
+            };
+            // the event loop may be gone already, then nobody is waiting for this anyway
+            if done.send((token, response)).is_ok() {
+                let _ = waker.wake();
+            }
+        }));
+    }
+}
+
+
+/// Helper function writing out what a connection owes, then closing it or updating what it waits for
+///
+/// Connections that have nothing to read or write are deregistered until they do,
+/// since an edge-triggered registration would otherwise keep firing.
+fn settle(
+    registry: &Registry,
+    connections: &mut HashMap<Token, Connection<TcpStream>>,
+    parked: &mut HashSet<Token>,
+    token: Token,
+    draining: bool
+) {
+    let conn = match connections.get_mut(&token) {
+        Some(a) => a,
+        None => return
+    };
+
+    // a draining connection gets its close_notify once everything owed to the client is out
+    let mut written = conn.write_ready();
+    if written.is_ok() && draining && !conn.is_closing() && !conn.wants_write() && !conn.is_busy() {
+        conn.close();
+        written = conn.write_ready();
+    }
+    if let Err(e) = written {
+        debug!(error = %e, "dropping connection");
+        parked.remove(&token);
+        connections.remove(&token);
+        return;
+    }
 
-        // failed to parse it
-        Err(format!("Failed to lookup domain {}", domain))
+    // either clean up or wait for the next thing to do
+    if conn.is_closed() {
+        if !parked.remove(&token) {
+            let _ = registry.deregister(conn.transport());
+        }
+        connections.remove(&token);
+        return;
+    }
+    let interest = match (conn.wants_read(), conn.wants_write()) {
+        (true, true) => Some(Interest::READABLE | Interest::WRITABLE),
+        (true, false) => Some(Interest::READABLE),
+        (false, true) => Some(Interest::WRITABLE),
+        (false, false) => None
+    };
+    let parked_now = parked.contains(&token);
+    let result = match (interest, parked_now) {
+        (Some(interest), true) => registry.register(conn.transport(), token, interest).map(|_| { parked.remove(&token); }),
+        (Some(interest), false) => registry.reregister(conn.transport(), token, interest),
+        (None, false) => registry.deregister(conn.transport()).map(|_| { parked.insert(token); }),
+        (None, true) => Ok(())
+    };
+    if let Err(e) = result {
+        warn!(error = %e, "failed to update connection registration");
     }
 }
 
@@ -170,11 +1423,47 @@ impl GridServer {
 
 //////////////////////// MISC HELPERS ///////////////////////////
 
+/// Builds the response sent to requests arriving while the server shuts down
+fn shutdown_response() -> GridBlock {
+    error_response(GridResponseCode::BSY, "Server is shutting down")
+}
+
+/// Builds a `BSY` response telling the client when to try again
+///
+/// ## Params:
+/// * message: the message following the headers
+/// * retry_after: how long the client should wait before retrying
+///
+/// ## Returns:
+/// * a response GridBlock, see `GridBlock::retry_after`
+pub fn busy_response(message: &str, retry_after: Duration) -> GridBlock {
+    let mut headers = Metadata::new();
+    headers.set_retry_after(retry_after.max(Duration::from_millis(1)));
+    // building a block without a path can't fail
+    GridBlock::with_metadata(GridResponseCode::BSY, None, &headers, message.as_bytes()).unwrap()
+}
+
+/// Builds a response block carrying a human readable message
+///
+/// ## Params:
+/// * code: the response code
+/// * message: the message to put in the metadata segment
+///
+/// ## Returns:
+/// * a response GridBlock
+pub fn error_response(code: GridResponseCode, message: &str) -> GridBlock {
+    // building a block without a path can't fail
+    GridBlock::new(code, None, &mut message.as_bytes().to_vec()).unwrap()
+}
+
 /// Generates a self-signed X.509 certificate for use in the server structure
-/// 
+///
+/// The certificate only lives in memory; see `identity::load_or_create_identity`
+/// for one that survives restarts.
+///
 /// ## Params:
-/// * names: optional vector of domain names this certificate should be valid for. If not provided, defaults to "localhost"
-/// 
+/// * names: optional vector of domain names and IP addresses this certificate should be valid for. If not provided, defaults to "localhost"
+///
 /// ## Returns:
 /// * Ok: returns a CertificateStore structure for use
 /// * Err: returns a string describing the issue encountered
@@ -186,11 +1475,15 @@ pub fn gen_certificate(names: Option<Vec<String>>) -> Result<CertificateStore, S
     };
 
     // build the certificate
-    let cert = match generate_simple_self_signed(domains.clone()) {
+    let cert = match Certificate::from_params(certificate_params(&domains, IDENTITY_VALIDITY_DAYS)) {
         Ok(a) => a,
         Err(e) => return Err(format!("Certificate generation failed: {}", e))
     };
+    let der = match cert.serialize_der() {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Certificate serialization failed: {}", e))
+    };
+
 
-    
-    Ok(CertificateStore::new(cert.serialize_private_key_der(), vec![], domains))
+    Ok(CertificateStore::new(vec![der], cert.serialize_private_key_der(), vec![], domains))
 }
@@ -0,0 +1,73 @@
+// Defines SNI based selection of certificates and handlers for virtual hosting
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+
+
+/// Looks up the entry for a server name, honoring `*.domain` wildcard entries
+///
+/// ## Params:
+/// * entries: map of lowercase host names (or wildcards) to values
+/// * name: the server name the client asked for
+///
+/// ## Returns:
+/// The matching value, preferring an exact match over a wildcard one
+pub fn lookup_host<'a, T>(entries: &'a HashMap<String, T>, name: &str) -> Option<&'a T> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(a) = entries.get(&name) {
+        return Some(a);
+    }
+
+    // wildcards only cover a single label
+    let (_, parent) = name.split_once('.')?;
+    entries.get(&format!("*.{}", parent))
+}
+
+
+/// structure selecting the certificate to present based on the SNI name of the client
+#[derive(Default, Clone)]
+pub struct SniResolver {
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>
+}
+
+impl SniResolver {
+    /// Creates a new, empty `SniResolver`
+    pub fn new() -> Self {
+        SniResolver::default()
+    }
+
+    /// Registers a certificate for a host name
+    ///
+    /// ## Params:
+    /// * name: the host name, or a `*.domain` wildcard
+    /// * key: the certificate chain and signing key to present
+    ///
+    /// ## Returns:
+    /// None
+    pub fn add(&mut self, name: &str, key: Arc<CertifiedKey>) {
+        self.hosts.insert(name.to_ascii_lowercase(), key);
+    }
+
+    /// Sets the certificate presented when no host matches or the client sent no SNI
+    pub fn set_default(&mut self, key: Arc<CertifiedKey>) {
+        self.default = Some(key);
+    }
+
+    /// Returns the host names with a registered certificate
+    pub fn names(&self) -> Vec<String> {
+        self.hosts.keys().cloned().collect()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| lookup_host(&self.hosts, name))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
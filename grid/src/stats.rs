@@ -0,0 +1,312 @@
+// Defines the live statistics servers keep and report through INF and the metrics endpoint
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::admission::ConnectionCounter;
+use crate::definitions::{Compression, GridCode, GridRequestCode, GridResponseCode};
+use crate::metadata::{Metadata, MetadataKey};
+use crate::metrics::{Histogram, LATENCY_BUCKETS, SIZE_BUCKETS, describe};
+
+
+/// Request OPCODES servers count and answer
+pub const REQUEST_CODES: [GridRequestCode; 5] = [
+    GridRequestCode::GET,
+    GridRequestCode::PUT,
+    GridRequestCode::SET,
+    GridRequestCode::CER,
+    GridRequestCode::INF
+];
+
+/// Response codes servers count
+pub const RESPONSE_CODES: [GridResponseCode; 6] = [
+    GridResponseCode::ROK,
+    GridResponseCode::GER,
+    GridResponseCode::NOF,
+    GridResponseCode::BSY,
+    GridResponseCode::NMD,
+    GridResponseCode::DNY
+];
+
+
+/// structure counting what a server has been up to since it started
+pub(crate) struct ServerStats {
+    started: Instant,
+    connections: ConnectionCounter,     // shared with the server, counts open connections
+    connections_total: AtomicU64,
+    handshake_failures: AtomicU64,      // connections dropped over a failed TLS handshake
+    requests: [AtomicU64; REQUEST_CODES.len()],
+    invalid_requests: AtomicU64,        // frames without a known request OPCODE
+    responses: [AtomicU64; RESPONSE_CODES.len()],
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    latency: [Histogram; REQUEST_CODES.len()],  // microseconds from reading a request to its response
+    request_sizes: Histogram,
+    response_sizes: Histogram,
+    worker_queue: Arc<AtomicUsize>      // shared with the worker pool, requests waiting for a thread
+}
+
+impl ServerStats {
+    /// Creates a new `ServerStats` instance
+    pub fn new(connections: ConnectionCounter) -> Self {
+        ServerStats {
+            started: Instant::now(),
+            connections,
+            connections_total: AtomicU64::new(0),
+            handshake_failures: AtomicU64::new(0),
+            requests: Default::default(),
+            invalid_requests: AtomicU64::new(0),
+            responses: Default::default(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            latency: std::array::from_fn(|_| Histogram::new(&LATENCY_BUCKETS)),
+            request_sizes: Histogram::new(&SIZE_BUCKETS),
+            response_sizes: Histogram::new(&SIZE_BUCKETS),
+            worker_queue: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    /// Counts an accepted connection
+    pub fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection dropped because its TLS handshake failed
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the counter of requests waiting for a worker thread, for the pool to keep up to date
+    pub fn worker_queue(&self) -> Arc<AtomicUsize> {
+        self.worker_queue.clone()
+    }
+
+    /// Counts a request and the response it got
+    ///
+    /// ## Params:
+    /// * opcode: the first byte of the request frame, if there was one
+    /// * bytes_in: the size of the request frame
+    /// * response: the serialized response frame
+    /// * latency: how long the request took to answer
+    ///
+    /// ## Returns:
+    /// None
+    pub fn record(&self, opcode: Option<u8>, bytes_in: usize, response: &[u8], latency: Duration) {
+        match REQUEST_CODES.iter().position(|c| Some(*c as u8) == opcode) {
+            Some(i) => {
+                self.requests[i].fetch_add(1, Ordering::Relaxed);
+                self.latency[i].observe(latency.as_micros() as u64);
+            },
+            None => {
+                self.invalid_requests.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let Some(i) = RESPONSE_CODES.iter().position(|c| Some(&(*c as u8)) == response.first()) {
+            self.responses[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes_in.fetch_add(bytes_in as u64, Ordering::Relaxed);
+        self.bytes_out.fetch_add(response.len() as u64, Ordering::Relaxed);
+        self.request_sizes.observe(bytes_in as u64);
+        self.response_sizes.observe(response.len() as u64);
+    }
+
+    /// Takes a consistent enough copy of the counters
+    ///
+    /// ## Params:
+    /// * software: name and version of the server software, e.g. `crash/0.1.0`
+    /// * capabilities: optional features the server offers
+    ///
+    /// ## Returns:
+    /// * the counters along with what the server supports
+    pub fn snapshot(&self, software: &str, capabilities: Vec<String>) -> ServerInfo {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        ServerInfo {
+            software: software.to_string(),
+            opcodes: REQUEST_CODES.iter().map(|c| format!("{:?}", c)).collect(),
+            capabilities,
+            uptime: self.started.elapsed(),
+            connections_open: self.connections.count() as u64,
+            connections_total: load(&self.connections_total),
+            requests: REQUEST_CODES.iter().zip(&self.requests).map(|(c, n)| (format!("{:?}", c), load(n))).collect(),
+            invalid_requests: load(&self.invalid_requests),
+            responses: RESPONSE_CODES.iter().zip(&self.responses).map(|(c, n)| (format!("{:?}", c), load(n))).collect(),
+            bytes_in: load(&self.bytes_in),
+            bytes_out: load(&self.bytes_out)
+        }
+    }
+
+    /// Renders the counters in the Prometheus text exposition format
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * the metrics, one sample per line
+    pub fn render_metrics(&self) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut sample = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+            describe(&mut out, name, kind, help);
+            for (labels, value) in samples {
+                match labels.is_empty() {
+                    true => out.push_str(&format!("{} {}\n", name, value)),
+                    false => out.push_str(&format!("{}{{{}}} {}\n", name, labels, value))
+                }
+            }
+        };
+
+        sample("grid_uptime_seconds", "gauge", "Seconds since the server started.", vec![(String::new(), self.started.elapsed().as_secs())]);
+        sample("grid_connections_open", "gauge", "Connections open right now.", vec![(String::new(), self.connections.count() as u64)]);
+        sample("grid_connections_total", "counter", "Connections accepted.", vec![(String::new(), load(&self.connections_total))]);
+        sample(
+            "grid_tls_handshake_failures_total", "counter", "Connections dropped because their TLS handshake failed.",
+            vec![(String::new(), load(&self.handshake_failures))]
+        );
+        let mut requests: Vec<(String, u64)> = REQUEST_CODES.iter().zip(&self.requests).map(|(c, n)| (format!("opcode=\"{:?}\"", c), load(n))).collect();
+        requests.push(("opcode=\"invalid\"".to_string(), load(&self.invalid_requests)));
+        sample("grid_requests_total", "counter", "Requests answered, by OPCODE.", requests);
+        sample(
+            "grid_responses_total", "counter", "Responses sent, by response code.",
+            RESPONSE_CODES.iter().zip(&self.responses).map(|(c, n)| (format!("code=\"{:?}\"", c), load(n))).collect()
+        );
+        sample("grid_received_bytes_total", "counter", "Bytes of request frames received.", vec![(String::new(), load(&self.bytes_in))]);
+        sample("grid_sent_bytes_total", "counter", "Bytes of response frames sent.", vec![(String::new(), load(&self.bytes_out))]);
+        sample(
+            "grid_worker_queue_depth", "gauge", "Requests waiting for a worker thread.",
+            vec![(String::new(), self.worker_queue.load(Ordering::Relaxed) as u64)]
+        );
+
+        describe(&mut out, "grid_request_duration_seconds", "histogram", "Time from reading a request to having its response, by OPCODE.");
+        for (code, histogram) in REQUEST_CODES.iter().zip(&self.latency) {
+            histogram.write(&mut out, "grid_request_duration_seconds", &format!("opcode=\"{:?}\"", code), 1e6);
+        }
+        describe(&mut out, "grid_request_size_bytes", "histogram", "Size of request frames.");
+        self.request_sizes.write(&mut out, "grid_request_size_bytes", "", 1.0);
+        describe(&mut out, "grid_response_size_bytes", "histogram", "Size of response frames.");
+        self.response_sizes.write(&mut out, "grid_response_size_bytes", "", 1.0);
+        out
+    }
+}
+
+
+/// structure describing a server and its statistics, as carried by `INF` responses
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerInfo {
+    /// Name and version of the server software
+    pub software: String,
+    /// Request OPCODES the server answers
+    pub opcodes: Vec<String>,
+    /// Optional features, e.g. `zstd` or `tokens`
+    pub capabilities: Vec<String>,
+    /// Time since the server started
+    pub uptime: Duration,
+    /// Connections open right now
+    pub connections_open: u64,
+    /// Connections accepted since the server started
+    pub connections_total: u64,
+    /// Requests answered per OPCODE
+    pub requests: Vec<(String, u64)>,
+    /// Frames answered that carried no known request OPCODE
+    pub invalid_requests: u64,
+    /// Responses sent per response code
+    pub responses: Vec<(String, u64)>,
+    /// Bytes of request frames received
+    pub bytes_in: u64,
+    /// Bytes of response frames sent
+    pub bytes_out: u64
+}
+
+impl ServerInfo {
+    /// Encodes the information as structured headers
+    ///
+    /// Lists are space separated text, counters 8 byte integers. Counters per
+    /// OPCODE and response code are keyed `requests-get`, `responses-rok` and so on.
+    ///
+    /// ## Params:
+    /// None
+    ///
+    /// ## Returns:
+    /// * Ok: the headers
+    /// * Err: a string describing the issue encountered
+    pub fn to_metadata(&self) -> Result<Metadata, String> {
+        let mut headers = Metadata::new();
+        headers.set_text(MetadataKey::from_name("software")?, &self.software)?;
+        headers.set_text(MetadataKey::from_name("opcodes")?, &self.opcodes.join(" "))?;
+        headers.set_text(MetadataKey::from_name("capabilities")?, &self.capabilities.join(" "))?;
+
+        let mut counters = vec![
+            ("uptime".to_string(), self.uptime.as_secs()),
+            ("connections-open".to_string(), self.connections_open),
+            ("connections-total".to_string(), self.connections_total),
+            ("requests-invalid".to_string(), self.invalid_requests),
+            ("bytes-in".to_string(), self.bytes_in),
+            ("bytes-out".to_string(), self.bytes_out)
+        ];
+        counters.extend(self.requests.iter().map(|(c, n)| (format!("requests-{}", c.to_lowercase()), *n)));
+        counters.extend(self.responses.iter().map(|(c, n)| (format!("responses-{}", c.to_lowercase()), *n)));
+        for (name, value) in counters {
+            headers.insert(MetadataKey::from_name(&name)?, value.to_be_bytes().to_vec())?;
+        }
+        Ok(headers)
+    }
+
+    /// Reads the information back from structured headers
+    ///
+    /// Headers this version does not know are skipped.
+    ///
+    /// ## Params:
+    /// * headers: the headers of an `INF` response
+    ///
+    /// ## Returns:
+    /// * Ok: the information
+    /// * Err: a string naming the malformed header
+    pub fn from_metadata(headers: &Metadata) -> Result<Self, String> {
+        let mut info = ServerInfo::default();
+        for (key, value) in headers.iter() {
+            let name = key.name();
+            let text = || String::from_utf8_lossy(value).into_owned();
+            let list = || text().split_whitespace().map(|s| s.to_string()).collect::<Vec<String>>();
+            let counter = || match <[u8; 8]>::try_from(value) {
+                Ok(a) => Ok(u64::from_be_bytes(a)),
+                Err(_) => Err(format!("'{}' is not an 8 byte counter", name))
+            };
+            match name.as_str() {
+                "software" => info.software = text(),
+                "opcodes" => info.opcodes = list(),
+                "capabilities" => info.capabilities = list(),
+                "uptime" => info.uptime = Duration::from_secs(counter()?),
+                "connections-open" => info.connections_open = counter()?,
+                "connections-total" => info.connections_total = counter()?,
+                "requests-invalid" => info.invalid_requests = counter()?,
+                "bytes-in" => info.bytes_in = counter()?,
+                "bytes-out" => info.bytes_out = counter()?,
+                n if n.starts_with("requests-") => info.requests.push((n["requests-".len()..].to_uppercase(), counter()?)),
+                n if n.starts_with("responses-") => info.responses.push((n["responses-".len()..].to_uppercase(), counter()?)),
+                _ => ()
+            }
+        }
+        Ok(info)
+    }
+}
+
+
+/// Returns the capabilities every server built from this library has
+///
+/// ## Params:
+/// None
+///
+/// ## Returns:
+/// * the names of compression algorithms, digests and protocol features
+pub fn library_capabilities() -> Vec<String> {
+    let mut capabilities: Vec<String> = Compression::supported().iter().map(|c| format!("{:?}", c).to_lowercase()).collect();
+    capabilities.extend(["sha256", "blake3", "conditional", "structured-metadata"].iter().map(|c| c.to_string()));
+    capabilities
+}
+
+/// Helper function reading the OPCODE byte of a frame, if it is a request
+pub(crate) fn request_opcode(frame: &[u8]) -> Option<u8> {
+    match frame.first().map(|b| GridCode::from_byte(*b)) {
+        Some(Ok(GridCode::Request(code))) => Some(code as u8),
+        _ => None
+    }
+}
@@ -0,0 +1,491 @@
+// Defines where documents uploaded with PUT are kept
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::{debug, info, warn};
+
+
+use crate::path::{normalize_path, split_query};
+use crate::definitions::{GridBlock, GridResponseCode};
+use crate::metadata::Metadata;
+use crate::properties::Properties;
+use crate::server::error_response;
+
+
+/// File below a storage root recording who uploaded what
+pub const OWNERS_FILE: &str = ".grid-owners";
+
+/// Extension of the hidden files properties are kept in, next to their document
+pub const PROPERTIES_EXTENSION: &str = "props";
+
+
+/// structure describing what an upload is allowed to do
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadLimits {
+    /// Largest document accepted, in bytes
+    pub max_size: Option<u64>,
+    /// Bytes the whole store may hold
+    pub host_quota: Option<u64>,
+    /// Bytes a single user may have uploaded
+    pub user_quota: Option<u64>,
+    /// Whether a user may replace a document they uploaded before
+    pub overwrite: bool
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        UploadLimits { max_size: None, host_quota: None, user_quota: None, overwrite: true }
+    }
+}
+
+
+/// Describes why a document could not be read or stored
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageError {
+    /// Nothing is stored at the path
+    NotFound,
+    /// The path can't name a document, e.g. it is empty or hidden
+    InvalidPath(String),
+    /// A document exists at the path and overwrites are off
+    Exists,
+    /// The document at the path was not uploaded by this user
+    NotOwner,
+    /// The document is larger than allowed
+    TooLarge { size: u64, limit: u64 },
+    /// Storing the document would go over a quota
+    QuotaExceeded { quota: &'static str, limit: u64 },
+    /// The storage itself failed
+    Io(String)
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound => write!(f, "Not found"),
+            StorageError::InvalidPath(path) => write!(f, "Cannot store a document at '{}'", path),
+            StorageError::Exists => write!(f, "Document exists and overwrites are not allowed"),
+            StorageError::NotOwner => write!(f, "Document belongs to someone else"),
+            StorageError::TooLarge { size, limit } => write!(f, "Document of {} bytes is over the limit of {} bytes", size, limit),
+            StorageError::QuotaExceeded { quota, limit } => write!(f, "Upload would exceed the {} quota of {} bytes", quota, limit),
+            StorageError::Io(e) => write!(f, "Storage failed: {}", e)
+        }
+    }
+}
+
+
+/// defines a place documents can be uploaded to and read back from
+pub trait Storage: Send + Sync {
+    /// Reads a stored document
+    ///
+    /// ## Params:
+    /// * path: the request path of the document
+    ///
+    /// ## Returns:
+    /// * Ok: the document
+    /// * Err: why it could not be read
+    fn get(&self, path: &str) -> Result<Vec<u8>, StorageError>;
+
+    /// Stores a document, all at once or not at all
+    ///
+    /// ## Params:
+    /// * path: the request path of the document
+    /// * body: the document
+    /// * owner: the user uploading it
+    /// * limits: the size cap, quotas and overwrite rule to enforce
+    ///
+    /// ## Returns:
+    /// * Ok: nothing, the document is stored
+    /// * Err: why it was refused
+    fn put(&self, path: &str, body: &[u8], owner: &str, limits: &UploadLimits) -> Result<(), StorageError>;
+
+    /// Returns how many bytes are stored in total, or by one user
+    fn usage(&self, owner: Option<&str>) -> u64;
+
+    /// Returns the user who uploaded a document, if it was uploaded at all
+    fn owner(&self, path: &str) -> Option<String>;
+
+    /// Reads the properties of a document
+    ///
+    /// ## Params:
+    /// * path: the request path of the document
+    ///
+    /// ## Returns:
+    /// * Ok: the properties, empty if none were ever set
+    /// * Err: why they could not be read
+    fn properties(&self, path: &str) -> Result<Properties, StorageError>;
+
+    /// Changes the properties of a document without touching the document itself
+    ///
+    /// ## Params:
+    /// * path: the request path of the document
+    /// * changes: the properties to set, empty values removing theirs
+    /// * owner: the user asking, who must have uploaded the document
+    ///
+    /// ## Returns:
+    /// * Ok: the properties after the change
+    /// * Err: why they were not changed
+    fn set_properties(&self, path: &str, changes: &Properties, owner: &str) -> Result<Properties, StorageError>;
+}
+
+
+/// structure keeping track of who owns what in a `FileStorage`
+#[derive(Default)]
+struct Ledger {
+    total: Option<u64>,                         // bytes of every file below the root, once something asked
+    owners: HashMap<String, (String, u64)>      // path -> uploader and size
+}
+
+impl Ledger {
+    /// Helper function returning the bytes below the root, walking it the first time only
+    ///
+    /// Roots that never take uploads with a host quota are never walked.
+    fn total(&mut self, root: &Path) -> u64 {
+        *self.total.get_or_insert_with(|| directory_size(root))
+    }
+}
+
+/// structure defining a storage keeping documents as files below a root directory
+///
+/// Uploads are written to a temporary file next to their destination and renamed
+/// into place, so readers never see half a document. Who uploaded what is kept in
+/// `OWNERS_FILE` below the root.
+pub struct FileStorage {
+    root: PathBuf,
+    ledger: Mutex<Ledger>,
+    temp_counter: AtomicU64
+}
+
+impl FileStorage {
+    /// Opens a storage root, creating it if needed
+    ///
+    /// ## Params:
+    /// * root: the directory documents are kept in
+    ///
+    /// ## Returns:
+    /// * Ok: an instance of the structure
+    /// * Err: a string describing the issue encountered
+    pub fn open(root: impl AsRef<Path>) -> Result<Self, String> {
+        if let Err(e) = fs::create_dir_all(root.as_ref()) {
+            return Err(format!("Cannot create storage root {}: {}", root.as_ref().display(), e));
+        }
+        let root = match fs::canonicalize(root.as_ref()) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Cannot open storage root {}: {}", root.as_ref().display(), e))
+        };
+
+        // everything below the root counts against the host quota, uploaded or not,
+        // but it is only added up once a quota needs it
+        let mut ledger = Ledger::default();
+        if let Ok(contents) = fs::read_to_string(root.join(OWNERS_FILE)) {
+            for line in contents.lines() {
+                let (owner, path) = match line.split_once('\t') {
+                    Some(a) => a,
+                    None => continue
+                };
+                // files removed behind our back no longer count
+                if let Ok(meta) = fs::metadata(root.join(path.trim_start_matches('/'))) {
+                    ledger.owners.insert(path.to_string(), (owner.to_string(), meta.len()));
+                }
+            }
+        }
+        info!(root = %root.display(), uploads = ledger.owners.len(), "opened storage");
+
+        Ok(FileStorage { root, ledger: Mutex::new(ledger), temp_counter: AtomicU64::new(0) })
+    }
+
+    /// Returns the directory documents are kept in
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Helper function mapping a request path to a file below the root
+    ///
+    /// Hidden segments are refused so nobody can touch the owners file or temporary files.
+    fn locate(&self, path: &str) -> Result<(String, PathBuf), StorageError> {
+        let normalized = normalize_path(path);
+        let valid = normalized != "/"
+            && !normalized.chars().any(|c| c.is_control())
+            && normalized.split('/').skip(1).all(|segment| !segment.starts_with('.'));
+        if !valid {
+            return Err(StorageError::InvalidPath(path.to_string()));
+        }
+        let file = self.root.join(normalized.trim_start_matches('/'));
+        Ok((normalized, file))
+    }
+
+    /// Helper function naming the hidden file the properties of a document are kept in
+    fn properties_file(file: &Path) -> PathBuf {
+        let name = file.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        file.with_file_name(format!(".{}.{}", name, PROPERTIES_EXTENSION))
+    }
+
+    /// Helper function writing the owners file, atomically like everything else
+    fn save_ledger(&self, ledger: &Ledger) -> Result<(), StorageError> {
+        let mut contents = String::new();
+        for (path, (owner, _)) in &ledger.owners {
+            contents.push_str(&format!("{}\t{}\n", owner, path));
+        }
+        self.write_atomic(&self.root.join(OWNERS_FILE), contents.as_bytes())
+    }
+
+    /// Helper function creating the missing parent directories of a file, one at a time
+    ///
+    /// Each directory on the way is checked to lie inside the root before anything is
+    /// created in it, so a link inside the root can't have uploads build trees elsewhere.
+    ///
+    /// ## Returns:
+    /// * Ok: the parent directory, with any links in it resolved
+    /// * Err: `InvalidPath` if the way leaves the root or runs into a file
+    fn create_parents(&self, path: &str, file: &Path) -> Result<PathBuf, StorageError> {
+        let relative = match file.parent().map(|p| p.strip_prefix(&self.root)) {
+            Some(Ok(a)) => a,
+            _ => return Err(StorageError::InvalidPath(path.to_string()))
+        };
+
+        let mut current = self.root.clone();
+        for component in relative.components() {
+            current.push(component);
+            match fs::symlink_metadata(&current) {
+                Ok(meta) if meta.is_dir() => (),
+                // links may only lead to directories inside the root
+                Ok(meta) if meta.file_type().is_symlink() => match fs::canonicalize(&current) {
+                    Ok(a) if a.starts_with(&self.root) && a.is_dir() => current = a,
+                    _ => return Err(StorageError::InvalidPath(path.to_string()))
+                },
+                Ok(_) => return Err(StorageError::InvalidPath(path.to_string())),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    if let Err(e) = fs::create_dir(&current) {
+                        return Err(StorageError::Io(format!("{}: {}", current.display(), e)));
+                    }
+                },
+                Err(e) => return Err(StorageError::Io(format!("{}: {}", current.display(), e)))
+            }
+        }
+        Ok(current)
+    }
+
+    /// Helper function writing a file through a temporary file and a rename
+    fn write_atomic(&self, file: &Path, body: &[u8]) -> Result<(), StorageError> {
+        let name = file.file_name().and_then(|n| n.to_str()).unwrap_or("upload");
+        let temp = file.with_file_name(format!(
+            ".{}.tmp-{}-{}",
+            name,
+            std::process::id(),
+            self.temp_counter.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        let written = fs::File::create(&temp).and_then(|mut f| {
+            f.write_all(body)?;
+            f.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&temp, file)) {
+            let _ = fs::remove_file(&temp);
+            return Err(StorageError::Io(format!("{}: {}", file.display(), e)));
+        }
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&self, path: &str) -> Result<Vec<u8>, StorageError> {
+        let (_, file) = self.locate(path)?;
+        match fs::read(&file) {
+            Ok(a) => Ok(a),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(StorageError::NotFound),
+            Err(e) => Err(StorageError::Io(e.to_string()))
+        }
+    }
+
+    fn put(&self, path: &str, body: &[u8], owner: &str, limits: &UploadLimits) -> Result<(), StorageError> {
+        let (normalized, file) = self.locate(path)?;
+        let size = body.len() as u64;
+        if let Some(limit) = limits.max_size.filter(|limit| size > *limit) {
+            return Err(StorageError::TooLarge { size, limit });
+        }
+        // the owners file is line based, so owners can't span lines or columns
+        let owner: String = owner.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+
+        // one upload at a time, so quotas can't be raced past
+        let mut ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        let existing = match fs::symlink_metadata(&file) {
+            Ok(meta) if meta.is_file() => Some(meta.len()),
+            Ok(_) => return Err(StorageError::InvalidPath(path.to_string())),
+            Err(_) => None
+        };
+        if let Some(old_size) = existing {
+            if !limits.overwrite {
+                return Err(StorageError::Exists);
+            }
+            match ledger.owners.get(&normalized) {
+                Some((uploader, _)) if *uploader == owner => debug!(path = %normalized, old_size, "replacing upload"),
+                _ => return Err(StorageError::NotOwner)
+            }
+        }
+        let replaced = existing.unwrap_or(0);
+
+        if let Some(limit) = limits.host_quota {
+            if ledger.total(&self.root).saturating_sub(replaced) + size > limit {
+                return Err(StorageError::QuotaExceeded { quota: "host", limit });
+            }
+        }
+        if let Some(limit) = limits.user_quota {
+            let used: u64 = ledger.owners.values().filter(|(o, _)| *o == owner).map(|(_, s)| s).sum();
+            if used.saturating_sub(replaced) + size > limit {
+                return Err(StorageError::QuotaExceeded { quota: "user", limit });
+            }
+        }
+
+        // make the parent directories, without following links out of the root
+        let parent = self.create_parents(path, &file)?;
+        let file = match file.file_name() {
+            Some(name) => parent.join(name),
+            None => return Err(StorageError::InvalidPath(path.to_string()))
+        };
+
+        self.write_atomic(&file, body)?;
+        ledger.total = ledger.total.map(|total| total.saturating_sub(replaced) + size);
+        ledger.owners.insert(normalized.clone(), (owner.clone(), size));
+        if let Err(e) = self.save_ledger(&ledger) {
+            warn!(error = %e, "failed to record upload owner");
+        }
+        info!(path = %normalized, owner = %owner, bytes = size, "stored upload");
+        Ok(())
+    }
+
+    fn usage(&self, owner: Option<&str>) -> u64 {
+        let mut ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        match owner {
+            Some(owner) => ledger.owners.values().filter(|(o, _)| o == owner).map(|(_, s)| s).sum(),
+            None => ledger.total(&self.root)
+        }
+    }
+
+    fn owner(&self, path: &str) -> Option<String> {
+        let (normalized, _) = self.locate(path).ok()?;
+        let ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        ledger.owners.get(&normalized).map(|(owner, _)| owner.clone())
+    }
+
+    fn properties(&self, path: &str) -> Result<Properties, StorageError> {
+        let (_, file) = self.locate(path)?;
+        match fs::read(Self::properties_file(&file)) {
+            Ok(bytes) => Properties::parse(&bytes).map_err(StorageError::Io),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Properties::default()),
+            Err(e) => Err(StorageError::Io(e.to_string()))
+        }
+    }
+
+    fn set_properties(&self, path: &str, changes: &Properties, owner: &str) -> Result<Properties, StorageError> {
+        let (normalized, file) = self.locate(path)?;
+
+        // the ledger lock keeps concurrent changes to the same document from losing each other
+        let ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        if !file.is_file() {
+            return Err(StorageError::NotFound);
+        }
+        match ledger.owners.get(&normalized) {
+            Some((uploader, _)) if uploader == owner => (),
+            _ => return Err(StorageError::NotOwner)
+        }
+
+        let mut properties = self.properties(path)?;
+        properties.merge(changes);
+        let props_file = Self::properties_file(&file);
+        match properties.is_empty() {
+            true => match fs::remove_file(&props_file) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(StorageError::Io(e.to_string())),
+                _ => ()
+            },
+            false => self.write_atomic(&props_file, &properties.encode())?
+        }
+        info!(path = %normalized, owner = %owner, "updated properties");
+        Ok(properties)
+    }
+}
+
+
+/// Stores the body of a PUT request and builds the response to it
+///
+/// ## Params:
+/// * storage: where to store the document
+/// * limits: the size cap, quotas and overwrite rule to enforce
+/// * request: the PUT request
+/// * owner: the user uploading the document
+///
+/// ## Returns:
+/// * `ROK` once stored, `GER` with the reason otherwise
+pub fn handle_put(storage: &dyn Storage, limits: &UploadLimits, request: &GridBlock, owner: &str) -> GridBlock {
+    let path = match request.path() {
+        Ok(a) => split_query(&a).0,
+        Err(e) => return error_response(GridResponseCode::GER, &e)
+    };
+    match storage.put(&path, request.content(), owner, limits) {
+        Ok(_) => error_response(GridResponseCode::ROK, ""),
+        Err(e) => {
+            debug!(path = %path, owner = %owner, error = %e, "upload refused");
+            error_response(GridResponseCode::GER, &e.to_string())
+        }
+    }
+}
+
+/// Changes the properties of a document as asked by a SET request and builds the response
+///
+/// ## Params:
+/// * storage: where the document is stored
+/// * request: the SET request, carrying the changes as structured headers
+/// * owner: the user asking
+///
+/// ## Returns:
+/// * `ROK` carrying the properties after the change, `NOF` if there is no such
+///   document, `GER` with the reason otherwise
+pub fn handle_set(storage: &dyn Storage, request: &GridBlock, owner: &str) -> GridBlock {
+    let path = match request.path() {
+        Ok(a) => split_query(&a).0,
+        Err(e) => return error_response(GridResponseCode::GER, &e)
+    };
+    let changes = match request.headers() {
+        Ok(Some(headers)) => Properties::from_metadata(&headers),
+        Ok(None) => Err("SET carries the properties as structured metadata".to_string()),
+        Err(e) => Err(e)
+    };
+    let changes = match changes {
+        Ok(a) => a,
+        Err(e) => return error_response(GridResponseCode::GER, &e)
+    };
+    match storage.set_properties(&path, &changes, owner) {
+        Ok(properties) => {
+            let mut headers = Metadata::new();
+            match properties.write_metadata(&mut headers) {
+                // building a block without a path can't fail
+                Ok(_) => GridBlock::with_metadata(GridResponseCode::ROK, None, &headers, &[]).unwrap(),
+                Err(e) => error_response(GridResponseCode::GER, &e)
+            }
+        },
+        Err(StorageError::NotFound) => error_response(GridResponseCode::NOF, "Not found"),
+        Err(e) => {
+            debug!(path = %path, owner = %owner, error = %e, "property change refused");
+            error_response(GridResponseCode::GER, &e.to_string())
+        }
+    }
+}
+
+/// Helper function adding up the sizes of the files below a directory
+fn directory_size(dir: &Path) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(a) => a,
+        Err(_) => return 0
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name() != OWNERS_FILE)
+        .map(|e| match e.file_type() {
+            Ok(t) if t.is_dir() => directory_size(&e.path()),
+            Ok(t) if t.is_file() => e.metadata().map(|m| m.len()).unwrap_or(0),
+            _ => 0
+        })
+        .sum()
+}
@@ -0,0 +1,106 @@
+// Defines the byte transports GRID clients and servers run over
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+
+
+/// Any duplex byte stream a GRID connection can run over
+///
+/// TCP sockets and in-process pipes both qualify. TLS, if any, is layered on top
+/// by the client and server.
+pub trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Transport for T {}
+
+
+
+/// one direction of a `MemoryPipe`
+#[derive(Default)]
+struct PipeBuffer {
+    state: Mutex<(VecDeque<u8>, bool)>,    // pending bytes and whether the writer has gone away
+    ready: Condvar
+}
+
+impl PipeBuffer {
+    /// Marks the buffer as closed and wakes any blocked readers
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.1 = true;
+        self.ready.notify_all();
+    }
+}
+
+
+/// structure defining one end of an in-process duplex pipe
+///
+/// Reads block until the other end writes or is dropped, at which point they
+/// return 0 like a closed socket.
+pub struct MemoryPipe {
+    incoming: Arc<PipeBuffer>,
+    outgoing: Arc<PipeBuffer>
+}
+
+/// Creates a connected pair of in-process pipe ends
+///
+/// ## Params:
+/// None
+///
+/// ## Returns:
+/// A tuple of two `MemoryPipe`s; bytes written to one can be read from the other
+pub fn pipe() -> (MemoryPipe, MemoryPipe) {
+    let a = Arc::new(PipeBuffer::default());
+    let b = Arc::new(PipeBuffer::default());
+
+    (
+        MemoryPipe { incoming: a.clone(), outgoing: b.clone() },
+        MemoryPipe { incoming: b, outgoing: a }
+    )
+}
+
+impl MemoryPipe {
+    /// Closes the pipe in both directions
+    pub fn shutdown(&self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+impl Read for MemoryPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.incoming.state.lock().unwrap();
+
+        // wait until we have something or the writer is gone
+        while state.0.is_empty() && !state.1 {
+            state = self.incoming.ready.wait(state).unwrap();
+        }
+
+        let count = buf.len().min(state.0.len());
+        for (dst, src) in buf.iter_mut().zip(state.0.drain(..count)) {
+            *dst = src;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for MemoryPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.state.lock().unwrap();
+        if state.1 {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed"));
+        }
+
+        state.0.extend(buf);
+        self.outgoing.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemoryPipe {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -0,0 +1,70 @@
+// Defines the worker threads request handlers run on
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+
+/// a unit of work handed to the pool
+pub(crate) type Job = Box<dyn FnOnce() + Send>;
+
+
+/// structure defining a fixed set of threads working through a bounded queue of jobs
+///
+/// Dropping the pool lets the threads finish the jobs they hold and exit.
+pub(crate) struct WorkerPool {
+    jobs: Sender<Job>,
+    queued: Arc<AtomicUsize>,   // jobs submitted but not picked up by a thread yet
+    capacity: usize
+}
+
+impl WorkerPool {
+    /// Creates a new `WorkerPool` instance and starts its threads
+    ///
+    /// ## Params:
+    /// * threads: how many threads to start, at least 1
+    /// * capacity: how many jobs may wait for a thread, at least 1
+    /// * queued: counts the jobs waiting for a thread, shared with whoever reports it
+    ///
+    /// ## Returns:
+    /// * Ok: an instance of the structure
+    /// * Err: a string describing the issue encountered
+    pub fn new(threads: usize, capacity: usize, queued: Arc<AtomicUsize>) -> Result<Self, String> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..threads.max(1) {
+            let (receiver, queued) = (receiver.clone(), queued.clone());
+            let spawned = thread::Builder::new().name(format!("grid-worker-{}", i)).spawn(move || loop {
+                // only hold the lock while waiting, so the others can pick up work meanwhile
+                let job = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                match job {
+                    Ok(job) => {
+                        queued.fetch_sub(1, Ordering::SeqCst);
+                        job();
+                    },
+                    Err(_) => return
+                }
+            });
+            if let Err(e) = spawned {
+                return Err(format!("Failed to start worker thread: {}", e));
+            }
+        }
+        Ok(WorkerPool { jobs, queued, capacity: capacity.max(1) })
+    }
+
+    /// Returns whether another job fits in the queue
+    pub fn has_room(&self) -> bool {
+        self.queued.load(Ordering::SeqCst) < self.capacity
+    }
+
+    /// Hands a job to the threads
+    ///
+    /// Callers check `has_room` first, the queue itself never refuses a job.
+    pub fn submit(&self, job: Job) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        if self.jobs.send(job).is_err() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
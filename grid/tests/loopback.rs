@@ -0,0 +1,670 @@
+// Exercises GridClient and GridServer together over in-process pipes
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use grid::ca::CertificateAuthority;
+use grid::cache::ResponseCache;
+use grid::client::{GridClient, tls_config, tls_config_with_identity};
+use grid::definitions::{
+    GridBlock,
+    GridCode,
+    GridRequestCode,
+    GridResponseCode,
+    Compression,
+    DigestAlgorithm
+};
+use grid::metadata::{Metadata, MetadataKey};
+use grid::access_log::{AccessLog, AccessLogFormat};
+use grid::admission::RateLimit;
+use grid::auth::{AccessControl, AclRule, TokenVerifier, issue_token};
+use grid::server::{GridServer, NotFoundHandler, RequestContext, error_response, gen_certificate};
+use grid::transport::pipe;
+
+
+/// Builds a server backed by a tiny in-memory document store
+fn document_server(server: &mut GridServer) {
+    let store: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::new(Mutex::new(HashMap::new()));
+    store.lock().unwrap().insert("/index".to_string(), b"hello grid".to_vec());
+
+    server.set_handler(move |request: &GridBlock, ctx: &RequestContext| {
+        let path = request.path().unwrap();
+        let mut docs = store.lock().unwrap();
+        match request.opcode() {
+            GridCode::Request(GridRequestCode::GET) if path == "/whoami" => error_response(GridResponseCode::ROK, ctx.identity().unwrap_or("")),
+            GridCode::Request(GridRequestCode::GET) => match docs.get(&path) {
+                Some(body) => GridBlock::new(GridResponseCode::ROK, None, &mut body.clone()).unwrap(),
+                None => error_response(GridResponseCode::NOF, "no such document")
+            },
+            GridCode::Request(GridRequestCode::PUT) => {
+                docs.insert(path, request.content().to_vec());
+                error_response(GridResponseCode::ROK, "")
+            },
+            GridCode::Request(GridRequestCode::SET) => match docs.contains_key(&path) {
+                true => error_response(GridResponseCode::ROK, ""),
+                false => error_response(GridResponseCode::NOF, "no such document")
+            },
+            _ => error_response(GridResponseCode::BSY, "try again later")
+        }
+    });
+}
+
+/// Spins up a plaintext `server` on one end of a pipe and returns a client on the other
+fn connect(server: GridServer) -> GridClient {
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_transport(server_end, None));
+    GridClient::with_transport(client_end, "grid!localhost", None).unwrap()
+}
+
+/// Builds a TLS server with a fresh self-signed certificate and a client trusting it
+fn tls_pair() -> GridClient {
+    let certs = gen_certificate(None).unwrap();
+    let roots = certs.clone().get_certificates().unwrap();
+    let mut server = GridServer::new(0, Some(certs)).unwrap();
+    document_server(&mut server);
+
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_transport(server_end, None));
+    GridClient::with_transport(client_end, "grid!localhost", Some(tls_config(&roots).unwrap())).unwrap()
+}
+
+fn request(client: &mut GridClient, code: GridRequestCode, path: &str, body: &[u8]) -> GridBlock {
+    let mut block = GridBlock::new(code, Some(path), &mut body.to_vec()).unwrap();
+    client.send(&mut block).unwrap()
+}
+
+
+#[test]
+fn plaintext_get_put_set() {
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    let mut client = connect(server);
+
+    let response = request(&mut client, GridRequestCode::GET, "/index", b"");
+    assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::ROK));
+    assert_eq!(response.content(), b"hello grid");
+
+    let response = request(&mut client, GridRequestCode::GET, "/missing", b"");
+    assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::NOF));
+
+    let response = request(&mut client, GridRequestCode::PUT, "/new", b"fresh document");
+    assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::ROK));
+    let response = request(&mut client, GridRequestCode::GET, "/new", b"");
+    assert_eq!(response.content(), b"fresh document");
+
+    let response = request(&mut client, GridRequestCode::SET, "/new", b"");
+    assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::ROK));
+    let response = request(&mut client, GridRequestCode::SET, "/gone", b"");
+    assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::NOF));
+
+    let response = request(&mut client, GridRequestCode::CER, "/", b"");
+    assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::BSY));
+}
+
+#[test]
+fn tls_get_put() {
+    let mut client = tls_pair();
+
+    let response = request(&mut client, GridRequestCode::GET, "/index", b"");
+    assert_eq!(response.content(), b"hello grid");
+
+    let response = request(&mut client, GridRequestCode::PUT, "/new", b"over tls");
+    assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::ROK));
+    let response = request(&mut client, GridRequestCode::GET, "/new", b"");
+    assert_eq!(response.content(), b"over tls");
+}
+
+#[test]
+fn untrusted_certificate_is_rejected() {
+    let mut server = GridServer::new(0, Some(gen_certificate(None).unwrap())).unwrap();
+    document_server(&mut server);
+
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_transport(server_end, None));
+    assert!(GridClient::with_transport(client_end, "grid!localhost", Some(tls_config(&[]).unwrap())).is_err());
+}
+
+#[test]
+fn malformed_request_gets_general_error() {
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    let (mut client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_transport(server_end, None));
+
+    // a full header claiming to be a response code
+    let mut frame = vec![GridResponseCode::ROK as u8];
+    frame.extend_from_slice(&[0u8; 48]);
+    client_end.write_all(&frame).unwrap();
+
+    let mut reply = vec![0u8; 49];
+    client_end.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[0], GridResponseCode::GER as u8);
+
+    // a request carrying a digest header too short for its algorithm fails its integrity check
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    let (mut client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_transport(server_end, None));
+    let mut headers = Metadata::new();
+    headers.insert(MetadataKey::Digest, vec![DigestAlgorithm::Sha256 as u8, 0, 0]).unwrap();
+    let frame = GridBlock::with_metadata(GridRequestCode::PUT, Some("/index"), &headers, b"tiny").unwrap().serialize();
+    client_end.write_all(&frame).unwrap();
+    let response = read_frame(&mut client_end);
+    assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::GER));
+    assert!(String::from_utf8_lossy(response.content()).contains("Integrity check failed"));
+}
+
+#[test]
+fn oversized_responses_are_refused() {
+    // a server claiming an absurd length must not make the client allocate it
+    let (client_end, mut server_end) = pipe();
+    let mut client = GridClient::with_transport(client_end, "grid!localhost", None).unwrap();
+    thread::spawn(move || {
+        read_frame(&mut server_end);
+        let mut header = vec![GridResponseCode::ROK as u8];
+        header.extend_from_slice(&0u128.to_be_bytes());
+        header.extend_from_slice(&(u128::MAX / 2).to_be_bytes());
+        header.extend_from_slice(&0u128.to_be_bytes());
+        server_end.write_all(&header).unwrap();
+    });
+    let mut block = GridBlock::new(GridRequestCode::GET, Some("/index"), &mut Vec::new()).unwrap();
+    assert!(client.send(&mut block).unwrap_err().to_string().contains("exceeds the limit"));
+
+    // and the limit can be lowered below what a real server sends
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    let mut client = connect(server);
+    client.set_max_response_size(16);
+    let mut block = GridBlock::new(GridRequestCode::GET, Some("/index"), &mut Vec::new()).unwrap();
+    assert!(client.send(&mut block).is_err());
+}
+
+#[test]
+fn compression_digest_and_revalidation() {
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    let mut client = connect(server);
+
+    // large bodies go both ways compressed once the server has advertised support
+    let body = "compress me please ".repeat(500).into_bytes();
+    request(&mut client, GridRequestCode::PUT, "/big", b"");
+    request(&mut client, GridRequestCode::PUT, "/big", &body);
+    let response = request(&mut client, GridRequestCode::GET, "/big", b"");
+    assert_eq!(response.content(), &body[..]);
+
+    // digests are attached on request
+    let mut get = GridBlock::new(GridRequestCode::GET, Some("/big"), &mut Vec::new()).unwrap();
+    get.request_digest(Some(DigestAlgorithm::Blake3));
+    let response = client.send(&mut get).unwrap();
+    assert_eq!(response.verify_digest().unwrap().0, DigestAlgorithm::Blake3);
+
+    // cached documents are revalidated instead of refetched
+    client.set_cache(ResponseCache::new(8));
+    assert_eq!(client.get("/big").unwrap().content(), &body[..]);
+    let mut conditional = GridBlock::new(GridRequestCode::GET, Some("/big"), &mut Vec::new()).unwrap();
+    conditional.set_condition(&GridBlock::new(GridResponseCode::ROK, None, &mut body.clone()).unwrap().validator()).unwrap();
+    let response = client.send(&mut conditional).unwrap();
+    assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::NMD));
+    assert_eq!(client.get("/big").unwrap().content(), &body[..]);
+
+    // metadata that only fits the limit while compressed is refused
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    server.set_max_request_size(1024);
+    let mut client = connect(server);
+    request(&mut client, GridRequestCode::PUT, "/big", b"");
+    let mut bomb = GridBlock::new(GridRequestCode::PUT, Some("/big"), &mut vec![0u8; 64 * 1024]).unwrap();
+    bomb.compress(Compression::negotiate(Compression::supported_mask()), 0).unwrap();
+    assert!(bomb.clone().serialize().len() < 1024);
+    let response = client.send(&mut bomb).unwrap();
+    assert_eq!(response.opcode(), GridCode::Response(GridResponseCode::GER));
+}
+
+#[test]
+fn tcp_event_loop() {
+    // the mio event loop should behave just like the pipe transport
+    let certs = gen_certificate(None).unwrap();
+    let roots = certs.clone().get_certificates().unwrap();
+    let mut server = GridServer::new(0, Some(certs)).unwrap();
+    document_server(&mut server);
+    server.bind_to("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let config = tls_config(&roots).unwrap();
+    for _ in 0..2 {
+        let stream = std::net::TcpStream::connect(address).unwrap();
+        let mut client = GridClient::with_transport(stream, "grid!localhost", Some(config.clone())).unwrap();
+        let body = "over the wire ".repeat(4000).into_bytes();
+        request(&mut client, GridRequestCode::PUT, "/wire", &body);
+        assert_eq!(request(&mut client, GridRequestCode::GET, "/wire", b"").content(), &body[..]);
+    }
+}
+
+#[test]
+fn virtual_hosts_by_sni() {
+    let default = gen_certificate(None).unwrap();
+    let alpha = gen_certificate(Some(vec!["alpha.test".to_string(), "*.alpha.test".to_string()])).unwrap();
+    let beta = gen_certificate(Some(vec!["beta.test".to_string()])).unwrap();
+
+    let mut roots = default.clone().get_certificates().unwrap();
+    roots.extend(alpha.clone().get_certificates().unwrap());
+    roots.extend(beta.clone().get_certificates().unwrap());
+    let config = tls_config(&roots).unwrap();
+
+    let mut server = GridServer::new(0, Some(default)).unwrap();
+    server.set_handler(|_: &GridBlock, _: &RequestContext| error_response(GridResponseCode::ROK, "default"));
+    server.add_host(alpha, |_: &GridBlock, _: &RequestContext| error_response(GridResponseCode::ROK, "alpha")).unwrap();
+    server.add_host(beta, |_: &GridBlock, ctx: &RequestContext| {
+        error_response(GridResponseCode::ROK, ctx.server_name.as_deref().unwrap_or(""))
+    }).unwrap();
+    let server = Arc::new(server);
+
+    for (remote, expected) in [
+        ("grid!localhost", "default"),
+        ("grid!alpha.test", "alpha"),
+        ("grid!docs.alpha.test", "alpha"),
+        ("grid!beta.test", "beta.test")
+    ] {
+        let (client_end, server_end) = pipe();
+        let server = server.clone();
+        thread::spawn(move || server.serve_transport(server_end, None));
+
+        // each host presents its own certificate, so the handshake only succeeds if SNI picked it
+        let mut client = GridClient::with_transport(client_end, remote, Some(config.clone())).unwrap();
+        assert_eq!(request(&mut client, GridRequestCode::GET, "/", b"").content(), expected.as_bytes());
+    }
+
+    assert!(GridServer::plaintext(0).add_host(gen_certificate(None).unwrap(), NotFoundHandler).is_err());
+}
+
+#[test]
+fn private_ca_and_client_certificates() {
+    let dir = std::env::temp_dir().join(format!("grid-ca-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let ca = CertificateAuthority::create(&dir, "GRID test CA").unwrap();
+    assert!(CertificateAuthority::create(&dir, "GRID test CA").is_err());
+
+    let server_certs = ca.issue_server(vec!["localhost".to_string()], 30, &dir.join("server.pem"), &dir.join("server.key")).unwrap();
+    let alice = ca.issue_client("alice", 30, &dir.join("alice.pem"), &dir.join("alice.key")).unwrap();
+    let roots = [CertificateAuthority::open(&dir).unwrap().certificate()];
+
+    let serve = |crls: Vec<Vec<u8>>, identity| {
+        let mut server = GridServer::new(0, Some(server_certs.clone())).unwrap();
+        server.set_client_ca(&roots, crls, true).unwrap();
+        server.set_handler(|_: &GridBlock, ctx: &RequestContext| {
+            error_response(GridResponseCode::ROK, ctx.client_subject.as_deref().unwrap_or(""))
+        });
+
+        let (client_end, server_end) = pipe();
+        thread::spawn(move || server.serve_transport(server_end, None));
+        let config = tls_config_with_identity(&roots, identity).unwrap();
+        GridClient::with_transport(client_end, "grid!localhost", Some(config)).and_then(|mut client| {
+            let mut block = GridBlock::new(GridRequestCode::GET, Some("/"), &mut Vec::new()).unwrap();
+            client.send(&mut block).map_err(String::from)
+        })
+    };
+
+    // the server trusts the CA for the client and the client trusts it for the server
+    assert_eq!(serve(vec![ca.crl().unwrap()], Some(alice.clone())).unwrap().content(), b"CN=alice");
+    assert!(serve(vec![], None).is_err());
+
+    // once revoked, the client certificate is turned away
+    ca.revoke(&alice.clone().get_certificates().unwrap()[0]).unwrap();
+    let crls = grid::ca::load_crls(dir.join("crl.pem")).unwrap();
+    assert!(serve(crls, Some(alice)).is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn reload_swaps_certificates_and_handlers() {
+    let old = gen_certificate(None).unwrap();
+    let new = gen_certificate(None).unwrap();
+    let old_roots = tls_config(&old.clone().get_certificates().unwrap()).unwrap();
+    let new_roots = tls_config(&new.clone().get_certificates().unwrap()).unwrap();
+
+    let mut server = GridServer::new(0, Some(old)).unwrap();
+    server.set_handler(|_: &GridBlock, _: &RequestContext| error_response(GridResponseCode::ROK, "old"));
+    let handle = server.reload_handle();
+    let server = Arc::new(server);
+    let connect = |roots: &Arc<rustls::ClientConfig>| {
+        let (client_end, server_end) = pipe();
+        let server = server.clone();
+        thread::spawn(move || server.serve_transport(server_end, None));
+        GridClient::with_transport(client_end, "grid!localhost", Some(roots.clone()))
+    };
+
+    let mut established = connect(&old_roots).unwrap();
+    assert_eq!(request(&mut established, GridRequestCode::GET, "/", b"").content(), b"old");
+
+    let mut next = GridServer::new(0, Some(new)).unwrap();
+    next.set_handler(|_: &GridBlock, _: &RequestContext| error_response(GridResponseCode::ROK, "new"));
+    handle.reload(next).unwrap();
+
+    // the established session carries on, new handshakes see the new certificate
+    assert_eq!(request(&mut established, GridRequestCode::GET, "/", b"").content(), b"new");
+    assert!(connect(&old_roots).is_err());
+    let mut fresh = connect(&new_roots).unwrap();
+    assert_eq!(request(&mut fresh, GridRequestCode::GET, "/", b"").content(), b"new");
+
+    // a server that can't take over is rejected and the current settings stay
+    assert!(handle.reload(GridServer::plaintext(0)).is_err());
+    assert!(connect(&new_roots).is_ok());
+}
+
+#[test]
+fn graceful_shutdown() {
+    // requests arriving after a shutdown are answered with BSY
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    let handle = server.shutdown_handle();
+    let mut client = connect(server);
+    assert_eq!(request(&mut client, GridRequestCode::GET, "/index", b"").content(), b"hello grid");
+    handle.shutdown(Duration::from_secs(5));
+    assert_eq!(request(&mut client, GridRequestCode::GET, "/index", b"").opcode(), GridCode::Response(GridResponseCode::BSY));
+
+    // the event loop closes idle connections, stops listening and returns
+    let certs = gen_certificate(None).unwrap();
+    let config = tls_config(&certs.clone().get_certificates().unwrap()).unwrap();
+    let mut server = GridServer::new(0, Some(certs)).unwrap();
+    document_server(&mut server);
+    server.bind_to("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    let stream = std::net::TcpStream::connect(address).unwrap();
+    let mut client = GridClient::with_transport(stream, "grid!localhost", Some(config.clone())).unwrap();
+    assert_eq!(request(&mut client, GridRequestCode::GET, "/index", b"").content(), b"hello grid");
+
+    let started = Instant::now();
+    handle.shutdown(Duration::from_secs(5));
+    assert!(running.join().unwrap().is_ok());
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    let mut block = GridBlock::new(GridRequestCode::GET, Some("/index"), &mut Vec::new()).unwrap();
+    assert!(client.send(&mut block).is_err());
+    assert!(std::net::TcpStream::connect(address).is_err());
+}
+
+/// Reads one whole response frame off a raw plaintext stream
+fn read_frame(stream: &mut impl Read) -> GridBlock {
+    let mut frame = vec![0u8; 49];
+    stream.read_exact(&mut frame).unwrap();
+    let path_size = u128::from_be_bytes(frame[1..17].try_into().unwrap());
+    let metadata_size = u128::from_be_bytes(frame[17..33].try_into().unwrap());
+    let mut rest = vec![0u8; (path_size + metadata_size) as usize];
+    stream.read_exact(&mut rest).unwrap();
+    frame.extend_from_slice(&rest);
+    GridBlock::from_bytes(frame).unwrap()
+}
+
+#[test]
+fn connection_and_rate_limits() {
+    let busy = GridCode::Response(GridResponseCode::BSY);
+
+    // a client over its rate limit is told when to come back
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    server.set_ip_rate_limit(Some(RateLimit::new(1.0, 2)));
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_transport(server_end, Some("127.0.0.1:9".parse().unwrap())));
+    let mut client = GridClient::with_transport(client_end, "grid!localhost", None).unwrap();
+    assert_eq!(request(&mut client, GridRequestCode::GET, "/index", b"").content(), b"hello grid");
+    assert_eq!(request(&mut client, GridRequestCode::GET, "/index", b"").content(), b"hello grid");
+    let refused = request(&mut client, GridRequestCode::GET, "/index", b"");
+    assert_eq!(refused.opcode(), busy);
+    let wait = refused.retry_after().unwrap();
+    assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+
+    // connections beyond the limit get a BSY and are hung up on
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    server.set_max_connections(Some(1));
+    let server = Arc::new(server);
+    let (first_end, server_end) = pipe();
+    let first_server = server.clone();
+    thread::spawn(move || first_server.serve_transport(server_end, None));
+    let mut first = GridClient::with_transport(first_end, "grid!localhost", None).unwrap();
+    assert_eq!(request(&mut first, GridRequestCode::GET, "/index", b"").content(), b"hello grid");
+
+    let (second_end, server_end) = pipe();
+    let second_server = server.clone();
+    thread::spawn(move || second_server.serve_transport(server_end, None));
+    let mut second = GridClient::with_transport(second_end, "grid!localhost", None).unwrap();
+    let refused = request(&mut second, GridRequestCode::GET, "/index", b"");
+    assert_eq!(refused.opcode(), busy);
+    assert!(refused.retry_after().is_some());
+    assert_eq!(request(&mut first, GridRequestCode::GET, "/index", b"").content(), b"hello grid");
+
+    // pipelined requests beyond the in-flight limit are turned away
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    server.set_max_in_flight(Some(1));
+    let (mut client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_transport(server_end, None));
+    let mut frames = Vec::new();
+    for _ in 0..2 {
+        frames.extend(GridBlock::new(GridRequestCode::GET, Some("/index"), &mut Vec::new()).unwrap().serialize());
+    }
+    client_end.write_all(&frames).unwrap();
+    assert_eq!(read_frame(&mut client_end).content(), b"hello grid");
+    assert_eq!(read_frame(&mut client_end).opcode(), busy);
+}
+
+#[test]
+fn access_log_lines_and_rotation() {
+    let dir = std::env::temp_dir().join(format!("grid-access-log-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let log = Arc::new(AccessLog::file(&path, AccessLogFormat::Json).unwrap());
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    server.set_access_log(Some(log.clone()));
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_transport(server_end, Some("127.0.0.1:9".parse().unwrap())));
+    let mut client = GridClient::with_transport(client_end, "grid!localhost", None).unwrap();
+    request(&mut client, GridRequestCode::GET, "/index", b"");
+
+    // rotate the file away, the next request lands in a fresh one after reopening
+    std::fs::rename(&path, dir.join("access.log.1")).unwrap();
+    log.reopen().unwrap();
+    request(&mut client, GridRequestCode::GET, "/missing \"doc\"", b"");
+
+    let first = std::fs::read_to_string(dir.join("access.log.1")).unwrap();
+    assert_eq!(first.lines().count(), 1);
+    for field in ["\"peer\":\"127.0.0.1:9\"", "\"opcode\":\"GET\"", "\"path\":\"/index\"", "\"code\":\"ROK\"", "\"sni\":null"] {
+        assert!(first.contains(field), "{} missing from {}", field, first);
+    }
+    let second = std::fs::read_to_string(&path).unwrap();
+    assert!(second.contains("\"path\":\"/missing \\\"doc\\\"\""), "{}", second);
+    assert!(second.contains("\"code\":\"NOF\""));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn worker_pool_keeps_other_connections_moving() {
+    // "/slow" waits until the test lets it go
+    let (release, gate) = std::sync::mpsc::channel::<()>();
+    let gate = Mutex::new(gate);
+    let mut server = GridServer::plaintext(0);
+    server.set_handler(move |request: &GridBlock, _ctx: &RequestContext| {
+        let path = request.path().unwrap();
+        if path == "/slow" {
+            gate.lock().unwrap().recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+        GridBlock::new(GridResponseCode::ROK, None, &mut path.into_bytes()).unwrap()
+    });
+    server.set_workers(2, 4);
+    server.bind_to("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    // a slow request followed by a fast one on the same connection
+    let mut pipelined = std::net::TcpStream::connect(address).unwrap();
+    let mut frames = Vec::new();
+    for path in ["/slow", "/fast"] {
+        frames.extend(GridBlock::new(GridRequestCode::GET, Some(path), &mut Vec::new()).unwrap().serialize());
+    }
+    pipelined.write_all(&frames).unwrap();
+
+    // another connection is answered while the slow handler is stuck
+    let stream = std::net::TcpStream::connect(address).unwrap();
+    let mut other = GridClient::with_transport(stream, "grid!localhost", None).unwrap();
+    assert_eq!(request(&mut other, GridRequestCode::GET, "/other", b"").content(), b"/other");
+
+    // once released, the pipelined responses come back in order
+    release.send(()).unwrap();
+    assert_eq!(read_frame(&mut pipelined).content(), b"/slow");
+    assert_eq!(read_frame(&mut pipelined).content(), b"/fast");
+
+    // more slow requests than threads and queue slots together wait their turn instead of failing
+    let mut clients: Vec<std::net::TcpStream> = (0..8).map(|_| std::net::TcpStream::connect(address).unwrap()).collect();
+    for client in clients.iter_mut() {
+        client.write_all(&GridBlock::new(GridRequestCode::GET, Some("/slow"), &mut Vec::new()).unwrap().serialize()).unwrap();
+    }
+    for _ in 0..clients.len() {
+        release.send(()).unwrap();
+    }
+    for client in clients.iter_mut() {
+        assert_eq!(read_frame(client).content(), b"/slow");
+    }
+
+    // a shutdown waits for requests still with the workers
+    pipelined.write_all(&GridBlock::new(GridRequestCode::GET, Some("/slow"), &mut Vec::new()).unwrap().serialize()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let started = Instant::now();
+    handle.shutdown(Duration::from_secs(5));
+    release.send(()).unwrap();
+    assert_eq!(read_frame(&mut pipelined).content(), b"/slow");
+    assert!(running.join().unwrap().is_ok());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn tokens_and_path_rules() {
+    let denied = GridCode::Response(GridResponseCode::DNY);
+    let key = b"shared secret".to_vec();
+    let rules = vec![
+        AclRule::new("/private", "authenticated", true, false),
+        AclRule::new("/private", "alice", true, true)
+    ];
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    server.set_access_control(Some(Arc::new(AccessControl::new(Some(TokenVerifier::Hmac(key.clone())), rules))));
+    let server = Arc::new(server);
+    let connect_as = |token: Option<String>| {
+        let (client_end, server_end) = pipe();
+        let server = server.clone();
+        thread::spawn(move || server.serve_transport(server_end, None));
+        let mut client = GridClient::with_transport(client_end, "grid!localhost", None).unwrap();
+        client.set_auth_token(token);
+        client
+    };
+
+    // paths no rule covers stay open, covered ones need a token
+    let mut anonymous = connect_as(None);
+    assert_eq!(request(&mut anonymous, GridRequestCode::GET, "/index", b"").content(), b"hello grid");
+    let response = request(&mut anonymous, GridRequestCode::PUT, "/private/notes", b"sneaky");
+    assert_eq!(response.opcode(), denied);
+    assert_eq!(response.content(), b"Authentication required");
+
+    // alice may write, bob may only read, and forged tokens get nowhere
+    let mut alice = connect_as(Some(issue_token(&key, "alice", Duration::from_secs(60))));
+    assert_eq!(request(&mut alice, GridRequestCode::PUT, "/private/notes", b"mine").opcode(), GridCode::Response(GridResponseCode::ROK));
+    let mut bob = connect_as(Some(issue_token(&key, "bob", Duration::from_secs(60))));
+    assert_eq!(request(&mut bob, GridRequestCode::GET, "/private/notes", b"").content(), b"mine");
+    assert_eq!(request(&mut bob, GridRequestCode::PUT, "/private/notes", b"ours").content(), b"Permission denied");
+    let mut mallory = connect_as(Some(issue_token(b"guessed", "alice", Duration::from_secs(60))));
+    assert_eq!(request(&mut mallory, GridRequestCode::GET, "/index", b"").opcode(), denied);
+
+    // handlers see who the token belongs to
+    assert_eq!(request(&mut bob, GridRequestCode::GET, "/whoami", b"").content(), b"bob");
+    assert_eq!(request(&mut anonymous, GridRequestCode::GET, "/whoami", b"").content(), b"");
+
+    // conditional requests keep their token
+    bob.set_cache(ResponseCache::new(8));
+    assert_eq!(bob.get("/private/notes").unwrap().content(), b"mine");
+    assert_eq!(bob.get("/private/notes").unwrap().content(), b"mine");
+}
+
+#[test]
+fn info_reports_live_statistics() {
+    let mut server = GridServer::plaintext(0);
+    document_server(&mut server);
+    server.set_software("loopback/1.0");
+    let mut client = connect(server);
+
+    request(&mut client, GridRequestCode::GET, "/index", b"");
+    request(&mut client, GridRequestCode::GET, "/missing", b"");
+    request(&mut client, GridRequestCode::PUT, "/new", b"fresh");
+
+    // the INF request itself is only counted once it has been answered
+    let info = client.info().unwrap();
+    assert_eq!(info.software, "loopback/1.0");
+    assert!(info.opcodes.contains(&"INF".to_string()));
+    assert!(info.capabilities.contains(&"structured-metadata".to_string()));
+    assert_eq!(info.connections_open, 1);
+    let count = |list: &Vec<(String, u64)>, name: &str| list.iter().find(|(n, _)| n == name).map(|(_, c)| *c);
+    assert_eq!(count(&info.requests, "GET"), Some(2));
+    assert_eq!(count(&info.requests, "PUT"), Some(1));
+    assert_eq!(count(&info.requests, "INF"), Some(0));
+    assert_eq!(count(&info.responses, "ROK"), Some(2));
+    assert_eq!(count(&info.responses, "NOF"), Some(1));
+    assert!(info.bytes_out > info.bytes_in);
+    assert_eq!(count(&client.info().unwrap().requests, "INF"), Some(1));
+}
+
+#[test]
+fn metrics_are_served_over_http() {
+    let certs = gen_certificate(None).unwrap();
+    let roots = certs.clone().get_certificates().unwrap();
+    let mut server = GridServer::new(0, Some(certs)).unwrap();
+    document_server(&mut server);
+    let address = server.bind_metrics("127.0.0.1:0".parse().unwrap()).unwrap();
+    let server = Arc::new(server);
+
+    // a client that doesn't speak TLS fails its handshake
+    let (mut garbage, server_end) = pipe();
+    garbage.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert!(server.serve_transport(server_end, None).is_err());
+
+    let (client_end, server_end) = pipe();
+    let serving = server.clone();
+    thread::spawn(move || serving.serve_transport(server_end, None));
+    let mut client = GridClient::with_transport(client_end, "grid!localhost", Some(tls_config(&roots).unwrap())).unwrap();
+    request(&mut client, GridRequestCode::GET, "/index", b"");
+    request(&mut client, GridRequestCode::GET, "/missing", b"");
+
+    let scrape = |request: &str| {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = scrape("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    for line in [
+        "# TYPE grid_request_duration_seconds histogram",
+        "grid_connections_total 2",
+        "grid_connections_open 1",
+        "grid_tls_handshake_failures_total 1",
+        "grid_requests_total{opcode=\"GET\"} 2",
+        "grid_responses_total{code=\"NOF\"} 1",
+        "grid_request_duration_seconds_count{opcode=\"GET\"} 2",
+        "grid_request_duration_seconds_bucket{opcode=\"PUT\",le=\"+Inf\"} 0",
+        "grid_response_size_bytes_count 2",
+        "grid_worker_queue_depth 0"
+    ] {
+        assert!(response.lines().any(|l| l == line), "missing '{}' in:\n{}", line, response);
+    }
+    assert!(scrape("GET /other HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+    assert!(scrape("POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+}
@@ -0,0 +1,110 @@
+// Defines the `gtu ca` subcommand managing a local certificate authority
+use std::path::{Path, PathBuf};
+
+use clap::Subcommand;
+
+use grid::ca::{CertificateAuthority, load_certificates};
+use grid::identity::IDENTITY_VALIDITY_DAYS;
+
+
+#[derive(Subcommand, Debug)]
+pub enum CaAction {
+    /// Create a new root CA in the CA directory
+    Init {
+        /// Common name of the CA
+        #[arg(long="name", default_value="GRID local CA")]
+        name: String
+    },
+
+    /// Issue a certificate for a GRID server
+    Server {
+        /// DNS names and IP addresses the server answers to
+        #[arg(required=true)]
+        names: Vec<String>,
+
+        /// Write <OUT>.pem and <OUT>.key instead of <DIR>/<first name>.pem and .key
+        #[arg(short='o', long="out")]
+        out: Option<PathBuf>,
+
+        /// Days the certificate is valid for
+        #[arg(long="days", default_value_t=IDENTITY_VALIDITY_DAYS)]
+        days: i64
+    },
+
+    /// Issue a certificate for a GRID client
+    Client {
+        /// Name identifying the client, used as the common name
+        name: String,
+
+        /// Write <OUT>.pem and <OUT>.key instead of <DIR>/<name>.pem and .key
+        #[arg(short='o', long="out")]
+        out: Option<PathBuf>,
+
+        /// Days the certificate is valid for
+        #[arg(long="days", default_value_t=IDENTITY_VALIDITY_DAYS)]
+        days: i64
+    },
+
+    /// Revoke a certificate issued by the CA and rewrite the revocation list
+    Revoke {
+        /// PEM file holding the certificate to revoke
+        certificate: PathBuf
+    },
+
+    /// Sign a fresh revocation list, e.g. before the current one expires
+    Crl
+}
+
+
+/// Runs a CA subcommand
+///
+/// ## Params:
+/// * dir: the directory the CA is kept in
+/// * action: what to do
+///
+/// ## Returns:
+/// * Ok: nothing
+/// * Err: a string describing the issue encountered
+pub fn run(dir: &Path, action: CaAction) -> Result<(), String> {
+    match action {
+        CaAction::Init { name } => {
+            let ca = CertificateAuthority::create(dir, &name)?;
+            println!("Created CA {:?}, trust {}", name, ca.certificate_path().display());
+        },
+        CaAction::Server { names, out, days } => {
+            let ca = CertificateAuthority::open(dir)?;
+            let (cert_path, key_path) = output_paths(dir, out, &names[0]);
+            ca.issue_server(names, days, &cert_path, &key_path)?;
+            println!("Issued {} and {}", cert_path.display(), key_path.display());
+        },
+        CaAction::Client { name, out, days } => {
+            let ca = CertificateAuthority::open(dir)?;
+            let (cert_path, key_path) = output_paths(dir, out, &name);
+            ca.issue_client(&name, days, &cert_path, &key_path)?;
+            println!("Issued {} and {}", cert_path.display(), key_path.display());
+        },
+        CaAction::Revoke { certificate } => {
+            let ca = CertificateAuthority::open(dir)?;
+            let leaf = match load_certificates(&certificate)?.into_iter().next() {
+                Some(a) => a,
+                None => return Err(format!("No certificate in {}", certificate.display()))
+            };
+            let crl = ca.revoke(&leaf)?;
+            println!("Revoked {}, updated {}", certificate.display(), crl.display());
+        },
+        CaAction::Crl => {
+            let crl = CertificateAuthority::open(dir)?.write_crl()?;
+            println!("Wrote {}", crl.display());
+        }
+    }
+    Ok(())
+}
+
+/// Helper function picking where an issued certificate and its key go
+fn output_paths(dir: &Path, out: Option<PathBuf>, name: &str) -> (PathBuf, PathBuf) {
+    let stem = out.unwrap_or_else(|| dir.join(name.replace(['/', '\\', ':'], "_")));
+    (
+        PathBuf::from(format!("{}.pem", stem.display())),
+        PathBuf::from(format!("{}.key", stem.display()))
+    )
+}
@@ -1,31 +1,184 @@
-use grid::client::GridClient;
-use grid::definitions::{GridBlock, GridRequestCode};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
 
-use clap::Parser;
+use grid::auth::issue_token;
+use grid::ca::load_certificates;
+use grid::client::{GridClient, tls_config_with_identity};
+use grid::definitions::{GridBlock, GridRequestCode, DigestAlgorithm, to_hex};
+use grid::metadata::ValueKind;
+use grid::stats::ServerInfo;
+use grid::server::CertificateStore;
+
+use clap::{Parser, Subcommand};
+
+mod ca;
 
 #[derive(Parser, Debug)] // requires `derive` feature
-#[command(term_width = 0)] // Just to make testing across clap features easier
+#[command(term_width = 0, subcommand_negates_reqs = true)] // Just to make testing across clap features easier
 struct Arguments {
     /// The GRID remote address. Ex: grid!localhost:1337
-    #[arg(short='r', long="remote")]
-    remote: String
+    #[arg(short='r', long="remote", required=true)]
+    remote: Option<String>,
+
+    /// Path to fetch, with an optional query. Ex: /docs/?sort=size&order=desc
+    path: Option<String>,
+
+    /// PEM file with a CA certificate to trust besides the public roots
+    #[arg(long="ca")]
+    ca: Option<PathBuf>,
+
+    /// PEM client certificate chain to authenticate with
+    #[arg(long="cert", requires="key")]
+    cert: Option<PathBuf>,
+
+    /// PEM private key matching --cert
+    #[arg(long="key", requires="cert")]
+    key: Option<PathBuf>,
+
+    /// Bearer token to authenticate with. Defaults to the remote's entry in the tokens file
+    #[arg(long="token", env="GRID_TOKEN", hide_env_values=true)]
+    token: Option<String>,
+
+    /// File listing a token per remote as `remote token` lines [default: ~/.config/grid/tokens]
+    #[arg(long="tokens-file")]
+    tokens_file: Option<PathBuf>,
+
+    /// Ask the remote for a SHA-256 digest of the response, print it and check it
+    #[arg(long="verify")]
+    verify: bool,
+
+    /// Print libGRID diagnostics to stderr. Repeat for more detail (-vvv dumps frames)
+    #[arg(short='v', long="verbose", action=clap::ArgAction::Count)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Option<Command>
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage a local certificate authority for private GRID deployments
+    Ca {
+        /// Directory the CA is kept in
+        #[arg(short='d', long="dir", default_value="grid-ca")]
+        dir: PathBuf,
+
+        #[command(subcommand)]
+        action: ca::CaAction
+    },
+
+    /// Show what a server runs, what it supports and its live statistics
+    Info {
+        /// The GRID remote address. Ex: grid!localhost:1337
+        remote: String
+    },
+
+    /// Issue a token for servers checking tokens with an HMAC key
+    Token {
+        /// File holding the signing key shared with the server
+        #[arg(short='k', long="key")]
+        key: PathBuf,
+
+        /// Days the token is valid for
+        #[arg(long="days", default_value_t=30)]
+        days: u64,
+
+        /// Who the token stands for
+        principal: String
+    }
 }
 
 
 fn main() {
     let args = Arguments::parse();
 
+    // diagnostics go to stderr so the response can be piped
+    let level = match args.verbose {
+        0 => tracing::Level::WARN,
+        1 => tracing::Level::INFO,
+        2 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let mut info = None;
+    match args.command {
+        Some(Command::Info { remote }) => info = Some(remote),
+        Some(Command::Ca { dir, action }) => {
+            if let Err(e) = ca::run(&dir, action) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        },
+        Some(Command::Token { key, days, principal }) => {
+            let key = match fs::read(&key) {
+                Ok(a) => a,
+                Err(e) => {
+                    eprintln!("Failed to read {}: {}", key.display(), e);
+                    std::process::exit(1);
+                }
+            };
+            let valid_for = std::time::Duration::from_secs(days.saturating_mul(24 * 60 * 60));
+            println!("{}", issue_token(key.trim_ascii(), &principal, valid_for));
+            return;
+        },
+        None => ()
+    }
+
+    // trust the extra CA and present the client certificate, if we were given any
+    let roots = match &args.ca {
+        Some(path) => load_certificates(path).unwrap_or_else(|e| panic!("Failed to load CA: {}", e)),
+        None => Vec::new()
+    };
+    let identity = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => match CertificateStore::from_pem_files(cert, key, Vec::new()) {
+            Ok(a) => Some(a),
+            Err(e) => panic!("Failed to load client certificate: {}", e)
+        },
+        _ => None
+    };
+    let rc_config = match tls_config_with_identity(&roots, identity) {
+        Ok(a) => a,
+        Err(e) => panic!("Failed to build TLS configuration: {}", e)
+    };
+
     // build our client (DEBUG: CONNECTING TO LOCALHOST 1337)
-    let mut client = match GridClient::new(&args.remote) {
+    let remote = info.clone().or(args.remote).unwrap_or_default();
+    let mut client = match GridClient::with_tls(&remote, rc_config) {
         Ok(a) => a,
         Err(e) => panic!("Failed to initialize GRID client: {}", e)
     };
+    let tokens_file = args.tokens_file.unwrap_or_else(default_tokens_file);
+    match args.token.map(Ok).or_else(|| lookup_token(&tokens_file, &remote).transpose()) {
+        Some(Ok(token)) => client.set_auth_token(Some(token)),
+        Some(Err(e)) => panic!("Failed to read tokens file: {}", e),
+        None => ()
+    }
+
+    if info.is_some() {
+        match client.info() {
+            Ok(a) => print_info(&a),
+            Err(e) => {
+                eprintln!("Failed to get server information: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
     // build the GridBlock with a GET request
-    let mut request = match GridBlock::new(GridRequestCode::GET, None, &mut Vec::new()) {
+    let mut request = match GridBlock::new(GridRequestCode::GET, args.path.as_deref(), &mut Vec::new()) {
         Ok(a) => a,
         Err(e) => panic!("Failed to create new GRID request structure: {}", e)
     };
+    if args.verify {
+        request.request_digest(Some(DigestAlgorithm::Sha256));
+    }
 
     // send it to the server and print what we got
     let response = match client.send(&mut request) {
@@ -33,5 +186,102 @@ fn main() {
         Err(e) => panic!("Failed to send GRID Block: {}", e)
     };
 
-    println!("Response: {:?}", response);
+    // the code and headers go to stderr, so the content alone can be piped
+    eprintln!("Response: {:?}", response.opcode());
+    match response.headers() {
+        Ok(Some(headers)) => for (key, value) in headers.iter() {
+            match key.kind() {
+                ValueKind::Text => eprintln!("{}: {}", key.name(), String::from_utf8_lossy(value)),
+                ValueKind::Integer => eprintln!("{}: {}", key.name(), headers.get_integer(key).unwrap_or_default()),
+                ValueKind::Bytes => eprintln!("{}: {}", key.name(), to_hex(value))
+            }
+        },
+        Ok(None) => (),
+        Err(e) => eprintln!("Malformed headers: {}", e)
+    }
+    if let Err(e) = std::io::stdout().write_all(response.content()) {
+        eprintln!("Failed to write response: {}", e);
+        std::process::exit(1);
+    }
+
+    if args.verify {
+        match response.verify_digest() {
+            Ok((algorithm, digest)) => println!("Digest ({:?}): {} OK", algorithm, to_hex(&digest)),
+            Err(e) => {
+                eprintln!("Integrity check failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+
+/// Returns where tokens are looked up when none is given
+fn default_tokens_file() -> PathBuf {
+    let config = match (std::env::var_os("XDG_CONFIG_HOME"), std::env::var_os("HOME")) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(home)) => PathBuf::from(home).join(".config"),
+        (None, None) => PathBuf::from(".")
+    };
+    config.join("grid").join("tokens")
+}
+
+/// Looks up the token for a remote in a tokens file
+///
+/// ## Params:
+/// * path: the tokens file, made of `remote token` lines. `#` starts a comment line
+/// * remote: the remote as given on the command line
+///
+/// ## Returns:
+/// * Ok: the token, or `None` if the file does not exist or has no entry for the remote
+/// * Err: a string describing the issue encountered
+fn lookup_token(path: &PathBuf, remote: &str) -> Result<Option<String>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(a) => a,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: {}", path.display(), e))
+    };
+    let token = text
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| l.split_once(char::is_whitespace))
+        .find(|(r, _)| *r == remote)
+        .map(|(_, t)| t.trim().to_string());
+    Ok(token)
+}
+
+/// Prints what an `INF` request returned in a human friendly way
+fn print_info(info: &ServerInfo) {
+    let uptime = info.uptime.as_secs();
+    println!("Software:      {}", info.software);
+    println!("Uptime:        {}d {:02}:{:02}:{:02}", uptime / 86400, uptime / 3600 % 24, uptime / 60 % 60, uptime % 60);
+    println!("Opcodes:       {}", info.opcodes.join(" "));
+    println!("Capabilities:  {}", info.capabilities.join(" "));
+    println!("Connections:   {} open, {} total", info.connections_open, info.connections_total);
+    println!("Traffic:       {} in, {} out", format_bytes(info.bytes_in), format_bytes(info.bytes_out));
+    println!("Requests:");
+    for (opcode, count) in &info.requests {
+        println!("  {:<12}{}", opcode, count);
+    }
+    println!("  {:<12}{}", "invalid", info.invalid_requests);
+    println!("Responses:");
+    for (code, count) in &info.responses {
+        println!("  {:<12}{}", code, count);
+    }
+}
+
+/// Formats a byte count with binary units
+fn format_bytes(bytes: u64) -> String {
+    let mut value = bytes as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if value < 1024.0 {
+            return match unit {
+                "B" => format!("{} B", bytes),
+                _ => format!("{:.1} {}", value, unit)
+            };
+        }
+        value /= 1024.0;
+    }
+    format!("{:.1} TiB", value)
 }
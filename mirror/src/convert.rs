@@ -0,0 +1,440 @@
+// Defines how HTML pages are turned into GML documents
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use scraper::{ElementRef, Html, Node, Selector};
+
+use grid::path::{normalize_path, percent_decode, percent_encode_path};
+use grid::gml::{GmlDocument, GmlLine};
+
+
+/// Extensions of files only web browsers make use of, which are left out of the mirror
+pub const SKIPPED_EXTENSIONS: [&str; 6] = ["js", "css", "woff", "woff2", "ttf", "map"];
+
+/// Elements whose content is shown inline, as part of the surrounding paragraph
+const INLINE_ELEMENTS: [&str; 24] = [
+    "span", "code", "em", "strong", "b", "i", "u", "small", "sup", "sub", "kbd", "var", "abbr",
+    "cite", "q", "mark", "s", "del", "ins", "time", "label", "samp", "tt", "wbr"
+];
+
+/// Elements that only group other content
+const CONTAINER_ELEMENTS: [&str; 19] = [
+    "html", "body", "div", "p", "section", "article", "main", "header", "footer", "nav", "aside",
+    "details", "summary", "figure", "figcaption", "center", "dl", "dd", "address"
+];
+
+/// Elements that cannot be shown in GML and are dropped along with their content
+const DROPPED_ELEMENTS: [&str; 18] = [
+    "script", "style", "noscript", "template", "form", "input", "button", "select", "textarea",
+    "iframe", "svg", "canvas", "video", "audio", "object", "embed", "math", "rustdoc-search"
+];
+
+
+/// structure counting the constructs that could not be carried over as they were
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Report {
+    pub unsupported: BTreeMap<String, usize>    // what was lost, e.g. `<script> (dropped)`, and how often
+}
+
+impl Report {
+    /// Counts one occurrence of an unsupported construct
+    pub fn note(&mut self, what: &str) {
+        *self.unsupported.entry(what.to_string()).or_insert(0) += 1;
+    }
+
+    /// Returns the constructs, most frequent first
+    pub fn by_count(&self) -> Vec<(&str, usize)> {
+        let mut counts: Vec<(&str, usize)> = self.unsupported.iter().map(|(w, c)| (w.as_str(), *c)).collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+}
+
+
+/// structure rewriting the links of a page so they point into the mirror
+pub struct SiteLinks<'a> {
+    root: &'a Path,         // the HTML tree being imported
+    page: String,           // path of the page below `root`, e.g. `/std/vec/index.html`
+    base: &'a str           // what mirrored paths are appended to, e.g. `grid!docs.example:7500/rust`
+}
+
+impl<'a> SiteLinks<'a> {
+    /// Creates a new `SiteLinks` instance
+    ///
+    /// ## Params:
+    /// * root: the directory holding the HTML tree
+    /// * page: the path of the page below `root`
+    /// * base: the remote and path prefix the mirror is served at, without a trailing slash
+    ///
+    /// ## Returns:
+    /// * an instance of the structure
+    pub fn new(root: &'a Path, page: &str, base: &'a str) -> Self {
+        SiteLinks { root, page: normalize_path(page), base }
+    }
+
+    /// Rewrites a link found on the page
+    ///
+    /// Links to other sites are kept. Links into the tree become `grid!` URLs of
+    /// the mirrored documents, with HTML pages turned into their GML versions.
+    ///
+    /// ## Params:
+    /// * href: the link target as written in the page
+    ///
+    /// ## Returns:
+    /// * Ok: the target to use in GML
+    /// * Err: the kind of link that cannot be carried over, for the report
+    pub fn rewrite(&self, href: &str) -> Result<String, &'static str> {
+        let href = href.trim();
+        if href.is_empty() {
+            return Err("empty link");
+        }
+        if href.starts_with("//") {
+            return Ok(format!("https:{}", href));
+        }
+        // anything with a scheme leaves the site
+        if let Some((scheme, _)) = href.split_once(':') {
+            if !scheme.contains('/') && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) {
+                return match scheme.eq_ignore_ascii_case("javascript") {
+                    true => Err("javascript: link"),
+                    false => Ok(href.to_string())
+                };
+            }
+        }
+
+        // fragments and queries mean nothing to a static tree of GML documents
+        let path = href.split(['#', '?']).next().unwrap_or("");
+        if path.is_empty() {
+            return Err("in-page link");
+        }
+        let path = match path.starts_with('/') {
+            true => normalize_path(&percent_decode(path)),
+            false => {
+                let dir = &self.page[..self.page.rfind('/').unwrap_or(0) + 1];
+                normalize_path(&format!("{}{}", dir, percent_decode(path)))
+            }
+        };
+
+        let mut target = path.clone();
+        let file = self.root.join(path.trim_start_matches('/'));
+        if file.is_dir() {
+            if !file.join("index.html").is_file() {
+                return Err("link to a directory without index.html");
+            }
+            target = format!("{}/index.html", path.trim_end_matches('/'));
+        } else if !file.is_file() {
+            return Err("broken link");
+        }
+        if is_skipped(Path::new(&target)) {
+            return Err("link to a script or stylesheet");
+        }
+        Ok(format!("{}{}", self.base, percent_encode_path(&mirrored_path(&target))))
+    }
+}
+
+
+/// Converts an HTML page into a GML document
+///
+/// Headings, paragraphs, lists, quotes, preformatted blocks and links carry over.
+/// Tables are flattened into text; scripts, forms and media are dropped. Both
+/// are counted in the report.
+///
+/// ## Params:
+/// * html: the page
+/// * links: rewrites the links of the page
+/// * report: where constructs that didn't carry over are counted
+///
+/// ## Returns:
+/// * the document, headed by the page title if it has no `<h1>` of its own
+pub fn convert_page(html: &str, links: &SiteLinks, report: &mut Report) -> GmlDocument {
+    let page = Html::parse_document(html);
+    let mut converter = Converter { document: GmlDocument::new(), paragraph: String::new(), pending: Vec::new(), lists: Vec::new(), quote: false, links, report };
+
+    let body = Selector::parse("body").expect("static selector");
+    if let Some(body) = page.select(&body).next() {
+        converter.element(body);
+    }
+    converter.flush();
+
+    // a page without a top-level heading is named after its title
+    let mut document = converter.document;
+    let h1 = Selector::parse("h1").expect("static selector");
+    if page.select(&h1).next().is_none() {
+        let title = Selector::parse("title").expect("static selector");
+        if let Some(title) = page.select(&title).next().map(|t| collapse(&t.text().collect::<String>())) {
+            if !title.is_empty() {
+                document.lines.insert(0, GmlLine::Heading(1, title));
+            }
+        }
+    }
+    tidy(&mut document);
+    document
+}
+
+/// Returns where a file of the tree ends up in the mirror, e.g. `/a/b.gml` for `/a/b.html`
+pub fn mirrored_path(path: &str) -> String {
+    for extension in [".html", ".htm"] {
+        if let Some(stem) = path.strip_suffix(extension) {
+            return format!("{}.gml", stem);
+        }
+    }
+    path.to_string()
+}
+
+/// Returns whether a file is left out of the mirror
+pub fn is_skipped(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()).is_some_and(|e| SKIPPED_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+}
+
+
+/// structure walking the elements of a page, collecting GML lines as it goes
+struct Converter<'a, 'r> {
+    document: GmlDocument,
+    paragraph: String,              // inline text waiting for the end of its block
+    pending: Vec<(String, String)>, // links met in the paragraph, emitted after it
+    lists: Vec<Option<usize>>,      // the lists we are in, with the next number for ordered ones
+    quote: bool,                    // whether we are inside a blockquote
+    links: &'a SiteLinks<'a>,
+    report: &'r mut Report
+}
+
+impl Converter<'_, '_> {
+    /// Helper function converting an element and everything below it
+    fn element(&mut self, element: ElementRef) {
+        let name = element.value().name();
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.flush();
+                let level = name[1..].parse::<u8>().unwrap_or(1);
+                let text = collapse(&element.text().collect::<String>());
+                if !text.is_empty() {
+                    self.document.heading(level.min(3), &text);
+                }
+                if level > 3 {
+                    self.report.note("heading below level 3 (shown as level 3)");
+                }
+            },
+            "a" => {
+                let label = collapse(&element.text().collect::<String>());
+                match element.value().attr("href").map(|href| self.links.rewrite(href)) {
+                    Some(Ok(target)) => self.pending.push((target, label)),
+                    Some(Err(kind)) => self.report.note(kind),
+                    None => ()
+                }
+                self.children(element);
+            },
+            "img" => {
+                let alt = element.value().attr("alt").unwrap_or("").trim().to_string();
+                match element.value().attr("src").map(|src| self.links.rewrite(src)) {
+                    Some(Ok(target)) => {
+                        self.flush();
+                        self.document.link(&target, if alt.is_empty() { "Image" } else { &alt });
+                    },
+                    Some(Err(kind)) => self.report.note(kind),
+                    None => ()
+                }
+            },
+            "br" => self.flush(),
+            "hr" => {
+                self.flush();
+                self.document.blank();
+            },
+            "pre" => {
+                self.flush();
+                let alt = element.value().classes().next().unwrap_or("").to_string();
+                let text: String = element.text().collect();
+                let lines = text.trim_end_matches('\n').lines().map(|l| l.to_string()).collect();
+                self.document.lines.push(GmlLine::Preformatted { alt, lines });
+            },
+            "blockquote" => {
+                self.flush();
+                let outer = std::mem::replace(&mut self.quote, true);
+                self.children(element);
+                self.flush();
+                self.quote = outer;
+            },
+            "ul" | "ol" | "menu" => {
+                self.flush();
+                self.lists.push(if name == "ol" { Some(1) } else { None });
+                self.children(element);
+                self.flush();
+                self.lists.pop();
+            },
+            "li" | "dt" => {
+                self.flush();
+                // a term outside of any list still reads best as an item
+                let outside = self.lists.is_empty();
+                if outside {
+                    self.lists.push(None);
+                }
+                self.children(element);
+                self.flush();
+                if let Some(Some(number)) = self.lists.last_mut() {
+                    *number += 1;
+                }
+                if outside {
+                    self.lists.pop();
+                }
+            },
+            "table" => {
+                self.flush();
+                self.report.note("<table> (flattened to text)");
+                self.children(element);
+                self.flush();
+            },
+            "thead" | "tbody" | "tfoot" | "caption" => self.children(element),
+            "tr" => {
+                self.flush();
+                self.children(element);
+                self.flush();
+            },
+            "td" | "th" => {
+                if !self.paragraph.is_empty() {
+                    self.paragraph.push_str(" | ");
+                }
+                self.children(element);
+            },
+            n if DROPPED_ELEMENTS.contains(&n) => self.report.note(&format!("<{}> (dropped)", n)),
+            n if CONTAINER_ELEMENTS.contains(&n) => {
+                self.flush();
+                self.children(element);
+                self.flush();
+            },
+            n if INLINE_ELEMENTS.contains(&n) => self.children(element),
+            n => {
+                self.report.note(&format!("<{}> (unknown, content kept)", n));
+                self.children(element);
+            }
+        }
+    }
+
+    /// Helper function converting the children of an element
+    fn children(&mut self, element: ElementRef) {
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => self.text(text),
+                Node::Element(_) => {
+                    if let Some(element) = ElementRef::wrap(child) {
+                        self.element(element);
+                    }
+                },
+                _ => ()
+            }
+        }
+    }
+
+    /// Helper function adding inline text to the paragraph, folding whitespace as browsers do
+    fn text(&mut self, text: &str) {
+        if text.starts_with(char::is_whitespace) && !self.paragraph.is_empty() && !self.paragraph.ends_with(' ') {
+            self.paragraph.push(' ');
+        }
+        self.paragraph.push_str(&collapse(text));
+        if text.ends_with(char::is_whitespace) && !self.paragraph.is_empty() && !self.paragraph.ends_with(' ') {
+            self.paragraph.push(' ');
+        }
+    }
+
+    /// Helper function ending the paragraph, then writing out the links it held
+    fn flush(&mut self) {
+        let text = collapse(&std::mem::take(&mut self.paragraph));
+        let pending = std::mem::take(&mut self.pending);
+
+        // a block holding nothing but a link is that link
+        if let [(target, label)] = &pending[..] {
+            if *label == text {
+                self.document.link(target, label);
+                return;
+            }
+        }
+
+        if !text.is_empty() {
+            match (self.lists.last(), self.quote) {
+                (Some(Some(number)), _) => self.document.list_item(&format!("{}. {}", number, text)),
+                (Some(None), _) => self.document.list_item(&text),
+                (None, true) => self.document.lines.push(GmlLine::Quote(text)),
+                (None, false) => self.document.text(&text)
+            }
+        }
+        for (target, label) in pending {
+            self.document.link(&target, &label);
+        }
+    }
+}
+
+
+/// Helper function folding runs of whitespace into single spaces
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Helper function dropping blank lines that don't separate anything
+fn tidy(document: &mut GmlDocument) {
+    let blank = |line: &GmlLine| matches!(line, GmlLine::Text(t) if t.is_empty());
+    let mut lines: Vec<GmlLine> = Vec::with_capacity(document.lines.len());
+    for line in document.lines.drain(..) {
+        if blank(&line) && lines.last().is_none_or(blank) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last().is_some_and(blank) {
+        lines.pop();
+    }
+    document.lines = lines;
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn pages_become_gml_with_mirrored_links() {
+        let root = std::env::temp_dir().join(format!("mirror-convert-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("std/vec")).unwrap();
+        for file in ["std/index.html", "std/vec/index.html", "std/vec/struct.Vec.html", "logo.png", "main.js"] {
+            fs::write(root.join(file), b"").unwrap();
+        }
+
+        let html = r##"<!DOCTYPE html><html><head><title>std - Rust</title><script src="../main.js"></script></head>
+            <body><nav><ul><li><a href="../std/index.html">std</a></li><li><a href="vec/">vec</a></li></ul></nav>
+            <p>A <em>growable</em> list, see <a href="vec/struct.Vec.html#method.push">Vec::push</a> and
+            <a href="https://doc.rust-lang.org/">the book</a>.</p>
+            <h4>Details &amp; more</h4>
+            <pre class="rust"><code>let v = vec![1, 2];
+v.push(3);</code></pre>
+            <ol><li>first</li><li>second</li></ol>
+            <blockquote><p>quoted</p></blockquote>
+            <table><tr><th>a</th><th>b</th></tr><tr><td>1</td><td>2</td></tr></table>
+            <img src="../logo.png" alt="Logo"><a href="missing.html">gone</a> <a href="#top">top</a>
+            <a href="../main.js">script</a> <form><input></form><custom-thing>kept</custom-thing></body></html>"##;
+
+        let mut report = Report::default();
+        let links = SiteLinks::new(&root, "/std/index.html", "grid!docs:7500/rust");
+        let document = convert_page(html, &links, &mut report);
+        let lines = &document.lines;
+
+        assert_eq!(lines[0], GmlLine::Heading(1, "std - Rust".to_string()));
+        assert_eq!(lines[1], GmlLine::Link { target: "grid!docs:7500/rust/std/index.gml".to_string(), label: "std".to_string() });
+        assert_eq!(lines[2], GmlLine::Link { target: "grid!docs:7500/rust/std/vec/index.gml".to_string(), label: "vec".to_string() });
+        assert_eq!(lines[3], GmlLine::Text("A growable list, see Vec::push and the book.".to_string()));
+        assert_eq!(lines[4], GmlLine::Link { target: "grid!docs:7500/rust/std/vec/struct.Vec.gml".to_string(), label: "Vec::push".to_string() });
+        assert_eq!(lines[5], GmlLine::Link { target: "https://doc.rust-lang.org/".to_string(), label: "the book".to_string() });
+        assert_eq!(lines[6], GmlLine::Heading(3, "Details & more".to_string()));
+        assert_eq!(lines[7], GmlLine::Preformatted { alt: "rust".to_string(), lines: vec!["let v = vec![1, 2];".to_string(), "v.push(3);".to_string()] });
+        assert_eq!(lines[8..10], [GmlLine::ListItem("1. first".to_string()), GmlLine::ListItem("2. second".to_string())]);
+        assert_eq!(lines[10], GmlLine::Quote("quoted".to_string()));
+        assert_eq!(lines[11..13], [GmlLine::Text("a | b".to_string()), GmlLine::Text("1 | 2".to_string())]);
+        assert_eq!(lines[13], GmlLine::Link { target: "grid!docs:7500/rust/logo.png".to_string(), label: "Logo".to_string() });
+
+        for (what, count) in [
+            ("<table> (flattened to text)", 1), ("broken link", 1), ("in-page link", 1), ("link to a script or stylesheet", 1),
+            ("<form> (dropped)", 1), ("<custom-thing> (unknown, content kept)", 1), ("heading below level 3 (shown as level 3)", 1)
+        ] {
+            assert_eq!(report.unsupported.get(what), Some(&count), "{}: {:?}", what, report);
+        }
+        assert!(document.to_string().contains("gone top script kept"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
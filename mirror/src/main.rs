@@ -0,0 +1,311 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use clap::Parser;
+
+use walkdir::WalkDir;
+
+use grid::path::{normalize_path, percent_encode_path};
+use grid::definitions::string_to_domain;
+use grid::gml::GmlDocument;
+
+mod convert;
+
+use convert::{Report, SiteLinks, convert_page, is_skipped, mirrored_path};
+
+
+/// Name of the document listing everything in the mirror
+const MANIFEST: &str = "manifest.gml";
+
+
+#[derive(Parser, Debug)]
+#[command(term_width = 0)]
+struct Arguments {
+    /// Directory holding the HTML tree, e.g. `target/doc`
+    source: PathBuf,
+
+    /// Directory to write the document root to
+    dest: PathBuf,
+
+    /// Server the mirror will be served from, e.g. `grid!docs.example.org:7500`
+    #[arg(short='r', long="remote")]
+    remote: String,
+
+    /// Path the document root will be served at on that server
+    #[arg(short='p', long="prefix", default_value="/")]
+    prefix: String,
+
+    /// Write into the destination even if it is not empty
+    #[arg(short='f', long="force")]
+    force: bool
+}
+
+
+/// structure describing what a run produced
+#[derive(Debug, Default)]
+struct Summary {
+    pages: Vec<(String, String)>,   // converted documents and their titles
+    files: Vec<String>,             // files copied as they were
+    skipped: usize,                 // scripts, stylesheets and fonts left out
+    report: Report
+}
+
+
+/// Mirrors an HTML tree into a GML document root
+///
+/// ## Params:
+/// * source: the directory holding the HTML tree
+/// * dest: the directory to write to
+/// * base: the remote and prefix the document root is served at, without a trailing slash
+/// * force: whether to write into a destination that is not empty
+///
+/// ## Returns:
+/// * Ok: what was written and what could not be carried over
+/// * Err: a string describing the issue encountered
+fn mirror(source: &Path, dest: &Path, base: &str, force: bool) -> Result<Summary, String> {
+    let source = match source.canonicalize() {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Failed to open {}: {}", source.display(), e))
+    };
+    if !source.is_dir() {
+        return Err(format!("{} is not a directory", source.display()));
+    }
+    // walking the source would otherwise pick up our own output, so check before creating anything
+    let dest = resolve(dest)?;
+    if dest.starts_with(&source) {
+        return Err(format!("{} is inside {}", dest.display(), source.display()));
+    }
+    if let Err(e) = fs::create_dir_all(&dest) {
+        return Err(format!("Failed to create {}: {}", dest.display(), e));
+    }
+    let empty = match fs::read_dir(&dest) {
+        Ok(mut a) => a.next().is_none(),
+        Err(e) => return Err(format!("Failed to read {}: {}", dest.display(), e))
+    };
+    if !empty && !force {
+        return Err(format!("{} is not empty, use --force to write into it anyway", dest.display()));
+    }
+
+    let mut summary = Summary::default();
+    let mut written: BTreeSet<String> = BTreeSet::from([format!("/{}", MANIFEST)]);
+    let walk = WalkDir::new(&source)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'));
+
+    let mut entries: Vec<(PathBuf, String)> = Vec::new();
+    for entry in walk {
+        let entry = match entry {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Failed to walk {}: {}", source.display(), e))
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        match entry.path().strip_prefix(&source).ok().and_then(|p| p.to_str()) {
+            Some(a) => entries.push((entry.path().to_path_buf(), format!("/{}", a.replace('\\', "/")))),
+            None => summary.report.note("file name that is not UTF-8 (skipped)")
+        }
+    }
+    // pages go first, so they win over files already named like their GML versions
+    entries.sort_by_key(|(_, relative)| (mirrored_path(relative) == *relative, relative.clone()));
+
+    for (path, relative) in entries {
+        if is_skipped(&path) {
+            summary.skipped += 1;
+            continue;
+        }
+
+        // `a.html` and `a.gml` side by side would end up in the same place
+        let target = mirrored_path(&relative);
+        if !written.insert(target.clone()) {
+            summary.report.note("name clash with another file (skipped)");
+            continue;
+        }
+        let out = dest.join(target.trim_start_matches('/'));
+        if let Some(parent) = out.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                return Err(format!("Failed to create {}: {}", parent.display(), e));
+            }
+        }
+
+        if target == relative {
+            if let Err(e) = fs::copy(&path, &out) {
+                return Err(format!("Failed to copy {}: {}", path.display(), e));
+            }
+            summary.files.push(target);
+            continue;
+        }
+
+        let html = match fs::read(&path) {
+            Ok(a) => String::from_utf8_lossy(&a).into_owned(),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e))
+        };
+        let document = convert_page(&html, &SiteLinks::new(&source, &relative, base), &mut summary.report);
+        if let Err(e) = fs::write(&out, document.to_string()) {
+            return Err(format!("Failed to write {}: {}", out.display(), e));
+        }
+        let title = document.title().unwrap_or(&target).to_string();
+        summary.pages.push((target, title));
+    }
+
+    let manifest = dest.join(MANIFEST);
+    if let Err(e) = fs::write(&manifest, render_manifest(&summary, base).to_string()) {
+        return Err(format!("Failed to write {}: {}", manifest.display(), e));
+    }
+    Ok(summary)
+}
+
+/// Resolves a path that may not exist yet
+///
+/// The part that exists is canonicalized, so links in it are followed, and the
+/// rest is appended the way `create_dir_all` would create it.
+///
+/// ## Params:
+/// * path: the path to resolve, relative to the working directory or absolute
+///
+/// ## Returns:
+/// * Ok: the absolute path
+/// * Err: a string describing the issue encountered
+fn resolve(path: &Path) -> Result<PathBuf, String> {
+    let absolute = match std::path::absolute(path) {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Failed to resolve {}: {}", path.display(), e))
+    };
+    let existing = match absolute.ancestors().find(|a| a.exists()) {
+        Some(a) => a,
+        None => return Err(format!("Failed to resolve {}: no part of it exists", path.display()))
+    };
+    let mut resolved = match existing.canonicalize() {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Failed to open {}: {}", existing.display(), e))
+    };
+    for component in absolute.strip_prefix(existing).unwrap_or(Path::new("")).components() {
+        match component {
+            Component::ParentDir => { resolved.pop(); },
+            Component::Normal(name) => resolved.push(name),
+            _ => {}
+        }
+    }
+    Ok(resolved)
+}
+
+/// Lists the documents and files of the mirror, along with what was lost converting them
+///
+/// ## Params:
+/// * summary: what the run produced
+/// * base: the remote and prefix the document root is served at
+///
+/// ## Returns:
+/// * the manifest document
+fn render_manifest(summary: &Summary, base: &str) -> GmlDocument {
+    let mut document = GmlDocument::new();
+    document.heading(1, "Mirror manifest");
+    document.text(&format!("{} documents converted, {} files copied, {} skipped.", summary.pages.len(), summary.files.len(), summary.skipped));
+
+    document.blank();
+    document.heading(2, "Documents");
+    for (path, title) in &summary.pages {
+        document.link(&format!("{}{}", base, percent_encode_path(path)), title);
+    }
+
+    if !summary.files.is_empty() {
+        document.blank();
+        document.heading(2, "Files");
+        for path in &summary.files {
+            document.link(&format!("{}{}", base, percent_encode_path(path)), path);
+        }
+    }
+
+    if !summary.report.unsupported.is_empty() {
+        document.blank();
+        document.heading(2, "Unsupported constructs");
+        for (what, count) in summary.report.by_count() {
+            document.list_item(&format!("{}: {}", what, count));
+        }
+    }
+    document
+}
+
+
+fn main() {
+    let args = Arguments::parse();
+
+    if let Err(e) = string_to_domain(&args.remote) {
+        eprintln!("Invalid remote {}: {}", args.remote, e);
+        std::process::exit(1);
+    }
+    let prefix = normalize_path(&args.prefix);
+    let base = format!("{}{}", args.remote.trim_end_matches('/'), prefix.trim_end_matches('/'));
+
+    let summary = match mirror(&args.source, &args.dest, &base, args.force) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    println!(
+        "Converted {} pages, copied {} files, skipped {} scripts, stylesheets and fonts",
+        summary.pages.len(), summary.files.len(), summary.skipped
+    );
+    if !summary.report.unsupported.is_empty() {
+        println!("Unsupported constructs:");
+        for (what, count) in summary.report.by_count() {
+            println!("{:>8}  {}", count, what);
+        }
+    }
+    println!("Serve {} at {} and start from {}/{}", args.dest.display(), prefix, base, MANIFEST);
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trees_are_mirrored_with_a_manifest() {
+        let root = std::env::temp_dir().join(format!("mirror-test-{}", std::process::id()));
+        let (source, dest) = (root.join("doc"), root.join("out"));
+        fs::create_dir_all(source.join("crate")).unwrap();
+        fs::create_dir_all(source.join(".hidden")).unwrap();
+        fs::write(source.join("crate/index.html"), "<html><head><title>crate</title></head><body><p><a href=\"../help.html\">Help</a></p><script>x()</script></body></html>").unwrap();
+        fs::write(source.join("help.html"), "<h1>Help</h1><p>Read <a href=\"crate/\">the docs</a>.</p>").unwrap();
+        fs::write(source.join("help.gml"), "# Clash").unwrap();
+        fs::write(source.join("logo.svg"), "<svg/>").unwrap();
+        fs::write(source.join("search.js"), "").unwrap();
+        fs::write(source.join(".hidden/secret.html"), "").unwrap();
+
+        let summary = mirror(&source, &dest, "grid!docs:7500/rust", false).unwrap();
+        assert_eq!(summary.pages, vec![
+            ("/crate/index.gml".to_string(), "crate".to_string()),
+            ("/help.gml".to_string(), "Help".to_string())
+        ]);
+        assert_eq!(summary.files, vec!["/logo.svg".to_string()]);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.report.unsupported.get("name clash with another file (skipped)"), Some(&1));
+        assert_eq!(summary.report.unsupported.get("<script> (dropped)"), Some(&1));
+
+        let page = fs::read_to_string(dest.join("crate/index.gml")).unwrap();
+        assert_eq!(page, "# crate\n=> grid!docs:7500/rust/help.gml Help\n");
+        let help = fs::read_to_string(dest.join("help.gml")).unwrap();
+        assert!(help.contains("=> grid!docs:7500/rust/crate/index.gml the docs"));
+        assert!(!dest.join(".hidden").exists());
+        assert!(!dest.join("search.js").exists());
+
+        let manifest = fs::read_to_string(dest.join(MANIFEST)).unwrap();
+        assert!(manifest.contains("=> grid!docs:7500/rust/help.gml Help"));
+        assert!(manifest.contains("=> grid!docs:7500/rust/logo.svg /logo.svg"));
+        assert!(manifest.contains("* <script> (dropped): 1"));
+
+        // a second run must not clobber the first by accident
+        assert!(mirror(&source, &dest, "grid!docs:7500/rust", false).is_err());
+        assert!(mirror(&source, &dest, "grid!docs:7500/rust", true).is_ok());
+        assert!(mirror(&source, &source.join("crate/out"), "grid!docs:7500/rust", true).is_err());
+        assert!(mirror(&source, &root.join("new/../doc/out"), "grid!docs:7500/rust", true).is_err());
+        assert!(!source.join("crate/out").exists() && !source.join("out").exists() && !root.join("new").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}